@@ -0,0 +1,293 @@
+use crate::command_executor::{self, Command, CommandContext};
+use crate::log_entry::LogLevel;
+use anyhow::Result;
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+/// Largest request head accepted; bodies are never read
+const MAX_REQUEST_HEAD_BYTES: usize = 8192;
+/// How long a client gets to send its request head
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the local admin HTTP endpoints on `listener` until the task is dropped:
+/// - `GET /health`: the `node_health` report
+/// - `GET /metrics`: `probe_task_restarts_total` in the Prometheus text format
+/// - `DELETE /buffer[?level=<min_level>]`: clear the buffer, or only the entries below `min_level`
+/// - `POST /buffer/snapshot`: run `capture_snapshot`
+///
+/// The buffer endpoints need an `X-Admin-Token` header matching `admin_token`,
+/// and are refused with 403 while no token is configured.
+pub async fn run(listener: TcpListener, ctx: CommandContext) {
+    if let Ok(address) = listener.local_addr() {
+        info!("Admin server listening on {}", address);
+    }
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Admin server accept failed: {}", e);
+                continue;
+            }
+        };
+
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &ctx).await {
+                warn!("Admin request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Response body, JSON unless Prometheus metrics were asked for
+enum Body {
+    Json(serde_json::Value),
+    Text(String),
+}
+
+/// Method, path, query string and `X-Admin-Token` of a request
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    admin_token: Option<String>,
+}
+
+async fn handle(mut stream: TcpStream, ctx: &CommandContext) -> Result<()> {
+    let (status, body) = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(Some(request))) if (request.method.as_str(), request.path.as_str()) == ("GET", "/metrics") => {
+            (200, Body::Text(ctx.task_restarts.to_prometheus()))
+        }
+        Ok(Ok(Some(request))) => {
+            let (status, body) = route(&request, ctx).await;
+            (status, Body::Json(body))
+        }
+        Ok(Ok(None)) => (400, Body::Json(serde_json::json!({ "error": "malformed request" }))),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => (408, Body::Json(serde_json::json!({ "error": "request timed out" }))),
+    };
+
+    let (content_type, body) = match body {
+        Body::Json(body) => ("application/json", body.to_string()),
+        Body::Text(body) => ("text/plain; version=0.0.4", body),
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read up to the end of the request head. `None` if it is malformed or too long.
+async fn read_request(stream: &mut TcpStream) -> Result<Option<Request>> {
+    let mut head = Vec::new();
+    let mut chunk = [0; 1024];
+    let end = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        head.extend_from_slice(&chunk[..n]);
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if head.len() > MAX_REQUEST_HEAD_BYTES {
+            return Ok(None);
+        }
+    };
+
+    let Ok(head) = std::str::from_utf8(&head[..end]) else {
+        return Ok(None);
+    };
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Ok(None);
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let admin_token = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("x-admin-token").then(|| value.trim().to_string())
+    });
+
+    Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        admin_token,
+    }))
+}
+
+async fn route(request: &Request, ctx: &CommandContext) -> (u16, serde_json::Value) {
+    let needs_token = request.path.starts_with("/buffer");
+    if needs_token && !authorized(request, ctx.config.admin_token.as_deref()) {
+        warn!("Refused admin request {} {} without a valid token", request.method, request.path);
+        return (403, serde_json::json!({ "error": "missing or wrong X-Admin-Token" }));
+    }
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/health") => run_command(ctx, "node_health").await,
+        ("DELETE", "/buffer") => clear_buffer(&request.query, ctx).await,
+        ("POST", "/buffer/snapshot") => run_command(ctx, "capture_snapshot").await,
+        (_, "/health" | "/metrics" | "/buffer" | "/buffer/snapshot") => (405, serde_json::json!({ "error": "method not allowed" })),
+        _ => (404, serde_json::json!({ "error": "not found" })),
+    }
+}
+
+/// Whether `request` carries `admin_token`; never true without a configured token
+fn authorized(request: &Request, admin_token: Option<&str>) -> bool {
+    match (admin_token, request.admin_token.as_deref()) {
+        (Some(expected), Some(given)) => !expected.is_empty() && expected == given,
+        _ => false,
+    }
+}
+
+/// Drop every buffered entry, or only those below `level=` when given
+async fn clear_buffer(query: &str, ctx: &CommandContext) -> (u16, serde_json::Value) {
+    let level = query.split('&').find_map(|pair| pair.strip_prefix("level="));
+    let min_level = match level.map(|level| (level, LogLevel::parse(level))) {
+        Some((level, None)) => return (400, serde_json::json!({ "error": format!("Unknown log level '{}'", level) })),
+        Some((_, min_level)) => min_level,
+        None => None,
+    };
+
+    let mut buffer = ctx.buffer.write().await;
+    let cleared = match min_level {
+        Some(min_level) => buffer.drain_below_level(min_level),
+        None => buffer.clear(),
+    };
+    warn!("Admin request cleared {} buffered log entries", cleared);
+    (200, serde_json::json!({ "cleared": cleared, "remaining": buffer.len() }))
+}
+
+async fn run_command(ctx: &CommandContext, name: &str) -> (u16, serde_json::Value) {
+    let command = Command {
+        command: name.to_string(),
+        parameters: serde_json::Value::Null,
+    };
+    match command_executor::execute_command(command, ctx).await {
+        Ok(result) => (200, result.data),
+        Err(e) => (500, serde_json::json!({ "error": e.to_string() })),
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_executor::testing;
+    use crate::config::Config;
+    use crate::log_entry::LogEntry;
+
+    const TEST_CONFIG: &str = "usb_port = \"/dev/null\"\nserver_url = \"http://127.0.0.1:9\"\napi_key = \"k\"\nnode_id = 1\n\
+                               node_firmware_url = \"http://127.0.0.1:9/node\"\nprobe_firmware_url = \"http://127.0.0.1:9/probe\"\n";
+
+    /// An admin server for a context with `settings` added to the config and
+    /// a buffer holding one entry per level, plus its base URL
+    async fn server(settings: &str) -> (CommandContext, String) {
+        let config: Config = toml::from_str(&format!("{}{}", TEST_CONFIG, settings)).unwrap();
+        let (ctx, _mock) = testing::context(config);
+        for level in ["DEBUG", "INFO", "WARN", "ERROR"] {
+            let entry = LogEntry::new("2024-05-01T12:00:00Z".to_string(), format!("[{}] message", level));
+            ctx.buffer.write().await.push(entry);
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(run(listener, ctx.clone()));
+        (ctx, url)
+    }
+
+    async fn delete(url: &str, token: Option<&str>) -> (u16, serde_json::Value) {
+        let mut request = reqwest::Client::new().delete(url);
+        if let Some(token) = token {
+            request = request.header("X-Admin-Token", token);
+        }
+        let response = request.send().await.unwrap();
+        (response.status().as_u16(), response.json().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn buffer_endpoints_need_the_configured_token() {
+        let (ctx, url) = server("").await;
+        assert_eq!(delete(&format!("{}/buffer", url), Some("anything")).await.0, 403);
+
+        let (ctx_with_token, url_with_token) = server("admin_token = \"letmein\"\n").await;
+        assert_eq!(delete(&format!("{}/buffer", url_with_token), None).await.0, 403);
+        assert_eq!(delete(&format!("{}/buffer", url_with_token), Some("wrong")).await.0, 403);
+        let snapshot = reqwest::Client::new().post(format!("{}/buffer/snapshot", url_with_token)).send().await.unwrap();
+        assert_eq!(snapshot.status().as_u16(), 403);
+
+        assert_eq!(ctx.buffer.read().await.len(), 4);
+        assert_eq!(ctx_with_token.buffer.read().await.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn delete_buffer_clears_everything_or_below_a_level() {
+        let (ctx, url) = server("admin_token = \"letmein\"\n").await;
+
+        let (status, body) = delete(&format!("{}/buffer?level=warn", url), Some("letmein")).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, serde_json::json!({ "cleared": 2, "remaining": 2 }));
+        assert_eq!(delete(&format!("{}/buffer?level=loud", url), Some("letmein")).await.0, 400);
+
+        let (status, body) = delete(&format!("{}/buffer", url), Some("letmein")).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, serde_json::json!({ "cleared": 2, "remaining": 0 }));
+        assert_eq!(ctx.buffer.read().await.total_dropped(), 4);
+    }
+
+    #[tokio::test]
+    async fn snapshot_and_health_run_their_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_ctx, url) = server(&format!("admin_token = \"letmein\"\nsnapshot_dir = {:?}\n", dir.path())).await;
+        let client = reqwest::Client::new();
+
+        let response = client.post(format!("{}/buffer/snapshot", url)).header("X-Admin-Token", "letmein").send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["entry_count"], 4);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let response = client.get(format!("{}/health", url)).send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.json::<serde_json::Value>().await.unwrap()["health"].is_string());
+
+        assert_eq!(client.get(format!("{}/buffer", url)).send().await.unwrap().status().as_u16(), 403);
+        assert_eq!(client.put(format!("{}/health", url)).send().await.unwrap().status().as_u16(), 405);
+        assert_eq!(client.get(format!("{}/nothing", url)).send().await.unwrap().status().as_u16(), 404);
+    }
+
+    #[tokio::test]
+    async fn metrics_count_task_restarts() {
+        let (ctx, url) = server("").await;
+        ctx.task_restarts.record("telemetry_sync");
+        ctx.task_restarts.record("telemetry_sync");
+
+        let response = reqwest::get(format!("{}/metrics", url)).await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["content-type"], "text/plain; version=0.0.4");
+        let text = response.text().await.unwrap();
+        assert!(text.contains("# TYPE probe_task_restarts_total counter\n"));
+        assert!(text.contains("probe_task_restarts_total{task=\"telemetry_sync\"} 2\n"));
+    }
+}
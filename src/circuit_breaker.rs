@@ -0,0 +1,262 @@
+use crate::error::ProbeError;
+use log::{info, warn};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::time::{Duration, Instant};
+use tower::{BoxError, Layer, Service};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests go through
+    Closed,
+    /// Requests fail right away until the open timeout has passed
+    Open,
+    /// One trial request decides whether to close or reopen
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Stops requests to a server that keeps failing, shared by every clone of
+/// the service it guards
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_timeout: Duration,
+    inner: Mutex<BreakerState>,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    /// Consecutive failures while closed
+    failure_count: u32,
+    success_count: u64,
+    opened_at: Instant,
+    trial_in_flight: bool,
+}
+
+impl CircuitBreaker {
+    /// Open after `failure_threshold` consecutive failures (0 never opens) and
+    /// try again after `open_timeout`
+    pub fn new(failure_threshold: u32, open_timeout: Duration) -> Self {
+        Self {
+            failure_threshold,
+            open_timeout,
+            inner: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                failure_count: 0,
+                success_count: 0,
+                opened_at: Instant::now(),
+                trial_in_flight: false,
+            }),
+        }
+    }
+
+    /// Admit a request, moving from open to half-open once the timeout has passed
+    fn try_acquire(&self) -> Result<(), ProbeError> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == CircuitState::Open {
+            let elapsed = inner.opened_at.elapsed();
+            if elapsed < self.open_timeout {
+                return Err(ProbeError::CircuitOpen {
+                    retry_in_seconds: (self.open_timeout - elapsed).as_secs(),
+                });
+            }
+            info!("Upload circuit half-open, sending a trial request");
+            inner.state = CircuitState::HalfOpen;
+            inner.trial_in_flight = false;
+        }
+
+        if inner.state == CircuitState::HalfOpen {
+            if inner.trial_in_flight {
+                return Err(ProbeError::CircuitOpen { retry_in_seconds: 0 });
+            }
+            inner.trial_in_flight = true;
+        }
+        Ok(())
+    }
+
+    fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state != CircuitState::Closed {
+            info!("Upload circuit closed, the hub is answering again");
+        }
+        inner.state = CircuitState::Closed;
+        inner.failure_count = 0;
+        inner.success_count += 1;
+        inner.trial_in_flight = false;
+    }
+
+    fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failure_count = inner.failure_count.saturating_add(1);
+        let trips = match inner.state {
+            CircuitState::HalfOpen => true,
+            CircuitState::Closed => self.failure_threshold > 0 && inner.failure_count >= self.failure_threshold,
+            CircuitState::Open => false,
+        };
+        if trips {
+            warn!(
+                "Upload circuit open after {} consecutive failures, pausing uploads for {}s",
+                inner.failure_count,
+                self.open_timeout.as_secs()
+            );
+            inner.state = CircuitState::Open;
+            inner.opened_at = Instant::now();
+        }
+        inner.trial_in_flight = false;
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let inner = self.inner.lock().unwrap();
+        serde_json::json!({
+            "state": inner.state.as_str(),
+            "failure_count": inner.failure_count,
+            "success_count": inner.success_count,
+        })
+    }
+}
+
+/// Wraps a service with a shared `CircuitBreaker`. Only errors `is_failure`
+/// accepts count against the server; any other answer shows it is up.
+#[derive(Clone)]
+pub struct CircuitBreakerLayer {
+    breaker: Arc<CircuitBreaker>,
+    is_failure: fn(&BoxError) -> bool,
+}
+
+impl CircuitBreakerLayer {
+    pub fn new(breaker: Arc<CircuitBreaker>, is_failure: fn(&BoxError) -> bool) -> Self {
+        Self { breaker, is_failure }
+    }
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer {
+    type Service = CircuitBreakerService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CircuitBreakerService {
+            inner,
+            breaker: Arc::clone(&self.breaker),
+            is_failure: self.is_failure,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CircuitBreakerService<S> {
+    inner: S,
+    breaker: Arc<CircuitBreaker>,
+    is_failure: fn(&BoxError) -> bool,
+}
+
+impl<S, Request> Service<Request> for CircuitBreakerService<S>
+where
+    S: Service<Request>,
+    S::Response: Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if let Err(e) = self.breaker.try_acquire() {
+            return Box::pin(std::future::ready(Err(e.into())));
+        }
+
+        let future = self.inner.call(request);
+        let breaker = Arc::clone(&self.breaker);
+        let is_failure = self.is_failure;
+        Box::pin(async move {
+            let result = future.await.map_err(Into::into);
+            match &result {
+                Err(e) if is_failure(e) => breaker.record_failure(),
+                _ => breaker.record_success(),
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPEN_TIMEOUT: Duration = Duration::from_secs(30);
+
+    fn state(breaker: &CircuitBreaker) -> CircuitState {
+        breaker.inner.lock().unwrap().state
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn walks_closed_open_half_open_closed() {
+        let breaker = CircuitBreaker::new(3, OPEN_TIMEOUT);
+        for _ in 0..2 {
+            breaker.try_acquire().unwrap();
+            breaker.record_failure();
+        }
+        assert_eq!(state(&breaker), CircuitState::Closed);
+
+        breaker.try_acquire().unwrap();
+        breaker.record_failure();
+        assert_eq!(state(&breaker), CircuitState::Open);
+        assert!(matches!(breaker.try_acquire(), Err(ProbeError::CircuitOpen { retry_in_seconds: 30 })));
+
+        tokio::time::advance(OPEN_TIMEOUT).await;
+        breaker.try_acquire().unwrap();
+        assert_eq!(state(&breaker), CircuitState::HalfOpen);
+        // Only the single trial request goes through while it is in flight
+        assert!(matches!(breaker.try_acquire(), Err(ProbeError::CircuitOpen { retry_in_seconds: 0 })));
+
+        breaker.record_success();
+        assert_eq!(state(&breaker), CircuitState::Closed);
+        assert_eq!(breaker.to_json()["failure_count"], 0);
+        breaker.try_acquire().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_trial_reopens() {
+        let breaker = CircuitBreaker::new(1, OPEN_TIMEOUT);
+        breaker.record_failure();
+        tokio::time::advance(OPEN_TIMEOUT).await;
+        breaker.try_acquire().unwrap();
+        breaker.record_failure();
+        assert_eq!(state(&breaker), CircuitState::Open);
+        assert!(breaker.try_acquire().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn success_resets_the_failure_streak() {
+        let breaker = CircuitBreaker::new(2, OPEN_TIMEOUT);
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(state(&breaker), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_threshold_never_opens() {
+        let breaker = CircuitBreaker::new(0, OPEN_TIMEOUT);
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert_eq!(state(&breaker), CircuitState::Closed);
+    }
+}
@@ -1,13 +1,101 @@
-use crate::config::Config;
-use crate::update_manager;
+use crate::compress::{self, CompressionFormat};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::{self, Config, ConfigSources};
+use crate::connectivity;
+use crate::debug_port::DebugPort;
+use crate::file_stream::FileStream;
+use crate::error::{self, ProbeError};
+use crate::error_reporter::{ErrorReporter, PendingErrors};
+use crate::log_buffer::LogBuffer;
+use crate::log_entry::{LogEntry, LogLevel};
+use crate::log_tail::LogTail;
+use crate::runtime_metrics::RuntimeMetrics;
+use crate::s3_export::{self, ExportTarget};
+use crate::task_supervisor::RestartTracker;
+use crate::telemetry_sync::UploadStats;
+use crate::update_manager::{self, FirmwareComponent};
+use crate::update_state::UpdateTracker;
 use crate::usb_manager::UsbHandle;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
-use log::{error, info, warn};
-use serde::Deserialize;
+use chrono_tz::Tz;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tokio::time::Duration;
+use tokio::sync::{broadcast, Mutex, Notify, RwLock};
+use tokio::time::{Duration, Instant};
+
+/// Node reply to `/PING`
+const NODE_PING_PREFIX: &str = "PONG";
+const PING_COUNT: usize = 10;
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Longest timeout the RP2040 hardware watchdog supports
+const MAX_WATCHDOG_TIMEOUT_MS: u32 = 8300;
+/// Accepted `set_node_log_output` values; "usb" is the node's default
+const NODE_LOG_OUTPUTS: [&str; 4] = ["usb", "uart", "rtt", "silent"];
+/// `set_node_power_mode` values and the letter of each in `/PM_<letter>_`
+/// and the `/PMQ` reply; "full" is the node's default
+const NODE_POWER_MODES: [(&str, char); 4] = [("full", 'F'), ("low", 'L'), ("sleep", 'S'), ("dormant", 'D')];
+/// Prefix of the node's reply to `/PMQ`
+const POWER_MODE_PREFIX: &str = "PM:";
+const POWER_MODE_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// `node_health` reports "degraded" above this buffer fill or USB round-trip time
+const HEALTH_BUFFER_FILL_PERCENT: f64 = 80.0;
+const HEALTH_USB_RTT_MS: f64 = 100.0;
+/// `get_node_info` fields with the node command answering each and its reply prefix
+const NODE_INFO_QUERIES: [(&str, &str, &str); 4] = [
+    ("version", "/VQ", update_manager::NODE_VERSION_PREFIX),
+    ("uptime_seconds", "/UPTIME", "UPTIME:"),
+    ("temperature_celsius", "/TEMP", "TEMP:"),
+    ("free_heap_bytes", "/HEAP", "HEAP:"),
+];
+/// Longest key `rotate_api_key` accepts
+const MAX_API_KEY_LENGTH: usize = 256;
+/// Node reply to `/SRQ`
+const SAMPLING_RATE_PREFIX: &str = "SR:";
+const SAMPLING_RATE_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// `confirm` value `factory_reset` requires
+const FACTORY_RESET_CONFIRMATION: &str = "FACTORY_RESET";
+
+/// Node reply to `/MQ`, followed by `<running>:<sequence>:<samples_collected>:<elapsed_ms>`
+const MEASUREMENT_STATUS_PREFIX: &str = "MSTATUS:";
+const MEASUREMENT_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Node reply to `/RTCQ`, followed by `<yyyy>-<mm>-<dd>T<HH>:<MM>:<SS>`
+const RTC_PREFIX: &str = "RTC:";
+const RTC_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Upper bounds of the command duration histogram buckets, in ms
+const COMMAND_DURATION_BUCKETS_MS: [u64; 7] = [10, 50, 100, 500, 1000, 5000, 10000];
+
+/// Slowest commands listed in a diagnostics report
+const DIAGNOSTICS_SLOWEST_COMMANDS: usize = 3;
+
+/// Entries per level returned by get_sample_logs when `n_per_level` is not given
+const DEFAULT_SAMPLE_PER_LEVEL: usize = 10;
+
+/// Commands run without waiting for `command_lock`
+const LOCK_FREE_COMMANDS: &[&str] = &["cancel_update"];
+
+/// Commands that take `command_lock` and then run in the background, so the
+/// transport that received them can go on taking commands (`cancel_update`)
+/// while they run. Their outcome shows up in the update state and events.
+const BACKGROUND_COMMANDS: &[&str] = &["update_node"];
+
+/// Longest stream_to_file capture
+const MAX_STREAM_DURATION_SECONDS: u64 = 3600;
+
+/// Longest performance_test run
+const MAX_PERFORMANCE_TEST_SECONDS: u64 = 60;
+/// How often performance_test samples the buffer fill
+const PERFORMANCE_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Newest buffered log entries included in a diagnostics report
+const DIAGNOSTICS_LOG_ENTRIES: usize = 100;
 
 /// Schedule for upload intervals with active/inactive periods
 #[derive(Debug, Clone)]
@@ -16,13 +104,40 @@ pub struct UploadSchedule {
     pub end_time: Option<DateTime<Utc>>,
     pub active_period: u64,
     pub inactive_period: u64,
+    /// Half-width of the ramp between the two periods around each window
+    /// boundary; 0 switches instantly
+    pub smooth_transition_seconds: u64,
 }
 
 impl UploadSchedule {
+    /// A fixed interval with no active window
+    pub fn fixed(period: u64) -> Self {
+        Self {
+            start_time: None,
+            end_time: None,
+            active_period: period,
+            inactive_period: period,
+            smooth_transition_seconds: 0,
+        }
+    }
+
     /// Calculate the current upload interval based on whether we're in the active window
     pub fn current_interval(&self) -> u64 {
+        self.interval_at(Utc::now())
+    }
+
+    fn interval_at(&self, now: DateTime<Utc>) -> u64 {
         if let (Some(start), Some(end)) = (self.start_time, self.end_time) {
-            let now = Utc::now();
+            let smooth = chrono::Duration::seconds(self.smooth_transition_seconds as i64);
+            if self.smooth_transition_seconds > 0 {
+                // Ramp linearly across [boundary - smooth, boundary + smooth]
+                if now >= start - smooth && now <= start + smooth {
+                    return interpolate(self.inactive_period, self.active_period, now - (start - smooth), smooth * 2);
+                }
+                if now >= end - smooth && now <= end + smooth {
+                    return interpolate(self.active_period, self.inactive_period, now - (end - smooth), smooth * 2);
+                }
+            }
             if now >= start && now <= end {
                 // We're in the active window
                 return self.active_period;
@@ -31,9 +146,61 @@ impl UploadSchedule {
         // Outside the active window (or no window defined)
         self.inactive_period
     }
+
+    /// The next window boundary (start or end) that is still in the future
+    pub fn next_change_at(&self) -> Option<DateTime<Utc>> {
+        self.next_change_after(Utc::now())
+    }
+
+    fn next_change_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        [self.start_time, self.end_time].into_iter().flatten().filter(|t| *t > now).min()
+    }
+
+    /// How long until `next_change_at`, or None when no boundary is ahead
+    pub fn time_until_next_change(&self) -> Option<Duration> {
+        self.next_change_at().and_then(|t| (t - Utc::now()).to_std().ok())
+    }
+}
+
+/// Measurement state reported by the node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MeasurementStatus {
+    pub running: bool,
+    pub sequence: u32,
+    pub samples_collected: u64,
+    pub elapsed_ms: u64,
+}
+
+impl MeasurementStatus {
+    /// `MSTATUS:true:5:1200:3000`; `running` may also be `1` or `0`
+    fn parse(reply: &str) -> Result<Self, ProbeError> {
+        let unreadable = || ProbeError::CommandError(format!("Unreadable measurement status reply: {}", reply));
+        let fields: Vec<&str> = reply.strip_prefix(MEASUREMENT_STATUS_PREFIX).ok_or_else(unreadable)?.trim().split(':').collect();
+        let [running, sequence, samples_collected, elapsed_ms] = fields[..] else {
+            return Err(unreadable());
+        };
+
+        Ok(Self {
+            running: match running {
+                "true" | "1" => true,
+                "false" | "0" => false,
+                _ => return Err(unreadable()),
+            },
+            sequence: sequence.parse().map_err(|_| unreadable())?,
+            samples_collected: samples_collected.parse().map_err(|_| unreadable())?,
+            elapsed_ms: elapsed_ms.parse().map_err(|_| unreadable())?,
+        })
+    }
+}
+
+/// Linear interpolation from `from` to `to` after `elapsed` of `span`
+fn interpolate(from: u64, to: u64, elapsed: chrono::Duration, span: chrono::Duration) -> u64 {
+    let fraction = elapsed.num_milliseconds() as f64 / span.num_milliseconds() as f64;
+    let value = from as f64 + (to as f64 - from as f64) * fraction.clamp(0.0, 1.0);
+    value.round() as u64
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 #[allow(dead_code)]
 struct CommandParameters {
     #[serde(default)]
@@ -45,6 +212,8 @@ struct CommandParameters {
     #[serde(default)]
     inactive_period: u64,
     #[serde(default)]
+    smooth_transition_seconds: u64,
+    #[serde(default)]
     level: String,
     #[serde(default)]
     log_level: String,
@@ -56,36 +225,806 @@ struct CommandParameters {
     command: String,
     #[serde(default)]
     sequence: u32,
+    #[serde(default)]
+    timeout_ms: u32,
+    #[serde(default)]
+    hex: String,
+    #[serde(default)]
+    ascii: String,
+    #[serde(default)]
+    frame: bool,
+    #[serde(default)]
+    upload_immediately: bool,
+    #[serde(default)]
+    output: String,
+    #[serde(default)]
+    commands: Vec<serde_json::Value>,
+    #[serde(default)]
+    execute_at: String,
+    #[serde(default)]
+    inner_command: Option<serde_json::Value>,
+    #[serde(default)]
+    failure_count: u32,
+    #[serde(default)]
+    params: BTreeMap<String, f64>,
+    #[serde(default)]
+    port: u16,
+    #[serde(default)]
+    old_key: String,
+    #[serde(default)]
+    new_key: String,
+    #[serde(default)]
+    confirm: String,
+    #[serde(default)]
+    rate_hz: Option<f64>,
+    #[serde(default)]
+    apply: bool,
+    #[serde(default)]
+    path: String,
+    #[serde(default)]
+    duration_seconds: u64,
+    #[serde(default)]
+    format: String,
+    #[serde(default)]
+    component: String,
+    #[serde(default)]
+    lines_per_second: u32,
+    #[serde(default)]
+    force_cancel: bool,
+    #[serde(default)]
+    n_per_level: usize,
+    #[serde(default)]
+    bucket: String,
+    #[serde(default)]
+    key_prefix: String,
+    #[serde(default)]
+    endpoint_url: String,
+    #[serde(default)]
+    tz: String,
+    #[serde(default)]
+    max_entries: Option<usize>,
+    #[serde(default)]
+    mode: String,
+    #[serde(default)]
+    hostname: String,
+}
+
+/// A command and the parameters it takes, as reported by `list_commands`
+#[derive(Debug, Serialize)]
+pub struct CommandDescriptor {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub parameters: &'static [ParamDescriptor],
+}
+
+#[derive(Debug, Serialize)]
+pub struct ParamDescriptor {
+    pub name: &'static str,
+    /// e.g. "u32", "string", "bool"
+    pub type_hint: &'static str,
+    pub required: bool,
+}
+
+const fn param(name: &'static str, type_hint: &'static str, required: bool) -> ParamDescriptor {
+    ParamDescriptor { name, type_hint, required }
 }
 
-#[derive(Debug, Deserialize)]
+/// Every command `dispatch_command` handles, reported by `list_commands`
+const COMMANDS: &[CommandDescriptor] = &[
+    CommandDescriptor {
+        name: "set_update_interval",
+        description: "Upload every active_period seconds between start_time and end_time and every inactive_period seconds outside",
+        parameters: &[
+            param("start_time", "string", false),
+            param("end_time", "string", false),
+            param("active_period", "u64", false),
+            param("inactive_period", "u64", false),
+            param("smooth_transition_seconds", "u64", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "set_log_level",
+        description: "Change the node log level (TRACE, DEBUG, INFO, WARN, ERROR); level is an alias of log_level",
+        parameters: &[
+            param("log_level", "string", false),
+            param("level", "string", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "set_log_filter",
+        description: "Set the substring filter for collected lines; value is an alias of log_filter",
+        parameters: &[
+            param("log_filter", "string", false),
+            param("value", "string", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "set_timezone",
+        description: "Write node log timestamps in an IANA timezone instead of UTC",
+        parameters: &[
+            param("tz", "string", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "run_command",
+        description: "Send a command line to the node; value is an alias of command",
+        parameters: &[
+            param("command", "string", false),
+            param("value", "string", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "update_node",
+        description: "Check for and install node firmware updates",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "cancel_update",
+        description: "Stop the running node firmware update at its next safe checkpoint",
+        parameters: &[
+            param("force_cancel", "bool", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "update_probe",
+        description: "Check for and install probe updates",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "reboot_probe",
+        description: "Reboot the probe host",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "set_node_log_output",
+        description: "Route node logs to usb, uart, rtt or silent",
+        parameters: &[
+            param("output", "string", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "set_node_power_mode",
+        description: "Put the node in full, low, sleep or dormant power mode",
+        parameters: &[
+            param("mode", "string", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "get_node_power_mode",
+        description: "Ask the node for its power mode",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "set_node_rtc",
+        description: "Set the node real-time clock to the probe's UTC time",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "get_node_rtc",
+        description: "Read the node real-time clock and its drift from the probe's clock",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "start_measurement",
+        description: "Start a measurement, first sending any params as set_measurement_params does",
+        parameters: &[
+            param("sequence", "u32", true),
+            param("params", "map<string, f64>", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "get_measurement_status",
+        description: "Ask the node whether a measurement is running and how far it got",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "set_measurement_params",
+        description: "Send measurement parameters to the node",
+        parameters: &[
+            param("params", "map<string, f64>", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "set_sampling_rate",
+        description: "Set the node measurement sampling rate within min_sampling_rate_hz and max_sampling_rate_hz",
+        parameters: &[
+            param("rate_hz", "f64", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "get_sampling_rate",
+        description: "Ask the node for its current sampling rate",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "enable_watchdog",
+        description: "Enable the node hardware watchdog",
+        parameters: &[
+            param("timeout_ms", "u32", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "disable_watchdog",
+        description: "Disable the node hardware watchdog",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "send_raw_usb",
+        description: "Write hex or ascii bytes to the node, as one frame if frame is set",
+        parameters: &[
+            param("hex", "string", false),
+            param("ascii", "string", false),
+            param("frame", "bool", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "schedule_command",
+        description: "Run inner_command at execute_at (RFC 3339)",
+        parameters: &[
+            param("execute_at", "string", true),
+            param("inner_command", "command", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "cancel_scheduled",
+        description: "Drop all pending scheduled commands",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "simulate_disconnect",
+        description: "Drop the USB connection (test command)",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "simulate_connect_failure",
+        description: "Fail the next failure_count USB connection attempts (test command)",
+        parameters: &[
+            param("failure_count", "u32", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "performance_test",
+        description: "Have the node emit synthetic lines at lines_per_second and report the rate the probe kept up with (test command)",
+        parameters: &[
+            param("lines_per_second", "u32", true),
+            param("duration_seconds", "u64", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "enable_debug_port",
+        description: "Open a TCP pass-through to the node on 127.0.0.1:<port>",
+        parameters: &[
+            param("port", "u16", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "disable_debug_port",
+        description: "Close the debug port",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "tail_logs",
+        description: "Stream the last 50 and then every new buffered entry as JSON lines on 127.0.0.1:<port>",
+        parameters: &[
+            param("port", "u16", true),
+            param("level", "string", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "stop_tail_logs",
+        description: "Close the tail_logs listener and its clients",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "stream_to_file",
+        description: "Append every raw node line to a file in stream_to_file_dir for a while",
+        parameters: &[
+            param("path", "string", true),
+            param("duration_seconds", "u64", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "stop_stream_to_file",
+        description: "Stop a running stream_to_file capture",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "measure_usb_latency",
+        description: "Ping the node and report USB round-trip times",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "batch_commands",
+        description: "Run commands in order without other commands interleaving",
+        parameters: &[
+            param("commands", "array<command>", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "capture_snapshot",
+        description: "Write the buffered log entries to a snapshot file",
+        parameters: &[
+            param("upload_immediately", "bool", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "export_logs",
+        description: "Upload the buffered log entries as gzip JSON to an S3-compatible bucket",
+        parameters: &[
+            param("bucket", "string", true),
+            param("key_prefix", "string", false),
+            param("endpoint_url", "string", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "replay_logs",
+        description: "Put entries from a local archive file back at the front of the buffer for upload",
+        parameters: &[
+            param("path", "string", true),
+            param("max_entries", "usize", false),
+            param("upload_immediately", "bool", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "diagnostics_report",
+        description: "Write a compressed report of config, logs, stats and system state",
+        parameters: &[
+            param("upload_immediately", "bool", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "get_status",
+        description: "Report probe version, USB, upload and node update status",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "rotate_api_key",
+        description: "Replace the upload API key and save it to the config file",
+        parameters: &[
+            param("old_key", "string", true),
+            param("new_key", "string", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "node_register",
+        description: "Register the node with the hub and save the API key it issues to the config file",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "factory_reset",
+        description: "Clear buffered logs, scheduled commands, old firmware, snapshots and dead letters, then reset the node",
+        parameters: &[
+            param("confirm", "string", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "set_probe_hostname",
+        description: "Rename the probe host with hostnamectl and /etc/hostname",
+        parameters: &[
+            param("hostname", "string", true),
+        ],
+    },
+    CommandDescriptor {
+        name: "export_config",
+        description: "Return the config in effect with secrets masked",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "export_config_env",
+        description: "Return the config as PROBE_<FIELD> environment variables with secrets masked",
+        parameters: &[
+            param("format", "string", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "generate_config",
+        description: "Write the settings in effect to a new config file, optionally replacing the current one",
+        parameters: &[
+            param("apply", "bool", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "list_config_backups",
+        description: "List the config file backups taken before the probe rewrote it",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "get_buffer_stats",
+        description: "Report log buffer statistics",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "get_sample_logs",
+        description: "Return up to n_per_level randomly chosen buffered entries of each log level, errors first",
+        parameters: &[
+            param("n_per_level", "usize", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "list_deployed_versions",
+        description: "List node firmware and probe binaries kept on disk",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "node_health",
+        description: "Grade probe health from USB, firmware, buffer and upload checks",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "test_server_connectivity",
+        description: "Check DNS, TCP, TLS and HTTP reachability of server_url",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "hardware_info",
+        description: "Report the probe host's CPU, memory, kernel, OS and hostname",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "get_node_info",
+        description: "Query the node for version, uptime, temperature and free heap",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "test_firmware_url",
+        description: "Check that version.json parses and the firmware file it names exists, without downloading it",
+        parameters: &[
+            param("component", "string", false),
+        ],
+    },
+    CommandDescriptor {
+        name: "get_firmware_version",
+        description: "Report deployed and live node and probe versions",
+        parameters: &[],
+    },
+    CommandDescriptor {
+        name: "list_commands",
+        description: "List the supported commands and their parameters",
+        parameters: &[],
+    },
+];
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Command {
     pub command: String,
     #[serde(default)]
     pub parameters: serde_json::Value,
 }
 
-pub async fn execute_command(
-    command: Command,
-    _config: &Config,
-    filter_string: &Arc<RwLock<String>>,
-    upload_interval: &Arc<RwLock<Duration>>,
-    usb_handle: &UsbHandle,
-) -> Result<()> {
+/// Outcome of a command, reported back to the hub with the next upload
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandResult {
+    pub command: String,
+    pub data: serde_json::Value,
+}
+
+impl CommandResult {
+    fn new(command: &str, data: serde_json::Value) -> Self {
+        Self {
+            command: command.to_string(),
+            data,
+        }
+    }
+
+    fn empty(command: &str) -> Self {
+        Self::new(command, serde_json::Value::Null)
+    }
+
+    /// Whether the command produced anything worth reporting
+    pub fn has_data(&self) -> bool {
+        !self.data.is_null()
+    }
+}
+
+/// Shared probe state that commands read and modify
+#[derive(Clone)]
+pub struct CommandContext {
+    pub config: Arc<Config>,
+    pub buffer: Arc<RwLock<LogBuffer>>,
+    pub filter_string: Arc<RwLock<String>>,
+    /// Timezone of node log timestamps, as last set by `set_timezone`; UTC when unset
+    pub timezone: Arc<RwLock<Option<Tz>>>,
+    pub upload_schedule: Arc<RwLock<UploadSchedule>>,
+    pub usb_handle: UsbHandle,
+    pub scheduled_commands: ScheduledCommands,
+    /// Where the node currently sends its logs, as last set by `set_node_log_output`
+    pub node_log_output: Arc<RwLock<String>>,
+    /// Node power mode, as last set by `set_node_power_mode` or reported to `get_node_power_mode`
+    pub node_power_mode: Arc<RwLock<String>>,
+    /// Sequence numbers of measurements started and not yet seen to finish
+    pub running_measurements: Arc<RwLock<HashSet<u32>>>,
+    /// Node sampling rate in Hz, as last set or queried; `None` until then
+    pub sampling_rate: Arc<RwLock<Option<f64>>>,
+    /// Node watchdog timeout in ms as last set by `enable_watchdog`; `None` until
+    /// then and after `disable_watchdog`
+    pub watchdog_timeout_ms: Arc<RwLock<Option<u32>>>,
+    /// Held while a command runs
+    pub command_lock: Arc<Mutex<()>>,
+    /// Wakes the sync task to upload without waiting for the interval
+    pub upload_now: Arc<Notify>,
+    /// Progress of the current or last node firmware update
+    pub update_state: UpdateTracker,
+    /// Shared client for firmware checks and downloads
+    pub firmware_client: reqwest::Client,
+    /// When telemetry was last delivered, or when the probe started if it
+    /// has not been yet
+    pub last_upload_at: Arc<RwLock<DateTime<Utc>>>,
+    /// Rolling upload latency and size averages
+    pub upload_stats: Arc<UploadStats>,
+    /// Pauses uploads while the hub keeps failing
+    pub circuit_breaker: Arc<CircuitBreaker>,
+    /// API key sent with every upload; starts as `config.api_key` and is
+    /// replaced by `rotate_api_key` or a config reload
+    pub api_key: Arc<RwLock<String>>,
+    /// Tokio task and runtime statistics, if enabled
+    pub runtime_metrics: RuntimeMetrics,
+    /// Files the config was loaded from
+    pub config_sources: Arc<ConfigSources>,
+    /// Every line received from the node, before filtering
+    pub raw_lines: broadcast::Sender<String>,
+    /// Open debug port, if any
+    pub debug_port: Arc<Mutex<Option<DebugPort>>>,
+    /// Every entry the collector buffers, as it arrives
+    pub log_feed: broadcast::Sender<LogEntry>,
+    /// Open tail_logs listener, if any
+    pub log_tail: Arc<Mutex<Option<LogTail>>>,
+    /// Running stream_to_file capture, if any
+    pub file_stream: Arc<Mutex<Option<FileStream>>>,
+    /// How long each command took to run
+    pub command_timings: CommandTimings,
+    /// Sends task errors to the hub with the next upload
+    pub error_reporter: ErrorReporter,
+    /// Reported errors waiting for the next upload
+    pub pending_errors: PendingErrors,
+    /// Restarts of the supervised tasks
+    pub task_restarts: RestartTracker,
+}
+
+/// Run time of one command, accumulated over every execution
+#[derive(Debug, Clone, Default)]
+pub struct CommandTimingStats {
+    pub count: u64,
+    pub total_ms: u64,
+    pub max_ms: u64,
+    /// Executions per `COMMAND_DURATION_BUCKETS_MS` bucket, the last one
+    /// counting those over the largest bound
+    pub buckets: [u64; COMMAND_DURATION_BUCKETS_MS.len() + 1],
+}
+
+impl CommandTimingStats {
+    fn record(&mut self, elapsed_ms: u64) {
+        self.count += 1;
+        self.total_ms += elapsed_ms;
+        self.max_ms = self.max_ms.max(elapsed_ms);
+        let bucket = COMMAND_DURATION_BUCKETS_MS
+            .iter()
+            .position(|bound| elapsed_ms <= *bound)
+            .unwrap_or(COMMAND_DURATION_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+    }
+
+    /// Counts per bucket are cumulative, as in a Prometheus histogram
+    fn to_json(&self) -> serde_json::Value {
+        let mut histogram = serde_json::Map::new();
+        let mut cumulative = 0;
+        for (bound, count) in COMMAND_DURATION_BUCKETS_MS.iter().zip(&self.buckets) {
+            cumulative += count;
+            histogram.insert(format!("le_{}", bound), cumulative.into());
+        }
+        histogram.insert("le_inf".to_string(), self.count.into());
+
+        serde_json::json!({
+            "count": self.count,
+            "total_ms": self.total_ms,
+            "max_ms": self.max_ms,
+            "avg_ms": self.total_ms.checked_div(self.count).unwrap_or(0),
+            "histogram": histogram,
+        })
+    }
+}
+
+/// Per-command timing statistics, keyed by command name
+#[derive(Clone, Default)]
+pub struct CommandTimings {
+    stats: Arc<std::sync::Mutex<HashMap<String, CommandTimingStats>>>,
+}
+
+impl CommandTimings {
+    /// Add one execution of `command`; names that are not known commands
+    /// are ignored so the map cannot grow without bound
+    fn record(&self, command: &str, elapsed: Duration) {
+        if !COMMANDS.iter().any(|descriptor| descriptor.name == command) {
+            return;
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.entry(command.to_string()).or_default().record(elapsed.as_millis() as u64);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let stats = self.stats.lock().unwrap();
+        let commands: serde_json::Map<_, _> = stats.iter().map(|(name, stats)| (name.clone(), stats.to_json())).collect();
+        commands.into()
+    }
+
+    /// The `n` commands with the longest single execution, slowest first
+    pub fn slowest(&self, n: usize) -> serde_json::Value {
+        let stats = self.stats.lock().unwrap();
+        let mut slowest: Vec<_> = stats.iter().collect();
+        slowest.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.max_ms));
+        slowest
+            .into_iter()
+            .take(n)
+            .map(|(name, stats)| {
+                let mut value = stats.to_json();
+                value["command"] = name.clone().into();
+                value
+            })
+            .collect()
+    }
+}
+
+/// Commands deferred by `schedule_command`, ordered by due time
+#[derive(Clone, Default)]
+pub struct ScheduledCommands {
+    queue: Arc<Mutex<BTreeMap<DateTime<Utc>, Vec<Command>>>>,
+    changed: Arc<Notify>,
+}
+
+impl ScheduledCommands {
+    /// Queue `command` to run at `at`, refusing once `max` commands are pending
+    async fn schedule(&self, at: DateTime<Utc>, command: Command, max: usize) -> Result<(), ProbeError> {
+        let mut queue = self.queue.lock().await;
+        let pending: usize = queue.values().map(Vec::len).sum();
+        if pending >= max {
+            return Err(ProbeError::CommandError(format!("Scheduled command queue is full ({} commands)", max)));
+        }
+        queue.entry(at).or_default().push(command);
+        drop(queue);
+
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Drop every pending command, returning how many there were
+    async fn clear(&self) -> usize {
+        let mut queue = self.queue.lock().await;
+        let count = queue.values().map(Vec::len).sum();
+        queue.clear();
+        drop(queue);
+
+        self.changed.notify_one();
+        count
+    }
+
+    async fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.queue.lock().await.keys().next().copied()
+    }
+
+    /// Remove and return all commands due at or before `now`, oldest first
+    async fn take_due(&self, now: DateTime<Utc>) -> Vec<Command> {
+        let mut queue = self.queue.lock().await;
+        let later = match now.checked_add_signed(chrono::Duration::nanoseconds(1)) {
+            Some(after_now) => queue.split_off(&after_now),
+            None => BTreeMap::new(),
+        };
+        let due = std::mem::replace(&mut *queue, later);
+        due.into_values().flatten().collect()
+    }
+}
+
+/// Execute scheduled commands when they fall due
+pub async fn run_scheduler(ctx: CommandContext) -> Result<()> {
+    info!("Command scheduler task started");
+
+    loop {
+        let notified = ctx.scheduled_commands.changed.notified();
+
+        match ctx.scheduled_commands.next_due().await {
+            Some(due) => {
+                let wait = (due - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+                tokio::select! {
+                    _ = tokio::time::sleep_until(tokio::time::Instant::now() + wait) => {}
+                    _ = notified => continue,
+                }
+            }
+            None => {
+                notified.await;
+                continue;
+            }
+        }
+
+        for command in ctx.scheduled_commands.take_due(Utc::now()).await {
+            info!("Running scheduled command: {}", command.command);
+            match Box::pin(execute_command(command, &ctx)).await {
+                Ok(result) if result.has_data() => info!("Scheduled command {} returned: {}", result.command, result.data),
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Scheduled command execution error: {}", e);
+                    ctx.error_reporter.report("command_scheduler", &e);
+                }
+            }
+        }
+    }
+}
+
+/// Execute one command. Commands run one at a time, so a `batch_commands`
+/// batch never interleaves with commands from other sources.
+pub async fn execute_command(command: Command, ctx: &CommandContext) -> Result<CommandResult> {
+    // Must get through while another command, e.g. update_node, holds the lock
+    if LOCK_FREE_COMMANDS.contains(&command.command.as_str()) {
+        return timed_dispatch(command, ctx).await;
+    }
+    let guard = Arc::clone(&ctx.command_lock).lock_owned().await;
+    if BACKGROUND_COMMANDS.contains(&command.command.as_str()) {
+        let name = command.command.clone();
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let _guard = guard;
+            match timed_dispatch(command, &ctx).await {
+                Ok(result) if result.has_data() => info!("Command {} finished: {}", result.command, result.data),
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Command execution error: {}", e);
+                    ctx.error_reporter.report("command_executor", &e);
+                }
+            }
+        });
+        return Ok(CommandResult::new(&name, serde_json::json!({ "started": true })));
+    }
+    timed_dispatch(command, ctx).await
+}
+
+/// `dispatch_command`, recording how long the command took
+async fn timed_dispatch(command: Command, ctx: &CommandContext) -> Result<CommandResult> {
+    let name = command.command.clone();
+    let started = tokio::time::Instant::now();
+    let result = dispatch_command(command, ctx).await;
+    let elapsed = started.elapsed();
+    debug!("Command '{}' took {}ms", name, elapsed.as_millis());
+    ctx.command_timings.record(&name, elapsed);
+    result
+}
+
+/// Execute a command with `command_lock` already held
+async fn dispatch_command(command: Command, ctx: &CommandContext) -> Result<CommandResult> {
+    let CommandContext {
+        config,
+        buffer,
+        filter_string,
+        timezone,
+        upload_schedule,
+        usb_handle,
+        scheduled_commands,
+        node_log_output,
+        node_power_mode,
+        running_measurements,
+        sampling_rate,
+        watchdog_timeout_ms,
+        command_lock: _,
+        upload_now,
+        update_state,
+        firmware_client,
+        last_upload_at: _,
+        upload_stats,
+        circuit_breaker,
+        api_key,
+        runtime_metrics,
+        config_sources,
+        raw_lines,
+        debug_port,
+        log_feed,
+        log_tail,
+        file_stream,
+        command_timings,
+        error_reporter: _,
+        pending_errors: _,
+        task_restarts,
+    } = ctx;
+
     info!("Executing command: {}", command.command);
 
-    let params: CommandParameters = serde_json::from_value(command.parameters).unwrap_or_else(|_| CommandParameters {
-        start_time: String::new(),
-        end_time: String::new(),
-        active_period: 0,
-        inactive_period: 0,
-        level: String::new(),
-        log_level: String::new(),
-        value: String::new(),
-        log_filter: String::new(),
-        command: String::new(),
-        sequence: 0,
-    });
+    let params: CommandParameters = serde_json::from_value(command.parameters).unwrap_or_default();
+    let mut data = serde_json::Value::Null;
 
     match command.command.as_str() {
         "set_update_interval" => {
@@ -117,7 +1056,7 @@ pub async fn execute_command(
             // Validate periods
             if params.active_period == 0 && params.inactive_period == 0 {
                 warn!("set_update_interval requires at least one period to be set");
-                return Ok(());
+                return Ok(CommandResult::empty(&command.command));
             }
 
             // Create schedule
@@ -134,11 +1073,12 @@ pub async fn execute_command(
                 } else {
                     params.active_period
                 },
+                smooth_transition_seconds: params.smooth_transition_seconds,
             };
 
             // Calculate current interval based on schedule
             let current_interval_secs = schedule.current_interval();
-            *upload_interval.write().await = Duration::from_secs(current_interval_secs);
+            *upload_schedule.write().await = schedule;
 
             if let (Some(start), Some(end)) = (start_time, end_time) {
                 info!(
@@ -165,7 +1105,7 @@ pub async fn execute_command(
                 "ERROR" => "/LE",
                 _ => {
                     warn!("Unknown log level: {}", level);
-                    return Ok(());
+                    return Ok(CommandResult::empty(&command.command));
                 }
             };
 
@@ -180,6 +1120,20 @@ pub async fn execute_command(
             *filter_string.write().await = new_filter;
         }
 
+        "set_timezone" => {
+            let tz: Tz = params
+                .tz
+                .parse()
+                .map_err(|_| ProbeError::CommandError(format!("unknown timezone '{}'", params.tz)))?;
+            *timezone.write().await = Some(tz);
+            info!("Log timestamps are now written in {}", tz);
+
+            data = serde_json::json!({
+                "tz": tz.name(),
+                "utc_offset": Utc::now().with_timezone(&tz).format("%:z").to_string(),
+            });
+        }
+
         "run_command" => {
             if !params.command.is_empty() {
                 usb_handle.send_command(params.command).await?;
@@ -190,15 +1144,26 @@ pub async fn execute_command(
 
         "update_node" => {
             info!("Triggering node firmware update...");
-            if let Err(e) = update_manager::check_and_update_node_firmware(_config, usb_handle).await {
+            if let Err(e) = update_manager::check_and_update_node_firmware(config, firmware_client, usb_handle, update_state).await {
                 error!("Node firmware update failed: {}", e);
+                data = update_failure(&e);
             }
         }
 
+        "cancel_update" => {
+            let state = update_state.cancel(params.force_cancel)?;
+            info!("Cancelling node firmware update while {}", state.name());
+            data = serde_json::json!({
+                "state": state.name(),
+                "force_cancel": params.force_cancel,
+            });
+        }
+
         "update_probe" => {
             info!("Triggering probe self-update...");
-            if let Err(e) = update_manager::check_and_update_probe(_config).await {
+            if let Err(e) = update_manager::check_and_update_probe(config, firmware_client).await {
                 error!("Probe update failed: {}", e);
+                data = update_failure(&e);
             }
         }
 
@@ -208,21 +1173,2163 @@ pub async fn execute_command(
             update_manager::reboot_system().await?;
         }
 
+        "set_node_log_output" => {
+            let output = params.output.to_lowercase();
+            if !NODE_LOG_OUTPUTS.contains(&output.as_str()) {
+                return Err(ProbeError::CommandError(format!(
+                    "set_node_log_output output must be one of {}, got '{}'",
+                    NODE_LOG_OUTPUTS.join(", "),
+                    params.output
+                ))
+                .into());
+            }
+
+            info!("Switching node log output to {}", output);
+            usb_handle.send_command(format!("/LO_{}_", output)).await?;
+            apply_node_log_output(usb_handle, node_power_mode, node_log_output, output).await?;
+        }
+
+        "set_node_power_mode" => {
+            let mode = params.mode.to_lowercase();
+            let Some(&(mode, letter)) = NODE_POWER_MODES.iter().find(|(name, _)| *name == mode) else {
+                return Err(ProbeError::CommandError(format!(
+                    "set_node_power_mode mode must be one of {}, got '{}'",
+                    NODE_POWER_MODES.map(|(name, _)| name).join(", "),
+                    params.mode
+                ))
+                .into());
+            };
+
+            info!("Switching node power mode to {}", mode);
+            usb_handle.send_command(format!("/PM_{}_", letter)).await?;
+            apply_node_power_mode(usb_handle, node_power_mode, node_log_output, mode).await?;
+        }
+
+        "get_node_power_mode" => {
+            let reply = usb_handle.query("/PMQ".to_string(), POWER_MODE_PREFIX, POWER_MODE_QUERY_TIMEOUT).await?;
+            let mode = parse_power_mode_reply(&reply)?;
+            apply_node_power_mode(usb_handle, node_power_mode, node_log_output, mode).await?;
+            data = serde_json::json!({ "mode": mode });
+        }
+
+        "set_sampling_rate" => {
+            let rate = params.rate_hz.unwrap_or(f64::NAN);
+            if !rate.is_finite() || rate <= 0.0 {
+                return Err(ProbeError::CommandError(format!("rate_hz must be a positive finite number, got {}", rate)).into());
+            }
+            if rate < config.min_sampling_rate_hz || rate > config.max_sampling_rate_hz {
+                return Err(ProbeError::CommandError(format!(
+                    "rate_hz must be {}-{}, got {}",
+                    config.min_sampling_rate_hz, config.max_sampling_rate_hz, rate
+                ))
+                .into());
+            }
+
+            info!("Setting node sampling rate to {} Hz", rate);
+            usb_handle.send_command(format!("/SR_{}_", encode_sampling_rate(rate))).await?;
+            *sampling_rate.write().await = Some(rate);
+            data = serde_json::json!({ "rate_hz": rate });
+        }
+
+        "get_sampling_rate" => {
+            let reply = usb_handle.query("/SRQ".to_string(), SAMPLING_RATE_PREFIX, SAMPLING_RATE_QUERY_TIMEOUT).await?;
+            let rate = reply[SAMPLING_RATE_PREFIX.len()..]
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| ProbeError::CommandError(format!("Unreadable sampling rate reply: {}", reply)))?;
+            *sampling_rate.write().await = Some(rate);
+            data = serde_json::json!({ "rate_hz": rate });
+        }
+
+        "set_node_rtc" => {
+            let now = Utc::now();
+            usb_handle.send_command(rtc_command(now)).await?;
+            info!("Set node RTC to {}", now.to_rfc3339());
+            data = serde_json::json!({ "rtc": now.to_rfc3339() });
+        }
+
+        "get_node_rtc" => {
+            let reply = usb_handle.query("/RTCQ".to_string(), RTC_PREFIX, RTC_QUERY_TIMEOUT).await?;
+            let now = Utc::now();
+            let node_time = parse_rtc_reply(&reply)?;
+            data = serde_json::json!({
+                "node_time": node_time.to_rfc3339(),
+                "probe_time": now.to_rfc3339(),
+                "drift_seconds": (node_time - now).num_seconds(),
+            });
+        }
+
         "start_measurement" => {
             if params.sequence == 0 {
                 warn!("start_measurement requires a non-zero sequence number");
-                return Ok(());
+                return Ok(CommandResult::empty(&command.command));
+            }
+
+            if !params.params.is_empty() {
+                let params_command = measurement_params_command(&params.params, &config.valid_measurement_params)?;
+                usb_handle.send_command(params_command).await?;
             }
 
             let usb_command = format!("/M_{}_", params.sequence);
             info!("Starting measurement with sequence {}", params.sequence);
             usb_handle.send_command(usb_command).await?;
+            running_measurements.write().await.insert(params.sequence);
         }
 
-        _ => {
-            warn!("Unknown command: {}", command.command);
+        "get_measurement_status" => {
+            let status = measurement_status(usb_handle, running_measurements).await?;
+            data = serde_json::to_value(status)?;
         }
-    }
 
-    Ok(())
+        "set_measurement_params" => {
+            if params.params.is_empty() {
+                return Err(ProbeError::CommandError("set_measurement_params requires params".to_string()).into());
+            }
+
+            let usb_command = measurement_params_command(&params.params, &config.valid_measurement_params)?;
+            info!("Setting measurement parameters: {}", usb_command);
+            usb_handle.send_command(usb_command).await?;
+        }
+
+        "enable_watchdog" => {
+            if params.timeout_ms == 0 || params.timeout_ms > MAX_WATCHDOG_TIMEOUT_MS {
+                return Err(ProbeError::CommandError(format!(
+                    "enable_watchdog timeout_ms must be between 1 and {}, got {}",
+                    MAX_WATCHDOG_TIMEOUT_MS, params.timeout_ms
+                ))
+                .into());
+            }
+
+            info!("Enabling node watchdog with {}ms timeout", params.timeout_ms);
+            usb_handle.send_command(watchdog_command(params.timeout_ms)).await?;
+            *watchdog_timeout_ms.write().await = Some(params.timeout_ms);
+        }
+
+        "disable_watchdog" => {
+            info!("Disabling node watchdog");
+            usb_handle.send_command(watchdog_command(0)).await?;
+            *watchdog_timeout_ms.write().await = None;
+        }
+
+        "send_raw_usb" => {
+            if !config.allow_raw_usb {
+                return Err(ProbeError::CommandError("send_raw_usb is disabled".to_string()).into());
+            }
+
+            let bytes = if !params.hex.is_empty() {
+                decode_hex(&params.hex)?
+            } else if !params.ascii.is_empty() {
+                params.ascii.into_bytes()
+            } else {
+                return Err(ProbeError::CommandError("send_raw_usb requires hex or ascii".to_string()).into());
+            };
+
+            warn!("Sending {} raw bytes to USB: {:02x?}", bytes.len(), bytes);
+            if params.frame {
+                usb_handle.send_frame(bytes).await?;
+            } else {
+                usb_handle.send_raw(bytes).await?;
+            }
+        }
+
+        "schedule_command" => {
+            let execute_at = DateTime::parse_from_rfc3339(&params.execute_at)
+                .map_err(|e| ProbeError::CommandError(format!("Invalid execute_at '{}': {}", params.execute_at, e)))?
+                .with_timezone(&Utc);
+            let inner: Command = serde_json::from_value(params.inner_command.unwrap_or_default())
+                .map_err(|e| ProbeError::CommandError(format!("Invalid inner_command: {}", e)))?;
+
+            info!("Scheduling command {} for {}", inner.command, execute_at.to_rfc3339());
+            scheduled_commands.schedule(execute_at, inner, config.max_scheduled_commands).await?;
+        }
+
+        "cancel_scheduled" => {
+            let cleared = scheduled_commands.clear().await;
+            info!("Cancelled {} scheduled commands", cleared);
+        }
+
+        "simulate_disconnect" => {
+            if !config.enable_test_commands {
+                return Err(ProbeError::CommandError("simulate_disconnect is disabled".to_string()).into());
+            }
+            usb_handle.simulate_disconnect().await?;
+        }
+
+        "simulate_connect_failure" => {
+            if !config.enable_test_commands {
+                return Err(ProbeError::CommandError("simulate_connect_failure is disabled".to_string()).into());
+            }
+            usb_handle.simulate_connect_failure(params.failure_count).await?;
+            data = serde_json::json!({ "failure_count": params.failure_count });
+        }
+
+        "performance_test" => {
+            if !config.enable_test_commands {
+                return Err(ProbeError::CommandError("performance_test is disabled".to_string()).into());
+            }
+            if params.lines_per_second == 0 {
+                return Err(ProbeError::CommandError("lines_per_second must be greater than 0".to_string()).into());
+            }
+            if params.duration_seconds == 0 || params.duration_seconds > MAX_PERFORMANCE_TEST_SECONDS {
+                return Err(ProbeError::CommandError(format!(
+                    "duration_seconds must be 1-{}, got {}",
+                    MAX_PERFORMANCE_TEST_SECONDS, params.duration_seconds
+                ))
+                .into());
+            }
+
+            data = performance_test(usb_handle, buffer, raw_lines, params.lines_per_second, params.duration_seconds).await?;
+        }
+
+        "enable_debug_port" => {
+            if !config.enable_debug_port {
+                return Err(ProbeError::CommandError("enable_debug_port is disabled".to_string()).into());
+            }
+            if params.port < 1024 {
+                return Err(ProbeError::CommandError(format!("debug port must be 1024-65535, got {}", params.port)).into());
+            }
+
+            let mut debug_port = debug_port.lock().await;
+            if let Some(open) = debug_port.as_ref().filter(|port| port.is_open()) {
+                return Err(ProbeError::CommandError(format!("debug port already open on {}", open.port())).into());
+            }
+
+            // Any earlier port has closed itself after being idle and is dropped here
+            *debug_port = Some(
+                DebugPort::open(
+                    params.port,
+                    usb_handle.clone(),
+                    raw_lines.clone(),
+                    config.usb_protocol.eq_ignore_ascii_case("frame"),
+                    Duration::from_secs(config.debug_port_inactivity_timeout_seconds),
+                )
+                .await?,
+            );
+            data = serde_json::json!({ "port": params.port });
+        }
+
+        "disable_debug_port" => {
+            if debug_port.lock().await.take().is_none() {
+                info!("Debug port is not open");
+            }
+        }
+
+        "tail_logs" => {
+            if params.port < 1024 {
+                return Err(ProbeError::CommandError(format!("tail port must be 1024-65535, got {}", params.port)).into());
+            }
+            let min_level = match params.level.as_str() {
+                "" => None,
+                level => Some(
+                    LogLevel::parse(level).ok_or_else(|| ProbeError::CommandError(format!("Unknown log level '{}'", level)))?,
+                ),
+            };
+
+            // Replacing an open tail closes it and its clients first
+            let mut log_tail = log_tail.lock().await;
+            log_tail.take();
+            let tail = LogTail::open(params.port, Arc::clone(buffer), log_feed.clone(), min_level).await?;
+            data = serde_json::json!({
+                "port": tail.port(),
+                "level": tail.min_level().map(|level| format!("{:?}", level).to_lowercase()),
+            });
+            *log_tail = Some(tail);
+        }
+
+        "stop_tail_logs" => {
+            if log_tail.lock().await.take().is_none() {
+                info!("Log tail is not open");
+            }
+        }
+
+        "stream_to_file" => {
+            if params.duration_seconds == 0 || params.duration_seconds > MAX_STREAM_DURATION_SECONDS {
+                return Err(ProbeError::CommandError(format!(
+                    "duration_seconds must be 1 to {}, got {}",
+                    MAX_STREAM_DURATION_SECONDS, params.duration_seconds
+                ))
+                .into());
+            }
+
+            let mut file_stream = file_stream.lock().await;
+            if let Some(open) = file_stream.as_ref().filter(|stream| stream.is_open()) {
+                return Err(ProbeError::CommandError(format!("already streaming to {:?}", open.path())).into());
+            }
+
+            let path = stream_file_path(&config.stream_to_file_dir, &params.path).await?;
+            let duration = Duration::from_secs(params.duration_seconds);
+            *file_stream = Some(FileStream::open(path.clone(), raw_lines, duration).await?);
+            data = serde_json::json!({
+                "path": path,
+                "duration_seconds": params.duration_seconds,
+            });
+        }
+
+        "stop_stream_to_file" => match file_stream.lock().await.take() {
+            Some(stream) => data = serde_json::json!({ "path": stream.path() }),
+            None => info!("No stream_to_file capture is running"),
+        },
+
+        "measure_usb_latency" => {
+            data = measure_usb_latency(usb_handle).await;
+        }
+
+        "batch_commands" => {
+            if params.commands.len() > config.max_batch_commands {
+                return Err(ProbeError::CommandError(format!(
+                    "batch_commands has {} commands, more than max_batch_commands ({})",
+                    params.commands.len(),
+                    config.max_batch_commands
+                ))
+                .into());
+            }
+            data = run_batch(params.commands, ctx).await;
+        }
+
+        "capture_snapshot" => {
+            let entries: Vec<_> = buffer.read().await.iter().cloned().collect();
+            let path = write_snapshot(&config.snapshot_dir, &entries, config.max_snapshots).await?;
+            info!("Captured {} buffered log entries to {:?}", entries.len(), path);
+
+            if params.upload_immediately {
+                upload_now.notify_one();
+            }
+
+            data = serde_json::json!({
+                "path": path.display().to_string(),
+                "entry_count": entries.len(),
+            });
+        }
+
+        "export_logs" => {
+            if params.bucket.is_empty() || params.endpoint_url.is_empty() {
+                return Err(ProbeError::CommandError("export_logs needs bucket and endpoint_url".to_string()).into());
+            }
+
+            let entries: Vec<_> = buffer.read().await.iter().cloned().collect();
+            let target = ExportTarget {
+                endpoint_url: &params.endpoint_url,
+                bucket: &params.bucket,
+                key_prefix: &params.key_prefix,
+            };
+            let exported = s3_export::export_logs(config, &target, &entries).await?;
+            info!("Exported {} buffered log entries to {}", entries.len(), exported.url);
+
+            data = serde_json::json!({
+                "url": exported.url,
+                "key": exported.key,
+                "size_bytes": exported.size_bytes,
+                "entry_count": entries.len(),
+            });
+        }
+
+        "replay_logs" => {
+            let Some(archive_path) = &config.local_archive_path else {
+                return Err(ProbeError::CommandError("replay_logs needs local_archive_path to be set".to_string()).into());
+            };
+            let path = archive_file_path(archive_path, &params.path).await?;
+            let (mut entries, malformed) = read_archive(&path).await?;
+            let read = entries.len();
+            if let Some(max_entries) = params.max_entries {
+                entries.drain(..read.saturating_sub(max_entries));
+            }
+
+            let requested = entries.len();
+            let mut buf = buffer.write().await;
+            let replayed = buf.prepend(entries);
+            info!("Replayed {} log entries from {:?}", replayed, path);
+            if replayed < requested {
+                warn!("Buffer full, {} older archived entries were not replayed", requested - replayed);
+            }
+            // Replayed entries may overlap what is buffered, e.g. when a file is replayed twice
+            let cleanup = config.deduplicate_on_load.then(|| {
+                let unparseable = buf.sort_by_timestamp();
+                let duplicates = buf.dedup_by_content();
+                info!("Sorted the buffer ({} unparseable timestamps) and removed {} duplicates", unparseable, duplicates);
+                (unparseable, duplicates)
+            });
+            drop(buf);
+
+            if params.upload_immediately {
+                upload_now.notify_one();
+            }
+
+            data = serde_json::json!({
+                "path": path,
+                "entries_read": read,
+                "malformed_lines": malformed,
+                "replayed": replayed,
+                "skipped_buffer_full": requested - replayed,
+            });
+            if let Some((unparseable, duplicates)) = cleanup {
+                data["unparseable_timestamps"] = unparseable.into();
+                data["duplicates_removed"] = duplicates.into();
+            }
+        }
+
+        "get_status" => {
+            data = serde_json::json!({
+                "node_id": config.node_id,
+                "probe_version": config.probe_version,
+                "build_timestamp": config.build_timestamp,
+                "node_log_output": *node_log_output.read().await,
+                "node_power_mode": *node_power_mode.read().await,
+                "sampling_rate_hz": *sampling_rate.read().await,
+                "usb": usb_handle.stats().to_json(),
+                "watchdog": watchdog_status(*watchdog_timeout_ms.read().await),
+                "upload": upload_stats.to_json(),
+                "upload_circuit": circuit_breaker.to_json(),
+                "commands": command_timings.to_json(),
+                "runtime": runtime_metrics.to_json(),
+                "node_update": update_state.to_json(),
+                "task_restarts": task_restarts.to_json(),
+            });
+        }
+
+        "diagnostics_report" => {
+            let report = diagnostics_report(ctx).await?;
+            let (path, uncompressed_size) = write_diagnostics(&config.snapshot_dir, &report, config.max_snapshots).await?;
+            info!("Wrote diagnostics report to {:?} ({} bytes uncompressed)", path, uncompressed_size);
+
+            data = serde_json::json!({
+                "path": path.display().to_string(),
+                "uncompressed_size_bytes": uncompressed_size,
+            });
+            if params.upload_immediately {
+                let key = api_key.read().await.clone();
+                match upload_diagnostics(config, &key, &path).await {
+                    Ok(()) => data["uploaded"] = true.into(),
+                    Err(e) => {
+                        warn!("Failed to upload diagnostics report: {}", e);
+                        data["uploaded"] = false.into();
+                        data["upload_error"] = e.to_string().into();
+                    }
+                }
+            }
+        }
+
+        "rotate_api_key" => {
+            if params.new_key.is_empty() || params.new_key.chars().count() > MAX_API_KEY_LENGTH {
+                return Err(ProbeError::CommandError(format!(
+                    "new_key must be 1 to {} characters long",
+                    MAX_API_KEY_LENGTH
+                ))
+                .into());
+            }
+
+            // Hold the write lock across the check and the file update so two
+            // rotations cannot interleave; uploads wait at most this long
+            let mut current = api_key.write().await;
+            if params.old_key != *current {
+                return Err(ProbeError::CommandError("old_key does not match the current API key".to_string()).into());
+            }
+
+            let path = config::persist_api_key(config, config_sources, &params.new_key, "api_key_rotation")?;
+            *current = params.new_key;
+            info!("API key rotated and saved to {:?}", path);
+            data = serde_json::json!({ "rotated": true, "path": path });
+        }
+
+        "node_register" => {
+            let node_firmware_version = update_manager::get_current_node_version(config, usb_handle).await.ok();
+            data = match register_node(config, config_sources, node_firmware_version).await? {
+                Registration::Registered { api_key: new_key, path } => {
+                    *api_key.write().await = new_key;
+                    info!("Node registered with the hub, API key saved to {:?}", path);
+                    serde_json::json!({ "registered": true, "path": path })
+                }
+                Registration::AlreadyRegistered => serde_json::json!({ "registered": false, "already_registered": true }),
+            };
+        }
+
+        "factory_reset" => {
+            if !config.enable_factory_reset {
+                return Err(ProbeError::CommandError("factory_reset is disabled".to_string()).into());
+            }
+            if params.confirm != FACTORY_RESET_CONFIRMATION {
+                return Err(ProbeError::CommandError(format!(
+                    "factory_reset requires confirm = \"{}\"",
+                    FACTORY_RESET_CONFIRMATION
+                ))
+                .into());
+            }
+
+            warn!("Factory reset: clearing buffered logs, scheduled commands and stored files");
+            let cleared_entries = buffer.write().await.drain().count();
+            let cancelled_commands = scheduled_commands.clear().await;
+            let mut removed_files = update_manager::remove_inactive_versions().await?;
+            removed_files.extend(remove_persisted_state(config).await?);
+            for path in &removed_files {
+                info!("Factory reset: removed {:?}", path);
+            }
+
+            usb_handle.send_command("/RS".to_string()).await?;
+            data = serde_json::json!({
+                "cleared_entries": cleared_entries,
+                "cancelled_commands": cancelled_commands,
+                "removed_files": removed_files,
+            });
+        }
+
+        "set_probe_hostname" => {
+            if !config.allow_system_commands {
+                return Err(ProbeError::CommandError("set_probe_hostname requires allow_system_commands".to_string()).into());
+            }
+            if !is_valid_hostname(&params.hostname) {
+                return Err(ProbeError::CommandError(format!(
+                    "'{}' is not a valid hostname: 1-63 letters, digits or hyphens, not starting or ending with a hyphen",
+                    params.hostname
+                ))
+                .into());
+            }
+
+            set_probe_hostname(&params.hostname).await?;
+            info!("Probe hostname set to {}", params.hostname);
+            data = serde_json::json!({ "hostname": params.hostname });
+        }
+
+        "export_config" => {
+            if !config.allow_config_export {
+                return Err(ProbeError::CommandError("export_config is disabled".to_string()).into());
+            }
+
+            data = config_export(config, config_sources)?;
+        }
+
+        "export_config_env" => {
+            if !config.allow_config_export {
+                return Err(ProbeError::CommandError("export_config is disabled".to_string()).into());
+            }
+
+            let format = if params.format.is_empty() { "shell" } else { params.format.as_str() };
+            data = serde_json::json!({
+                "format": format,
+                "content": config.to_env(format)?,
+            });
+        }
+
+        "generate_config" => {
+            if params.apply && !config.allow_config_write {
+                return Err(
+                    ProbeError::CommandError("generate_config cannot apply the config, allow_config_write is off".to_string()).into(),
+                );
+            }
+
+            // Settings commands and config reloads can change after startup
+            let mut generated = Config::clone(config);
+            generated.filter_string = filter_string.read().await.clone();
+            generated.upload_interval_seconds = upload_schedule.read().await.inactive_period;
+            generated.buffer_size = buffer.read().await.max_size();
+            generated.log_level = log::max_level().to_string().to_lowercase();
+            generated.api_key = api_key.read().await.clone();
+
+            let contents = generated.to_toml()?;
+            debug!("Generated config:\n{}", generated.with_secrets_masked().to_toml()?);
+            toml::from_str::<Config>(&contents)
+                .map_err(|e| ProbeError::ConfigError(format!("Generated config does not parse: {}", e)))?;
+
+            tokio::fs::create_dir_all(&config.config_backup_dir).await?;
+            let path = config
+                .config_backup_dir
+                .join(format!("config_generated_{}.toml", Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+            tokio::fs::write(&path, &contents).await?;
+            info!("Wrote generated config to {:?}", path);
+
+            let mut applied_to = None;
+            if params.apply {
+                let target = config_sources
+                    .paths
+                    .first()
+                    .ok_or_else(|| ProbeError::ConfigError("No config file to replace".to_string()))?;
+                config::backup_config_file(config, target, "generate_config")?;
+                config::replace_file(target, &contents)?;
+                warn!("Replaced config file {:?} with the generated config", target);
+                applied_to = Some(target.clone());
+            }
+
+            data = serde_json::json!({
+                "path": path,
+                "applied_to": applied_to,
+            });
+        }
+
+        "list_config_backups" => {
+            data = serde_json::json!({
+                "dir": config.config_backup_dir,
+                "max_config_backups": config.max_config_backups,
+                "backups": config::list_config_backups(&config.config_backup_dir)?,
+            });
+        }
+
+        "get_buffer_stats" => {
+            data = serde_json::to_value(buffer.read().await.stats())?;
+        }
+
+        "get_sample_logs" => {
+            let n_per_level = if params.n_per_level == 0 { DEFAULT_SAMPLE_PER_LEVEL } else { params.n_per_level };
+            let buffer = buffer.read().await;
+            data = serde_json::json!({
+                "n_per_level": n_per_level,
+                "buffered": buffer.len(),
+                "logs": buffer.sample_by_level(n_per_level),
+            });
+        }
+
+        "list_deployed_versions" => {
+            let deployed = update_manager::list_deployed_versions().await?;
+            data = serde_json::to_value(deployed)?;
+        }
+
+        "node_health" => {
+            data = node_health(ctx).await;
+            info!("Node health: {}", data["health"]);
+        }
+
+        "test_server_connectivity" => {
+            data = connectivity::diagnose(config).await;
+        }
+
+        "hardware_info" => {
+            data = hardware_info().await;
+        }
+
+        "get_node_info" => {
+            data = node_info(usb_handle, Duration::from_millis(config.node_info_timeout_ms)).await;
+        }
+
+        "test_firmware_url" => {
+            let components = if params.component.is_empty() {
+                vec![FirmwareComponent::Node, FirmwareComponent::Probe]
+            } else {
+                vec![FirmwareComponent::parse(&params.component)?]
+            };
+
+            let mut results = serde_json::Map::new();
+            for component in components {
+                let check = update_manager::check_firmware_endpoint(config, firmware_client, component).await;
+                results.insert(component.as_str().to_string(), serde_json::to_value(check)?);
+            }
+            data = serde_json::Value::Object(results);
+        }
+
+        "get_firmware_version" => {
+            data = firmware_versions(config, firmware_client, usb_handle).await;
+            info!("Firmware versions: {}", data);
+        }
+
+        "list_commands" => {
+            data = serde_json::to_value(COMMANDS)?;
+        }
+
+        _ => {
+            warn!("Unknown command: {}", command.command);
+        }
+    }
+
+    Ok(CommandResult::new(&command.command, data))
+}
+
+/// Run `commands` in order, stopping at the first failure
+async fn run_batch(commands: Vec<serde_json::Value>, ctx: &CommandContext) -> serde_json::Value {
+    let total = commands.len();
+    let mut results = Vec::with_capacity(total);
+
+    for value in commands {
+        let name = value.get("command").and_then(|c| c.as_str()).unwrap_or_default().to_string();
+        let outcome = match serde_json::from_value::<Command>(value) {
+            Ok(command) => Box::pin(timed_dispatch(command, ctx)).await,
+            Err(e) => Err(ProbeError::CommandError(format!("Invalid command in batch: {}", e)).into()),
+        };
+
+        match outcome {
+            Ok(result) => results.push(serde_json::json!({
+                "command": name,
+                "success": true,
+                "error": null,
+                "data": result.data,
+            })),
+            Err(e) => {
+                error!("Batch command {} failed, skipping the rest of the batch: {}", name, e);
+                results.push(serde_json::json!({
+                    "command": name,
+                    "success": false,
+                    "error": e.to_string(),
+                    "data": null,
+                }));
+                break;
+            }
+        }
+    }
+
+    let skipped = total - results.len();
+    serde_json::json!({ "results": results, "skipped": skipped })
+}
+
+/// Write `entries` to `<dir>/snapshot_<timestamp>.json`, keeping only the newest
+/// `max_snapshots` snapshot files
+async fn write_snapshot(dir: &Path, entries: &[LogEntry], max_snapshots: usize) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(dir).await?;
+
+    let captured_at = Utc::now();
+    let path = dir.join(format!("snapshot_{}.json", captured_at.format("%Y%m%dT%H%M%S%.3fZ")));
+    let snapshot = serde_json::json!({
+        "captured_at": captured_at.to_rfc3339(),
+        "entry_count": entries.len(),
+        "entries": entries,
+    });
+    tokio::fs::write(&path, serde_json::to_vec_pretty(&snapshot)?).await?;
+    prune_old_files(dir, "snapshot_", ".json", max_snapshots).await?;
+
+    Ok(path)
+}
+
+/// Resolve `path` (relative to `dir` unless absolute) to a file inside `dir`,
+/// creating `dir` if needed
+async fn stream_file_path(dir: &Path, path: &str) -> Result<PathBuf> {
+    let outside = || ProbeError::CommandError(format!("path must be a file inside {:?}, got {:?}", dir, path));
+    if path.is_empty() {
+        return Err(outside().into());
+    }
+    tokio::fs::create_dir_all(dir).await?;
+    let dir = tokio::fs::canonicalize(dir).await?;
+
+    let requested = dir.join(path);
+    let file_name = requested.file_name().ok_or_else(outside)?;
+    // Resolves `..` and symlinks in the directory part
+    let parent = tokio::fs::canonicalize(requested.parent().ok_or_else(outside)?)
+        .await
+        .map_err(|_| outside())?;
+    if !parent.starts_with(&dir) {
+        return Err(outside().into());
+    }
+    Ok(parent.join(file_name))
+}
+
+/// Resolve `path` (relative to the archive's directory unless absolute) to an
+/// existing file in the same directory as `archive`
+async fn archive_file_path(archive: &Path, path: &str) -> Result<PathBuf> {
+    let dir = archive.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let outside = || ProbeError::CommandError(format!("path must be an archive file in {:?}, got {:?}", dir, path));
+    if path.is_empty() {
+        return Err(outside().into());
+    }
+    let dir = tokio::fs::canonicalize(dir).await.map_err(|_| outside())?;
+
+    // Resolves `..` and symlinks
+    let file = tokio::fs::canonicalize(dir.join(path)).await.map_err(|_| outside())?;
+    if !file.starts_with(&dir) || !tokio::fs::metadata(&file).await?.is_file() {
+        return Err(outside().into());
+    }
+    Ok(file)
+}
+
+/// Entries of a JSON-lines archive file, gunzipped first if it ends in `.gz`,
+/// and the number of lines that were not a valid entry
+async fn read_archive(path: &Path) -> Result<(Vec<LogEntry>, usize)> {
+    let mut data = tokio::fs::read(path).await?;
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        data = compress::decompress_payload(&data, Some("gzip"))?;
+    }
+
+    let mut entries = Vec::new();
+    let mut malformed = 0;
+    for line in data.split(|&b| b == b'\n').filter(|line| !line.trim_ascii().is_empty()) {
+        match serde_json::from_slice::<LogEntry>(line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => malformed += 1,
+        }
+    }
+    if malformed > 0 {
+        warn!("Skipped {} malformed lines in {:?}", malformed, path);
+    }
+    Ok((entries, malformed))
+}
+
+/// Delete all but the newest `keep` files in `dir` named `<prefix><timestamp><suffix>`
+async fn prune_old_files(dir: &Path, prefix: &str, suffix: &str, keep: usize) -> Result<()> {
+    // Timestamped names sort chronologically
+    let mut files = Vec::new();
+    let mut dir_entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = dir_entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(prefix) && name.ends_with(suffix) {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    for old in &files[..files.len().saturating_sub(keep)] {
+        if let Err(e) = tokio::fs::remove_file(old).await {
+            warn!("Failed to remove old file {:?}: {}", old, e);
+        }
+    }
+    Ok(())
+}
+
+/// The config in effect with secrets masked, as returned by `export_config`
+fn config_export(config: &Config, config_sources: &ConfigSources) -> Result<serde_json::Value> {
+    Ok(serde_json::json!({
+        "config": config.sanitized()?,
+        "paths": config_sources.paths,
+        "overridden_fields": config_sources.overridden_fields,
+    }))
+}
+
+/// Everything support needs to look into a probe remotely, in one JSON value
+async fn diagnostics_report(ctx: &CommandContext) -> Result<serde_json::Value> {
+    let config = &ctx.config;
+    let (recent_logs, buffer_stats) = {
+        let buffer = ctx.buffer.read().await;
+        let skip = buffer.len().saturating_sub(DIAGNOSTICS_LOG_ENTRIES);
+        (buffer.iter().skip(skip).cloned().collect::<Vec<_>>(), buffer.stats())
+    };
+    let config_export = match config.allow_config_export {
+        true => config_export(config, &ctx.config_sources)?,
+        false => serde_json::Value::Null,
+    };
+
+    let schedule = ctx.upload_schedule.read().await.clone();
+    let upload_schedule = serde_json::json!({
+        "current_interval_seconds": schedule.current_interval(),
+        "active_period": schedule.active_period,
+        "inactive_period": schedule.inactive_period,
+        "start_time": schedule.start_time,
+        "end_time": schedule.end_time,
+        "smooth_transition_seconds": schedule.smooth_transition_seconds,
+        "next_change_at": schedule.next_change_at(),
+        "last_upload_at": *ctx.last_upload_at.read().await,
+    });
+
+    let node_firmware = serde_json::json!({
+        "deployed_version": update_manager::deployed_node_version().await.ok(),
+        "live_version": update_manager::query_node_version(&ctx.usb_handle).await,
+        "update": ctx.update_state.to_json(),
+    });
+
+    let probe_binary = match update_manager::running_probe_binary().await {
+        Ok((path, sha256)) => serde_json::json!({ "path": path, "sha256": sha256 }),
+        Err(e) => serde_json::json!({ "error": e.to_string() }),
+    };
+
+    Ok(serde_json::json!({
+        "generated_at": Utc::now().to_rfc3339(),
+        "node_id": config.node_id,
+        "probe_version": config.probe_version,
+        "build_timestamp": config.build_timestamp,
+        "config": config_export,
+        "recent_logs": recent_logs,
+        "buffer": buffer_stats,
+        "usb": ctx.usb_handle.stats().to_json(),
+        "upload": ctx.upload_stats.to_json(),
+        "runtime": ctx.runtime_metrics.to_json(),
+        "slowest_commands": ctx.command_timings.slowest(DIAGNOSTICS_SLOWEST_COMMANDS),
+        "upload_schedule": upload_schedule,
+        "node_firmware": node_firmware,
+        "probe_binary": probe_binary,
+        "system": system_info().await,
+    }))
+}
+
+/// Uptime, memory and free disk space; values this platform does not provide are `null`
+async fn system_info() -> serde_json::Value {
+    let uptime_seconds = tokio::fs::read_to_string("/proc/uptime")
+        .await
+        .ok()
+        .and_then(|uptime| uptime.split_whitespace().next()?.parse::<f64>().ok());
+    let meminfo = tokio::fs::read_to_string("/proc/meminfo").await.unwrap_or_default();
+
+    serde_json::json!({
+        "uptime_seconds": uptime_seconds,
+        "mem_total_bytes": meminfo_bytes(&meminfo, "MemTotal"),
+        "mem_available_bytes": meminfo_bytes(&meminfo, "MemAvailable"),
+        "disk_available_bytes": update_manager::available_disk_space(Path::new(".")).ok(),
+    })
+}
+
+/// Result of `hardware_info`, for sending without a hub request
+pub async fn hardware_info_result() -> CommandResult {
+    CommandResult::new("hardware_info", hardware_info().await)
+}
+
+/// Probe host CPU, memory, kernel, OS and hostname; fields that cannot be
+/// read are `null`
+#[cfg(target_os = "linux")]
+async fn hardware_info() -> serde_json::Value {
+    let read = |path: &'static str| async move { tokio::fs::read_to_string(path).await.unwrap_or_default() };
+    let cpuinfo = read("/proc/cpuinfo").await;
+    let meminfo = read("/proc/meminfo").await;
+    let version = read("/proc/version").await;
+    let os_release = read("/etc/os-release").await;
+
+    serde_json::json!({
+        "cpu_model": cpu_model(&cpuinfo),
+        "cpu_count": std::thread::available_parallelism().map(|n| n.get()).ok(),
+        "arch": std::env::consts::ARCH,
+        "mem_total_bytes": meminfo_bytes(&meminfo, "MemTotal"),
+        "mem_available_bytes": meminfo_bytes(&meminfo, "MemAvailable"),
+        "kernel_version": kernel_version(&version),
+        "os_name": os_pretty_name(&os_release),
+        "hostname": nix::unistd::gethostname().ok().and_then(|name| name.into_string().ok()),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn hardware_info() -> serde_json::Value {
+    serde_json::json!({
+        "cpu_model": null,
+        "cpu_count": std::thread::available_parallelism().map(|n| n.get()).ok(),
+        "arch": std::env::consts::ARCH,
+        "mem_total_bytes": null,
+        "mem_available_bytes": null,
+        "kernel_version": null,
+        "os_name": std::env::consts::OS,
+        "hostname": null,
+    })
+}
+
+/// `model name : Intel(R) ...` from `/proc/cpuinfo`, or the board `Model`
+/// line ARM kernels give instead
+#[cfg(target_os = "linux")]
+fn cpu_model(cpuinfo: &str) -> Option<String> {
+    let field = |key: &str| {
+        cpuinfo.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            (name.trim() == key && !value.trim().is_empty()).then(|| value.trim().to_string())
+        })
+    };
+    field("model name").or_else(|| field("Model"))
+}
+
+/// `Linux version 6.1.0-rpi7-rpi-v8 (...)` -> `6.1.0-rpi7-rpi-v8`
+#[cfg(target_os = "linux")]
+fn kernel_version(version: &str) -> Option<String> {
+    version.strip_prefix("Linux version ")?.split_whitespace().next().map(str::to_string)
+}
+
+/// `PRETTY_NAME="Debian GNU/Linux 12 (bookworm)"` from `/etc/os-release`
+#[cfg(target_os = "linux")]
+fn os_pretty_name(os_release: &str) -> Option<String> {
+    os_release.lines().find_map(|line| {
+        let value = line.strip_prefix("PRETTY_NAME=")?.trim();
+        Some(value.trim_matches(|c| c == '"' || c == '\'').to_string())
+    })
+}
+
+/// `MemAvailable:  1024 kB` -> 1048576
+fn meminfo_bytes(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let kb = line.strip_prefix(key)?.strip_prefix(':')?.trim().trim_end_matches("kB").trim();
+        Some(kb.parse::<u64>().ok()? * 1024)
+    })
+}
+
+/// Write `report` gzip-compressed to `<dir>/diag_<timestamp>.json.gz`, keeping
+/// only the newest `max_reports` reports. Returns the path and uncompressed size.
+async fn write_diagnostics(dir: &Path, report: &serde_json::Value, max_reports: usize) -> Result<(PathBuf, usize)> {
+    tokio::fs::create_dir_all(dir).await?;
+
+    let json = serde_json::to_vec(report)?;
+    let (compressed, _) = compress::compress_payload(&json, CompressionFormat::Gzip)?;
+    let path = dir.join(format!("diag_{}.json.gz", Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+    tokio::fs::write(&path, compressed).await?;
+    prune_old_files(dir, "diag_", ".json.gz", max_reports).await?;
+
+    Ok((path, json.len()))
+}
+
+/// POST a compressed diagnostics report to `{server_url}/diagnostics`
+async fn upload_diagnostics(config: &Config, api_key: &str, path: &Path) -> Result<()> {
+    let body = tokio::fs::read(path).await?;
+    let client = connectivity::client_builder(config)?
+        .timeout(Duration::from_secs(config.upload_timeout_seconds))
+        .build()?;
+    let response = client
+        .post(format!("{}/diagnostics", config.server_url))
+        .header("Content-Type", "application/json")
+        .header("Content-Encoding", "gzip")
+        .header("X-Node-ID", config.node_id.to_string())
+        .header("X-Api-Key", api_key)
+        .body(body)
+        .send()
+        .await
+        .map_err(ProbeError::from)?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        return Err(ProbeError::AuthError(format!("hub answered {}, check api_key", status)).into());
+    }
+    if !status.is_success() {
+        return Err(ProbeError::UploadFailed { status: status.as_u16() }.into());
+    }
+    Ok(())
+}
+
+/// Outcome of `register_node`
+#[derive(Debug)]
+pub enum Registration {
+    /// The hub issued `api_key`, which was saved to `path`
+    Registered { api_key: String, path: PathBuf },
+    /// The hub answered 409; the configured API key stays in use
+    AlreadyRegistered,
+}
+
+#[derive(Deserialize)]
+struct RegisterResponse {
+    api_key: String,
+}
+
+/// POST the node's identity to `{server_url}/register` and save the API key
+/// the hub issues to the config file
+pub async fn register_node(config: &Config, config_sources: &ConfigSources, node_firmware_version: Option<u32>) -> Result<Registration> {
+    let client = connectivity::client_builder(config)?
+        .timeout(Duration::from_secs(config.upload_timeout_seconds))
+        .build()?;
+    let request = serde_json::json!({
+        "node_id": config.node_id,
+        "hardware_info": hardware_info().await,
+        "node_firmware_version": node_firmware_version,
+        "probe_version": config.probe_version,
+    });
+    let response = client
+        .post(format!("{}/register", config.server_url))
+        .header("X-Node-ID", config.node_id.to_string())
+        .json(&request)
+        .send()
+        .await
+        .map_err(ProbeError::from)?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::CONFLICT {
+        warn!("Node {} is already registered with the hub, keeping the configured API key", config.node_id);
+        return Ok(Registration::AlreadyRegistered);
+    }
+    if !status.is_success() {
+        return Err(ProbeError::UploadFailed { status: status.as_u16() }.into());
+    }
+
+    let api_key = response.json::<RegisterResponse>().await.map_err(ProbeError::from)?.api_key;
+    if api_key.is_empty() || api_key.chars().count() > MAX_API_KEY_LENGTH {
+        return Err(ProbeError::AuthError(format!("hub issued an API key that is not 1 to {} characters long", MAX_API_KEY_LENGTH)).into());
+    }
+    let path = config::persist_api_key(config, config_sources, &api_key, "node_register")?;
+    Ok(Registration::Registered { api_key, path })
+}
+
+/// Delete the dead letter file, all snapshots and diagnostics reports,
+/// returning the removed paths
+async fn remove_persisted_state(config: &Config) -> Result<Vec<PathBuf>> {
+    let mut candidates: Vec<PathBuf> = config.dead_letter_path.iter().cloned().collect();
+    match tokio::fs::read_dir(&config.snapshot_dir).await {
+        Ok(mut dir_entries) => {
+            while let Some(entry) = dir_entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let snapshot = name.starts_with("snapshot_") && name.ends_with(".json");
+                let report = name.starts_with("diag_") && name.ends_with(".json.gz");
+                if snapshot || report {
+                    candidates.push(entry.path());
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut removed = Vec::new();
+    for path in candidates {
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => removed.push(path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(removed)
+}
+
+/// Ping the node `PING_COUNT` times and summarize the round-trip times in milliseconds
+async fn measure_usb_latency(usb_handle: &UsbHandle) -> serde_json::Value {
+    let mut rtts_ms = Vec::with_capacity(PING_COUNT);
+    for _ in 0..PING_COUNT {
+        let sent_at = tokio::time::Instant::now();
+        match usb_handle.query("/PING".to_string(), NODE_PING_PREFIX, PING_TIMEOUT).await {
+            Ok(_) => rtts_ms.push(sent_at.elapsed().as_secs_f64() * 1000.0),
+            Err(e) => debug!("USB ping lost: {}", e),
+        }
+    }
+
+    let loss_percent = (PING_COUNT - rtts_ms.len()) as f64 * 100.0 / PING_COUNT as f64;
+    if rtts_ms.is_empty() {
+        warn!("USB latency: all {} pings lost", PING_COUNT);
+        return serde_json::json!({ "sent": PING_COUNT, "loss_percent": loss_percent });
+    }
+
+    rtts_ms.sort_by(f64::total_cmp);
+    let min = rtts_ms[0];
+    let max = rtts_ms[rtts_ms.len() - 1];
+    let mean = rtts_ms.iter().sum::<f64>() / rtts_ms.len() as f64;
+    let p95 = rtts_ms[(rtts_ms.len() * 95).div_ceil(100) - 1];
+
+    usb_handle.stats().record_rtt(mean);
+    info!(
+        "USB latency: min {:.2}ms, max {:.2}ms, mean {:.2}ms, p95 {:.2}ms, loss {:.0}%",
+        min, max, mean, p95, loss_percent
+    );
+
+    serde_json::json!({
+        "sent": PING_COUNT,
+        "min_ms": min,
+        "max_ms": max,
+        "mean_ms": mean,
+        "p95_ms": p95,
+        "loss_percent": loss_percent,
+    })
+}
+
+/// Check USB, firmware, buffer and upload health concurrently and grade the
+/// probe "ok", "degraded" or "critical"
+async fn node_health(ctx: &CommandContext) -> serde_json::Value {
+    let (usb, firmware, buffer_fill_percent, last_upload_at) = tokio::join!(
+        measure_usb_latency(&ctx.usb_handle),
+        firmware_versions(&ctx.config, &ctx.firmware_client, &ctx.usb_handle),
+        async {
+            let buffer = ctx.buffer.read().await;
+            buffer.len() as f64 * 100.0 / buffer.max_size().max(1) as f64
+        },
+        async { *ctx.last_upload_at.read().await },
+    );
+    let upload_interval = ctx.upload_schedule.read().await.current_interval();
+    let last_upload_age = (Utc::now() - last_upload_at).num_seconds().max(0) as u64;
+
+    let usb_rtt_ms = usb["mean_ms"].as_f64();
+    let mut critical = Vec::new();
+    let mut degraded = Vec::new();
+
+    match usb_rtt_ms {
+        None => critical.push("usb_disconnected"),
+        Some(rtt) if rtt > HEALTH_USB_RTT_MS => degraded.push("usb_rtt_high"),
+        Some(_) => {}
+    }
+    if last_upload_age > upload_interval * 2 {
+        critical.push("upload_stale");
+    }
+    if buffer_fill_percent > HEALTH_BUFFER_FILL_PERCENT {
+        degraded.push("buffer_nearly_full");
+    }
+
+    let health = if !critical.is_empty() {
+        "critical"
+    } else if !degraded.is_empty() {
+        "degraded"
+    } else {
+        "ok"
+    };
+
+    serde_json::json!({
+        "health": health,
+        "reasons": critical.into_iter().chain(degraded).collect::<Vec<_>>(),
+        "usb": usb,
+        "firmware": firmware,
+        "buffer_fill_percent": buffer_fill_percent,
+        "last_upload_at": last_upload_at.to_rfc3339(),
+        "last_upload_age_seconds": last_upload_age,
+        "upload_interval_seconds": upload_interval,
+    })
+}
+
+/// Have the node emit `lines_per_second` synthetic lines (`/PERF_<lps>_<dur>_`)
+/// for `duration_seconds`, counting what arrives and sampling the buffer
+/// fill, then stop it with `/PERF_STOP_`
+async fn performance_test(
+    usb_handle: &UsbHandle,
+    buffer: &RwLock<LogBuffer>,
+    raw_lines: &broadcast::Sender<String>,
+    lines_per_second: u32,
+    duration_seconds: u64,
+) -> Result<serde_json::Value> {
+    let stats = usb_handle.stats();
+    let bytes_before = stats.bytes_received_total.load(Ordering::Relaxed);
+    let dedup_before = stats.dedup_hits_total.load(Ordering::Relaxed);
+    let (dropped_before, max_size) = {
+        let buffer = buffer.read().await;
+        (buffer.total_dropped(), buffer.max_size())
+    };
+
+    let mut lines = raw_lines.subscribe();
+    info!("Starting performance test: {} lines/s for {}s", lines_per_second, duration_seconds);
+    usb_handle.send_command(format!("/PERF_{}_{}_", lines_per_second, duration_seconds)).await?;
+
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(duration_seconds);
+    let mut sampler = tokio::time::interval(PERFORMANCE_SAMPLE_INTERVAL);
+    let mut received = 0u64;
+    let mut peak_len = 0usize;
+    loop {
+        tokio::select! {
+            line = lines.recv() => match line {
+                Ok(_) => received += 1,
+                // Received from the node even though this subscriber missed them
+                Err(broadcast::error::RecvError::Lagged(missed)) => received += missed,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = sampler.tick() => peak_len = peak_len.max(buffer.read().await.len()),
+            _ = tokio::time::sleep_until(deadline) => break,
+        }
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    usb_handle.send_command("/PERF_STOP_".to_string()).await?;
+
+    let actual_rate = received as f64 / elapsed;
+    let dropped = buffer.read().await.total_dropped() - dropped_before;
+    let bytes_received = stats.bytes_received_total.load(Ordering::Relaxed) - bytes_before;
+    info!("Performance test received {} lines ({:.1}/s), {} dropped from the buffer", received, actual_rate, dropped);
+
+    Ok(serde_json::json!({
+        "requested_lines_per_second": lines_per_second,
+        "duration_seconds": duration_seconds,
+        "expected_lines": u64::from(lines_per_second) * duration_seconds,
+        "received_lines": received,
+        "actual_lines_per_second": actual_rate,
+        "rate_percent": actual_rate * 100.0 / f64::from(lines_per_second),
+        "bytes_received": bytes_received,
+        "bytes_per_second": bytes_received as f64 / elapsed,
+        "buffer_peak_len": peak_len,
+        "buffer_peak_percent": if max_size > 0 { peak_len as f64 * 100.0 / max_size as f64 } else { 0.0 },
+        "buffer_dropped": dropped,
+        "dedup_hits": stats.dedup_hits_total.load(Ordering::Relaxed) - dedup_before,
+    }))
+}
+
+/// Query the node's system properties one after another, with `null` for
+/// any the node does not answer within `wait`
+async fn node_info(usb_handle: &UsbHandle, wait: Duration) -> serde_json::Value {
+    let mut info = serde_json::Map::new();
+    for (field, command, prefix) in NODE_INFO_QUERIES {
+        let value = match usb_handle.query(command.to_string(), prefix, wait).await {
+            Ok(line) => parse_number(&line[prefix.len()..]),
+            Err(e) => {
+                debug!("Node info {}: no answer to {}: {}", field, command, e);
+                None
+            }
+        };
+        debug!("Node info {}: {:?}", field, value);
+        info.insert(field.to_string(), value.unwrap_or(serde_json::Value::Null));
+    }
+    serde_json::Value::Object(info)
+}
+
+/// Parse a node reply value as an integer if possible, otherwise as a float
+fn parse_number(value: &str) -> Option<serde_json::Value> {
+    let value = value.trim();
+    value
+        .parse::<u64>()
+        .map(serde_json::Value::from)
+        .ok()
+        .or_else(|| value.parse::<f64>().ok().map(serde_json::Value::from))
+}
+
+/// Command result reporting a failed update, with `error_code` naming the `ProbeError` variant
+fn update_failure(e: &anyhow::Error) -> serde_json::Value {
+    serde_json::json!({
+        "error_code": error::error_code(e),
+        "error": e.to_string(),
+    })
+}
+
+/// Node and probe versions from the deployed files, the live node answer to
+/// `/VQ` and whether the firmware servers offer anything newer. Any value that
+/// cannot be determined is reported as `null`.
+async fn firmware_versions(config: &Config, client: &reqwest::Client, usb_handle: &UsbHandle) -> serde_json::Value {
+    let node_file = update_manager::deployed_node_version().await.ok();
+    let probe_file = update_manager::deployed_probe_version().await.ok();
+    let node_live = update_manager::query_node_version(usb_handle).await;
+
+    let latest_node = update_manager::latest_node_version(config, client).await.ok();
+    let latest_probe = update_manager::latest_probe_version(config, client).await.ok();
+
+    let update_available = match (latest_node, latest_probe) {
+        (None, None) => None,
+        _ => Some(
+            latest_node.is_some_and(|latest| latest > node_live.or(node_file).unwrap_or(0))
+                || latest_probe.is_some_and(|latest| latest > probe_file.unwrap_or(0)),
+        ),
+    };
+
+    serde_json::json!({
+        "node_version": node_file,
+        "probe_version": probe_file,
+        "node_live_version": node_live,
+        "latest_node_version": latest_node,
+        "latest_probe_version": latest_probe,
+        "update_available": update_available,
+    })
+}
+
+/// Query the node measurement status every `interval`, bringing
+/// `running_measurements` in line with it
+pub async fn run_measurement_status_poll(ctx: CommandContext, interval: Duration) -> Result<()> {
+    info!("Polling node measurement status every {}s", interval.as_secs());
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match measurement_status(&ctx.usb_handle, &ctx.running_measurements).await {
+            Ok(status) => debug!("Node measurement status: {:?}", status),
+            Err(e) => warn!("Failed to query node measurement status: {}", e),
+        }
+    }
+}
+
+/// Query `/MQ` and update `running_measurements` from the answer
+async fn measurement_status(usb_handle: &UsbHandle, running_measurements: &RwLock<HashSet<u32>>) -> Result<MeasurementStatus> {
+    let reply = usb_handle
+        .query("/MQ".to_string(), MEASUREMENT_STATUS_PREFIX, MEASUREMENT_QUERY_TIMEOUT)
+        .await?;
+    let status = MeasurementStatus::parse(&reply)?;
+
+    let mut running = running_measurements.write().await;
+    if status.running {
+        running.insert(status.sequence);
+    } else if !running.is_empty() {
+        warn!("Measurement desync detected: node reports none running, probe expected {:?}", running);
+        running.clear();
+    }
+    Ok(status)
+}
+
+/// Send the probe's time to the node every `interval`
+pub async fn run_rtc_sync(usb_handle: UsbHandle, interval: Duration) -> Result<()> {
+    info!("Node RTC sync every {}s", interval.as_secs());
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = usb_handle.send_command(rtc_command(Utc::now())).await {
+            warn!("Failed to sync node RTC: {}", e);
+        }
+    }
+}
+
+/// `/RTC_<yyyy>_<mm>_<dd>_<HH>_<MM>_<SS>_`
+fn rtc_command(now: DateTime<Utc>) -> String {
+    now.format("/RTC_%Y_%m_%d_%H_%M_%S_").to_string()
+}
+
+/// A single RFC 1123 label: 1-63 ASCII letters, digits or hyphens, no hyphen at either end
+fn is_valid_hostname(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+/// Apply `hostname` with `sudo hostnamectl set-hostname` and write it to
+/// `/etc/hostname` so it survives a reboot
+async fn set_probe_hostname(hostname: &str) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+    use tokio::process::Command;
+
+    let output = Command::new("sudo").args(["hostnamectl", "set-hostname", hostname]).output().await?;
+    if !output.status.success() {
+        return Err(ProbeError::CommandError(format!(
+            "hostnamectl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into());
+    }
+
+    let mut tee = Command::new("sudo")
+        .args(["tee", "/etc/hostname"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = tee.stdin.take() {
+        stdin.write_all(format!("{}\n", hostname).as_bytes()).await?;
+    }
+    let output = tee.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(ProbeError::CommandError(format!(
+            "Writing /etc/hostname failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Whether the node sends nothing over USB in power `mode` with its logs on
+/// `output`; the USB idle timeout stays suspended for as long as this holds
+fn node_quiet_on_usb(mode: &str, output: &str) -> bool {
+    matches!(mode, "sleep" | "dormant") || output != "usb"
+}
+
+/// Record the node's power `mode`, suspending or resuming the USB idle timeout
+/// when the node goes quiet or wakes up
+async fn apply_node_power_mode(
+    usb_handle: &UsbHandle,
+    node_power_mode: &RwLock<String>,
+    node_log_output: &RwLock<String>,
+    mode: &str,
+) -> Result<()> {
+    let mut current = node_power_mode.write().await;
+    let output = node_log_output.read().await;
+    let quiet = node_quiet_on_usb(mode, &output);
+    if quiet != node_quiet_on_usb(&current, &output) {
+        usb_handle.suspend_idle_timeout(quiet).await?;
+    }
+    *current = mode.to_string();
+    Ok(())
+}
+
+/// Record where the node sends its logs, suspending or resuming the USB idle
+/// timeout when log lines stop or start arriving over USB
+async fn apply_node_log_output(
+    usb_handle: &UsbHandle,
+    node_power_mode: &RwLock<String>,
+    node_log_output: &RwLock<String>,
+    output: String,
+) -> Result<()> {
+    // Same lock order as apply_node_power_mode
+    let mode = node_power_mode.read().await;
+    let mut current = node_log_output.write().await;
+    let quiet = node_quiet_on_usb(&mode, &output);
+    if quiet != node_quiet_on_usb(&mode, &current) {
+        usb_handle.suspend_idle_timeout(quiet).await?;
+    }
+    *current = output;
+    Ok(())
+}
+
+/// `PM:S` or `PM:sleep` -> "sleep"
+fn parse_power_mode_reply(reply: &str) -> Result<&'static str, ProbeError> {
+    let value = reply[POWER_MODE_PREFIX.len()..].trim();
+    NODE_POWER_MODES
+        .iter()
+        .find(|(name, letter)| name.eq_ignore_ascii_case(value) || value.eq_ignore_ascii_case(&letter.to_string()))
+        .map(|(name, _)| *name)
+        .ok_or_else(|| ProbeError::CommandError(format!("Unreadable power mode reply: {}", reply)))
+}
+
+/// `RTC:2024-05-01T12:30:00` -> 2024-05-01 12:30:00 UTC
+fn parse_rtc_reply(reply: &str) -> Result<DateTime<Utc>, ProbeError> {
+    chrono::NaiveDateTime::parse_from_str(reply[RTC_PREFIX.len()..].trim(), "%Y-%m-%dT%H:%M:%S")
+        .map(|time| time.and_utc())
+        .map_err(|_| ProbeError::CommandError(format!("Unreadable RTC reply: {}", reply)))
+}
+
+/// `rate` as sent in `/SR_<rate>_`: a plain integer when it is whole,
+/// otherwise fixed-point with 3 decimals
+fn encode_sampling_rate(rate: f64) -> String {
+    if rate.fract() == 0.0 {
+        format!("{}", rate as u64)
+    } else {
+        format!("{:.3}", rate)
+    }
+}
+
+/// `/MP_<key>_<value>_..._` for `params`, whose keys must be in `allowed` and
+/// whose values must be finite
+fn measurement_params_command(params: &BTreeMap<String, f64>, allowed: &[String]) -> Result<String, ProbeError> {
+    let mut command = "/MP_".to_string();
+    for (key, value) in params {
+        if !allowed.contains(key) {
+            return Err(ProbeError::CommandError(format!("unknown measurement parameter '{}'", key)));
+        }
+        if !value.is_finite() {
+            return Err(ProbeError::CommandError(format!("measurement parameter '{}' is not finite", key)));
+        }
+        command.push_str(&format!("{}_{}_", key, format_significant(*value)));
+    }
+    Ok(command)
+}
+
+/// Format `value` with at most 6 significant figures, without trailing zeros,
+/// switching to exponent notation for very large or small magnitudes
+fn format_significant(value: f64) -> String {
+    const SIGNIFICANT: i32 = 6;
+
+    if value == 0.0 {
+        return "0".to_string();
+    }
+
+    let exponent = value.abs().log10().floor() as i32;
+    if !(-4..SIGNIFICANT).contains(&exponent) {
+        let formatted = format!("{:.*e}", (SIGNIFICANT - 1) as usize, value);
+        let (mantissa, exponent) = formatted.split_once('e').unwrap_or((&formatted, "0"));
+        return format!("{}e{}", trim_fraction(mantissa), exponent);
+    }
+
+    let decimals = (SIGNIFICANT - 1 - exponent).max(0) as usize;
+    trim_fraction(&format!("{:.*}", decimals, value)).to_string()
+}
+
+/// Strip trailing zeros, and then a trailing decimal point, from a formatted number
+fn trim_fraction(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
+/// `get_status` view of the node watchdog
+fn watchdog_status(timeout_ms: Option<u32>) -> serde_json::Value {
+    serde_json::json!({
+        "enabled": timeout_ms.is_some(),
+        "timeout_ms": timeout_ms,
+    })
+}
+
+/// `/WD_<timeout_ms>_`, where a timeout of 0 disables the watchdog
+fn watchdog_command(timeout_ms: u32) -> String {
+    format!("/WD_{}_", timeout_ms)
+}
+
+/// Decode a hex string such as `2f42530d0a` into bytes
+fn decode_hex(hex: &str) -> Result<Vec<u8>, ProbeError> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(ProbeError::CommandError(format!("hex string has odd length {}", hex.len())));
+    }
+    if let Some(c) = hex.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(ProbeError::CommandError(format!("invalid hex character '{}'", c)));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| ProbeError::CommandError(e.to_string())))
+        .collect()
+}
+
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::usb_manager::testing::MockUsbManager;
+    use crate::usb_manager::UsbStats;
+    use tokio::sync::mpsc;
+
+    /// A context for `config` whose USB handle talks to a running `MockUsbManager`
+    pub fn context(config: Config) -> (CommandContext, MockUsbManager) {
+        let (command_tx, command_rx) = mpsc::channel(32);
+        let (message_tx, _) = mpsc::channel(32);
+        let mock = MockUsbManager::new(Vec::new(), command_rx, message_tx);
+        tokio::spawn(mock.clone().run());
+
+        let ctx = CommandContext {
+            buffer: Arc::new(RwLock::new(LogBuffer::new(config.buffer_size))),
+            filter_string: Arc::new(RwLock::new(String::new())),
+            timezone: Arc::new(RwLock::new(None)),
+            upload_schedule: Arc::new(RwLock::new(UploadSchedule::fixed(config.upload_interval_seconds))),
+            usb_handle: UsbHandle::new(command_tx, Arc::new(UsbStats::default()), config.max_pending_commands),
+            scheduled_commands: ScheduledCommands::default(),
+            node_log_output: Arc::new(RwLock::new("usb".to_string())),
+            node_power_mode: Arc::new(RwLock::new("full".to_string())),
+            running_measurements: Arc::new(RwLock::new(HashSet::new())),
+            sampling_rate: Arc::new(RwLock::new(None)),
+            watchdog_timeout_ms: Arc::new(RwLock::new(None)),
+            command_lock: Arc::new(Mutex::new(())),
+            upload_now: Arc::new(Notify::new()),
+            update_state: UpdateTracker::default(),
+            firmware_client: reqwest::Client::new(),
+            last_upload_at: Arc::new(RwLock::new(Utc::now())),
+            upload_stats: Arc::new(UploadStats::default()),
+            circuit_breaker: Arc::new(CircuitBreaker::new(5, Duration::from_secs(60))),
+            api_key: Arc::new(RwLock::new(config.api_key.clone())),
+            runtime_metrics: RuntimeMetrics::new(false),
+            config_sources: Arc::new(ConfigSources::default()),
+            raw_lines: broadcast::channel(16).0,
+            debug_port: Arc::new(Mutex::new(None)),
+            log_feed: broadcast::channel(16).0,
+            log_tail: Arc::new(Mutex::new(None)),
+            file_stream: Arc::new(Mutex::new(None)),
+            command_timings: CommandTimings::default(),
+            error_reporter: ErrorReporter::new().0,
+            pending_errors: PendingErrors::default(),
+            task_restarts: RestartTracker::default(),
+            config: Arc::new(config),
+        };
+        (ctx, mock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigChange;
+    use crate::usb_manager::testing::MockUsbManager;
+    use std::collections::BTreeSet;
+
+    const TEST_CONFIG: &str = r#"
+usb_port = "/dev/null"
+server_url = "http://127.0.0.1:9"
+api_key = "test-key"
+node_id = 1
+node_firmware_url = "http://127.0.0.1:9/node"
+probe_firmware_url = "http://127.0.0.1:9/probe"
+"#;
+
+    /// A context whose USB handle talks to a running `MockUsbManager`
+    fn test_context() -> (CommandContext, MockUsbManager) {
+        context_with("")
+    }
+
+    /// `test_context` with `settings` (TOML lines) added to the config
+    fn context_with(settings: &str) -> (CommandContext, MockUsbManager) {
+        testing::context(toml::from_str(&format!("{}{}", TEST_CONFIG, settings)).unwrap())
+    }
+
+    /// Commands the mock has captured once it has caught up with the queue,
+    /// leaving out control commands that put nothing on the wire
+    async fn sent_commands(mock: &MockUsbManager, at_least: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let sent: Vec<String> =
+                mock.sent_commands().iter().filter(|command| !command.is_empty()).map(|command| command.trim_end().to_string()).collect();
+            if sent.len() >= at_least {
+                return sent;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("mock captured only {:?}", mock.sent_commands());
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)
+    }
+
+    /// 60s inside 23:00-01:00 across midnight, 600s outside, ramped over 10 minutes either side of each boundary
+    fn overnight_schedule(smooth_transition_seconds: u64) -> UploadSchedule {
+        UploadSchedule {
+            start_time: Some(at("2024-05-01T23:00:00Z")),
+            end_time: Some(at("2024-05-02T01:00:00Z")),
+            active_period: 60,
+            inactive_period: 600,
+            smooth_transition_seconds,
+        }
+    }
+
+    #[test]
+    fn interval_switches_at_window_boundaries_without_smoothing() {
+        let schedule = overnight_schedule(0);
+
+        assert_eq!(schedule.interval_at(at("2024-05-01T22:59:59Z")), 600);
+        assert_eq!(schedule.interval_at(at("2024-05-01T23:00:00Z")), 60);
+        assert_eq!(schedule.interval_at(at("2024-05-02T00:00:00Z")), 60);
+        assert_eq!(schedule.interval_at(at("2024-05-02T01:00:00Z")), 60);
+        assert_eq!(schedule.interval_at(at("2024-05-02T01:00:01Z")), 600);
+    }
+
+    #[test]
+    fn interval_ramps_across_the_start_of_the_window() {
+        let schedule = overnight_schedule(600);
+
+        // Before the transition window
+        assert_eq!(schedule.interval_at(at("2024-05-01T22:49:59Z")), 600);
+        // At its start, halfway (the boundary itself) and at its end
+        assert_eq!(schedule.interval_at(at("2024-05-01T22:50:00Z")), 600);
+        assert_eq!(schedule.interval_at(at("2024-05-01T23:00:00Z")), 330);
+        assert_eq!(schedule.interval_at(at("2024-05-01T23:10:00Z")), 60);
+        // After it, inside the window
+        assert_eq!(schedule.interval_at(at("2024-05-01T23:30:00Z")), 60);
+    }
+
+    #[test]
+    fn interval_ramps_back_across_midnight_end() {
+        let schedule = overnight_schedule(600);
+
+        assert_eq!(schedule.interval_at(at("2024-05-02T00:49:59Z")), 60);
+        assert_eq!(schedule.interval_at(at("2024-05-02T00:50:00Z")), 60);
+        assert_eq!(schedule.interval_at(at("2024-05-02T00:55:00Z")), 195);
+        assert_eq!(schedule.interval_at(at("2024-05-02T01:00:00Z")), 330);
+        assert_eq!(schedule.interval_at(at("2024-05-02T01:10:00Z")), 600);
+        assert_eq!(schedule.interval_at(at("2024-05-02T02:00:00Z")), 600);
+    }
+
+    #[test]
+    fn fixed_schedule_never_changes() {
+        let schedule = UploadSchedule::fixed(120);
+
+        assert_eq!(schedule.interval_at(at("2024-05-01T12:00:00Z")), 120);
+        assert_eq!(schedule.next_change_after(at("2024-05-01T12:00:00Z")), None);
+    }
+
+    #[test]
+    fn next_change_is_the_next_boundary_ahead() {
+        let schedule = overnight_schedule(0);
+
+        assert_eq!(schedule.next_change_after(at("2024-05-01T12:00:00Z")), Some(at("2024-05-01T23:00:00Z")));
+        assert_eq!(schedule.next_change_after(at("2024-05-01T23:00:00Z")), Some(at("2024-05-02T01:00:00Z")));
+        assert_eq!(schedule.next_change_after(at("2024-05-02T00:30:00Z")), Some(at("2024-05-02T01:00:00Z")));
+        assert_eq!(schedule.next_change_after(at("2024-05-02T01:00:00Z")), None);
+    }
+
+    #[test]
+    fn interpolate_clamps_outside_the_span() {
+        let span = chrono::Duration::seconds(100);
+
+        assert_eq!(interpolate(600, 60, chrono::Duration::seconds(-10), span), 600);
+        assert_eq!(interpolate(600, 60, chrono::Duration::seconds(25), span), 465);
+        assert_eq!(interpolate(600, 60, chrono::Duration::seconds(150), span), 60);
+    }
+
+    #[tokio::test]
+    async fn replaying_a_file_twice_leaves_no_duplicates_with_deduplicate_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("archive.jsonl");
+        let lines = [("2024-05-01T12:00:02Z", "[INFO] b"), ("2024-05-01T12:00:01Z", "[INFO] a"), ("later", "[INFO] c")];
+        let lines: Vec<String> = lines
+            .iter()
+            .map(|(timestamp, message)| serde_json::to_string(&LogEntry::new(timestamp.to_string(), message.to_string())).unwrap())
+            .collect();
+        tokio::fs::write(&archive, lines.join("\n")).await.unwrap();
+        let config = format!("{}local_archive_path = {:?}\ndeduplicate_on_load = true\n", TEST_CONFIG, archive);
+        let (ctx, _mock) = testing::context(toml::from_str(&config).unwrap());
+        let replay = || execute_command(command("replay_logs", serde_json::json!({ "path": "archive.jsonl" })), &ctx);
+
+        let first = replay().await.unwrap().data;
+        assert_eq!((first["replayed"].as_u64(), first["duplicates_removed"].as_u64()), (Some(3), Some(0)));
+        assert_eq!(first["unparseable_timestamps"], 1);
+        let second = replay().await.unwrap().data;
+        assert_eq!((second["replayed"].as_u64(), second["duplicates_removed"].as_u64()), (Some(3), Some(3)));
+
+        // The entry without a valid timestamp goes last
+        let buffer = ctx.buffer.read().await;
+        let messages: Vec<&str> = buffer.iter().map(|entry| entry.message.as_str()).collect();
+        assert_eq!(messages, vec!["[INFO] a", "[INFO] b", "[INFO] c"]);
+    }
+
+    #[tokio::test]
+    async fn set_node_log_output_sends_the_matching_command() {
+        let (ctx, mock) = test_context();
+        for output in ["silent", "uart", "RTT", "usb"] {
+            let parameters = serde_json::json!({ "output": output });
+            execute_command(command("set_node_log_output", parameters), &ctx).await.unwrap();
+        }
+        let error = execute_command(command("set_node_log_output", serde_json::json!({ "output": "serial" })), &ctx).await;
+
+        assert!(error.unwrap_err().to_string().contains("serial"));
+        assert_eq!(sent_commands(&mock, 4).await, vec!["/LO_silent_", "/LO_uart_", "/LO_rtt_", "/LO_usb_"]);
+        assert_eq!(*ctx.node_log_output.read().await, "usb");
+    }
+
+    /// Wait for the mock to see the idle timeout suspended or resumed as `expected`
+    async fn assert_idle_timeout_suspended(mock: &MockUsbManager, expected: bool) {
+        for _ in 0..100 {
+            if mock.idle_timeout_suspended() == Some(expected) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("idle timeout suspended: {:?}, expected {}", mock.idle_timeout_suspended(), expected);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_is_suspended_while_the_node_is_quiet_on_usb() {
+        let (ctx, mock) = test_context();
+        let run = |name: &str, parameters: serde_json::Value| execute_command(command(name, parameters), &ctx);
+
+        run("set_node_log_output", serde_json::json!({ "output": "silent" })).await.unwrap();
+        assert_idle_timeout_suspended(&mock, true).await;
+
+        // Waking the node does not resume the timeout while its logs stay off USB
+        run("set_node_power_mode", serde_json::json!({ "mode": "sleep" })).await.unwrap();
+        run("set_node_power_mode", serde_json::json!({ "mode": "full" })).await.unwrap();
+        assert_eq!(sent_commands(&mock, 3).await.len(), 3);
+        assert_idle_timeout_suspended(&mock, true).await;
+
+        run("set_node_log_output", serde_json::json!({ "output": "usb" })).await.unwrap();
+        assert_eq!(sent_commands(&mock, 4).await, vec!["/LO_silent_", "/PM_S_", "/PM_F_", "/LO_usb_"]);
+        assert_idle_timeout_suspended(&mock, false).await;
+    }
+
+    #[tokio::test]
+    async fn batch_stops_at_first_failure() {
+        let (ctx, mock) = test_context();
+        let commands = vec![
+            serde_json::json!({ "command": "set_node_log_output", "parameters": { "output": "uart" } }),
+            serde_json::json!({ "command": "set_node_power_mode", "parameters": { "mode": "turbo" } }),
+            serde_json::json!({ "command": "set_node_log_output", "parameters": { "output": "usb" } }),
+            serde_json::json!({ "command": "set_node_power_mode", "parameters": { "mode": "low" } }),
+        ];
+
+        let outcome = run_batch(commands, &ctx).await;
+
+        let results = outcome["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["success"], true);
+        assert_eq!(results[1]["command"], "set_node_power_mode");
+        assert_eq!(results[1]["success"], false);
+        assert!(results[1]["error"].as_str().unwrap().contains("turbo"));
+        assert_eq!(outcome["skipped"], 2);
+        assert_eq!(sent_commands(&mock, 1).await, vec!["/LO_uart_"]);
+        assert_eq!(*ctx.node_log_output.read().await, "uart");
+    }
+
+    #[tokio::test]
+    async fn batch_reports_unparsable_commands_as_failures() {
+        let (ctx, _mock) = test_context();
+        let commands = vec![serde_json::json!({ "parameters": {} }), serde_json::json!({ "command": "get_status" })];
+
+        let outcome = run_batch(commands, &ctx).await;
+
+        assert_eq!(outcome["results"][0]["success"], false);
+        assert_eq!(outcome["skipped"], 1);
+    }
+
+    #[tokio::test]
+    async fn batch_without_failures_skips_nothing() {
+        let (ctx, mock) = test_context();
+        let commands = vec![
+            serde_json::json!({ "command": "set_node_log_output", "parameters": { "output": "rtt" } }),
+            serde_json::json!({ "command": "set_node_power_mode", "parameters": { "mode": "low" } }),
+        ];
+
+        let outcome = run_batch(commands, &ctx).await;
+
+        assert_eq!(outcome["results"].as_array().unwrap().len(), 2);
+        assert_eq!(outcome["skipped"], 0);
+        assert_eq!(sent_commands(&mock, 2).await, vec!["/LO_rtt_", "/PM_L_"]);
+    }
+
+    fn command(name: &str, parameters: serde_json::Value) -> Command {
+        Command { command: name.to_string(), parameters }
+    }
+
+    #[tokio::test]
+    async fn get_status_reports_the_watchdog_setting() {
+        let (ctx, mock) = test_context();
+        let watchdog = |result: CommandResult| result.data["watchdog"].clone();
+
+        let status = execute_command(command("get_status", serde_json::json!({})), &ctx).await.unwrap();
+        assert_eq!(watchdog(status), serde_json::json!({ "enabled": false, "timeout_ms": null }));
+
+        let enable = command("enable_watchdog", serde_json::json!({ "timeout_ms": MAX_WATCHDOG_TIMEOUT_MS }));
+        execute_command(enable, &ctx).await.unwrap();
+        let status = execute_command(command("get_status", serde_json::json!({})), &ctx).await.unwrap();
+        assert_eq!(watchdog(status), serde_json::json!({ "enabled": true, "timeout_ms": 8300 }));
+
+        execute_command(command("disable_watchdog", serde_json::json!({})), &ctx).await.unwrap();
+        let status = execute_command(command("get_status", serde_json::json!({})), &ctx).await.unwrap();
+        assert_eq!(watchdog(status)["enabled"], false);
+        assert_eq!(sent_commands(&mock, 2).await, vec!["/WD_8300_", "/WD_0_"]);
+    }
+
+    #[tokio::test]
+    async fn enable_watchdog_rejects_out_of_range_timeouts() {
+        let (ctx, mock) = test_context();
+        for timeout_ms in [0, MAX_WATCHDOG_TIMEOUT_MS + 1] {
+            let enable = command("enable_watchdog", serde_json::json!({ "timeout_ms": timeout_ms }));
+            assert!(execute_command(enable, &ctx).await.is_err());
+        }
+        let enable = command("enable_watchdog", serde_json::json!({ "timeout_ms": 1 }));
+        execute_command(enable, &ctx).await.unwrap();
+        assert_eq!(sent_commands(&mock, 1).await, vec!["/WD_1_"]);
+    }
+
+    #[test]
+    fn every_dispatched_command_is_described() {
+        let source = include_str!("command_executor.rs");
+        let dispatch = &source[source.find("async fn dispatch_command(").unwrap()..];
+        let dispatch = &dispatch[..dispatch.find("\n}\n").unwrap()];
+        // Arms of the match on the command name, e.g. `"get_status" => {`
+        let dispatched: BTreeSet<&str> = dispatch
+            .lines()
+            .filter(|line| line.starts_with("        \"") && line.contains("=>"))
+            .flat_map(|line| line.split("=>").next().unwrap().split('|'))
+            .map(|name| name.trim().trim_matches('"'))
+            .collect();
+        let described: BTreeSet<&str> = COMMANDS.iter().map(|descriptor| descriptor.name).collect();
+
+        assert!(!dispatched.is_empty());
+        assert_eq!(dispatched, described);
+    }
+
+    #[tokio::test]
+    async fn tail_logs_validates_port_and_level() {
+        let (ctx, _mock) = test_context();
+        for parameters in [serde_json::json!({ "port": 80 }), serde_json::json!({ "port": 40000, "level": "loud" })] {
+            assert!(execute_command(command("tail_logs", parameters), &ctx).await.is_err());
+        }
+        assert!(ctx.log_tail.lock().await.is_none());
+    }
+
+    #[test]
+    fn measurement_status_replies_parse_with_either_boolean_form() {
+        let running = MeasurementStatus { running: true, sequence: 5, samples_collected: 1200, elapsed_ms: 3000 };
+        assert_eq!(MeasurementStatus::parse("MSTATUS:true:5:1200:3000").unwrap(), running);
+        assert_eq!(MeasurementStatus::parse("MSTATUS:1:5:1200:3000\r").unwrap(), running);
+        assert!(!MeasurementStatus::parse("MSTATUS:0:0:0:0").unwrap().running);
+        let max = MeasurementStatus::parse(&format!("MSTATUS:false:{}:{}:0", u32::MAX, u64::MAX)).unwrap();
+        assert_eq!((max.sequence, max.samples_collected), (u32::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn malformed_measurement_status_replies_are_rejected() {
+        for reply in [
+            "",
+            "MSTATUS:",
+            "MQ:true:5:1200:3000",
+            "MSTATUS:yes:5:1200:3000",
+            "MSTATUS:true:5:1200",
+            "MSTATUS:true:5:1200:3000:7",
+            "MSTATUS:true:-5:1200:3000",
+            "MSTATUS:true:5:12.5:3000",
+            "MSTATUS:true:4294967296:0:0",
+        ] {
+            let error = MeasurementStatus::parse(reply).unwrap_err();
+            assert!(error.to_string().contains("Unreadable measurement status reply"), "{:?}", reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn measurement_status_resyncs_the_running_set() {
+        let (command_tx, command_rx) = tokio::sync::mpsc::channel(8);
+        let (message_tx, _messages) = tokio::sync::mpsc::channel(8);
+        let mock = MockUsbManager::new(Vec::new(), command_rx, message_tx).with_response("/MQ", "MSTATUS:false:0:0:0");
+        tokio::spawn(mock.run());
+        let handle = UsbHandle::new(command_tx, Arc::new(crate::usb_manager::UsbStats::default()), 8);
+        let running = RwLock::new(HashSet::from([3, 4]));
+
+        let status = measurement_status(&handle, &running).await.unwrap();
+
+        assert!(!status.running);
+        assert!(running.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn set_timezone_accepts_only_iana_names() {
+        let (ctx, _mock) = test_context();
+        for tz in ["Mars/Olympus_Mons", "", "EST5EDT6"] {
+            let error = execute_command(command("set_timezone", serde_json::json!({ "tz": tz })), &ctx).await.unwrap_err();
+            assert!(error.to_string().contains("unknown timezone"), "{}", error);
+        }
+        assert_eq!(*ctx.timezone.read().await, None);
+
+        let result = execute_command(command("set_timezone", serde_json::json!({ "tz": "Asia/Kolkata" })), &ctx).await.unwrap();
+        assert_eq!(result.data, serde_json::json!({ "tz": "Asia/Kolkata", "utc_offset": "+05:30" }));
+        assert_eq!(*ctx.timezone.read().await, Some(chrono_tz::Asia::Kolkata));
+    }
+
+    #[test]
+    fn rtc_command_pads_every_field() {
+        assert_eq!(rtc_command(at("2024-05-01T09:05:03Z")), "/RTC_2024_05_01_09_05_03_");
+        assert_eq!(rtc_command(at("2024-12-31T23:59:59.999Z")), "/RTC_2024_12_31_23_59_59_");
+    }
+
+    #[test]
+    fn rtc_replies_parse_as_utc_and_reject_anything_else() {
+        assert_eq!(parse_rtc_reply("RTC:2024-05-01T12:30:00").unwrap(), at("2024-05-01T12:30:00Z"));
+        assert_eq!(parse_rtc_reply("RTC: 2024-05-01T12:30:00\r").unwrap(), at("2024-05-01T12:30:00Z"));
+        for reply in ["RTC:", "RTC:2024-05-01", "RTC:2024-13-01T12:30:00", "RTC:2024-05-01T12:30:00Z"] {
+            assert!(parse_rtc_reply(reply).unwrap_err().to_string().contains("Unreadable RTC reply"), "{}", reply);
+        }
+    }
+
+    #[test]
+    fn sampling_rates_are_whole_or_three_decimal_places() {
+        assert_eq!(encode_sampling_rate(0.001), "0.001");
+        assert_eq!(encode_sampling_rate(1000.0), "1000");
+        assert_eq!(encode_sampling_rate(12.5), "12.500");
+        assert_eq!(encode_sampling_rate(0.0004), "0.000");
+        assert_eq!(encode_sampling_rate(10000.0), "10000");
+    }
+
+    #[tokio::test]
+    async fn set_sampling_rate_checks_bounds_before_sending() {
+        let (ctx, mock) = test_context();
+        for rate in [serde_json::json!(0.0005), serde_json::json!(-1.0), serde_json::json!(20000.0), serde_json::json!(null)] {
+            let error = execute_command(command("set_sampling_rate", serde_json::json!({ "rate_hz": rate })), &ctx).await.unwrap_err();
+            assert!(error.to_string().contains("rate_hz must be"), "{}", error);
+        }
+        assert_eq!(*ctx.sampling_rate.read().await, None);
+
+        for rate in [0.001, 1000.0] {
+            execute_command(command("set_sampling_rate", serde_json::json!({ "rate_hz": rate })), &ctx).await.unwrap();
+        }
+        assert_eq!(sent_commands(&mock, 2).await, vec!["/SR_0.001_", "/SR_1000_"]);
+        assert_eq!(*ctx.sampling_rate.read().await, Some(1000.0));
+    }
+
+    #[test]
+    fn measurement_values_keep_six_significant_figures() {
+        assert_eq!(format_significant(0.0), "0");
+        assert_eq!(format_significant(-0.0), "0");
+        assert_eq!(format_significant(2.5), "2.5");
+        assert_eq!(format_significant(-2.5), "-2.5");
+        assert_eq!(format_significant(0.1 + 0.2), "0.3");
+        assert_eq!(format_significant(123.4567), "123.457");
+        assert_eq!(format_significant(100000.0), "100000");
+        assert_eq!(format_significant(1234567.0), "1.23457e6");
+        assert_eq!(format_significant(0.0001), "0.0001");
+        assert_eq!(format_significant(0.00001234), "1.234e-5");
+    }
+
+    #[test]
+    fn measurement_params_are_encoded_in_key_order() {
+        let allowed = ["rate".to_string(), "gain".to_string(), "cutoff".to_string()];
+        let params = BTreeMap::from([("rate".to_string(), 1000.0), ("gain".to_string(), 1.5)]);
+        assert_eq!(measurement_params_command(&params, &allowed).unwrap(), "/MP_gain_1.5_rate_1000_");
+        assert_eq!(measurement_params_command(&BTreeMap::new(), &allowed).unwrap(), "/MP_");
+    }
+
+    #[test]
+    fn measurement_params_reject_unknown_keys_and_non_finite_values() {
+        let allowed = ["rate".to_string(), "gain".to_string(), "cutoff".to_string()];
+        for (key, value, message) in [
+            ("volume", 1.0, "unknown measurement parameter 'volume'"),
+            ("gain", f64::NAN, "'gain' is not finite"),
+            ("cutoff", f64::INFINITY, "'cutoff' is not finite"),
+            ("cutoff", f64::NEG_INFINITY, "'cutoff' is not finite"),
+        ] {
+            let error = measurement_params_command(&BTreeMap::from([(key.to_string(), value)]), &allowed).unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn start_measurement_sends_params_before_the_measurement() {
+        let (ctx, mock) = test_context();
+        let parameters = serde_json::json!({ "sequence": 4, "params": { "cutoff": 20.0 } });
+        execute_command(command("start_measurement", parameters), &ctx).await.unwrap();
+
+        let rejected = serde_json::json!({ "sequence": 5, "params": { "volume": 11.0 } });
+        assert!(execute_command(command("start_measurement", rejected), &ctx).await.is_err());
+        assert!(execute_command(command("set_measurement_params", serde_json::json!({})), &ctx).await.is_err());
+
+        assert_eq!(sent_commands(&mock, 2).await, vec!["/MP_cutoff_20_", "/M_4_"]);
+        assert!(ctx.running_measurements.read().await.contains(&4));
+        assert!(!ctx.running_measurements.read().await.contains(&5));
+    }
+
+    #[tokio::test]
+    async fn generated_config_round_trips_the_runtime_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ctx, _mock) = context_with(&format!("config_backup_dir = {:?}\nbuffer_size = 500\n", dir.path()));
+        *ctx.filter_string.write().await = "radio".to_string();
+        *ctx.upload_schedule.write().await = UploadSchedule::fixed(15);
+        ctx.buffer.write().await.set_max_size(200);
+        *ctx.api_key.write().await = "rotated-key".to_string();
+
+        let result = execute_command(command("generate_config", serde_json::json!({})), &ctx).await.unwrap();
+        let path = PathBuf::from(result.data["path"].as_str().unwrap());
+        assert!(path.starts_with(dir.path()));
+        assert_eq!(result.data["applied_to"], serde_json::Value::Null);
+        let generated: Config = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+        // The log level follows whatever the process logger is set to
+        let mut changes = ctx.config.diff(&generated);
+        changes.retain(|change| !matches!(change, ConfigChange::LogLevel(_)));
+        assert_eq!(
+            changes,
+            vec![
+                ConfigChange::FilterString("radio".to_string()),
+                ConfigChange::UploadInterval(15),
+                ConfigChange::BufferSize(200),
+                ConfigChange::ApiKey("rotated-key".to_string()),
+            ]
+        );
+        assert_eq!(generated.config_backup_dir, ctx.config.config_backup_dir);
+    }
+
+    #[tokio::test]
+    async fn generate_config_applies_only_with_allow_config_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("config.toml");
+        std::fs::write(&base, TEST_CONFIG).unwrap();
+        let backups = format!("config_backup_dir = {:?}\n", dir.path().join("backups"));
+        let apply = serde_json::json!({ "apply": true });
+
+        let (ctx, _mock) = context_with(&backups);
+        let error = execute_command(command("generate_config", apply.clone()), &ctx).await.unwrap_err();
+        assert!(error.to_string().contains("allow_config_write is off"));
+
+        let (mut ctx, _mock) = context_with(&format!("{}allow_config_write = true\n", backups));
+        ctx.config_sources = Arc::new(ConfigSources { paths: vec![base.clone()], overridden_fields: Vec::new() });
+        *ctx.filter_string.write().await = "radio".to_string();
+        let result = execute_command(command("generate_config", apply), &ctx).await.unwrap();
+
+        assert_eq!(result.data["applied_to"], serde_json::json!(base));
+        let (applied, _) = Config::load(&base, None).unwrap();
+        assert_eq!(applied.filter_string, "radio");
+        assert!(applied.allow_config_write);
+    }
+
+    #[test]
+    fn hostnames_follow_rfc_1123_labels() {
+        for name in ["probe-1", "P", "raspberrypi", "a1-b2-c3", &"x".repeat(63)] {
+            assert!(is_valid_hostname(name), "{}", name);
+        }
+        for name in ["", "-probe", "probe-", "probe.local", "probe_1", "próbe", "probe 1", &"x".repeat(64)] {
+            assert!(!is_valid_hostname(name), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn set_probe_hostname_needs_allow_system_commands_and_a_valid_name() {
+        let (ctx, _mock) = test_context();
+        let error = execute_command(command("set_probe_hostname", serde_json::json!({ "hostname": "probe-1" })), &ctx).await.unwrap_err();
+        assert!(error.to_string().contains("requires allow_system_commands"));
+
+        let (ctx, _mock) = context_with("allow_system_commands = true\n");
+        let error = execute_command(command("set_probe_hostname", serde_json::json!({ "hostname": "-x" })), &ctx).await.unwrap_err();
+        assert!(error.to_string().contains("not a valid hostname"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn set_probe_hostname_runs_hostnamectl_then_tee() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A stand-in `sudo` that records its arguments and stdin, failing for one hostname
+        let dir = tempfile::tempdir().unwrap();
+        let calls = dir.path().join("calls");
+        let sudo = dir.path().join("sudo");
+        std::fs::write(
+            &sudo,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {calls:?}\n\
+                 [ \"$1\" = tee ] && cat >> {calls:?}\n\
+                 [ \"$3\" = refused ] && echo 'Access denied' >&2 && exit 1\nexit 0\n"
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&sudo, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let mut shimmed = std::ffi::OsString::from(dir.path());
+        shimmed.push(":");
+        shimmed.push(&path);
+        std::env::set_var("PATH", shimmed);
+
+        let applied = set_probe_hostname("probe-7").await;
+        let refused = set_probe_hostname("refused").await;
+        std::env::set_var("PATH", path);
+
+        applied.unwrap();
+        assert!(refused.unwrap_err().to_string().contains("hostnamectl failed: Access denied"));
+        assert_eq!(
+            std::fs::read_to_string(&calls).unwrap(),
+            "hostnamectl set-hostname probe-7\ntee /etc/hostname\nprobe-7\nhostnamectl set-hostname refused\n"
+        );
+    }
+
+    #[test]
+    fn hex_decoding_rejects_odd_lengths_and_non_hex_characters() {
+        assert_eq!(decode_hex("2f42530D0a").unwrap(), b"/BS\r\n");
+        assert_eq!(decode_hex("").unwrap(), Vec::<u8>::new());
+        assert!(decode_hex("2f4").unwrap_err().to_string().contains("odd length 3"));
+        assert!(decode_hex("2g").unwrap_err().to_string().contains("invalid hex character 'g'"));
+        // Multi-byte characters are rejected rather than split mid-character
+        assert!(decode_hex("é").unwrap_err().to_string().contains("invalid hex character"));
+    }
+
+    #[tokio::test]
+    async fn send_raw_usb_needs_allow_raw_usb() {
+        let (ctx, mock) = test_context();
+        let error = execute_command(command("send_raw_usb", serde_json::json!({ "hex": "2f42530d0a" })), &ctx).await.unwrap_err();
+        assert!(error.to_string().contains("send_raw_usb is disabled"));
+
+        let (ctx_allowed, mock_allowed) = context_with("allow_raw_usb = true\n");
+        for parameters in [serde_json::json!({ "hex": "2f42530d0a" }), serde_json::json!({ "ascii": "/LT\r\n" })] {
+            execute_command(command("send_raw_usb", parameters), &ctx_allowed).await.unwrap();
+        }
+        assert!(execute_command(command("send_raw_usb", serde_json::json!({})), &ctx_allowed).await.is_err());
+        assert!(execute_command(command("send_raw_usb", serde_json::json!({ "hex": "2f4" })), &ctx_allowed).await.is_err());
+
+        assert_eq!(sent_commands(&mock_allowed, 2).await, vec!["/BS", "/LT"]);
+        assert!(mock.sent_commands().is_empty());
+    }
 }
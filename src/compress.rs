@@ -0,0 +1,97 @@
+use crate::error::ProbeError;
+use anyhow::Result;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use std::io::{Read, Write};
+
+/// Value for `Accept-Encoding` on requests whose responses we decode ourselves
+pub const ACCEPT_ENCODING: &str = "gzip, deflate, identity";
+
+/// HTTP content coding applied to upload bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionFormat {
+    None,
+    Gzip,
+    Deflate,
+}
+
+impl CompressionFormat {
+    pub fn parse(value: &str) -> Result<Self, ProbeError> {
+        match value.to_lowercase().as_str() {
+            "none" | "identity" => Ok(CompressionFormat::None),
+            "gzip" => Ok(CompressionFormat::Gzip),
+            "deflate" => Ok(CompressionFormat::Deflate),
+            other => Err(ProbeError::ConfigError(format!(
+                "Unknown upload_compression '{}', expected none, gzip or deflate",
+                other
+            ))),
+        }
+    }
+}
+
+/// Compress `data`, returning the bytes and their `Content-Encoding` value
+/// ("identity" for `None`, where `data` is returned unchanged)
+pub fn compress_payload(data: &[u8], format: CompressionFormat) -> Result<(Vec<u8>, &'static str)> {
+    match format {
+        CompressionFormat::None => Ok((data.to_vec(), "identity")),
+        CompressionFormat::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            Ok((encoder.finish()?, "gzip"))
+        }
+        CompressionFormat::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            Ok((encoder.finish()?, "deflate"))
+        }
+    }
+}
+
+/// Undo the `Content-Encoding` of a response body
+pub fn decompress_payload(data: &[u8], content_encoding: Option<&str>) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    match content_encoding.map(str::trim) {
+        None | Some("") | Some("identity") => return Ok(data.to_vec()),
+        Some("gzip") => GzDecoder::new(data).read_to_end(&mut decoded)?,
+        Some("deflate") => ZlibDecoder::new(data).read_to_end(&mut decoded)?,
+        Some(other) => return Err(anyhow::anyhow!("Unsupported response Content-Encoding '{}'", other)),
+    };
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: &[u8] = b"[{\"timestamp\":\"2024-01-01T00:00:00Z\",\"message\":\"hello hello hello hello\"}]";
+
+    #[test]
+    fn round_trips_every_format() {
+        for (format, encoding) in
+            [(CompressionFormat::None, "identity"), (CompressionFormat::Gzip, "gzip"), (CompressionFormat::Deflate, "deflate")]
+        {
+            let (compressed, content_encoding) = compress_payload(PAYLOAD, format).unwrap();
+            assert_eq!(content_encoding, encoding);
+            assert_eq!(decompress_payload(&compressed, Some(content_encoding)).unwrap(), PAYLOAD);
+        }
+    }
+
+    #[test]
+    fn passes_through_missing_encoding() {
+        assert_eq!(decompress_payload(PAYLOAD, None).unwrap(), PAYLOAD);
+        assert_eq!(decompress_payload(PAYLOAD, Some(" ")).unwrap(), PAYLOAD);
+    }
+
+    #[test]
+    fn rejects_unknown_encodings() {
+        assert!(decompress_payload(PAYLOAD, Some("br")).unwrap_err().to_string().contains("'br'"));
+        assert!(matches!(CompressionFormat::parse("zstd"), Err(ProbeError::ConfigError(_))));
+        assert_eq!(CompressionFormat::parse("GZIP").unwrap(), CompressionFormat::Gzip);
+    }
+
+    #[test]
+    fn rejects_corrupt_gzip() {
+        assert!(decompress_payload(PAYLOAD, Some("gzip")).is_err());
+    }
+}
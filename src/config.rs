@@ -1,23 +1,548 @@
+use crate::error::ProbeError;
 use anyhow::{Context, Result};
-use serde::Deserialize;
-use std::path::Path;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub usb_port: String,
+    /// Node output framing: "line" (newline-terminated text) or "frame" (length-prefixed CBOR)
+    #[serde(default = "default_usb_protocol")]
+    pub usb_protocol: String,
     pub server_url: String,
     pub api_key: String,
     pub node_id: u32,
     pub node_firmware_url: String,
     pub probe_firmware_url: String,
+    /// Node version assumed when neither the node nor `node_firmware/` reports one
+    #[serde(default)]
+    pub node_firmware_version_fallback: Option<u32>,
+    /// Probe version assumed when no probe binary with a version is found
+    #[serde(default)]
+    pub probe_firmware_version_fallback: Option<u32>,
+    /// Base URL of combined node+probe update bundles; replaces the separate checks when set
+    #[serde(default)]
+    pub bundle_update_url: Option<String>,
+    /// How telemetry reaches the hub: "http" or "mqtt"
+    #[serde(default = "default_transport")]
+    pub transport: String,
+    #[serde(default)]
+    pub mqtt_broker_url: Option<String>,
+    /// Defaults to the node ID
+    #[serde(default)]
+    pub mqtt_client_id: Option<String>,
+    #[serde(default = "default_mqtt_qos")]
+    pub mqtt_qos: u8,
+    #[serde(default)]
+    pub mqtt_username: Option<String>,
+    #[serde(default)]
+    pub mqtt_password: Option<String>,
+    /// Address of the local admin HTTP server, e.g. `0.0.0.0:8090`; unset disables it
+    #[serde(default)]
+    pub admin_listen_address: Option<String>,
+    /// Token the admin server's buffer endpoints require in `X-Admin-Token`
+    #[serde(default)]
+    pub admin_token: Option<String>,
     #[serde(default = "default_upload_interval")]
     pub upload_interval_seconds: u64,
     #[serde(default = "default_buffer_size")]
     pub buffer_size: usize,
+    /// Wait after the hub rejects the API key, instead of the usual backoff
+    #[serde(default = "default_api_key_error_retry")]
+    pub api_key_error_retry_seconds: u64,
+    /// Larger upload bodies are split into several requests
+    #[serde(default = "default_max_payload_size")]
+    pub max_payload_size_bytes: usize,
+    /// Upload body encoding: "json" or "msgpack"
+    #[serde(default = "default_upload_format")]
+    pub upload_format: String,
+    /// Upload body compression: "none", "gzip" or "deflate"
+    #[serde(default = "default_upload_compression")]
+    pub upload_compression: String,
+    /// Entries older than this are discarded instead of uploaded; unlimited when unset
+    #[serde(default)]
+    pub max_log_age_seconds: Option<u64>,
+    /// Also append every collected entry as a JSON line to this file; off when unset
+    #[serde(default)]
+    pub local_archive_path: Option<PathBuf>,
+    #[serde(default = "default_local_archive_max_bytes")]
+    pub local_archive_max_bytes: u64,
+    #[serde(default = "default_local_archive_keep_files")]
+    pub local_archive_keep_files: u8,
     #[serde(default = "default_filter_string")]
     pub filter_string: String,
+    /// IANA timezone node log timestamps are written in until set_timezone
+    /// changes it; UTC when unset
+    #[serde(default)]
+    pub timezone: Option<String>,
     #[serde(default = "default_log_level")]
     pub log_level: String,
+    /// Probe log output: "text" lines or "json" objects carrying the fields of
+    /// the spans they were logged in
+    #[serde(default = "default_log_format")]
+    pub log_format: String,
+    /// USB commands allowed to wait for the port before new ones are rejected
+    #[serde(default = "default_max_pending_commands")]
+    pub max_pending_commands: usize,
+    /// Allow the send_raw_usb debugging command
+    #[serde(default)]
+    pub allow_raw_usb: bool,
+    /// Allow simulate_disconnect and simulate_connect_failure; only accepted
+    /// in debug builds or with the `testing` feature
+    #[serde(default)]
+    pub enable_test_commands: bool,
+    /// Report tokio task and runtime statistics in get_status; needs the
+    /// `tokio-metrics` build feature
+    #[serde(default)]
+    pub enable_runtime_metrics: bool,
+    /// Report hardware_info with the upload after the first successful one
+    #[serde(default)]
+    pub send_hardware_info_on_startup: bool,
+    /// Allow the factory_reset command
+    #[serde(default)]
+    pub enable_factory_reset: bool,
+    /// Allow commands that change the probe host, such as set_probe_hostname
+    #[serde(default)]
+    pub allow_system_commands: bool,
+    /// Allow the export_config command
+    #[serde(default = "default_allow_config_export")]
+    pub allow_config_export: bool,
+    /// Allow generate_config to replace the config file
+    #[serde(default)]
+    pub allow_config_write: bool,
+    #[serde(default)]
+    pub suppress_duplicates: bool,
+    #[serde(default = "default_max_duplicate_gap")]
+    pub max_duplicate_gap_secs: u64,
+    /// Attach `key=value` fields of node lines to their entries as metadata
+    #[serde(default)]
+    pub parse_structured_logs: bool,
+    /// Check and strip `SEQ:<n>:` prefixes on node lines, warning about gaps
+    #[serde(default)]
+    pub expect_sequence_numbers: bool,
+    /// Drop node lines seen among the last this many distinct lines; 0 disables
+    #[serde(default)]
+    pub dedup_window: usize,
+    /// Sort the buffer by timestamp and drop duplicate entries after replay_logs
+    #[serde(default)]
+    pub deduplicate_on_load: bool,
+    #[serde(default = "default_true")]
+    pub node_firmware_auth: bool,
+    #[serde(default = "default_true")]
+    pub probe_firmware_auth: bool,
+    #[serde(default = "default_firmware_download_timeout")]
+    pub firmware_download_timeout_seconds: u64,
+    /// Idle connections kept per firmware server
+    #[serde(default = "default_firmware_client_pool_size")]
+    pub firmware_client_pool_size: usize,
+    #[serde(default = "default_firmware_connect_timeout")]
+    pub firmware_connect_timeout_seconds: u64,
+    /// Cap on firmware download speed in kilobytes per second; unset for no limit
+    #[serde(default)]
+    pub firmware_download_rate_limit_kbps: Option<u32>,
+    /// Proxy for `http://` requests to the hub and firmware servers
+    #[serde(default)]
+    pub http_proxy: Option<String>,
+    /// Proxy for `https://` requests to the hub and firmware servers
+    #[serde(default)]
+    pub https_proxy: Option<String>,
+    /// Hosts reached without the proxy, in `NO_PROXY` syntax; the `NO_PROXY`
+    /// environment variable is used when unset
+    #[serde(default)]
+    pub no_proxy: Option<Vec<String>>,
+    /// Credentials export_logs signs its uploads with
+    #[serde(default)]
+    pub s3_access_key: Option<String>,
+    #[serde(default)]
+    pub s3_secret_key: Option<String>,
+    /// Region export_logs signs its uploads for
+    #[serde(default = "default_s3_region")]
+    pub s3_region: String,
+    #[serde(default = "default_max_firmware_size")]
+    pub max_firmware_size_bytes: u64,
+    #[serde(default = "default_min_free_disk")]
+    pub min_free_disk_bytes: u64,
+    /// Expected node chip family ("rp2040" or "rp2350"); unchecked when unset
+    #[serde(default)]
+    pub node_target_family: Option<String>,
+    #[serde(default = "default_dedup_cache_size")]
+    pub dedup_cache_size: usize,
+    #[serde(default = "default_dedup_ttl")]
+    pub dedup_ttl_seconds: u64,
+    /// Where capture_snapshot writes buffer snapshots
+    #[serde(default = "default_snapshot_dir")]
+    pub snapshot_dir: PathBuf,
+    #[serde(default = "default_max_snapshots")]
+    pub max_snapshots: usize,
+    /// Directory stream_to_file may write to
+    #[serde(default = "default_stream_to_file_dir")]
+    pub stream_to_file_dir: PathBuf,
+    /// Where generate_config writes generated config files and where the
+    /// config file is backed up before the probe rewrites it
+    #[serde(default = "default_config_backup_dir")]
+    pub config_backup_dir: PathBuf,
+    /// Config file backups to keep; 0 disables them
+    #[serde(default = "default_max_config_backups")]
+    pub max_config_backups: usize,
+    /// Upper bound on commands in one batch_commands command
+    #[serde(default = "default_max_batch_commands")]
+    pub max_batch_commands: usize,
+    /// Upper bound on commands queued by schedule_command
+    #[serde(default = "default_max_scheduled_commands")]
+    pub max_scheduled_commands: usize,
+    /// Commands beyond this many in one hub response are ignored
+    #[serde(default = "default_max_commands_per_upload")]
+    pub max_commands_per_upload: usize,
+    /// Hub responses are ignored once this many commands arrived within the last hour
+    #[serde(default = "default_max_commands_per_hour")]
+    pub max_commands_per_hour: u32,
+    /// Reconnect when the node sends nothing for this long (0 disables)
+    #[serde(default = "default_usb_idle_timeout")]
+    pub usb_idle_timeout_seconds: u64,
+    /// Send `/KA` to a quiet node after this long (0 disables)
+    #[serde(default)]
+    pub usb_keepalive_seconds: u64,
+    /// Append every command sent to and line received from the node to this
+    /// text file; off when unset
+    #[serde(default)]
+    pub usb_command_log_path: Option<PathBuf>,
+    /// The USB command log is moved to `.1` once it grows past this
+    #[serde(default = "default_usb_command_log_max_bytes")]
+    pub usb_command_log_max_bytes: u64,
+    /// Set the node RTC to the probe's time this often; off when unset
+    #[serde(default)]
+    pub rtc_sync_interval_seconds: Option<u64>,
+    /// Query the node measurement status this often; off when unset
+    #[serde(default)]
+    pub measurement_status_poll_interval_seconds: Option<u64>,
+    /// Allow the enable_debug_port command
+    #[serde(default)]
+    pub enable_debug_port: bool,
+    /// Close the debug port after this long without client input
+    #[serde(default = "default_debug_port_inactivity_timeout")]
+    pub debug_port_inactivity_timeout_seconds: u64,
+    /// Measurement parameter names accepted by set_measurement_params
+    #[serde(default = "default_valid_measurement_params")]
+    pub valid_measurement_params: Vec<String>,
+    /// Range of rates set_sampling_rate accepts, in Hz
+    #[serde(default = "default_min_sampling_rate")]
+    pub min_sampling_rate_hz: f64,
+    #[serde(default = "default_max_sampling_rate")]
+    pub max_sampling_rate_hz: f64,
+    /// How to start a new probe binary: "reboot", "exec" or "exit"
+    #[serde(default = "default_restart_strategy")]
+    pub restart_strategy: String,
+    /// How long the node may take to reconnect and report the new version after a flash
+    #[serde(default = "default_post_flash_timeout")]
+    pub post_flash_timeout_seconds: u64,
+    /// Timeout for each node query made by get_node_info
+    #[serde(default = "default_node_info_timeout_ms")]
+    pub node_info_timeout_ms: u64,
+    /// Uploads the hub may reject (400) before the entries involved are discarded
+    #[serde(default = "default_max_entry_retries")]
+    pub max_entry_retries: u8,
+    /// Extra attempts for an upload failing with a network error, timeout or 5xx
+    #[serde(default = "default_upload_max_retries")]
+    pub upload_max_retries: u32,
+    /// Time limit for each upload attempt
+    #[serde(default = "default_upload_timeout")]
+    pub upload_timeout_seconds: u64,
+    /// Failed uploads in a row before uploads pause; 0 never pauses
+    #[serde(default = "default_circuit_breaker_failure_threshold")]
+    pub circuit_breaker_failure_threshold: u32,
+    /// How long uploads pause before a trial upload
+    #[serde(default = "default_circuit_breaker_timeout")]
+    pub circuit_breaker_timeout_seconds: u64,
+    /// Warn when the rolling average upload latency exceeds this; 0 disables
+    #[serde(default = "default_latency_warning_threshold_ms")]
+    pub latency_warning_threshold_ms: u64,
+    /// Restarts of one task within an hour above which the probe warns and reports them
+    #[serde(default = "default_restart_alert_threshold")]
+    pub restart_alert_threshold: u32,
+    /// Restarts of one task within an hour considered survivable; the probe reboots
+    /// once a task restarts more than twice this often
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+    /// JSON-lines file receiving entries discarded after too many rejections
+    #[serde(default)]
+    pub dead_letter_path: Option<PathBuf>,
+    /// Version of this probe build; never read from the config file
+    #[serde(skip_deserializing, default = "default_probe_version")]
+    pub probe_version: String,
+    /// Build time, when the build sets `VERGEN_BUILD_TIMESTAMP`; never read from the config file
+    #[serde(skip_deserializing, default = "default_build_timestamp")]
+    pub build_timestamp: Option<String>,
+}
+
+/// `Config` fields describing the build rather than the config file
+const BUILD_FIELDS: [&str; 2] = ["probe_version", "build_timestamp"];
+
+/// `Config` fields without a serde default
+const REQUIRED_FIELDS: [&str; 6] = ["usb_port", "server_url", "api_key", "node_id", "node_firmware_url", "probe_firmware_url"];
+
+/// A setting that differs between two loaded configs
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigChange {
+    FilterString(String),
+    UploadInterval(u64),
+    LogLevel(String),
+    BufferSize(usize),
+    ApiKey(String),
+    /// Field that only takes effect after a restart
+    RequiresRestart(&'static str),
+}
+
+/// Where a loaded `Config` came from, for logging once the logger is up.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConfigSources {
+    /// Files that were read, base first
+    pub paths: Vec<PathBuf>,
+    /// Dotted names of the fields replaced by an override file
+    pub overridden_fields: Vec<String>,
+}
+
+/// Defines `ConfigOverlay` with an optional copy of each listed `Config` field,
+/// `ConfigOverlay::merge` layering two of them and `Config::merge_overlay`
+/// applying the fields that are set.
+macro_rules! config_overlay {
+    ($($field:ident: $ty:ty,)*) => {
+        /// A partial config, as read from an override file. Fields left out of
+        /// the file are `None` and keep the base value when merged.
+        #[derive(Debug, Clone, Default, Deserialize)]
+        pub struct ConfigOverlay {
+            $(
+                #[serde(default)]
+                pub $field: Option<$ty>,
+            )*
+        }
+
+        impl ConfigOverlay {
+            /// This overlay with every field `other` sets replaced
+            pub fn merge(self, other: ConfigOverlay) -> ConfigOverlay {
+                ConfigOverlay {
+                    $(
+                        $field: other.$field.or(self.$field),
+                    )*
+                }
+            }
+
+            /// Names of the fields this overlay sets
+            pub fn set_fields(&self) -> Vec<String> {
+                let mut fields = Vec::new();
+                $(
+                    if self.$field.is_some() {
+                        fields.push(stringify!($field).to_string());
+                    }
+                )*
+                fields
+            }
+        }
+
+        impl Config {
+            /// Replace every field the overlay sets
+            pub fn merge_overlay(mut self, overlay: ConfigOverlay) -> Config {
+                $(
+                    if let Some(value) = overlay.$field {
+                        self.$field = value;
+                    }
+                )*
+                self
+            }
+        }
+    };
+}
+
+config_overlay! {
+    usb_port: String,
+    usb_protocol: String,
+    server_url: String,
+    api_key: String,
+    node_id: u32,
+    node_firmware_url: String,
+    probe_firmware_url: String,
+    node_firmware_version_fallback: Option<u32>,
+    probe_firmware_version_fallback: Option<u32>,
+    bundle_update_url: Option<String>,
+    transport: String,
+    mqtt_broker_url: Option<String>,
+    mqtt_client_id: Option<String>,
+    mqtt_qos: u8,
+    mqtt_username: Option<String>,
+    mqtt_password: Option<String>,
+    admin_listen_address: Option<String>,
+    admin_token: Option<String>,
+    upload_interval_seconds: u64,
+    buffer_size: usize,
+    api_key_error_retry_seconds: u64,
+    max_payload_size_bytes: usize,
+    upload_format: String,
+    upload_compression: String,
+    max_log_age_seconds: Option<u64>,
+    local_archive_path: Option<PathBuf>,
+    local_archive_max_bytes: u64,
+    local_archive_keep_files: u8,
+    filter_string: String,
+    timezone: Option<String>,
+    log_level: String,
+    log_format: String,
+    max_pending_commands: usize,
+    allow_raw_usb: bool,
+    enable_test_commands: bool,
+    enable_runtime_metrics: bool,
+    send_hardware_info_on_startup: bool,
+    enable_factory_reset: bool,
+    allow_system_commands: bool,
+    allow_config_export: bool,
+    allow_config_write: bool,
+    suppress_duplicates: bool,
+    max_duplicate_gap_secs: u64,
+    parse_structured_logs: bool,
+    expect_sequence_numbers: bool,
+    dedup_window: usize,
+    deduplicate_on_load: bool,
+    node_firmware_auth: bool,
+    probe_firmware_auth: bool,
+    firmware_download_timeout_seconds: u64,
+    firmware_client_pool_size: usize,
+    firmware_connect_timeout_seconds: u64,
+    firmware_download_rate_limit_kbps: Option<u32>,
+    http_proxy: Option<String>,
+    https_proxy: Option<String>,
+    no_proxy: Option<Vec<String>>,
+    s3_access_key: Option<String>,
+    s3_secret_key: Option<String>,
+    s3_region: String,
+    max_firmware_size_bytes: u64,
+    min_free_disk_bytes: u64,
+    node_target_family: Option<String>,
+    dedup_cache_size: usize,
+    dedup_ttl_seconds: u64,
+    snapshot_dir: PathBuf,
+    max_snapshots: usize,
+    stream_to_file_dir: PathBuf,
+    config_backup_dir: PathBuf,
+    max_config_backups: usize,
+    max_batch_commands: usize,
+    max_scheduled_commands: usize,
+    max_commands_per_upload: usize,
+    max_commands_per_hour: u32,
+    usb_idle_timeout_seconds: u64,
+    usb_keepalive_seconds: u64,
+    usb_command_log_path: Option<PathBuf>,
+    usb_command_log_max_bytes: u64,
+    rtc_sync_interval_seconds: Option<u64>,
+    measurement_status_poll_interval_seconds: Option<u64>,
+    enable_debug_port: bool,
+    debug_port_inactivity_timeout_seconds: u64,
+    valid_measurement_params: Vec<String>,
+    min_sampling_rate_hz: f64,
+    max_sampling_rate_hz: f64,
+    restart_strategy: String,
+    post_flash_timeout_seconds: u64,
+    node_info_timeout_ms: u64,
+    max_entry_retries: u8,
+    upload_max_retries: u32,
+    upload_timeout_seconds: u64,
+    circuit_breaker_failure_threshold: u32,
+    circuit_breaker_timeout_seconds: u64,
+    latency_warning_threshold_ms: u64,
+    restart_alert_threshold: u32,
+    max_restarts: u32,
+    dead_letter_path: Option<PathBuf>,
+}
+
+fn default_usb_idle_timeout() -> u64 {
+    300
+}
+
+fn default_usb_command_log_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_debug_port_inactivity_timeout() -> u64 {
+    600
+}
+
+fn default_valid_measurement_params() -> Vec<String> {
+    vec!["rate".to_string(), "gain".to_string(), "cutoff".to_string()]
+}
+
+fn default_min_sampling_rate() -> f64 {
+    0.001
+}
+
+fn default_max_sampling_rate() -> f64 {
+    10000.0
+}
+
+fn default_restart_strategy() -> String {
+    "reboot".to_string()
+}
+
+fn default_post_flash_timeout() -> u64 {
+    30
+}
+
+fn default_node_info_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_upload_max_retries() -> u32 {
+    3
+}
+
+fn default_upload_timeout() -> u64 {
+    30
+}
+
+fn default_circuit_breaker_failure_threshold() -> u32 {
+    5
+}
+
+fn default_restart_alert_threshold() -> u32 {
+    5
+}
+
+fn default_max_restarts() -> u32 {
+    10
+}
+
+fn default_circuit_breaker_timeout() -> u64 {
+    60
+}
+
+fn default_latency_warning_threshold_ms() -> u64 {
+    5000
+}
+
+fn default_max_entry_retries() -> u8 {
+    3
+}
+
+fn default_allow_config_export() -> bool {
+    true
+}
+
+fn default_probe_version() -> String {
+    env!("CARGO_PKG_VERSION").to_string()
+}
+
+fn default_build_timestamp() -> Option<String> {
+    option_env!("VERGEN_BUILD_TIMESTAMP").map(str::to_string)
+}
+
+fn default_usb_protocol() -> String {
+    "line".to_string()
+}
+
+fn default_transport() -> String {
+    "http".to_string()
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
 }
 
 fn default_upload_interval() -> u64 {
@@ -28,22 +553,657 @@ fn default_buffer_size() -> usize {
     10_000
 }
 
+fn default_api_key_error_retry() -> u64 {
+    3600
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_max_payload_size() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_upload_format() -> String {
+    "json".to_string()
+}
+
+fn default_local_archive_max_bytes() -> u64 {
+    50 * 1024 * 1024
+}
+
+fn default_local_archive_keep_files() -> u8 {
+    3
+}
+
+fn default_upload_compression() -> String {
+    "none".to_string()
+}
+
 fn default_filter_string() -> String {
     String::new()
 }
 
+fn default_log_format() -> String {
+    "text".to_string()
+}
+
 fn default_log_level() -> String {
     "info".to_string()
 }
 
+fn default_max_pending_commands() -> usize {
+    16
+}
+
+fn default_max_duplicate_gap() -> u64 {
+    5
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_firmware_download_timeout() -> u64 {
+    300
+}
+
+fn default_firmware_client_pool_size() -> usize {
+    2
+}
+
+fn default_firmware_connect_timeout() -> u64 {
+    15
+}
+
+fn default_max_firmware_size() -> u64 {
+    4 * 1024 * 1024
+}
+
+fn default_min_free_disk() -> u64 {
+    32 * 1024 * 1024
+}
+
+fn default_dedup_cache_size() -> usize {
+    1000
+}
+
+fn default_dedup_ttl() -> u64 {
+    300
+}
+
+fn default_snapshot_dir() -> PathBuf {
+    PathBuf::from("snapshots/")
+}
+
+fn default_max_snapshots() -> usize {
+    10
+}
+
+fn default_stream_to_file_dir() -> PathBuf {
+    PathBuf::from("captures/")
+}
+
+fn default_config_backup_dir() -> PathBuf {
+    PathBuf::from("config_backups/")
+}
+
+fn default_max_config_backups() -> usize {
+    5
+}
+
+fn default_max_batch_commands() -> usize {
+    20
+}
+
+fn default_max_scheduled_commands() -> usize {
+    10
+}
+
+fn default_max_commands_per_upload() -> usize {
+    10
+}
+
+fn default_max_commands_per_hour() -> u32 {
+    100
+}
+
 impl Config {
-    pub fn load(path: &Path) -> Result<Self> {
-        let contents = std::fs::read_to_string(path)
-            .with_context(|| format!("Failed to read config file: {:?}", path))?;
-        
-        let config: Config = toml::from_str(&contents)
-            .with_context(|| format!("Failed to parse config file: {:?}", path))?;
-        
-        Ok(config)
+    /// Load the base config and apply an override file on top of it.
+    ///
+    /// If `override_path` is `None`, a `<stem>.override.toml` sibling of the base
+    /// file is used when it exists.
+    pub fn load(path: &Path, override_path: Option<&Path>) -> Result<(Self, ConfigSources)> {
+        let mut sources = ConfigSources::default();
+        // The base may leave out required fields the override file sets
+        let mut overlay = Config::load_overlay(path)?;
+        sources.paths.push(path.to_path_buf());
+
+        let override_path = match override_path {
+            Some(p) => Some(p.to_path_buf()),
+            None => Some(default_override_path(path)).filter(|p| p.exists()),
+        };
+
+        if let Some(override_path) = override_path {
+            let override_overlay = Config::load_overlay(&override_path)?;
+            sources.overridden_fields = override_overlay.set_fields();
+            overlay = overlay.merge(override_overlay);
+            sources.paths.push(override_path);
+        }
+
+        let config = Config::from_overlay(overlay)?;
+        config.validate()?;
+        Ok((config, sources))
+    }
+
+    /// The configured `timezone`, if any; `validate` has checked it parses
+    pub fn log_timezone(&self) -> Option<Tz> {
+        self.timezone.as_deref().and_then(|timezone| timezone.parse().ok())
+    }
+
+    /// Reject settings that would only fail once the tasks are running
+    pub fn validate(&self) -> Result<(), ProbeError> {
+        if self.enable_test_commands && !cfg!(any(debug_assertions, feature = "testing")) {
+            return Err(ProbeError::ConfigError(
+                "enable_test_commands is only allowed in debug builds or with the testing feature".to_string(),
+            ));
+        }
+
+        if !matches!(self.log_format.as_str(), "text" | "json") {
+            return Err(ProbeError::ConfigError(format!("log_format must be \"text\" or \"json\", got '{}'", self.log_format)));
+        }
+
+        if let Some(timezone) = &self.timezone {
+            timezone
+                .parse::<Tz>()
+                .map_err(|_| ProbeError::ConfigError(format!("Unknown timezone '{}'", timezone)))?;
+        }
+
+        for (field, proxy) in [("http_proxy", &self.http_proxy), ("https_proxy", &self.https_proxy)] {
+            let Some(proxy) = proxy else { continue };
+            let url = reqwest::Url::parse(proxy).map_err(|e| ProbeError::ConfigError(format!("Invalid {} '{}': {}", field, proxy, e)))?;
+            if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+                return Err(ProbeError::ConfigError(format!(
+                    "Invalid {} '{}': expected http://host[:port] or https://host[:port]",
+                    field, proxy
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse a partial config file; fields it leaves out are unset
+    pub fn load_overlay(path: &Path) -> Result<ConfigOverlay> {
+        read_toml(path)?
+            .try_into()
+            .with_context(|| format!("Failed to parse config file: {:?}", path))
+    }
+
+    /// A config with the fields `overlay` sets and defaults for the rest;
+    /// fails if it leaves out a field that has no default
+    pub fn from_overlay(overlay: ConfigOverlay) -> Result<Config, ProbeError> {
+        let set_fields = overlay.set_fields();
+        let missing: Vec<&str> = REQUIRED_FIELDS.iter().copied().filter(|field| !set_fields.iter().any(|f| f == field)).collect();
+        if !missing.is_empty() {
+            return Err(ProbeError::ConfigError(format!("Missing required config fields: {}", missing.join(", "))));
+        }
+
+        let required = serde_json::json!({
+            "usb_port": overlay.usb_port,
+            "server_url": overlay.server_url,
+            "api_key": overlay.api_key,
+            "node_id": overlay.node_id,
+            "node_firmware_url": overlay.node_firmware_url,
+            "probe_firmware_url": overlay.probe_firmware_url,
+        });
+        let defaults: Config = serde_json::from_value(required).map_err(|e| ProbeError::ConfigError(e.to_string()))?;
+        Ok(defaults.merge_overlay(overlay))
+    }
+
+    /// The config as JSON with the API key and passwords masked
+    pub fn sanitized(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.with_secrets_masked())?)
+    }
+
+    /// A copy with the API key and any passwords or secret keys replaced by `***`
+    pub fn with_secrets_masked(&self) -> Config {
+        let mut masked = self.clone();
+        masked.api_key = "***".to_string();
+        if masked.mqtt_password.is_some() {
+            masked.mqtt_password = Some("***".to_string());
+        }
+        if masked.admin_token.is_some() {
+            masked.admin_token = Some("***".to_string());
+        }
+        if masked.s3_secret_key.is_some() {
+            masked.s3_secret_key = Some("***".to_string());
+        }
+        masked
+    }
+
+    /// `export PROBE_<FIELD>='<value>'` lines for `source`-ing in a shell
+    pub fn to_env_exports(&self) -> String {
+        self.env_vars()
+            .into_iter()
+            .map(|(name, value)| format!("export {}='{}'\n", name, value.replace('\'', "'\\''")))
+            .collect()
+    }
+
+    /// `PROBE_<FIELD>=<value>` lines for `docker run --env-file`
+    pub fn to_docker_env(&self) -> String {
+        self.env_vars()
+            .into_iter()
+            .map(|(name, value)| format!("{}={}\n", name, value))
+            .collect()
+    }
+
+    /// `to_env_exports` for "shell", `to_docker_env` for "docker"
+    pub fn to_env(&self, format: &str) -> Result<String, ProbeError> {
+        match format.to_lowercase().as_str() {
+            "shell" => Ok(self.to_env_exports()),
+            "docker" => Ok(self.to_docker_env()),
+            other => Err(ProbeError::ConfigError(format!("Unknown env format '{}', expected shell or docker", other))),
+        }
+    }
+
+    /// Settings as `PROBE_<FIELD>` variables with secrets masked. Unset
+    /// optional fields are left out and lists are given as JSON.
+    fn env_vars(&self) -> Vec<(String, String)> {
+        let value = serde_json::to_value(self.with_secrets_masked()).expect("Config serializes to JSON");
+        let serde_json::Value::Object(fields) = value else {
+            return Vec::new();
+        };
+        fields
+            .into_iter()
+            .filter(|(name, value)| !value.is_null() && !BUILD_FIELDS.contains(&name.as_str()))
+            .map(|(name, value)| {
+                let value = match value {
+                    serde_json::Value::String(text) => text,
+                    other => other.to_string(),
+                };
+                (format!("PROBE_{}", name.to_uppercase()), value)
+            })
+            .collect()
+    }
+
+    /// The config as a TOML file, without the fields describing the build
+    pub fn to_toml(&self) -> Result<String> {
+        let mut value = toml::Value::try_from(self)?;
+        if let Some(table) = value.as_table_mut() {
+            for field in BUILD_FIELDS {
+                table.remove(field);
+            }
+        }
+        Ok(toml::to_string_pretty(&value)?)
+    }
+
+    /// Settings that changed from `self` to `other`
+    pub fn diff(&self, other: &Config) -> Vec<ConfigChange> {
+        let mut changes = Vec::new();
+
+        if self.filter_string != other.filter_string {
+            changes.push(ConfigChange::FilterString(other.filter_string.clone()));
+        }
+        if self.upload_interval_seconds != other.upload_interval_seconds {
+            changes.push(ConfigChange::UploadInterval(other.upload_interval_seconds));
+        }
+        if !self.log_level.eq_ignore_ascii_case(&other.log_level) {
+            changes.push(ConfigChange::LogLevel(other.log_level.clone()));
+        }
+        if self.buffer_size != other.buffer_size {
+            changes.push(ConfigChange::BufferSize(other.buffer_size));
+        }
+
+        if self.usb_port != other.usb_port {
+            changes.push(ConfigChange::RequiresRestart("usb_port"));
+        }
+        if self.server_url != other.server_url {
+            changes.push(ConfigChange::RequiresRestart("server_url"));
+        }
+        if self.api_key != other.api_key {
+            changes.push(ConfigChange::ApiKey(other.api_key.clone()));
+        }
+        if self.node_id != other.node_id {
+            changes.push(ConfigChange::RequiresRestart("node_id"));
+        }
+        if self.log_format != other.log_format {
+            changes.push(ConfigChange::RequiresRestart("log_format"));
+        }
+
+        changes
+    }
+}
+
+/// Map a `log_level` setting to a filter, defaulting to info
+pub fn parse_log_level(level: &str) -> log::LevelFilter {
+    match level.to_lowercase().as_str() {
+        "error" => log::LevelFilter::Error,
+        "warn" => log::LevelFilter::Warn,
+        "info" => log::LevelFilter::Info,
+        "debug" => log::LevelFilter::Debug,
+        "trace" => log::LevelFilter::Trace,
+        _ => log::LevelFilter::Info,
+    }
+}
+
+/// Write `api_key` into the config file that sets it (the override file if it
+/// replaces the key, the base file otherwise), keeping the rest of the file as
+/// it is. The file is backed up for `reason` first and replaced atomically.
+/// Returns the path written.
+pub fn persist_api_key(config: &Config, sources: &ConfigSources, api_key: &str, reason: &str) -> Result<PathBuf> {
+    let overridden = sources.overridden_fields.iter().any(|field| field == "api_key");
+    let path = match (overridden, sources.paths.last(), sources.paths.first()) {
+        (true, Some(path), _) | (false, _, Some(path)) => path.clone(),
+        _ => return Err(ProbeError::ConfigError("No config file to store the API key in".to_string()).into()),
+    };
+
+    let contents = std::fs::read_to_string(&path).with_context(|| format!("Failed to read config file: {:?}", path))?;
+    let mut document: toml_edit::DocumentMut =
+        contents.parse().with_context(|| format!("Failed to parse config file: {:?}", path))?;
+    document["api_key"] = toml_edit::value(api_key);
+
+    backup_config_file(config, &path, reason)?;
+    replace_file(&path, &document.to_string())?;
+    Ok(path)
+}
+
+/// Replace the file at `path` with `contents` through a temporary file, so
+/// readers never see a partly written config
+pub fn replace_file(path: &Path, contents: &str) -> Result<()> {
+    let temp_path = path.with_extension("toml.tmp");
+    std::fs::write(&temp_path, contents).with_context(|| format!("Failed to write {:?}", temp_path))?;
+    std::fs::rename(&temp_path, path).with_context(|| format!("Failed to replace config file: {:?}", path))?;
+    Ok(())
+}
+
+/// Name of the file in `config_backup_dir` listing the backups
+pub const CONFIG_BACKUP_MANIFEST: &str = "config_backup_manifest.json";
+
+/// One config file backup, as listed in the manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigBackup {
+    pub path: PathBuf,
+    /// The config file that was copied
+    pub source: PathBuf,
+    /// What was about to rewrite the file, e.g. "api_key_rotation"
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Copy the config file at `path` to `<config_backup_dir>/config_<timestamp>.toml`
+/// and record it in the manifest, deleting the oldest backups beyond
+/// `max_config_backups`. Returns the backup path, or None when backups are off.
+pub fn backup_config_file(config: &Config, path: &Path, reason: &str) -> Result<Option<PathBuf>> {
+    if config.max_config_backups == 0 {
+        return Ok(None);
+    }
+
+    let dir = &config.config_backup_dir;
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    let created_at = Utc::now();
+    let stamp = created_at.format("%Y%m%dT%H%M%S%.3fZ").to_string();
+    // Backups taken within the same millisecond get a counter so none is overwritten
+    let backup_path = (0..)
+        .map(|n| match n {
+            0 => dir.join(format!("config_{}.toml", stamp)),
+            n => dir.join(format!("config_{}_{}.toml", stamp, n)),
+        })
+        .find(|candidate| !candidate.exists())
+        .expect("an unused backup name");
+    std::fs::copy(path, &backup_path).with_context(|| format!("Failed to back up {:?} to {:?}", path, backup_path))?;
+
+    let mut backups = list_config_backups(dir)?;
+    backups.push(ConfigBackup {
+        path: backup_path.clone(),
+        source: path.to_path_buf(),
+        reason: reason.to_string(),
+        created_at,
+    });
+    let excess = backups.len().saturating_sub(config.max_config_backups);
+    for old in backups.drain(..excess) {
+        match std::fs::remove_file(&old.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Failed to delete old config backup {:?}: {}", old.path, e),
+        }
+    }
+
+    let manifest = dir.join(CONFIG_BACKUP_MANIFEST);
+    let temp_path = manifest.with_extension("json.tmp");
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(&backups)?).with_context(|| format!("Failed to write {:?}", temp_path))?;
+    std::fs::rename(&temp_path, &manifest).with_context(|| format!("Failed to replace {:?}", manifest))?;
+
+    log::info!("Backed up {:?} to {:?} before {}", path, backup_path, reason);
+    Ok(Some(backup_path))
+}
+
+/// Backups recorded in the manifest in `dir`, oldest first; empty when there is none yet
+pub fn list_config_backups(dir: &Path) -> Result<Vec<ConfigBackup>> {
+    let manifest = dir.join(CONFIG_BACKUP_MANIFEST);
+    match std::fs::read(&manifest) {
+        Ok(contents) => serde_json::from_slice(&contents).with_context(|| format!("Failed to parse {:?}", manifest)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {:?}", manifest)),
+    }
+}
+
+fn read_toml(path: &Path) -> Result<toml::Value> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file: {:?}", path))?;
+
+    toml::from_str(&contents).with_context(|| format!("Failed to parse config file: {:?}", path))
+}
+
+/// `config.toml` -> `config.override.toml`
+pub fn default_override_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!("{}.override.toml", stem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = r#"
+usb_port = "/dev/ttyACM0"
+server_url = "https://hub.example"
+api_key = "base-key"
+node_id = 7
+node_firmware_url = "https://fw.example/node"
+probe_firmware_url = "https://fw.example/probe"
+buffer_size = 500
+"#;
+
+    fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_without_override_uses_base_and_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = write(dir.path(), "config.toml", BASE);
+
+        let (config, sources) = Config::load(&base, None).unwrap();
+
+        assert_eq!(config.api_key, "base-key");
+        assert_eq!(config.buffer_size, 500);
+        assert_eq!(config.log_format, "text");
+        assert_eq!(sources.paths, vec![base]);
+        assert!(sources.overridden_fields.is_empty());
+    }
+
+    #[test]
+    fn override_replaces_only_the_fields_it_sets() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = write(dir.path(), "config.toml", BASE);
+        let override_path = write(dir.path(), "local.toml", "api_key = \"local-key\"\nupload_interval_seconds = 60\n");
+
+        let (config, sources) = Config::load(&base, Some(&override_path)).unwrap();
+
+        assert_eq!(config.api_key, "local-key");
+        assert_eq!(config.upload_interval_seconds, 60);
+        assert_eq!(config.buffer_size, 500);
+        assert_eq!(config.usb_port, "/dev/ttyACM0");
+        assert_eq!(sources.overridden_fields, vec!["api_key", "upload_interval_seconds"]);
+        assert_eq!(sources.paths, vec![base, override_path]);
+    }
+
+    #[test]
+    fn override_setting_a_default_value_still_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = write(dir.path(), "config.toml", BASE);
+        let override_path = write(dir.path(), "local.toml", "buffer_size = 10000\n");
+
+        let (config, _) = Config::load(&base, Some(&override_path)).unwrap();
+
+        assert_eq!(config.buffer_size, 10000);
+    }
+
+    #[test]
+    fn sibling_override_file_is_picked_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = write(dir.path(), "config.toml", BASE);
+        let override_path = write(dir.path(), "config.override.toml", "node_id = 8\n");
+
+        let (config, sources) = Config::load(&base, None).unwrap();
+
+        assert_eq!(config.node_id, 8);
+        assert_eq!(sources.paths, vec![base, override_path]);
+    }
+
+    #[test]
+    fn partial_base_completed_by_override_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let partial = BASE.replace("api_key = \"base-key\"\n", "");
+        let base = write(dir.path(), "config.toml", &partial);
+        let override_path = write(dir.path(), "local.toml", "api_key = \"secret\"\n");
+
+        let (config, _) = Config::load(&base, Some(&override_path)).unwrap();
+
+        assert_eq!(config.api_key, "secret");
+        assert_eq!(config.buffer_size, 500);
+    }
+
+    #[test]
+    fn missing_required_field_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let partial = BASE.replace("api_key = \"base-key\"\n", "").replace("node_id = 7\n", "");
+        let base = write(dir.path(), "config.toml", &partial);
+
+        let error = Config::load(&base, None).unwrap_err().to_string();
+
+        assert!(error.contains("api_key, node_id"), "{}", error);
+    }
+
+    #[test]
+    fn diff_separates_runtime_changes_from_restart_only_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, _) = Config::load(&write(dir.path(), "old.toml", BASE), None).unwrap();
+        let changed = BASE
+            .replace("/dev/ttyACM0", "/dev/ttyACM1")
+            .replace("base-key", "new-key")
+            .replace("buffer_size = 500", "buffer_size = 200\nfilter_string = \"radio\"\nlog_level = \"DEBUG\"");
+        let (new, _) = Config::load(&write(dir.path(), "new.toml", &changed), None).unwrap();
+
+        assert!(old.diff(&old).is_empty());
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                ConfigChange::FilterString("radio".to_string()),
+                ConfigChange::LogLevel("DEBUG".to_string()),
+                ConfigChange::BufferSize(200),
+                ConfigChange::RequiresRestart("usb_port"),
+                ConfigChange::ApiKey("new-key".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn docker_env_lines_set_the_config_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = format!("{}filter_string = \"it's [WARN]\"\n", BASE);
+        let (config, _) = Config::load(&write(dir.path(), "config.toml", &settings), None).unwrap();
+
+        let env = config.to_docker_env();
+        for line in env.lines() {
+            let (name, value) = line.split_once('=').unwrap();
+            std::env::set_var(name, value);
+        }
+
+        assert_eq!(std::env::var("PROBE_FILTER_STRING").unwrap(), "it's [WARN]");
+        assert_eq!(std::env::var("PROBE_BUFFER_SIZE").unwrap(), "500");
+        assert_eq!(std::env::var("PROBE_NODE_ID").unwrap(), "7");
+        assert_ne!(std::env::var("PROBE_API_KEY").unwrap(), "base-key");
+        assert!(!env.contains("PROBE_BUILD_TIMESTAMP"));
+        assert!(config.to_env("yaml").is_err());
+    }
+
+    #[test]
+    fn shell_exports_survive_quotes_when_sourced() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = format!("{}filter_string = \"it's [WARN] $HOME\"\n", BASE);
+        let (config, _) = Config::load(&write(dir.path(), "config.toml", &settings), None).unwrap();
+        let script = write(dir.path(), "probe.env", &config.to_env("shell").unwrap());
+
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(". {:?} && printf '%s|%s' \"$PROBE_FILTER_STRING\" \"$PROBE_USB_PORT\"", script))
+            .output()
+            .unwrap();
+
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "it's [WARN] $HOME|/dev/ttyACM0");
+    }
+
+    #[test]
+    fn backups_beyond_the_cap_are_deleted_oldest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = dir.path().join("backups");
+        let settings = format!("{}config_backup_dir = {:?}\nmax_config_backups = 5\n", BASE, backups);
+        let base = write(dir.path(), "config.toml", &settings);
+        let (config, _) = Config::load(&base, None).unwrap();
+
+        let mut paths = Vec::new();
+        for n in 0..7 {
+            let reason = if n % 2 == 0 { "api_key_rotation" } else { "generate_config" };
+            paths.push(backup_config_file(&config, &base, reason).unwrap().unwrap());
+        }
+
+        let listed = list_config_backups(&backups).unwrap();
+        assert_eq!(listed.iter().map(|backup| &backup.path).collect::<Vec<_>>(), paths[2..].iter().collect::<Vec<_>>());
+        assert_eq!(listed[0].reason, "api_key_rotation");
+        assert_eq!(listed[1].reason, "generate_config");
+        assert!(listed.iter().all(|backup| backup.source == base));
+        assert!(paths[..2].iter().all(|path| !path.exists()));
+        assert!(paths[2..].iter().all(|path| std::fs::read_to_string(path).unwrap() == settings));
+        assert_eq!(std::fs::read_dir(&backups).unwrap().count(), 6);
+    }
+
+    #[test]
+    fn backups_are_off_with_a_cap_of_zero() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = dir.path().join("backups");
+        let settings = format!("{}config_backup_dir = {:?}\nmax_config_backups = 0\n", BASE, backups);
+        let base = write(dir.path(), "config.toml", &settings);
+        let (config, _) = Config::load(&base, None).unwrap();
+
+        assert_eq!(backup_config_file(&config, &base, "generate_config").unwrap(), None);
+        assert!(!backups.exists());
+        assert!(list_config_backups(&backups).unwrap().is_empty());
+    }
+
+    #[test]
+    fn overlay_merge_prefers_the_later_overlay() {
+        let base = ConfigOverlay { buffer_size: Some(1), upload_interval_seconds: Some(2), ..Default::default() };
+        let local = ConfigOverlay { buffer_size: Some(3), ..Default::default() };
+
+        let merged = base.merge(local);
+
+        assert_eq!(merged.buffer_size, Some(3));
+        assert_eq!(merged.upload_interval_seconds, Some(2));
+        assert_eq!(merged.api_key, None);
     }
 }
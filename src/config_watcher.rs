@@ -0,0 +1,196 @@
+use crate::command_executor::{CommandContext, UploadSchedule};
+use crate::config::{self, Config, ConfigChange};
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+
+/// Quiet period after a change before reloading, so editors that write in
+/// several steps trigger a single reload
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watch the config file (and its override file) and apply changes to the
+/// fields that can change at runtime.
+pub async fn run(config_path: PathBuf, override_path: Option<PathBuf>, ctx: CommandContext) -> Result<()> {
+    let override_file = override_path.clone().unwrap_or_else(|| config::default_override_path(&config_path));
+    let watched_files: HashSet<OsString> = [&config_path, &override_file]
+        .iter()
+        .filter_map(|p| p.file_name().map(OsString::from))
+        .collect();
+
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let _ = event_tx.send(event);
+    })?;
+
+    // Watch the directories rather than the files so replaced files are picked up
+    let mut watched_dirs = HashSet::new();
+    for path in [&config_path, &override_file] {
+        let dir = parent_dir(path);
+        if watched_dirs.insert(dir.clone()) {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        }
+    }
+    info!("Watching {:?} for configuration changes", config_path);
+
+    let mut current = (*ctx.config).clone();
+
+    while let Some(event) = event_rx.recv().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Config watcher error: {}", e);
+                continue;
+            }
+        };
+
+        let relevant = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            && event.paths.iter().any(|p| p.file_name().is_some_and(|name| watched_files.contains(name)));
+        if !relevant {
+            continue;
+        }
+
+        sleep(DEBOUNCE).await;
+        while event_rx.try_recv().is_ok() {}
+
+        let new = match Config::load(&config_path, override_path.as_deref()) {
+            Ok((new, _)) => new,
+            Err(e) => {
+                error!("Ignoring config change: {:#}", e);
+                ctx.error_reporter.report("config_watcher", &e);
+                continue;
+            }
+        };
+
+        let changes = current.diff(&new);
+        if changes.is_empty() {
+            debug!("Config file changed without affecting any setting");
+            continue;
+        }
+
+        for change in changes {
+            apply_change(&ctx, change).await;
+        }
+        current = new;
+    }
+
+    Ok(())
+}
+
+async fn apply_change(ctx: &CommandContext, change: ConfigChange) {
+    match change {
+        ConfigChange::FilterString(filter) => {
+            info!("Config reload: filter_string set to '{}'", filter);
+            *ctx.filter_string.write().await = filter;
+        }
+        ConfigChange::UploadInterval(seconds) => {
+            info!("Config reload: upload_interval_seconds set to {}", seconds);
+            *ctx.upload_schedule.write().await = UploadSchedule::fixed(seconds);
+        }
+        ConfigChange::LogLevel(level) => {
+            info!("Config reload: log_level set to {}", level);
+            log::set_max_level(config::parse_log_level(&level));
+        }
+        ConfigChange::BufferSize(size) => {
+            let evicted = ctx.buffer.write().await.set_max_size(size);
+            info!("Config reload: buffer_size set to {} ({} entries evicted)", size, evicted);
+        }
+        ConfigChange::ApiKey(api_key) => {
+            info!("Config reload: api_key changed");
+            *ctx.api_key.write().await = api_key;
+        }
+        ConfigChange::RequiresRestart(field) => {
+            warn!("Config reload: {} changed, restart the probe for it to take effect", field);
+        }
+    }
+}
+
+/// Directory containing `path`, `.` for bare file names
+fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_executor::testing;
+
+    const CONFIG: &str = r#"
+usb_port = "/dev/ttyACM0"
+server_url = "https://hub.example"
+api_key = "key"
+node_id = 7
+node_firmware_url = "https://fw.example/node"
+probe_firmware_url = "https://fw.example/probe"
+buffer_size = 500
+upload_interval_seconds = 60
+"#;
+
+    #[test]
+    fn parent_dir_of_a_bare_file_name_is_the_working_directory() {
+        assert_eq!(parent_dir(Path::new("config.toml")), PathBuf::from("."));
+        assert_eq!(parent_dir(Path::new("/etc/probe/config.toml")), PathBuf::from("/etc/probe"));
+    }
+
+    #[tokio::test]
+    async fn rewritten_config_is_applied_without_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, CONFIG).unwrap();
+        let (config, _) = Config::load(&path, None).unwrap();
+        let (ctx, _mock) = testing::context(config);
+        let watcher = tokio::spawn(run(path.clone(), None, ctx.clone()));
+        sleep(Duration::from_millis(200)).await;
+
+        let changed = CONFIG
+            .replace("buffer_size = 500", "buffer_size = 200\nfilter_string = \"radio\"")
+            .replace("upload_interval_seconds = 60", "upload_interval_seconds = 15")
+            .replace("/dev/ttyACM0", "/dev/ttyACM1");
+        std::fs::write(&path, changed).unwrap();
+
+        for _ in 0..50 {
+            if *ctx.filter_string.read().await == "radio" {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*ctx.filter_string.read().await, "radio");
+        assert_eq!(ctx.buffer.read().await.max_size(), 200);
+        assert_eq!(ctx.upload_schedule.read().await.current_interval(), 15);
+        assert_eq!(*ctx.api_key.read().await, "key");
+        watcher.abort();
+    }
+
+    #[tokio::test]
+    async fn invalid_config_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, CONFIG).unwrap();
+        let (config, _) = Config::load(&path, None).unwrap();
+        let (ctx, _mock) = testing::context(config);
+        let watcher = tokio::spawn(run(path.clone(), None, ctx.clone()));
+        sleep(Duration::from_millis(200)).await;
+
+        std::fs::write(&path, CONFIG.replace("buffer_size = 500", "buffer_size = \"lots\"")).unwrap();
+        sleep(DEBOUNCE * 3).await;
+        assert_eq!(ctx.buffer.read().await.max_size(), 500);
+
+        std::fs::write(&path, CONFIG.replace("api_key = \"key\"", "api_key = \"rotated\"")).unwrap();
+        for _ in 0..50 {
+            if *ctx.api_key.read().await == "rotated" {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*ctx.api_key.read().await, "rotated");
+        assert_eq!(ctx.buffer.read().await.max_size(), 500);
+        watcher.abort();
+    }
+}
@@ -0,0 +1,177 @@
+use crate::config::Config;
+use anyhow::{Context, Result};
+use log::info;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration, Instant};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Client builder for requests to the hub and firmware servers, going through
+/// `http_proxy`/`https_proxy` when set
+pub fn client_builder(config: &Config) -> Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::Client::builder().use_rustls_tls();
+    // Explicit proxies replace reqwest's own HTTP_PROXY/HTTPS_PROXY handling,
+    // so NO_PROXY has to be passed along with them
+    let no_proxy = match &config.no_proxy {
+        Some(hosts) => reqwest::NoProxy::from_string(&hosts.join(",")),
+        None => reqwest::NoProxy::from_env(),
+    };
+    if let Some(proxy) = &config.http_proxy {
+        builder = builder.proxy(reqwest::Proxy::http(proxy)?.no_proxy(no_proxy.clone()));
+    }
+    if let Some(proxy) = &config.https_proxy {
+        builder = builder.proxy(reqwest::Proxy::https(proxy)?.no_proxy(no_proxy));
+    }
+    Ok(builder)
+}
+
+/// Addresses of one network interface of the probe
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub addresses: Vec<String>,
+}
+
+/// Non-loopback IPv4 and IPv6 addresses of the probe, grouped by interface.
+/// Read fresh on every call since DHCP and interfaces coming up change them.
+#[cfg(target_os = "linux")]
+pub fn network_interfaces() -> Vec<NetworkInterface> {
+    let addrs = match nix::ifaddrs::getifaddrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            log::warn!("Failed to list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut interfaces: Vec<NetworkInterface> = Vec::new();
+    for ifaddr in addrs {
+        let Some(address) = ifaddr.address else { continue };
+        // Link-layer and other non-IP addresses are skipped
+        let ip = match (address.as_sockaddr_in(), address.as_sockaddr_in6()) {
+            (Some(v4), _) => std::net::IpAddr::V4(v4.ip()),
+            (_, Some(v6)) => std::net::IpAddr::V6(v6.ip()),
+            _ => continue,
+        };
+        if ip.is_loopback() {
+            continue;
+        }
+
+        match interfaces.iter_mut().find(|interface| interface.name == ifaddr.interface_name) {
+            Some(interface) => interface.addresses.push(ip.to_string()),
+            None => interfaces.push(NetworkInterface {
+                name: ifaddr.interface_name,
+                addresses: vec![ip.to_string()],
+            }),
+        }
+    }
+    interfaces
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn network_interfaces() -> Vec<NetworkInterface> {
+    Vec::new()
+}
+
+/// Check each layer between the probe and `server_url` in turn: DNS, TCP,
+/// TLS (for https) and HTTP `GET /health`. Each field holds a timing or
+/// status, or an error string; layers after a failed one are skipped.
+/// Only the HTTP check goes through a configured proxy.
+pub async fn diagnose(config: &Config) -> serde_json::Value {
+    let mut report = serde_json::Map::new();
+    let result = run_checks(config, &mut report).await;
+    if let Err(e) = result {
+        report.insert("error".to_string(), serde_json::Value::from(format!("{:#}", e)));
+    }
+
+    info!("Server connectivity: {}", serde_json::Value::Object(report.clone()));
+    serde_json::Value::Object(report)
+}
+
+async fn run_checks(config: &Config, report: &mut serde_json::Map<String, serde_json::Value>) -> Result<()> {
+    let server_url = config.server_url.as_str();
+    let url = reqwest::Url::parse(server_url).context("invalid server_url")?;
+    let host = url.host_str().context("server_url has no host")?.to_string();
+    let port = url.port_or_known_default().context("server_url has no port")?;
+
+    // DNS
+    let started = Instant::now();
+    let addr = match tokio::net::lookup_host((host.as_str(), port)).await.map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => {
+            report.insert("dns_ms".to_string(), elapsed_ms(started));
+            addr
+        }
+        Ok(None) => return record_failure(report, "dns_ms", format!("{} did not resolve", host)),
+        Err(e) => return record_failure(report, "dns_ms", e.to_string()),
+    };
+
+    // TCP
+    let started = Instant::now();
+    let stream = match connect(addr).await {
+        Ok(stream) => {
+            report.insert("tcp_ms".to_string(), elapsed_ms(started));
+            stream
+        }
+        Err(e) => return record_failure(report, "tcp_ms", format!("{:#}", e)),
+    };
+
+    // TLS
+    if url.scheme() == "https" {
+        let started = Instant::now();
+        match tls_handshake(stream, &host).await {
+            Ok(()) => {
+                report.insert("tls_ms".to_string(), elapsed_ms(started));
+            }
+            Err(e) => return record_failure(report, "tls_ms", format!("{:#}", e)),
+        }
+    }
+
+    // HTTP
+    let client = client_builder(config)?.timeout(HEALTH_TIMEOUT).build()?;
+    let started = Instant::now();
+    match client.get(format!("{}/health", server_url.trim_end_matches('/'))).send().await {
+        Ok(response) => {
+            report.insert("http_status".to_string(), serde_json::Value::from(response.status().as_u16()));
+            report.insert("http_ms".to_string(), elapsed_ms(started));
+        }
+        Err(e) => return record_failure(report, "http_status", e.to_string()),
+    }
+
+    Ok(())
+}
+
+async fn connect(addr: SocketAddr) -> Result<TcpStream> {
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .with_context(|| format!("connect to {} timed out after {}s", addr, CONNECT_TIMEOUT.as_secs()))??;
+    Ok(stream)
+}
+
+/// Complete a TLS handshake with `host` over `stream`, verifying its certificate
+async fn tls_handshake(stream: TcpStream, host: &str) -> Result<()> {
+    let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let server_name = ServerName::try_from(host.to_string())?;
+
+    timeout(CONNECT_TIMEOUT, TlsConnector::from(Arc::new(config)).connect(server_name, stream))
+        .await
+        .with_context(|| format!("TLS handshake timed out after {}s", CONNECT_TIMEOUT.as_secs()))??;
+    Ok(())
+}
+
+/// Record `error` as the result of `field`; the remaining checks are skipped
+fn record_failure(report: &mut serde_json::Map<String, serde_json::Value>, field: &str, error: String) -> Result<()> {
+    report.insert(field.to_string(), serde_json::Value::from(error));
+    Ok(())
+}
+
+fn elapsed_ms(started: Instant) -> serde_json::Value {
+    serde_json::Value::from(started.elapsed().as_millis() as u64)
+}
@@ -0,0 +1,117 @@
+use crate::usb_manager::UsbHandle;
+use anyhow::Result;
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+
+/// Local TCP pass-through between one client at a time and the node's USB port.
+/// Dropping it closes the listener and any open connection.
+pub struct DebugPort {
+    port: u16,
+    task: JoinHandle<()>,
+}
+
+impl DebugPort {
+    /// Listen on `127.0.0.1:<port>`. Client bytes go to the node as raw writes
+    /// (or frames when `frame` is set), node lines go to the client. The port
+    /// closes itself after `idle_timeout` without client input.
+    pub async fn open(
+        port: u16,
+        usb_handle: UsbHandle,
+        lines: broadcast::Sender<String>,
+        frame: bool,
+        idle_timeout: Duration,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        info!("Debug port listening on 127.0.0.1:{}", port);
+
+        let task = tokio::spawn(serve(listener, usb_handle, lines, frame, idle_timeout));
+        Ok(Self { port, task })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// False once the port closed itself after being idle
+    pub fn is_open(&self) -> bool {
+        !self.task.is_finished()
+    }
+}
+
+impl Drop for DebugPort {
+    fn drop(&mut self) {
+        self.task.abort();
+        info!("Debug port {} closed", self.port);
+    }
+}
+
+async fn serve(listener: TcpListener, usb_handle: UsbHandle, lines: broadcast::Sender<String>, frame: bool, idle_timeout: Duration) {
+    loop {
+        let (stream, peer) = match timeout(idle_timeout, listener.accept()).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => {
+                warn!("Debug port accept failed: {}", e);
+                continue;
+            }
+            Err(_) => {
+                info!("Debug port idle for {}s, closing", idle_timeout.as_secs());
+                return;
+            }
+        };
+
+        info!("Debug port client connected from {}", peer);
+        let idle = pass_through(stream, &usb_handle, lines.subscribe(), frame, idle_timeout).await;
+        info!("Debug port client {} disconnected", peer);
+        if idle {
+            info!("Debug port idle for {}s, closing", idle_timeout.as_secs());
+            return;
+        }
+    }
+}
+
+/// Relay between `stream` and the node until the client leaves or stays quiet
+/// for `idle_timeout`. Returns true in the latter case.
+async fn pass_through(
+    mut stream: TcpStream,
+    usb_handle: &UsbHandle,
+    mut lines: broadcast::Receiver<String>,
+    frame: bool,
+    idle_timeout: Duration,
+) -> bool {
+    let (mut reader, mut writer) = stream.split();
+    let mut buf = vec![0u8; 1024];
+    let mut idle_deadline = Instant::now() + idle_timeout;
+
+    loop {
+        tokio::select! {
+            read = reader.read(&mut buf) => {
+                let n = match read {
+                    Ok(0) | Err(_) => return false,
+                    Ok(n) => n,
+                };
+                idle_deadline = Instant::now() + idle_timeout;
+
+                let bytes = buf[..n].to_vec();
+                let sent = if frame { usb_handle.send_frame(bytes).await } else { usb_handle.send_raw(bytes).await };
+                if let Err(e) = sent {
+                    warn!("Debug port could not forward {} bytes to USB: {}", n, e);
+                }
+            }
+            line = lines.recv() => {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => format!("[debug port: {} lines missed]", missed),
+                    Err(broadcast::error::RecvError::Closed) => return false,
+                };
+                if writer.write_all(format!("{}\n", line).as_bytes()).await.is_err() {
+                    return false;
+                }
+            }
+            _ = sleep_until(idle_deadline) => return true,
+        }
+    }
+}
@@ -1,7 +1,9 @@
+use std::fmt;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 #[allow(dead_code)]
+#[allow(clippy::enum_variant_names)]
 pub enum ProbeError {
     #[error("USB serial port error: {0}")]
     UsbError(#[from] tokio_serial::Error),
@@ -21,6 +23,91 @@ pub enum ProbeError {
     #[error("Firmware update error: {0}")]
     FirmwareError(String),
     
+    #[error("Failed to download firmware from {url}: {source}")]
+    FirmwareDownloadError { url: String, source: reqwest::Error },
+    
+    #[error("Firmware CRC32 mismatch: expected {expected:08x}, computed {computed:08x}")]
+    FirmwareCrcMismatch { expected: u32, computed: u32 },
+    
+    #[error("Firmware signature mismatch for {component}")]
+    FirmwareSignatureMismatch { component: String },
+    
+    #[error("Firmware flash failed while {stage}: {source}")]
+    FirmwareFlashError { stage: FlashStage, source: anyhow::Error },
+    
+    #[error("Invalid node update transition: {event} in state {state}")]
+    InvalidUpdateTransition { state: &'static str, event: &'static str },
+    
+    #[error("Node firmware update cancelled while {during}")]
+    UpdateCancelled { during: &'static str },
+    
     #[error("Command execution error: {0}")]
     CommandError(String),
+    
+    #[error("Authentication rejected: {0}")]
+    AuthError(String),
+    
+    #[error("Hub rejected upload as malformed (status {status})")]
+    UploadRejected { status: u16 },
+    
+    #[error("Upload failed with status {status}")]
+    UploadFailed { status: u16 },
+    
+    #[error("Upload circuit open after repeated failures, next attempt in {retry_in_seconds}s")]
+    CircuitOpen { retry_in_seconds: u64 },
+}
+
+impl ProbeError {
+    /// Machine-readable variant name
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProbeError::UsbError(_) => "UsbError",
+            ProbeError::IoError(_) => "IoError",
+            ProbeError::HttpError(_) => "HttpError",
+            ProbeError::JsonError(_) => "JsonError",
+            ProbeError::ConfigError(_) => "ConfigError",
+            ProbeError::FirmwareError(_) => "FirmwareError",
+            ProbeError::FirmwareDownloadError { .. } => "FirmwareDownloadError",
+            ProbeError::FirmwareCrcMismatch { .. } => "FirmwareCrcMismatch",
+            ProbeError::FirmwareSignatureMismatch { .. } => "FirmwareSignatureMismatch",
+            ProbeError::FirmwareFlashError { .. } => "FirmwareFlashError",
+            ProbeError::InvalidUpdateTransition { .. } => "InvalidUpdateTransition",
+            ProbeError::UpdateCancelled { .. } => "UpdateCancelled",
+            ProbeError::CommandError(_) => "CommandError",
+            ProbeError::AuthError(_) => "AuthError",
+            ProbeError::UploadRejected { .. } => "UploadRejected",
+            ProbeError::UploadFailed { .. } => "UploadFailed",
+            ProbeError::CircuitOpen { .. } => "CircuitOpen",
+        }
+    }
+}
+
+/// Machine-readable code for any error, `"Other"` when it is not a `ProbeError`
+pub fn error_code(error: &anyhow::Error) -> &'static str {
+    error.downcast_ref::<ProbeError>().map_or("Other", ProbeError::code)
+}
+
+/// Step of flashing the node through its UF2 bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashStage {
+    EnteringBootloader,
+    WaitingForDevice,
+    Mounting,
+    Copying,
+    Unmounting,
+    /// Waiting for the node to come back with the new firmware
+    Verifying,
+}
+
+impl fmt::Display for FlashStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashStage::EnteringBootloader => write!(f, "entering bootloader"),
+            FlashStage::WaitingForDevice => write!(f, "waiting for device"),
+            FlashStage::Mounting => write!(f, "mounting"),
+            FlashStage::Copying => write!(f, "copying"),
+            FlashStage::Unmounting => write!(f, "unmounting"),
+            FlashStage::Verifying => write!(f, "verifying"),
+        }
+    }
 }
@@ -0,0 +1,172 @@
+use crate::error;
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Repeats of an error within this window are counted on the earlier event
+const DEDUP_WINDOW_SECONDS: i64 = 60;
+/// Events kept while the hub cannot be reached; the oldest go first beyond this
+const MAX_PENDING_EVENTS: usize = 100;
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Error from a probe task, sent to the hub with the next upload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorEvent {
+    pub task: String,
+    /// `ProbeError` variant name, "Other" for any other error
+    pub error_type: String,
+    pub message: String,
+    pub occurred_at: DateTime<Utc>,
+    /// Occurrences folded into this event
+    pub count: u32,
+}
+
+/// Hands task errors to the reporter task
+#[derive(Debug, Clone)]
+pub struct ErrorReporter {
+    sender: mpsc::Sender<ErrorEvent>,
+}
+
+impl ErrorReporter {
+    pub fn new() -> (Self, mpsc::Receiver<ErrorEvent>) {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        (Self { sender }, receiver)
+    }
+
+    /// Queue `error` from `task` for the hub. Never waits; the event is
+    /// dropped if the reporter task is behind.
+    pub fn report(&self, task: &str, error: &anyhow::Error) {
+        let event = ErrorEvent {
+            task: task.to_string(),
+            error_type: error::error_code(error).to_string(),
+            message: error.to_string(),
+            occurred_at: Utc::now(),
+            count: 1,
+        };
+        if let Err(e) = self.sender.try_send(event) {
+            debug!("Error event from {} not reported: {}", task, e);
+        }
+    }
+}
+
+/// Events waiting for the next upload, filled by the reporter task and
+/// emptied by the telemetry transport
+#[derive(Debug, Clone, Default)]
+pub struct PendingErrors(Arc<Mutex<Vec<ErrorEvent>>>);
+
+impl PendingErrors {
+    pub fn add(&self, event: ErrorEvent) {
+        merge_event(&mut self.0.lock().unwrap(), event);
+    }
+
+    pub fn take(&self) -> Vec<ErrorEvent> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Add `event` to `events`, or count it on the pending event of the same task
+/// and `error_type` if that occurred less than 60s before it
+pub fn merge_event(events: &mut Vec<ErrorEvent>, event: ErrorEvent) {
+    let repeat_of = events.iter_mut().rev().find(|pending| {
+        pending.task == event.task
+            && pending.error_type == event.error_type
+            && (event.occurred_at - pending.occurred_at).num_seconds() < DEDUP_WINDOW_SECONDS
+    });
+    if let Some(pending) = repeat_of {
+        pending.count = pending.count.saturating_add(event.count);
+        return;
+    }
+
+    if events.len() >= MAX_PENDING_EVENTS {
+        let dropped = events.remove(0);
+        warn!("Too many unreported error events, dropping one from {} ({})", dropped.task, dropped.error_type);
+    }
+    events.push(event);
+}
+
+/// Move reported events into `pending` until every `ErrorReporter` is gone
+pub async fn run(mut events: mpsc::Receiver<ErrorEvent>, pending: PendingErrors) {
+    while let Some(event) = events.recv().await {
+        pending.add(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProbeError;
+
+    fn event(task: &str, error_type: &str, seconds: i64) -> ErrorEvent {
+        ErrorEvent {
+            task: task.to_string(),
+            error_type: error_type.to_string(),
+            message: format!("{} failed", task),
+            occurred_at: DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z").unwrap().with_timezone(&Utc)
+                + chrono::Duration::seconds(seconds),
+            count: 1,
+        }
+    }
+
+    fn counts(events: &[ErrorEvent]) -> Vec<(&str, &str, u32)> {
+        events.iter().map(|event| (event.task.as_str(), event.error_type.as_str(), event.count)).collect()
+    }
+
+    #[test]
+    fn repeats_within_a_minute_are_counted_on_the_first_event() {
+        let mut events = Vec::new();
+        merge_event(&mut events, event("sync", "UploadError", 0));
+        merge_event(&mut events, event("sync", "UploadError", 30));
+        merge_event(&mut events, event("sync", "UsbError", 31));
+        merge_event(&mut events, event("scheduler", "UploadError", 32));
+        merge_event(&mut events, event("sync", "UploadError", 59));
+
+        assert_eq!(counts(&events), vec![("sync", "UploadError", 3), ("sync", "UsbError", 1), ("scheduler", "UploadError", 1)]);
+        assert_eq!(events[0].occurred_at, event("sync", "UploadError", 0).occurred_at);
+    }
+
+    #[test]
+    fn the_window_counts_from_the_first_event() {
+        let mut events = Vec::new();
+        merge_event(&mut events, event("sync", "UploadError", 0));
+        merge_event(&mut events, event("sync", "UploadError", 45));
+        merge_event(&mut events, event("sync", "UploadError", 60));
+        merge_event(&mut events, event("sync", "UploadError", 90));
+
+        assert_eq!(counts(&events), vec![("sync", "UploadError", 2), ("sync", "UploadError", 2)]);
+    }
+
+    #[test]
+    fn pending_events_are_capped_and_counts_saturate() {
+        let mut events = Vec::new();
+        for n in 0..=MAX_PENDING_EVENTS as i64 {
+            merge_event(&mut events, event("sync", "UploadError", n * DEDUP_WINDOW_SECONDS));
+        }
+        assert_eq!(events.len(), MAX_PENDING_EVENTS);
+        assert_eq!(events[0].occurred_at, event("sync", "UploadError", DEDUP_WINDOW_SECONDS).occurred_at);
+
+        let last = events.last().unwrap().occurred_at;
+        let repeat = ErrorEvent { occurred_at: last, count: u32::MAX, ..events.last().unwrap().clone() };
+        merge_event(&mut events, repeat);
+        assert_eq!(events.last().unwrap().count, u32::MAX);
+    }
+
+    #[tokio::test]
+    async fn reported_errors_reach_the_pending_events() {
+        let (reporter, receiver) = ErrorReporter::new();
+        let pending = PendingErrors::default();
+        let running = tokio::spawn(run(receiver, pending.clone()));
+
+        reporter.report("usb", &ProbeError::CommandError("no reply".to_string()).into());
+        reporter.report("usb", &ProbeError::CommandError("no reply".to_string()).into());
+        reporter.report("usb", &anyhow::anyhow!("disk full"));
+        drop(reporter);
+        running.await.unwrap();
+
+        let events = pending.take();
+        assert_eq!(counts(&events), vec![("usb", "CommandError", 2), ("usb", "Other", 1)]);
+        assert_eq!(events[0].message, "Command execution error: no reply");
+        assert!(pending.take().is_empty());
+    }
+}
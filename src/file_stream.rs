@@ -0,0 +1,71 @@
+use anyhow::Result;
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{broadcast, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// Copies every raw node line to a file for a limited time, alongside the
+/// normal filter and buffer pipeline. Dropping it stops the copy after
+/// flushing what was written so far.
+pub struct FileStream {
+    path: PathBuf,
+    task: JoinHandle<()>,
+    _stop: oneshot::Sender<()>,
+}
+
+impl FileStream {
+    /// Append node lines to `path` until `duration` has passed
+    pub async fn open(path: PathBuf, lines: &broadcast::Sender<String>, duration: Duration) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        info!("Streaming USB output to {:?} for {}s", path, duration.as_secs());
+
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(capture(BufWriter::new(file), path.clone(), lines.subscribe(), Instant::now() + duration, stopped));
+        Ok(Self { path, task, _stop: stop })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// False once the duration has passed or writing failed
+    pub fn is_open(&self) -> bool {
+        !self.task.is_finished()
+    }
+}
+
+async fn capture(
+    mut writer: BufWriter<tokio::fs::File>,
+    path: PathBuf,
+    mut lines: broadcast::Receiver<String>,
+    until: Instant,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let mut written = 0u64;
+    loop {
+        tokio::select! {
+            line = lines.recv() => match line {
+                Ok(line) => {
+                    if let Err(e) = writer.write_all(format!("{}\n", line).as_bytes()).await {
+                        warn!("Failed to write USB output to {:?}: {}", path, e);
+                        break;
+                    }
+                    written += 1;
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => warn!("Stream to {:?} missed {} lines", path, missed),
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = sleep_until(until) => break,
+            // Sender dropped with the `FileStream`
+            _ = &mut stopped => break,
+        }
+    }
+
+    if let Err(e) = writer.flush().await {
+        warn!("Failed to flush USB output to {:?}: {}", path, e);
+    }
+    info!("Stopped streaming USB output to {:?} after {} lines", path, written);
+}
@@ -0,0 +1,152 @@
+use crate::log_entry::LogEntry;
+use anyhow::Result;
+use log::{error, info, warn};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+/// Entries waiting for the writer; beyond this they are dropped rather than
+/// stalling the USB receive loop
+const QUEUE_SIZE: usize = 1000;
+
+/// Sending side of the archive writer task
+#[derive(Clone)]
+pub struct LocalArchive {
+    entry_tx: mpsc::Sender<LogEntry>,
+}
+
+impl LocalArchive {
+    /// Start a writer task appending entries as JSON lines to `path`, rotating
+    /// to `path.1` .. `path.<keep_files>` once the file exceeds `max_bytes`
+    pub fn spawn(path: PathBuf, max_bytes: u64, keep_files: u8) -> Self {
+        let (entry_tx, entry_rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(async move {
+            if let Err(e) = run_writer(&path, max_bytes, keep_files, entry_rx).await {
+                error!("Local archive writer for {:?} stopped: {}", path, e);
+            }
+        });
+        Self { entry_tx }
+    }
+
+    /// Queue `entry` for archiving without waiting
+    pub fn archive(&self, entry: &LogEntry) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.entry_tx.try_send(entry.clone()) {
+            warn!("Local archive queue full, entry not archived");
+        }
+    }
+}
+
+async fn run_writer(path: &Path, max_bytes: u64, keep_files: u8, mut entry_rx: mpsc::Receiver<LogEntry>) -> Result<()> {
+    info!("Archiving log entries to {:?}", path);
+
+    let mut file = open_append(path).await?;
+    let mut size = file.metadata().await?.len();
+
+    while let Some(entry) = entry_rx.recv().await {
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        if size > 0 && size + line.len() as u64 > max_bytes {
+            file.flush().await?;
+            drop(file);
+            rotate(path, keep_files).await?;
+            file = open_append(path).await?;
+            size = 0;
+        }
+
+        file.write_all(&line).await?;
+        size += line.len() as u64;
+    }
+
+    file.flush().await?;
+    Ok(())
+}
+
+async fn open_append(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path).await?)
+}
+
+/// Shift `path.N` to `path.N+1`, dropping the oldest, and move `path` to `path.1`
+pub async fn rotate(path: &Path, keep_files: u8) -> Result<()> {
+    if keep_files == 0 {
+        fs::remove_file(path).await?;
+        return Ok(());
+    }
+
+    for n in (1..keep_files).rev() {
+        let from = numbered_path(path, n);
+        if fs::try_exists(&from).await? {
+            fs::rename(&from, numbered_path(path, n + 1)).await?;
+        }
+    }
+    fs::rename(path, numbered_path(path, 1)).await?;
+    Ok(())
+}
+
+/// `probe.log` -> `probe.log.2`
+fn numbered_path(path: &Path, n: u8) -> PathBuf {
+    let mut numbered = path.as_os_str().to_owned();
+    numbered.push(format!(".{}", n));
+    PathBuf::from(numbered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(message: &str) -> LogEntry {
+        LogEntry::new("2024-05-01T12:00:00Z".to_string(), message.to_string())
+    }
+
+    fn line_len(message: &str) -> u64 {
+        serde_json::to_vec(&entry(message)).unwrap().len() as u64 + 1
+    }
+
+    async fn write_all(path: &Path, max_bytes: u64, keep_files: u8, messages: &[&str]) {
+        let (entry_tx, entry_rx) = mpsc::channel(QUEUE_SIZE);
+        for message in messages {
+            entry_tx.send(entry(message)).await.unwrap();
+        }
+        drop(entry_tx);
+        run_writer(path, max_bytes, keep_files, entry_rx).await.unwrap();
+    }
+
+    fn lines(path: &Path) -> usize {
+        std::fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    #[tokio::test]
+    async fn rotates_once_the_next_line_would_exceed_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("probe.log");
+        let max_bytes = line_len("aaaa") * 2;
+
+        write_all(&path, max_bytes, 3, &["aaaa", "bbbb"]).await;
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), max_bytes);
+        assert!(!numbered_path(&path, 1).exists());
+
+        write_all(&path, max_bytes, 3, &["cccc"]).await;
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&numbered_path(&path, 1)), 2);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("cccc"));
+    }
+
+    #[tokio::test]
+    async fn keeps_only_the_newest_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("probe.log");
+
+        write_all(&path, line_len("aaaa"), 2, &["aaaa", "bbbb", "cccc", "dddd"]).await;
+
+        assert!(std::fs::read_to_string(&path).unwrap().contains("dddd"));
+        assert!(std::fs::read_to_string(numbered_path(&path, 1)).unwrap().contains("cccc"));
+        assert!(std::fs::read_to_string(numbered_path(&path, 2)).unwrap().contains("bbbb"));
+        assert!(!numbered_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn numbered_path_appends_the_index() {
+        assert_eq!(numbered_path(Path::new("/var/log/probe.log"), 2), PathBuf::from("/var/log/probe.log.2"));
+    }
+}
@@ -0,0 +1,570 @@
+use crate::log_entry::{LogEntry, LogLevel};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Bounded FIFO of log entries waiting to be uploaded.
+///
+/// When full, pushing evicts the oldest entry and counts it in `total_dropped`.
+#[derive(Debug, Clone)]
+pub struct LogBuffer {
+    entries: VecDeque<LogEntry>,
+    max_size: usize,
+    total_pushed: u64,
+    total_dropped: u64,
+}
+
+/// Snapshot of buffer fill, throughput and level mix
+#[derive(Debug, Clone, Serialize)]
+pub struct BufferStats {
+    pub current_len: usize,
+    pub max_size: usize,
+    pub fill_percent: f64,
+    pub total_pushed: u64,
+    pub total_dropped: u64,
+    pub oldest_entry_age_seconds: Option<i64>,
+    pub newest_entry_age_seconds: Option<i64>,
+    pub trace_count: usize,
+    pub debug_count: usize,
+    pub info_count: usize,
+    pub warn_count: usize,
+    pub error_count: usize,
+}
+
+impl LogBuffer {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            max_size,
+            total_pushed: 0,
+            total_dropped: 0,
+        }
+    }
+
+    /// Append an entry, dropping the oldest one if the buffer is full
+    pub fn push(&mut self, entry: LogEntry) {
+        while self.max_size > 0 && self.entries.len() >= self.max_size {
+            self.entries.pop_front();
+            self.total_dropped += 1;
+        }
+        self.entries.push_back(entry);
+        self.total_pushed += 1;
+    }
+
+    /// Insert `entries` ahead of the buffered ones, keeping their order. Only
+    /// the newest that fit in the free space are kept; returns how many.
+    pub fn prepend(&mut self, entries: Vec<LogEntry>) -> usize {
+        let free = match self.max_size {
+            0 => usize::MAX,
+            max_size => max_size.saturating_sub(self.entries.len()),
+        };
+        let count = entries.len().min(free);
+        for entry in entries.into_iter().rev().take(count) {
+            self.entries.push_front(entry);
+        }
+        self.total_pushed += count as u64;
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    #[allow(dead_code)]
+    pub fn total_pushed(&self) -> u64 {
+        self.total_pushed
+    }
+
+    /// Number of entries evicted because the buffer was full
+    pub fn total_dropped(&self) -> u64 {
+        self.total_dropped
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        let now = Utc::now();
+        let age = |entry: Option<&LogEntry>| {
+            entry
+                .and_then(|e| DateTime::parse_from_rfc3339(&e.timestamp).ok())
+                .map(|t| (now - t.with_timezone(&Utc)).num_seconds())
+        };
+
+        let mut stats = BufferStats {
+            current_len: self.entries.len(),
+            max_size: self.max_size,
+            fill_percent: if self.max_size > 0 {
+                self.entries.len() as f64 * 100.0 / self.max_size as f64
+            } else {
+                0.0
+            },
+            total_pushed: self.total_pushed,
+            total_dropped: self.total_dropped,
+            oldest_entry_age_seconds: age(self.entries.front()),
+            newest_entry_age_seconds: age(self.entries.back()),
+            trace_count: 0,
+            debug_count: 0,
+            info_count: 0,
+            warn_count: 0,
+            error_count: 0,
+        };
+
+        for entry in &self.entries {
+            match entry.level() {
+                Some(LogLevel::Trace) => stats.trace_count += 1,
+                Some(LogLevel::Debug) => stats.debug_count += 1,
+                Some(LogLevel::Info) => stats.info_count += 1,
+                Some(LogLevel::Warn) => stats.warn_count += 1,
+                Some(LogLevel::Error) => stats.error_count += 1,
+                None => {}
+            }
+        }
+
+        stats
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LogEntry> {
+        self.entries.iter()
+    }
+
+    /// Up to `n` entries chosen uniformly at random, whatever their position
+    #[allow(dead_code)]
+    pub fn sample(&self, n: usize) -> Vec<&LogEntry> {
+        reservoir_sample(self.entries.iter(), n)
+    }
+
+    /// Up to `n_per_level` randomly chosen entries of each level, errors first,
+    /// then warnings, info, debug and trace. Entries without a level are left out.
+    pub fn sample_by_level(&self, n_per_level: usize) -> Vec<&LogEntry> {
+        [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace]
+            .into_iter()
+            .flat_map(|level| reservoir_sample(self.entries.iter().filter(|entry| entry.level() == Some(level)), n_per_level))
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut LogEntry> {
+        self.entries.iter_mut()
+    }
+
+    /// Remove and return all entries
+    pub fn drain(&mut self) -> impl Iterator<Item = LogEntry> + '_ {
+        self.entries.drain(..)
+    }
+
+    /// Put back `entries` drained for an upload that did not deliver them,
+    /// ahead of the entries collected since. If they no longer all fit, the
+    /// oldest are dropped and count towards `total_dropped`; returns how many.
+    pub fn restore(&mut self, entries: Vec<LogEntry>) -> usize {
+        let free = match self.max_size {
+            0 => usize::MAX,
+            max_size => max_size.saturating_sub(self.entries.len()),
+        };
+        let dropped = entries.len().saturating_sub(free);
+        for entry in entries.into_iter().skip(dropped).rev() {
+            self.entries.push_front(entry);
+        }
+        self.total_dropped += dropped as u64;
+        dropped
+    }
+
+    /// Discard all entries, returning how many there were. They count
+    /// towards `total_dropped`.
+    pub fn clear(&mut self) -> usize {
+        let removed = self.entries.len();
+        self.entries.clear();
+        self.total_dropped += removed as u64;
+        removed
+    }
+
+    /// Remove entries below `min_level`, and those without a level, returning
+    /// how many were removed. They count towards `total_dropped`.
+    pub fn drain_below_level(&mut self, min_level: LogLevel) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.level().is_some_and(|level| level >= min_level));
+        let removed = before - self.entries.len();
+        self.total_dropped += removed as u64;
+        removed
+    }
+
+    /// Change the capacity, evicting the oldest entries if the buffer is now
+    /// over it. Returns how many were evicted; they count towards `total_dropped`.
+    pub fn set_max_size(&mut self, max_size: usize) -> usize {
+        self.max_size = max_size;
+        let excess = if max_size > 0 { self.entries.len().saturating_sub(max_size) } else { 0 };
+        self.entries.drain(..excess);
+        self.total_dropped += excess as u64;
+        excess
+    }
+
+    /// Remove entries timestamped more than `max_age` ago, returning how many
+    /// were removed. They count towards `total_dropped`. Entries are buffered
+    /// oldest first, so this stops at the first fresh entry or one whose
+    /// timestamp cannot be parsed, and costs one parse when nothing is stale.
+    pub fn retain_recent(&mut self, max_age: Duration) -> usize {
+        let Ok(max_age) = chrono::Duration::from_std(max_age) else {
+            return 0;
+        };
+        let cutoff = Utc::now() - max_age;
+
+        let mut removed = 0;
+        while let Some(front) = self.entries.front() {
+            match DateTime::parse_from_rfc3339(&front.timestamp) {
+                Ok(timestamp) if timestamp < cutoff => {
+                    self.entries.pop_front();
+                    removed += 1;
+                }
+                _ => break,
+            }
+        }
+        self.total_dropped += removed as u64;
+        removed
+    }
+
+    /// Count a rejected upload against the entries at `indices`, then remove
+    /// and return the entries rejected more than `max_retries` times. They
+    /// count towards `total_dropped`.
+    pub fn record_rejected(&mut self, indices: &[usize], max_retries: u8) -> Vec<LogEntry> {
+        for &index in indices {
+            if let Some(entry) = self.entries.get_mut(index) {
+                entry.retry_count = entry.retry_count.saturating_add(1);
+            }
+        }
+
+        let mut discarded = Vec::new();
+        self.entries.retain(|entry| {
+            if entry.retry_count > max_retries {
+                discarded.push(entry.clone());
+                false
+            } else {
+                true
+            }
+        });
+        self.total_dropped += discarded.len() as u64;
+        discarded
+    }
+
+    /// Stable-sort entries by timestamp, oldest first. Entries whose timestamp
+    /// cannot be parsed keep their relative order after all others; returns
+    /// how many there were.
+    pub fn sort_by_timestamp(&mut self) -> usize {
+        let mut unparseable = 0;
+        self.entries.make_contiguous().sort_by_cached_key(|entry| {
+            let timestamp = DateTime::parse_from_rfc3339(&entry.timestamp).ok();
+            unparseable += usize::from(timestamp.is_none());
+            (timestamp.is_none(), timestamp)
+        });
+        unparseable
+    }
+
+    /// Remove entries identical (same timestamp and message) to the one before
+    /// them, returning how many were removed. Duplicates are not counted in
+    /// `total_dropped` since nothing was lost.
+    pub fn dedup_by_content(&mut self) -> usize {
+        let mut entries: Vec<LogEntry> = self.entries.drain(..).collect();
+        let before = entries.len();
+        entries.dedup_by(|a, b| a.timestamp == b.timestamp && a.message == b.message);
+        let removed = before - entries.len();
+        self.entries = entries.into();
+        removed
+    }
+
+    /// Remove up to `n` of the oldest entries, returning how many were removed
+    pub fn remove_front(&mut self, n: usize) -> usize {
+        let n = n.min(self.entries.len());
+        self.entries.drain(..n);
+        n
+    }
+}
+
+/// Reservoir sampling (Algorithm R): keep the first `n` entries, then replace
+/// a random one with the `i`th entry with probability `n / (i + 1)`, so each
+/// entry ends up in the sample with probability `n / len`
+fn reservoir_sample<'a>(entries: impl Iterator<Item = &'a LogEntry>, n: usize) -> Vec<&'a LogEntry> {
+    let mut reservoir = Vec::new();
+    if n == 0 {
+        return reservoir;
+    }
+    for (i, entry) in entries.enumerate() {
+        if i < n {
+            reservoir.push(entry);
+        } else {
+            let j = rand::random_range(0..=i);
+            if j < n {
+                reservoir[j] = entry;
+            }
+        }
+    }
+    reservoir
+}
+
+/// Consumes the buffer. Entries handed out this way are delivered, not dropped,
+/// so `total_dropped` is unaffected.
+impl IntoIterator for LogBuffer {
+    type Item = LogEntry;
+    type IntoIter = std::collections::vec_deque::IntoIter<LogEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a LogBuffer {
+    type Item = &'a LogEntry;
+    type IntoIter = std::collections::vec_deque::Iter<'a, LogEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(message: &str) -> LogEntry {
+        LogEntry::new("2024-05-01T12:00:00Z".to_string(), message.to_string())
+    }
+
+    fn buffer_with(messages: &[&str]) -> LogBuffer {
+        let mut buffer = LogBuffer::new(100);
+        for message in messages {
+            buffer.push(entry(message));
+        }
+        buffer
+    }
+
+    fn messages(buffer: &LogBuffer) -> Vec<&str> {
+        buffer.iter().map(|entry| entry.message.as_str()).collect()
+    }
+
+    #[test]
+    fn stats_report_fill_drops_ages_and_levels() {
+        let mut buffer = LogBuffer::new(20);
+        let levels = ["[ERROR]", "[WARN]", "[INFO]", "[INFO]", "[DEBUG]", "[TRACE]", "no level"];
+        let push = |buffer: &mut LogBuffer, i: i64| {
+            let timestamp = (Utc::now() - chrono::Duration::seconds(180 - 10 * i)).to_rfc3339();
+            buffer.push(LogEntry::new(timestamp, format!("{} {}", levels[i as usize % levels.len()], i)));
+        };
+        // 18 entries, one every 10s, the oldest 180s and the newest 10s old
+        for i in 0..18 {
+            push(&mut buffer, i);
+        }
+
+        let stats = buffer.stats();
+        assert_eq!((stats.current_len, stats.max_size), (18, 20));
+        assert!((stats.fill_percent - 90.0).abs() < 1e-9);
+        assert_eq!((stats.total_pushed, stats.total_dropped), (18, 0));
+        let (oldest, newest) = (stats.oldest_entry_age_seconds.unwrap(), stats.newest_entry_age_seconds.unwrap());
+        assert!((180..=181).contains(&oldest), "oldest {}", oldest);
+        assert!((10..=11).contains(&newest), "newest {}", newest);
+        assert_eq!(
+            (stats.error_count, stats.warn_count, stats.info_count, stats.debug_count, stats.trace_count),
+            (3, 3, 6, 2, 2)
+        );
+
+        for i in 18..21 {
+            push(&mut buffer, i);
+        }
+        let stats = buffer.stats();
+        assert_eq!((stats.current_len, stats.total_pushed, stats.total_dropped), (20, 21, 1));
+        assert!((stats.fill_percent - 100.0).abs() < 1e-9);
+        assert!(LogBuffer::new(0).stats().oldest_entry_age_seconds.is_none());
+    }
+
+    #[test]
+    fn retain_recent_evicts_stale_entries_from_the_front() {
+        let at = |age_secs: i64, message: &str| {
+            let timestamp = (Utc::now() - chrono::Duration::seconds(age_secs)).to_rfc3339();
+            LogEntry::new(timestamp, message.to_string())
+        };
+        let mut buffer = LogBuffer::new(100);
+        for entry in [at(7200, "old"), at(3700, "stale"), at(60, "fresh"), at(10, "newest")] {
+            buffer.push(entry);
+        }
+
+        assert_eq!(buffer.retain_recent(Duration::from_secs(3600)), 2);
+        assert_eq!(messages(&buffer), vec!["fresh", "newest"]);
+        assert_eq!(buffer.total_dropped(), 2);
+        assert_eq!(buffer.retain_recent(Duration::from_secs(3600)), 0);
+
+        // An unparseable timestamp at the front is kept and stops the eviction
+        buffer.prepend(vec![LogEntry::new("not a time".to_string(), "odd".to_string())]);
+        assert_eq!(buffer.retain_recent(Duration::from_secs(30)), 0);
+        assert_eq!(messages(&buffer), vec!["odd", "fresh", "newest"]);
+    }
+
+    #[test]
+    fn persistently_rejected_entries_are_eventually_discarded() {
+        let mut buffer = buffer_with(&["a", "bad", "c"]);
+
+        // Every upload is answered with a 400 for the same entry
+        for _ in 0..3 {
+            assert!(buffer.record_rejected(&[1], 3).is_empty());
+        }
+        let discarded = buffer.record_rejected(&[1], 3);
+
+        assert_eq!(discarded.len(), 1);
+        assert_eq!(discarded[0].message, "bad");
+        assert_eq!(discarded[0].retry_count, 4);
+        assert_eq!(messages(&buffer), vec!["a", "c"]);
+        assert_eq!(buffer.total_dropped(), 1);
+    }
+
+    #[test]
+    fn record_rejected_ignores_indices_past_the_end() {
+        let mut buffer = buffer_with(&["a"]);
+
+        assert!(buffer.record_rejected(&[5], 0).is_empty());
+        assert_eq!(buffer.iter().next().unwrap().retry_count, 0);
+    }
+
+    const TAGS: [&str; 6] = ["[ERROR]", "[WARN]", "[INFO]", "[DEBUG]", "[TRACE]", ""];
+
+    /// A buffer with one uniquely numbered entry per tag index into `TAGS`
+    fn tagged_buffer(tags: &[usize]) -> LogBuffer {
+        let mut buffer = LogBuffer::new(0);
+        for (i, &tag) in tags.iter().enumerate() {
+            buffer.push(entry(&format!("{} {}", TAGS[tag], i)));
+        }
+        buffer
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn sample_picks_distinct_buffered_entries(tags in proptest::collection::vec(0..TAGS.len(), 0..60), n in 0..80usize) {
+            let buffer = tagged_buffer(&tags);
+            let sample = buffer.sample(n);
+
+            proptest::prop_assert_eq!(sample.len(), n.min(buffer.len()));
+            let mut picked: Vec<&str> = sample.iter().map(|entry| entry.message.as_str()).collect();
+            picked.sort_unstable();
+            picked.dedup();
+            proptest::prop_assert_eq!(picked.len(), sample.len());
+            proptest::prop_assert!(picked.iter().all(|message| messages(&buffer).contains(message)));
+        }
+
+        #[test]
+        fn sample_by_level_caps_each_level_in_severity_order(
+            tags in proptest::collection::vec(0..TAGS.len(), 0..60),
+            n_per_level in 0..10usize,
+        ) {
+            let buffer = tagged_buffer(&tags);
+            let sample = buffer.sample_by_level(n_per_level);
+            let levels: Vec<LogLevel> = sample.iter().map(|entry| entry.level().unwrap()).collect();
+
+            // Errors first, then warnings, info, debug and trace; nothing without a level
+            proptest::prop_assert!(levels.windows(2).all(|pair| pair[0] >= pair[1]));
+            for level in [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace] {
+                let buffered = buffer.iter().filter(|entry| entry.level() == Some(level)).count();
+                let sampled = levels.iter().filter(|&&sampled| sampled == level).count();
+                proptest::prop_assert_eq!(sampled, n_per_level.min(buffered));
+            }
+        }
+    }
+
+    #[test]
+    fn sample_is_uniform_over_positions() {
+        let buffer = buffer_with(&["a", "b", "c", "d"]);
+        let mut counts = std::collections::HashMap::new();
+        for _ in 0..4000 {
+            *counts.entry(buffer.sample(1)[0].message.clone()).or_insert(0) += 1;
+        }
+        // Each entry is expected 1000 times; 800..1200 is over seven standard deviations wide
+        for message in ["a", "b", "c", "d"] {
+            let count = counts.get(message).copied().unwrap_or(0);
+            assert!((800..1200).contains(&count), "{} sampled {} times", message, count);
+        }
+    }
+
+    fn at(timestamp: &str, message: &str) -> LogEntry {
+        LogEntry::new(timestamp.to_string(), message.to_string())
+    }
+
+    fn contents(buffer: &LogBuffer) -> Vec<(&str, &str)> {
+        buffer.iter().map(|entry| (entry.timestamp.as_str(), entry.message.as_str())).collect()
+    }
+
+    #[test]
+    fn sort_by_timestamp_is_stable_and_puts_invalid_timestamps_last() {
+        let mut buffer = LogBuffer::new(0);
+        for entry in [
+            at("2024-05-01T12:00:02Z", "c"),
+            at("not a time", "x"),
+            at("2024-05-01T12:00:01Z", "a"),
+            at("2024-05-01T14:00:01+02:00", "b"),
+            at("", "y"),
+        ] {
+            buffer.push(entry);
+        }
+
+        assert_eq!(buffer.sort_by_timestamp(), 2);
+        // 14:00:01+02:00 is the same instant as 12:00:01Z, so "a" stays ahead of "b"
+        assert_eq!(
+            contents(&buffer),
+            vec![
+                ("2024-05-01T12:00:01Z", "a"),
+                ("2024-05-01T14:00:01+02:00", "b"),
+                ("2024-05-01T12:00:02Z", "c"),
+                ("not a time", "x"),
+                ("", "y"),
+            ]
+        );
+    }
+
+    #[test]
+    fn dedup_by_content_removes_consecutive_copies_only() {
+        let mut buffer = LogBuffer::new(0);
+        for entry in [
+            at("2024-05-01T12:00:00Z", "a"),
+            at("2024-05-01T12:00:00Z", "a"),
+            at("2024-05-01T12:00:01Z", "a"),
+            at("2024-05-01T12:00:02Z", "b"),
+            at("2024-05-01T12:00:01Z", "a"),
+        ] {
+            buffer.push(entry);
+        }
+
+        assert_eq!(buffer.dedup_by_content(), 1);
+        assert_eq!(buffer.len(), 4);
+        assert_eq!(buffer.total_dropped(), 0);
+        // Sorting first brings the copies together
+        buffer.sort_by_timestamp();
+        assert_eq!(buffer.dedup_by_content(), 1);
+        assert_eq!(
+            contents(&buffer),
+            vec![("2024-05-01T12:00:00Z", "a"), ("2024-05-01T12:00:01Z", "a"), ("2024-05-01T12:00:02Z", "b")]
+        );
+    }
+
+    #[test]
+    fn restore_puts_entries_back_first_and_drops_the_oldest_that_do_not_fit() {
+        let mut buffer = LogBuffer::new(4);
+        buffer.push(entry("a"));
+        buffer.push(entry("b"));
+        let taken: Vec<LogEntry> = buffer.drain().collect();
+        buffer.push(entry("c"));
+
+        assert_eq!(buffer.restore(taken), 0);
+        let messages: Vec<&str> = buffer.iter().map(|entry| entry.message.as_str()).collect();
+        assert_eq!(messages, vec!["a", "b", "c"]);
+
+        let taken: Vec<LogEntry> = buffer.drain().collect();
+        for message in ["d", "e"] {
+            buffer.push(entry(message));
+        }
+        assert_eq!(buffer.restore(taken), 1);
+        let messages: Vec<&str> = buffer.iter().map(|entry| entry.message.as_str()).collect();
+        assert_eq!(messages, vec!["b", "c", "d", "e"]);
+        assert_eq!(buffer.total_dropped(), 1);
+        assert_eq!(buffer.total_pushed(), 5);
+    }
+}
@@ -1,16 +1,187 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Severity tag of a node log line, e.g. `[WARN]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_uppercase().as_str() {
+            "TRACE" => Some(LogLevel::Trace),
+            "DEBUG" => Some(LogLevel::Debug),
+            "INFO" => Some(LogLevel::Info),
+            "WARN" | "WARNING" => Some(LogLevel::Warn),
+            "ERROR" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+}
 
 /// A single log entry captured from the RP2040.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogEntry {
-    /// ISO 8601 UTC timestamp
+    /// ISO 8601 timestamp, in UTC unless a timezone is set
     pub timestamp: String,
+    /// UTC offset of `timestamp`, e.g. `-05:00`, when a timezone is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone_offset: Option<String>,
     /// Original log line including [LEVEL]
     pub message: String,
+    /// `key=value` fields found in the message by `parse_structured`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Uploads of this entry the hub rejected as malformed
+    #[serde(skip)]
+    pub retry_count: u8,
 }
 
 impl LogEntry {
     pub fn new(timestamp: String, message: String) -> Self {
-        Self { timestamp, message }
+        Self {
+            timestamp,
+            timezone_offset: None,
+            message,
+            metadata: None,
+            retry_count: 0,
+        }
+    }
+
+    /// Entry for `raw` with the `key=value` fields after its `[LEVEL] module:`
+    /// prefix, e.g. `temp=23.4 ok=true name="probe 1"`, as metadata. Values
+    /// must be integers, floats, booleans or double-quoted strings; anything
+    /// else is skipped.
+    pub fn parse_structured(timestamp: String, raw: &str) -> (Self, HashMap<String, serde_json::Value>) {
+        let body = match raw.find(']') {
+            Some(end) if raw.starts_with('[') => &raw[end + 1..],
+            _ => raw,
+        };
+        let fields = parse_key_values(body);
+
+        let mut entry = Self::new(timestamp, raw.to_string());
+        if !fields.is_empty() {
+            let object: serde_json::Map<_, _> = fields.iter().map(|(key, value)| (key.clone(), value.clone())).collect();
+            entry.metadata = Some(object.into());
+        }
+        (entry, fields)
+    }
+
+    /// Level from the first `[LEVEL]` tag in the message, if any
+    pub fn level(&self) -> Option<LogLevel> {
+        let start = self.message.find('[')?;
+        let end = self.message[start..].find(']')? + start;
+        LogLevel::parse(&self.message[start + 1..end])
+    }
+}
+
+/// `key=value` pairs in `text` whose key is a whole word of letters, digits
+/// and underscores
+fn parse_key_values(text: &str) -> HashMap<String, serde_json::Value> {
+    let mut fields = HashMap::new();
+    let mut rest = text;
+    while let Some(eq) = rest.find('=') {
+        let key_start = rest[..eq]
+            .char_indices()
+            .rfind(|&(_, c)| !(c.is_ascii_alphanumeric() || c == '_'))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let at_word_start = key_start == 0 || rest[..key_start].ends_with(char::is_whitespace);
+        let key = &rest[key_start..eq];
+
+        let after = &rest[eq + 1..];
+        let (value, consumed) = parse_value(after);
+        if let Some(value) = value.filter(|_| at_word_start && !key.is_empty()) {
+            fields.insert(key.to_string(), value);
+        }
+        rest = &after[consumed..];
+    }
+    fields
+}
+
+/// Value at the start of `text` and the bytes it takes up. A value that
+/// cannot be read is `None`.
+fn parse_value(text: &str) -> (Option<serde_json::Value>, usize) {
+    if let Some(quoted) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = quoted.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return (Some(value.into()), i + 2),
+                '\\' => match chars.next() {
+                    Some((_, escaped)) => value.push(escaped),
+                    None => break,
+                },
+                c => value.push(c),
+            }
+        }
+        // Unterminated string
+        return (None, 1);
+    }
+
+    let token = text.split(char::is_whitespace).next().unwrap_or_default();
+    let value = match token {
+        "true" => Some(true.into()),
+        "false" => Some(false.into()),
+        _ => match token.parse::<i64>() {
+            Ok(integer) => Some(integer.into()),
+            Err(_) => token.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(serde_json::Value::Number),
+        },
+    };
+    (value, token.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fields(raw: &str) -> HashMap<String, serde_json::Value> {
+        LogEntry::parse_structured("2024-05-01T12:00:00Z".to_string(), raw).1
+    }
+
+    #[test]
+    fn parses_every_value_type() {
+        let parsed = fields(r#"[INFO] sens: temp=23.4 count=-7 ok=true off=false name="probe \"1\"" big=1e3"#);
+        assert_eq!(parsed["temp"], json!(23.4));
+        assert_eq!(parsed["count"], json!(-7));
+        assert_eq!(parsed["ok"], json!(true));
+        assert_eq!(parsed["off"], json!(false));
+        assert_eq!(parsed["name"], json!("probe \"1\""));
+        assert_eq!(parsed["big"], json!(1000.0));
+        assert_eq!(parsed.len(), 6);
+    }
+
+    #[test]
+    fn stores_fields_as_metadata() {
+        let (entry, _) = LogEntry::parse_structured("t".to_string(), "[WARN] sens: temp=5");
+        assert_eq!(entry.metadata, Some(json!({ "temp": 5 })));
+        assert_eq!(entry.message, "[WARN] sens: temp=5");
+        assert!(LogEntry::parse_structured("t".to_string(), "[INFO] no fields").0.metadata.is_none());
+    }
+
+    #[test]
+    fn handles_non_ascii_text() {
+        // A multi-byte character right before `=` is not part of a key
+        assert!(fields("[INFO] sens: temp°=5").is_empty());
+        assert_eq!(fields("[INFO] sens: °C temp=5 ünit=\"°C\" label=\"héllo\""), {
+            let mut expected = HashMap::new();
+            expected.insert("temp".to_string(), json!(5));
+            expected.insert("label".to_string(), json!("héllo"));
+            expected
+        });
+    }
+
+    #[test]
+    fn skips_malformed_pairs() {
+        let parsed = fields(r#"[INFO] a= b=abc c==1 =5 d=1.2.3 e="open f=2"#);
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed["f"], json!(2));
+        // Keys must start a word
+        assert!(fields("[INFO] sens:x=1 y-z=2").is_empty());
     }
 }
@@ -0,0 +1,181 @@
+use crate::log_buffer::LogBuffer;
+use crate::log_entry::{LogEntry, LogLevel};
+use anyhow::Result;
+use log::{info, warn};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
+
+/// Buffered entries sent to a client as soon as it connects
+const TAIL_BACKLOG: usize = 50;
+
+/// Local TCP feed of collected log entries, one JSON object per line, for any
+/// number of clients. Dropping it closes the listener and every connection.
+pub struct LogTail {
+    port: u16,
+    min_level: Option<LogLevel>,
+    task: JoinHandle<()>,
+}
+
+impl LogTail {
+    /// Listen on `127.0.0.1:<port>` (0 picks a free port). Each client first
+    /// gets the last `TAIL_BACKLOG` buffered entries at or above `min_level`,
+    /// then every new one from `feed`. Clients falling further behind than the
+    /// feed holds are disconnected.
+    pub async fn open(
+        port: u16,
+        buffer: Arc<RwLock<LogBuffer>>,
+        feed: broadcast::Sender<LogEntry>,
+        min_level: Option<LogLevel>,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        let port = listener.local_addr()?.port();
+        info!("Log tail listening on 127.0.0.1:{}", port);
+
+        let task = tokio::spawn(serve(listener, buffer, feed, min_level));
+        Ok(Self { port, min_level, task })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn min_level(&self) -> Option<LogLevel> {
+        self.min_level
+    }
+}
+
+impl Drop for LogTail {
+    fn drop(&mut self) {
+        self.task.abort();
+        info!("Log tail {} closed", self.port);
+    }
+}
+
+async fn serve(listener: TcpListener, buffer: Arc<RwLock<LogBuffer>>, feed: broadcast::Sender<LogEntry>, min_level: Option<LogLevel>) {
+    // Dropped with this task, which aborts every client
+    let mut clients = JoinSet::new();
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Log tail accept failed: {}", e);
+                continue;
+            }
+        };
+        while clients.try_join_next().is_some() {}
+
+        info!("Log tail client connected from {}", peer);
+        // Subscribe before taking the backlog so no entry falls between the two
+        let entries = feed.subscribe();
+        let backlog = recent_entries(&*buffer.read().await, min_level);
+        clients.spawn(async move {
+            follow(stream, backlog, entries, min_level).await;
+            info!("Log tail client {} disconnected", peer);
+        });
+    }
+}
+
+/// The last `TAIL_BACKLOG` buffered entries at or above `min_level`, oldest first
+fn recent_entries(buffer: &LogBuffer, min_level: Option<LogLevel>) -> Vec<LogEntry> {
+    let mut recent: Vec<LogEntry> =
+        buffer.into_iter().rev().filter(|entry| at_least(entry, min_level)).take(TAIL_BACKLOG).cloned().collect();
+    recent.reverse();
+    recent
+}
+
+/// Whether `entry` passes the `min_level` filter; entries without a level only
+/// pass when there is no filter
+fn at_least(entry: &LogEntry, min_level: Option<LogLevel>) -> bool {
+    min_level.is_none_or(|min_level| entry.level().is_some_and(|level| level >= min_level))
+}
+
+/// Send `backlog`, then every matching entry from `entries`, until the client
+/// goes away or lags behind
+async fn follow(mut stream: TcpStream, backlog: Vec<LogEntry>, mut entries: broadcast::Receiver<LogEntry>, min_level: Option<LogLevel>) {
+    for entry in &backlog {
+        if send(&mut stream, entry).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        let entry = match entries.recv().await {
+            Ok(entry) => entry,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Log tail client fell {} entries behind, disconnecting", missed);
+                return;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        if at_least(&entry, min_level) && send(&mut stream, &entry).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn send(stream: &mut TcpStream, entry: &LogEntry) -> Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    fn entry(message: &str) -> LogEntry {
+        LogEntry::new("2024-05-01T12:00:00Z".to_string(), message.to_string())
+    }
+
+    async fn connect(tail: &LogTail) -> tokio::io::Lines<BufReader<TcpStream>> {
+        BufReader::new(TcpStream::connect(("127.0.0.1", tail.port())).await.unwrap()).lines()
+    }
+
+    fn message(line: &str) -> String {
+        serde_json::from_str::<LogEntry>(line).unwrap().message
+    }
+
+    #[tokio::test]
+    async fn sends_recent_then_live_entries_at_the_level() {
+        let mut buffer = LogBuffer::new(0);
+        for i in 0..60 {
+            buffer.push(entry(&format!("[WARN] w{}", i)));
+            buffer.push(entry(&format!("[INFO] i{}", i)));
+        }
+        let (feed, _) = broadcast::channel(16);
+        let tail = LogTail::open(0, Arc::new(RwLock::new(buffer)), feed.clone(), Some(LogLevel::Warn)).await.unwrap();
+        let mut lines = connect(&tail).await;
+
+        let mut backlog = Vec::new();
+        for _ in 0..TAIL_BACKLOG {
+            backlog.push(message(&lines.next_line().await.unwrap().unwrap()));
+        }
+        assert_eq!(backlog.first().unwrap(), "[WARN] w10");
+        assert_eq!(backlog.last().unwrap(), "[WARN] w59");
+
+        feed.send(entry("[DEBUG] skipped")).unwrap();
+        feed.send(entry("[ERROR] live")).unwrap();
+        assert_eq!(message(&lines.next_line().await.unwrap().unwrap()), "[ERROR] live");
+    }
+
+    #[tokio::test]
+    async fn disconnects_clients_that_fall_behind() {
+        let (feed, _) = broadcast::channel(4);
+        let tail = LogTail::open(0, Arc::new(RwLock::new(LogBuffer::new(0))), feed.clone(), None).await.unwrap();
+        let mut lines = connect(&tail).await;
+        while feed.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // Sent without yielding, so the client cannot keep up
+        for i in 0..10 {
+            feed.send(entry(&format!("[INFO] {}", i))).unwrap();
+        }
+        assert!(lines.next_line().await.unwrap().is_none());
+    }
+}
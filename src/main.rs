@@ -1,23 +1,61 @@
+mod admin_server;
+mod circuit_breaker;
 mod config;
+mod config_watcher;
+mod connectivity;
+mod debug_port;
+mod file_stream;
+mod local_archive;
+mod log_buffer;
 mod log_entry;
+mod log_tail;
 mod usb_manager;
 mod usb_collector;
+mod usb_traffic_log;
 mod telemetry_sync;
+mod telemetry_service;
+mod mqtt_transport;
+mod rate_limit;
+mod runtime_metrics;
+mod s3_export;
+mod task_supervisor;
 mod update_manager;
+mod update_state;
 mod command_executor;
+mod compress;
 mod error;
+mod error_reporter;
+mod uf2;
 
 use anyhow::Result;
 use clap::Parser;
-use log::{error, info};
-use std::path::PathBuf;
+use log::{error, info, warn};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
-use tokio::time::Duration;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tracing_log::AsTrace;
+use tracing_subscriber::filter;
+use tracing_subscriber::prelude::*;
 
+use circuit_breaker::CircuitBreaker;
+use command_executor::{CommandContext, CommandTimings, Registration, ScheduledCommands, UploadSchedule};
 use config::Config;
-use log_entry::LogEntry;
-use usb_manager::{UsbManager, UsbHandle};
+use error::ProbeError;
+use error_reporter::{ErrorReporter, PendingErrors};
+use log_buffer::LogBuffer;
+use mqtt_transport::MqttTransport;
+use runtime_metrics::RuntimeMetrics;
+use task_supervisor::{RestartTracker, Supervisor};
+use telemetry_sync::UploadStats;
+use update_manager::IntegrityCheck;
+use update_state::UpdateTracker;
+use usb_collector::CollectorSettings;
+use usb_manager::{UsbManager, UsbHandle, UsbProtocol, UsbStats};
+
+/// Entries a live log subscriber may fall behind by before it misses some
+const LOG_FEED_CAPACITY: usize = 1000;
 
 #[derive(Parser, Debug)]
 #[command(name = "moonblokz-probe")]
@@ -26,89 +64,323 @@ struct Args {
     /// Path to the configuration file
     #[arg(short, long, default_value = "config.toml")]
     config: PathBuf,
+
+    /// Path to an override file applied on top of the configuration file
+    /// (defaults to `<config_stem>.override.toml` next to it, if present)
+    #[arg(long)]
+    config_override: Option<PathBuf>,
+
+    /// Skip the startup self-test (e.g. in CI, where there is no node or hub)
+    #[arg(long)]
+    skip_self_test: bool,
+
+    /// Print the config as environment variables and exit; FORMAT is
+    /// `shell` (`export` lines, the default) or `docker` (an `--env-file`)
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "shell")]
+    output_env: Option<String>,
+
+    /// Register the node with the hub before starting, saving the API key it
+    /// issues to the config file
+    #[arg(long)]
+    register: bool,
+
+    /// List the serial ports that can be used as `usb_port` and exit; works
+    /// without a config file
+    #[arg(long)]
+    list_serial_ports: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    if args.list_serial_ports {
+        match tokio_serial::available_ports() {
+            Ok(ports) => print!("{}", usb_manager::format_serial_ports(&ports)),
+            Err(e) => {
+                eprintln!("Failed to list serial ports: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
     
     // Load configuration
-    let config = Config::load(&args.config)?;
-    
-    // Initialize logger with level from config
-    let log_level = match config.log_level.to_lowercase().as_str() {
-        "error" => log::LevelFilter::Error,
-        "warn" => log::LevelFilter::Warn,
-        "info" => log::LevelFilter::Info,
-        "debug" => log::LevelFilter::Debug,
-        "trace" => log::LevelFilter::Trace,
-        _ => log::LevelFilter::Info,
-    };
+    let (mut config, config_sources) = Config::load(&args.config, args.config_override.as_deref())?;
+    if let Some(format) = &args.output_env {
+        print!("{}", config.to_env(format)?);
+        return Ok(());
+    }
     
-    simple_logger::SimpleLogger::new()
-        .with_level(log_level)
-        .with_utc_timestamps()
-        .init()
-        .unwrap();
+    // Initialize logging with level from config. `log` records are passed on
+    // to tracing so they carry the fields of the spans they happen in. The
+    // level is read from `log::max_level` on every event, so the config
+    // watcher can change it later for tracing events too.
+    tracing_log::LogTracer::init()?;
+    let level = filter::filter_fn(|metadata| *metadata.level() <= log::max_level().as_trace());
+    let registry = tracing_subscriber::registry();
+    if config.log_format == "json" {
+        tracing::subscriber::set_global_default(registry.with(json_log_layer(std::io::stdout).with_filter(level)))?;
+    } else {
+        tracing::subscriber::set_global_default(registry.with(tracing_subscriber::fmt::layer().with_filter(level)))?;
+    }
+    log::set_max_level(config::parse_log_level(&config.log_level));
     
-    info!("Loaded configuration from {:?}", args.config);
+    // Refuse to run a binary that was corrupted in storage
+    if let Some(code) = integrity_exit_code(update_manager::check_probe_binary_integrity().await) {
+        std::process::exit(code);
+    }
+
+    info!("Loaded configuration from {:?}", config_sources.paths);
+    if !config_sources.overridden_fields.is_empty() {
+        info!("Overridden config fields: {}", config_sources.overridden_fields.join(", "));
+    }
+    info!("Probe version: {}", config.probe_version);
     info!("Node ID: {}", config.node_id);
     info!("USB Port: {}", config.usb_port);
     info!("Server URL: {}", config.server_url);
     info!("Upload interval: {}s", config.upload_interval_seconds);
     info!("Buffer size: {}", config.buffer_size);
     
+    if args.register {
+        // The node is not connected yet, so its version comes from the deployed firmware
+        let node_firmware_version = match update_manager::deployed_node_version().await {
+            Ok(version) if version > 0 => Some(version),
+            _ => config.node_firmware_version_fallback,
+        };
+        match command_executor::register_node(&config, &config_sources, node_firmware_version).await? {
+            Registration::Registered { api_key, path } => {
+                info!("Registered node {} with the hub, API key saved to {:?}", config.node_id, path);
+                config.api_key = api_key;
+            }
+            Registration::AlreadyRegistered => {}
+        }
+    }
+
+    // Reject a bad restart_strategy now rather than after installing an update
+    update_manager::RestartStrategy::parse(&config.restart_strategy)?;
+
+    if args.skip_self_test {
+        warn!("Skipping startup self-test");
+    } else {
+        startup_self_test(&config).await?;
+    }
+
     // Create channels for USB communication
     let (usb_cmd_tx, usb_cmd_rx) = mpsc::channel(32);
     let (usb_msg_tx, usb_msg_rx) = mpsc::channel(100);
+    // Live feed of collected entries; subscribers lagging further behind miss entries
+    let (log_tx, _) = broadcast::channel(LOG_FEED_CAPACITY);
+    let (raw_line_tx, _) = broadcast::channel(LOG_FEED_CAPACITY);
     
     // Create USB handle for sending commands
-    let usb_handle = UsbHandle::new(usb_cmd_tx);
+    let usb_stats = Arc::new(UsbStats::default());
+    let usb_handle = UsbHandle::new(usb_cmd_tx, Arc::clone(&usb_stats), config.max_pending_commands);
     
     // Shared state
-    let buffer = Arc::new(RwLock::new(Vec::<LogEntry>::new()));
+    let buffer = Arc::new(RwLock::new(LogBuffer::new(config.buffer_size)));
     let filter_string = Arc::new(RwLock::new(config.filter_string.clone()));
-    let upload_interval = Arc::new(RwLock::new(Duration::from_secs(config.upload_interval_seconds)));
+    let timezone = Arc::new(RwLock::new(config.log_timezone()));
+    let node_log_output = Arc::new(RwLock::new("usb".to_string()));
+    let upload_schedule = Arc::new(RwLock::new(UploadSchedule::fixed(config.upload_interval_seconds)));
+    let update_state = UpdateTracker::default();
+    let runtime_metrics = RuntimeMetrics::new(config.enable_runtime_metrics);
+    let (error_reporter, error_events) = ErrorReporter::new();
+    let pending_errors = PendingErrors::default();
+    tokio::spawn(error_reporter::run(error_events, pending_errors.clone()));
+    let task_restarts = RestartTracker::default();
+    let supervisor = Supervisor::new(
+        task_restarts.clone(),
+        config.restart_alert_threshold,
+        config.max_restarts,
+        error_reporter.clone(),
+    );
     
     // Clone references for tasks
     let buffer_usb = Arc::clone(&buffer);
-    let buffer_sync = Arc::clone(&buffer);
-    let filter_usb = Arc::clone(&filter_string);
-    let interval_sync = Arc::clone(&upload_interval);
+    let collector_settings = CollectorSettings {
+        filter_string: Arc::clone(&filter_string),
+        timezone: Arc::clone(&timezone),
+        node_log_output: Arc::clone(&node_log_output),
+    };
+    let usb_stats_collector = Arc::clone(&usb_stats);
     let config_sync = Arc::new(config.clone());
     let config_usb = Arc::clone(&config_sync);
     let config_node_update = Arc::clone(&config_sync);
     let config_probe_update = Arc::clone(&config_sync);
-    let usb_handle_cmd = usb_handle.clone();
+    // One pooled client for all firmware checks, separate from telemetry uploads
+    let firmware_client = update_manager::firmware_client(&config)?;
+    let firmware_client_node_update = firmware_client.clone();
+    let command_ctx = CommandContext {
+        config: Arc::clone(&config_sync),
+        buffer: Arc::clone(&buffer),
+        filter_string: Arc::clone(&filter_string),
+        timezone,
+        upload_schedule: Arc::clone(&upload_schedule),
+        usb_handle: usb_handle.clone(),
+        scheduled_commands: ScheduledCommands::default(),
+        node_log_output,
+        node_power_mode: Arc::new(RwLock::new("full".to_string())),
+        running_measurements: Arc::new(RwLock::new(HashSet::new())),
+        sampling_rate: Arc::new(RwLock::new(None)),
+        watchdog_timeout_ms: Arc::new(RwLock::new(None)),
+        command_lock: Arc::new(tokio::sync::Mutex::new(())),
+        upload_now: Arc::new(tokio::sync::Notify::new()),
+        update_state: update_state.clone(),
+        firmware_client: firmware_client.clone(),
+        last_upload_at: Arc::new(RwLock::new(chrono::Utc::now())),
+        upload_stats: Arc::new(UploadStats::default()),
+        circuit_breaker: Arc::new(CircuitBreaker::new(
+            config.circuit_breaker_failure_threshold,
+            Duration::from_secs(config.circuit_breaker_timeout_seconds),
+        )),
+        api_key: Arc::new(RwLock::new(config.api_key.clone())),
+        runtime_metrics: runtime_metrics.clone(),
+        config_sources: Arc::new(config_sources),
+        raw_lines: raw_line_tx.clone(),
+        debug_port: Arc::new(tokio::sync::Mutex::new(None)),
+        log_feed: log_tx.clone(),
+        log_tail: Arc::new(tokio::sync::Mutex::new(None)),
+        file_stream: Arc::new(tokio::sync::Mutex::new(None)),
+        command_timings: CommandTimings::default(),
+        error_reporter: error_reporter.clone(),
+        pending_errors,
+        task_restarts: task_restarts.clone(),
+    };
+    let command_ctx_scheduler = command_ctx.clone();
+    let command_ctx_watcher = command_ctx.clone();
+    let command_ctx_admin = command_ctx.clone();
     let usb_handle_node_update = usb_handle.clone();
+    let error_reporter_node_update = error_reporter.clone();
     
     // Spawn USB manager task
-    let usb_manager = UsbManager::new(config.usb_port.clone(), usb_cmd_rx, usb_msg_tx);
-    let usb_task = tokio::spawn(async move {
+    let usb_manager = UsbManager::new(
+        config.usb_port.clone(),
+        usb_cmd_rx,
+        usb_msg_tx,
+        Arc::clone(&usb_stats),
+        UsbProtocol::parse(&config.usb_protocol)?,
+    )
+    .with_idle_timeout(
+        Duration::from_secs(config.usb_idle_timeout_seconds),
+        Duration::from_secs(config.usb_keepalive_seconds),
+    );
+    let usb_manager = match &config.usb_command_log_path {
+        Some(path) => usb_manager.with_traffic_log(path.clone(), config.usb_command_log_max_bytes),
+        None => usb_manager,
+    };
+    tokio::spawn(usb_stats.run_rate_ticker());
+    let usb_task = tokio::spawn(runtime_metrics.instrument("usb_manager", async move {
         usb_manager.run().await
-    });
+    }));
     
     // Spawn USB log collector task (receives messages from USB manager)
-    let collector_task = tokio::spawn(async move {
-        usb_collector::run(config_usb, buffer_usb, filter_usb, usb_msg_rx).await
-    });
+    let collector_task = tokio::spawn(runtime_metrics.instrument("usb_collector", async move {
+        usb_collector::run(config_usb, buffer_usb, collector_settings, usb_msg_rx, log_tx, raw_line_tx, usb_stats_collector).await
+    }));
+    
+    // Spawn telemetry sync task over the configured transport, started again if it ends
+    match config.transport.to_lowercase().as_str() {
+        "http" => {
+            let runtime_metrics = runtime_metrics.clone();
+            tokio::spawn(supervisor.clone().supervise("telemetry_sync", move || {
+                runtime_metrics.instrument("telemetry_sync", telemetry_sync::run(command_ctx.clone()))
+            }));
+        }
+        "mqtt" => {
+            // Built here so a bad broker setting stops the probe at startup
+            let mut transport = Some(MqttTransport::new(&config)?);
+            let config_mqtt = Arc::clone(&config_sync);
+            let runtime_metrics = runtime_metrics.clone();
+            tokio::spawn(supervisor.clone().supervise("telemetry_sync", move || {
+                let transport = transport.take().map_or_else(|| MqttTransport::new(&config_mqtt), Ok);
+                let ctx = command_ctx.clone();
+                runtime_metrics.instrument("telemetry_sync", async move { transport?.run(ctx).await })
+            }));
+        }
+        other => {
+            return Err(ProbeError::ConfigError(format!("Unknown transport '{}', expected http or mqtt", other)).into());
+        }
+    }
+    
+    // Keep the node RTC in step with the probe, if configured
+    if let Some(interval) = config.rtc_sync_interval_seconds.filter(|secs| *secs > 0) {
+        let usb_handle_rtc = usb_handle.clone();
+        let error_reporter_rtc = error_reporter.clone();
+        tokio::spawn(async move {
+            if let Err(e) = command_executor::run_rtc_sync(usb_handle_rtc, Duration::from_secs(interval)).await {
+                error!("Node RTC sync stopped: {}", e);
+                error_reporter_rtc.report("rtc_sync", &e);
+            }
+        });
+    }
+
+    // Keep track of node measurements, if configured
+    if let Some(interval) = config.measurement_status_poll_interval_seconds.filter(|secs| *secs > 0) {
+        let command_ctx_measurements = command_ctx_scheduler.clone();
+        tokio::spawn(async move {
+            let interval = Duration::from_secs(interval);
+            let error_reporter = command_ctx_measurements.error_reporter.clone();
+            if let Err(e) = command_executor::run_measurement_status_poll(command_ctx_measurements, interval).await {
+                error!("Measurement status polling stopped: {}", e);
+                error_reporter.report("measurement_status_poll", &e);
+            }
+        });
+    }
+
+    // Serve the local admin endpoints, if configured
+    if let Some(address) = &config.admin_listen_address {
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .map_err(|e| ProbeError::ConfigError(format!("Cannot listen on admin_listen_address {}: {}", address, e)))?;
+        if config.admin_token.is_none() {
+            warn!("admin_token is not set, the admin buffer endpoints will refuse every request");
+        }
+        tokio::spawn(admin_server::run(listener, command_ctx_admin));
+    }
+
+    // Spawn scheduled command runner
+    let runtime_metrics_scheduler = runtime_metrics.clone();
+    tokio::spawn(supervisor.clone().supervise("command_scheduler", move || {
+        runtime_metrics_scheduler.instrument("command_scheduler", command_executor::run_scheduler(command_ctx_scheduler.clone()))
+    }));
     
-    // Spawn telemetry sync task
-    let sync_task = tokio::spawn(async move {
-        telemetry_sync::run(config_sync, buffer_sync, interval_sync, filter_string, usb_handle_cmd).await
+    // Spawn config file watcher; the probe keeps running without hot-reload if it fails
+    let config_path = args.config.clone();
+    let config_override = args.config_override.clone();
+    let error_reporter_watcher = command_ctx_watcher.error_reporter.clone();
+    tokio::spawn(async move {
+        if let Err(e) = config_watcher::run(config_path, config_override, command_ctx_watcher).await {
+            error!("Config watcher stopped: {}", e);
+            error_reporter_watcher.report("config_watcher", &e);
+        }
     });
     
     // Spawn node firmware update manager
-    let node_update_task = tokio::spawn(async move {
-        update_manager::run_node_update(config_node_update, usb_handle_node_update).await
-    });
+    let runtime_metrics_node_update = runtime_metrics.clone();
+    tokio::spawn(supervisor.clone().supervise("node_update", move || {
+        runtime_metrics_node_update.instrument(
+            "node_update",
+            update_manager::run_node_update(
+                Arc::clone(&config_node_update),
+                firmware_client_node_update.clone(),
+                usb_handle_node_update.clone(),
+                update_state.clone(),
+                error_reporter_node_update.clone(),
+            ),
+        )
+    }));
     
     // Spawn probe self-update manager
-    let probe_update_task = tokio::spawn(async move {
-        update_manager::run_probe_update(config_probe_update).await
-    });
+    let runtime_metrics_probe_update = runtime_metrics.clone();
+    tokio::spawn(supervisor.supervise("probe_update", move || {
+        runtime_metrics_probe_update.instrument(
+            "probe_update",
+            update_manager::run_probe_update(Arc::clone(&config_probe_update), firmware_client.clone(), error_reporter.clone()),
+        )
+    }));
     
-    // Wait for any task to complete (they should run indefinitely)
+    // The USB tasks own the channel ends between them and cannot be started
+    // again on their own; the probe exits for its service manager to restart it
     tokio::select! {
         result = usb_task => {
             error!("USB manager task ended: {:?}", result);
@@ -116,16 +388,269 @@ async fn main() -> Result<()> {
         result = collector_task => {
             error!("USB collector task ended: {:?}", result);
         }
-        result = sync_task => {
-            error!("Telemetry sync task ended: {:?}", result);
+    }
+    
+    Ok(())
+}
+
+/// JSON log lines carrying the fields of the span they happen in and of its parents
+fn json_log_layer<S, W>(writer: W) -> impl tracing_subscriber::Layer<S>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + 'static,
+{
+    tracing_subscriber::fmt::layer().json().with_current_span(true).with_span_list(true).with_writer(writer)
+}
+
+/// Exit code when the probe binary does not match its `.sha256` sidecar
+const CORRUPTED_BINARY_EXIT_CODE: i32 = 2;
+
+/// Log the outcome of the binary integrity check, returning the exit code to
+/// stop with when the probe must not start
+fn integrity_exit_code(check: Result<IntegrityCheck>) -> Option<i32> {
+    match check {
+        Ok(IntegrityCheck::Passed) => info!("Probe binary integrity check passed"),
+        Ok(IntegrityCheck::SidecarMissing) => warn!("No .sha256 sidecar next to probe binary, skipping integrity check"),
+        Ok(IntegrityCheck::Mismatch { expected, computed }) => {
+            error!("CRITICAL: probe binary is corrupted (expected SHA-256 {}, computed {}). Refusing to start.", expected, computed);
+            return Some(CORRUPTED_BINARY_EXIT_CODE);
         }
-        result = node_update_task => {
-            error!("Node update task ended: {:?}", result);
+        Err(e) => warn!("Probe binary integrity check could not run: {}", e),
+    }
+    None
+}
+
+/// Check that the hub, the node's serial port and local storage are usable
+/// before starting the tasks
+async fn startup_self_test(config: &Config) -> Result<()> {
+    let checks = [
+        check_hub(config).await,
+        check_usb_port(&config.usb_port),
+        check_writable(Path::new(update_manager::DEPLOYED_DIR)).await,
+        check_disk_space(Path::new("."), config.min_free_disk_bytes),
+    ];
+
+    let failures = checks.iter().filter(|passed| !**passed).count();
+    if failures > 0 {
+        return Err(anyhow::anyhow!("Startup self-test failed ({} checks)", failures));
+    }
+
+    info!("Startup self-test passed");
+    Ok(())
+}
+
+/// Telemetry hub answers `GET /health`
+async fn check_hub(config: &Config) -> bool {
+    let health_url = format!("{}/health", config.server_url);
+    let client = match connectivity::client_builder(config).and_then(|builder| {
+        Ok(builder.timeout(std::time::Duration::from_secs(10)).build()?)
+    }) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Self-test [hub]: cannot build HTTP client: {}", e);
+            return false;
+        }
+    };
+    match client.get(&health_url).send().await {
+        Ok(response) if response.status().is_success() => {
+            info!("Self-test [hub]: {} OK", health_url);
+            true
         }
-        result = probe_update_task => {
-            error!("Probe update task ended: {:?}", result);
+        Ok(response) => {
+            error!("Self-test [hub]: {} returned {}", health_url, response.status());
+            false
+        }
+        Err(e) => {
+            error!("Self-test [hub]: {} unreachable: {}", health_url, e);
+            false
         }
     }
-    
-    Ok(())
+}
+
+/// USB serial port present
+fn check_usb_port(usb_port: &str) -> bool {
+    if Path::new(usb_port).exists() {
+        info!("Self-test [usb]: {} present", usb_port);
+        true
+    } else {
+        error!("Self-test [usb]: {} does not exist", usb_port);
+        false
+    }
+}
+
+/// Firmware directory writable
+async fn check_writable(dir: &Path) -> bool {
+    let probe_file = dir.join(".self_test");
+    let writable = async {
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::write(&probe_file, b"ok").await?;
+        tokio::fs::remove_file(&probe_file).await
+    };
+    match writable.await {
+        Ok(()) => {
+            info!("Self-test [storage]: {} writable", dir.display());
+            true
+        }
+        Err(e) => {
+            error!("Self-test [storage]: {} not writable: {}", dir.display(), e);
+            false
+        }
+    }
+}
+
+/// At least `min_free_bytes` free on the disk holding `path`
+fn check_disk_space(path: &Path, min_free_bytes: u64) -> bool {
+    match update_manager::available_disk_space(path) {
+        Ok(free) if free >= min_free_bytes => {
+            info!("Self-test [disk]: {} bytes free", free);
+            true
+        }
+        Ok(free) => {
+            error!("Self-test [disk]: only {} bytes free, need {}", free, min_free_bytes);
+            false
+        }
+        Err(e) => {
+            error!("Self-test [disk]: could not determine free space: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Log output shared between the subscriber and the test
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_logs_carry_span_fields_for_tracing_and_log_records() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry().with(json_log_layer(move || writer.clone()));
+        let _ = tracing_log::LogTracer::init();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let session = tracing::info_span!("usb_session", port = "/dev/ttyACM0", attempt = 2);
+            let _session = session.enter();
+            let upload = tracing::info_span!("telemetry_upload", batch_size = tracing::field::Empty);
+            upload.record("batch_size", 40);
+            let _upload = upload.enter();
+            tracing::info!("sent batch");
+            log::warn!("bridged from log");
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2, "{}", output);
+        for line in &lines {
+            assert_eq!(line["span"]["name"], "telemetry_upload");
+            assert_eq!(line["span"]["batch_size"], 40);
+            assert_eq!(line["spans"][0], serde_json::json!({ "name": "usb_session", "port": "/dev/ttyACM0", "attempt": 2 }));
+        }
+        assert_eq!(lines[0]["fields"]["message"], "sent batch");
+        assert_eq!(lines[1]["fields"]["message"], "bridged from log");
+        assert_eq!(lines[1]["level"], "WARN");
+    }
+
+    /// A config whose hub is `server_url`
+    fn config(server_url: &str) -> Config {
+        toml::from_str(&format!(
+            "usb_port = \"/dev/null\"\nserver_url = \"{}\"\napi_key = \"k\"\nnode_id = 1\n\
+             node_firmware_url = \"http://127.0.0.1:9/node\"\nprobe_firmware_url = \"http://127.0.0.1:9/probe\"\n",
+            server_url
+        ))
+        .unwrap()
+    }
+
+    /// A hub answering one request with `status`, and its base URL
+    async fn hub(status: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = socket.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..n]);
+            }
+            assert!(request.starts_with(b"GET /health "));
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn hub_check_needs_a_successful_health_response() {
+        assert!(check_hub(&config(&hub("200 OK").await)).await);
+        assert!(!check_hub(&config(&hub("503 Service Unavailable").await)).await);
+
+        // Nothing listens on the discard port
+        assert!(!check_hub(&config("http://127.0.0.1:9")).await);
+    }
+
+    #[tokio::test]
+    async fn only_a_corrupted_binary_stops_startup_with_exit_code_2() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("moonblokz_probe_7");
+        let sidecar = dir.path().join("moonblokz_probe_7.sha256");
+        std::fs::write(&binary, b"probe binary").unwrap();
+        let check = || update_manager::verify_sha256_sidecar(&binary);
+
+        assert_eq!(integrity_exit_code(check().await), None);
+
+        let sha256 = format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(b"probe binary"));
+        std::fs::write(&sidecar, format!("{}  moonblokz_probe_7\n", sha256.to_uppercase())).unwrap();
+        assert!(matches!(check().await, Ok(IntegrityCheck::Passed)));
+        assert_eq!(integrity_exit_code(check().await), None);
+
+        std::fs::write(&binary, b"probe binarY").unwrap();
+        assert_eq!(integrity_exit_code(check().await), Some(2));
+
+        // A sidecar that cannot be read is reported, not fatal
+        std::fs::remove_file(&sidecar).unwrap();
+        std::fs::create_dir(&sidecar).unwrap();
+        assert!(check().await.is_err());
+        assert_eq!(integrity_exit_code(check().await), None);
+    }
+
+    #[test]
+    fn usb_check_needs_the_port_to_exist() {
+        assert!(check_usb_port("/dev/null"));
+        assert!(!check_usb_port("/dev/ttyACM-missing"));
+    }
+
+    #[tokio::test]
+    async fn storage_check_needs_a_writable_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_writable(&dir.path().join("deployed")).await);
+        assert!(dir.path().join("deployed").is_dir());
+
+        // A regular file is in the way of the directory
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(!check_writable(&file.join("deployed")).await);
+    }
+
+    #[test]
+    fn disk_check_needs_the_minimum_free_space() {
+        assert!(check_disk_space(Path::new("."), 0));
+        assert!(!check_disk_space(Path::new("."), u64::MAX));
+        assert!(!check_disk_space(Path::new("/nonexistent/path"), 0));
+    }
 }
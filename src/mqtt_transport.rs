@@ -0,0 +1,275 @@
+use crate::command_executor::{self, Command, CommandContext, CommandResult};
+use crate::config::Config;
+use crate::error::ProbeError;
+use crate::error_reporter::{self, ErrorEvent};
+use crate::log_entry::LogEntry;
+use crate::rate_limit::CommandRateLimiter;
+use crate::telemetry_sync::{self, ProbeInfo};
+use anyhow::Result;
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS, Transport};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Requests rumqttc may hold while the broker is unreachable
+const REQUEST_QUEUE_SIZE: usize = 100;
+
+#[derive(Debug, Serialize)]
+struct TelemetryMessage<'a> {
+    node_id: u32,
+    probe_info: &'a ProbeInfo,
+    logs: &'a [LogEntry],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    command_results: &'a [CommandResult],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    error_events: &'a [ErrorEvent],
+}
+
+/// A command message holds either one command or a list of them
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum CommandMessage {
+    Many(Vec<Command>),
+    One(Command),
+}
+
+/// Publishes log batches to `moonblokz/<node_id>/telemetry` and executes commands
+/// received on `moonblokz/<node_id>/commands`, in place of HTTP uploads.
+pub struct MqttTransport {
+    client: AsyncClient,
+    eventloop: EventLoop,
+    qos: QoS,
+    telemetry_topic: String,
+    commands_topic: String,
+    max_payload_size: usize,
+}
+
+impl MqttTransport {
+    pub fn new(config: &Config) -> Result<Self> {
+        let broker = config
+            .mqtt_broker_url
+            .as_deref()
+            .ok_or_else(|| ProbeError::ConfigError("transport \"mqtt\" requires mqtt_broker_url".to_string()))?;
+        let url = reqwest::Url::parse(broker)
+            .map_err(|e| ProbeError::ConfigError(format!("Invalid mqtt_broker_url '{}': {}", broker, e)))?;
+
+        let tls = match url.scheme() {
+            "mqtt" | "tcp" => false,
+            "mqtts" | "ssl" => true,
+            other => return Err(ProbeError::ConfigError(format!("Unsupported mqtt_broker_url scheme '{}'", other)).into()),
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| ProbeError::ConfigError(format!("mqtt_broker_url '{}' has no host", broker)))?;
+        let port = url.port().unwrap_or(if tls { 8883 } else { 1883 });
+
+        let qos = rumqttc::qos(config.mqtt_qos)
+            .map_err(|_| ProbeError::ConfigError(format!("mqtt_qos must be 0, 1 or 2, got {}", config.mqtt_qos)))?;
+
+        let client_id = config.mqtt_client_id.clone().unwrap_or_else(|| config.node_id.to_string());
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(KEEP_ALIVE);
+        // Keep the session so QoS 1/2 messages survive reconnects
+        options.set_clean_session(false);
+        options.set_max_packet_size(config.max_payload_size_bytes, config.max_payload_size_bytes);
+        if let Some(username) = &config.mqtt_username {
+            options.set_credentials(username, config.mqtt_password.clone().unwrap_or_default());
+        }
+        if tls {
+            options.set_transport(Transport::tls_with_default_config());
+        }
+
+        let (client, eventloop) = AsyncClient::new(options, REQUEST_QUEUE_SIZE);
+
+        Ok(Self {
+            client,
+            eventloop,
+            qos,
+            telemetry_topic: format!("moonblokz/{}/telemetry", config.node_id),
+            commands_topic: format!("moonblokz/{}/commands", config.node_id),
+            max_payload_size: config.max_payload_size_bytes,
+        })
+    }
+
+    pub async fn run(self, ctx: CommandContext) -> Result<()> {
+        let MqttTransport {
+            client,
+            eventloop,
+            qos,
+            telemetry_topic,
+            commands_topic,
+            max_payload_size,
+        } = self;
+
+        info!("MQTT transport started, publishing to {}", telemetry_topic);
+
+        let (command_tx, mut command_rx) = mpsc::channel(32);
+        tokio::spawn(drive_eventloop(eventloop, client.clone(), commands_topic, qos, command_tx));
+
+        let mut command_results = Vec::new();
+        let mut error_events = Vec::new();
+        let mut hardware_info_sent = false;
+        let mut command_limiter = CommandRateLimiter::new(ctx.config.max_commands_per_upload, ctx.config.max_commands_per_hour);
+
+        loop {
+            let interval = Duration::from_secs(ctx.upload_schedule.read().await.current_interval());
+            let deadline = Instant::now() + interval;
+
+            // Run commands as they arrive until the next publish is due
+            loop {
+                tokio::select! {
+                    _ = sleep_until(deadline) => break,
+                    _ = ctx.upload_now.notified() => break,
+                    Some(commands) = command_rx.recv() => {
+                        for command in command_limiter.admit(commands) {
+                            match command_executor::execute_command(command, &ctx).await {
+                                Ok(result) if result.has_data() => command_results.push(result),
+                                Ok(_) => {}
+                                Err(e) => {
+                                    error!("Command execution error: {}", e);
+                                    ctx.error_reporter.report("telemetry_sync", &e);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            for event in ctx.pending_errors.take() {
+                error_reporter::merge_event(&mut error_events, event);
+            }
+            let reports = Reports {
+                command_results: &mut command_results,
+                error_events: &mut error_events,
+            };
+            match publish_logs(&client, qos, &telemetry_topic, max_payload_size, &ctx, reports).await {
+                Ok(()) => {
+                    *ctx.last_upload_at.write().await = Utc::now();
+                    if ctx.config.send_hardware_info_on_startup && !hardware_info_sent {
+                        hardware_info_sent = true;
+                        command_results.push(command_executor::hardware_info_result().await);
+                        ctx.upload_now.notify_one();
+                    }
+                }
+                Err(e) => {
+                    error!("MQTT publish error: {}", e);
+                    ctx.error_reporter.report("telemetry_sync", &e);
+                }
+            }
+        }
+    }
+}
+
+/// Command results and error events waiting to go out with the logs; cleared
+/// once a message carrying them is queued
+struct Reports<'a> {
+    command_results: &'a mut Vec<CommandResult>,
+    error_events: &'a mut Vec<ErrorEvent>,
+}
+
+/// Queue the buffered logs for publishing, in as many messages as needed to stay
+/// under `max_payload_size`. The logs are taken out of the buffer, and any not
+/// queued go back to it.
+async fn publish_logs(
+    client: &AsyncClient,
+    qos: QoS,
+    topic: &str,
+    max_payload_size: usize,
+    ctx: &CommandContext,
+    reports: Reports<'_>,
+) -> Result<()> {
+    let Reports { command_results, error_events } = reports;
+    let mut logs: Vec<LogEntry> = ctx.buffer.write().await.drain().collect();
+    if logs.is_empty() && command_results.is_empty() && error_events.is_empty() {
+        return Ok(());
+    }
+
+    let probe_info = ProbeInfo::collect(ctx);
+    let mut published = 0;
+    let mut result = Ok(());
+    loop {
+        let remaining = &logs[published..];
+        let mut batch_len = remaining.len();
+        let payload = loop {
+            let message = TelemetryMessage {
+                node_id: ctx.config.node_id,
+                probe_info: &probe_info,
+                logs: &remaining[..batch_len],
+                command_results,
+                error_events,
+            };
+            let payload = serde_json::to_vec(&message);
+            match &payload {
+                Ok(bytes) if bytes.len() > max_payload_size && batch_len > 1 => batch_len /= 2,
+                _ => break payload,
+            }
+        };
+
+        // rumqttc retransmits queued QoS 1/2 messages until the broker acknowledges them
+        // No early return on errors: the unqueued entries must go back to the buffer
+        let queued = match payload {
+            Ok(payload) => client
+                .try_publish(topic, qos, false, payload)
+                .map_err(|e| anyhow::anyhow!("Failed to queue MQTT message: {}", e)),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = queued {
+            result = Err(e);
+            break;
+        }
+        command_results.clear();
+        error_events.clear();
+        published += batch_len;
+
+        if published == logs.len() {
+            break;
+        }
+    }
+
+    if published > 0 {
+        debug!("Queued {} log entries for MQTT publishing", published);
+    }
+    telemetry_sync::restore_unsent(ctx, logs.split_off(published), &[]).await;
+    result
+}
+
+/// Poll the MQTT connection, subscribing to `commands_topic` on every (re)connect
+/// and forwarding received commands
+async fn drive_eventloop(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    commands_topic: String,
+    qos: QoS,
+    command_tx: mpsc::Sender<Vec<Command>>,
+) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker, subscribing to {}", commands_topic);
+                if let Err(e) = client.try_subscribe(&commands_topic, qos) {
+                    error!("Failed to subscribe to {}: {}", commands_topic, e);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == commands_topic => {
+                match serde_json::from_slice::<CommandMessage>(&publish.payload) {
+                    Ok(CommandMessage::Many(commands)) => {
+                        let _ = command_tx.send(commands).await;
+                    }
+                    Ok(CommandMessage::One(command)) => {
+                        let _ = command_tx.send(vec![command]).await;
+                    }
+                    Err(e) => warn!("Ignoring malformed MQTT command message: {}", e),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("MQTT connection error: {}. Reconnecting in {}s...", e, RECONNECT_DELAY.as_secs());
+                sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use bytes::Bytes;
+use futures_util::Stream;
+use log::{error, warn};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::time::{sleep_until, Duration, Instant, Sleep};
+
+/// Window of the hourly command limit
+const COMMAND_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Wraps a byte stream and delays chunks so the average rate since the first
+/// poll stays at or below `bytes_per_sec`
+pub struct RateLimitedStream<S> {
+    inner: S,
+    bytes_per_sec: u64,
+    started: Option<Instant>,
+    consumed: u64,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> RateLimitedStream<S> {
+    pub fn new(inner: S, bytes_per_sec: u64) -> Self {
+        Self { inner, bytes_per_sec: bytes_per_sec.max(1), started: None, consumed: 0, delay: None }
+    }
+}
+
+impl<S, E> Stream for RateLimitedStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Hold back the next chunk until the ones already handed out are paid for
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let item = ready!(Pin::new(&mut self.inner).poll_next(cx));
+
+        if let Some(Ok(chunk)) = &item {
+            self.consumed += chunk.len() as u64;
+            let due = started + Duration::from_secs_f64(self.consumed as f64 / self.bytes_per_sec as f64);
+            if due > Instant::now() {
+                self.delay = Some(Box::pin(sleep_until(due)));
+            }
+        }
+
+        Poll::Ready(item)
+    }
+}
+
+/// Caps the commands taken from hub responses, so a misbehaving hub cannot
+/// flood the probe
+pub struct CommandRateLimiter {
+    max_per_upload: usize,
+    max_per_hour: u32,
+    /// When each command accepted within the last hour arrived
+    accepted: VecDeque<Instant>,
+}
+
+impl CommandRateLimiter {
+    pub fn new(max_per_upload: usize, max_per_hour: u32) -> Self {
+        Self { max_per_upload, max_per_hour, accepted: VecDeque::new() }
+    }
+
+    /// The commands of one response that may run: none once `max_per_hour`
+    /// were accepted within the last hour, otherwise at most `max_per_upload`
+    pub fn admit<T>(&mut self, mut commands: Vec<T>) -> Vec<T> {
+        if commands.is_empty() {
+            return commands;
+        }
+
+        let now = Instant::now();
+        while self.accepted.front().is_some_and(|&at| now.duration_since(at) >= COMMAND_WINDOW) {
+            self.accepted.pop_front();
+        }
+
+        let budget = (self.max_per_hour as usize).saturating_sub(self.accepted.len());
+        if budget == 0 {
+            error!(
+                "Hub sent {} commands after {} were accepted within the last hour; ignoring all of them",
+                commands.len(),
+                self.accepted.len()
+            );
+            return Vec::new();
+        }
+
+        let allowed = self.max_per_upload.min(budget);
+        if commands.len() > allowed {
+            warn!("Hub sent {} commands in one response, running only the first {}", commands.len(), allowed);
+            commands.truncate(allowed);
+        }
+        self.accepted.extend(std::iter::repeat_n(now, commands.len()));
+        commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_commands_per_upload() {
+        let mut limiter = CommandRateLimiter::new(3, 100);
+        assert_eq!(limiter.admit(vec![1, 2, 3, 4, 5]), vec![1, 2, 3]);
+        assert_eq!(limiter.admit(vec![6, 7]), vec![6, 7]);
+        assert!(limiter.admit(Vec::<u8>::new()).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_commands_per_hour() {
+        let mut limiter = CommandRateLimiter::new(3, 5);
+        assert_eq!(limiter.admit(vec![1, 2, 3]).len(), 3);
+        tokio::time::advance(Duration::from_secs(1800)).await;
+        // Only two of the hourly budget are left
+        assert_eq!(limiter.admit(vec![4, 5, 6]), vec![4, 5]);
+        assert!(limiter.admit(vec![7]).is_empty());
+
+        // The first three fall out of the window an hour after they arrived
+        tokio::time::advance(Duration::from_secs(1800)).await;
+        assert_eq!(limiter.admit(vec![8, 9, 10, 11]), vec![8, 9, 10]);
+        assert!(limiter.admit(vec![12]).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delays_chunks_to_the_byte_rate() {
+        let chunks = (0..4).map(|_| Ok::<_, std::io::Error>(Bytes::from_static(&[0; 100])));
+        let mut stream = RateLimitedStream::new(futures_util::stream::iter(chunks), 100);
+        let started = Instant::now();
+        let mut arrivals = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            chunk.unwrap();
+            arrivals.push(started.elapsed().as_secs());
+        }
+        // Each chunk is handed out once the ones before it are paid for
+        assert_eq!(arrivals, vec![0, 1, 2, 3]);
+    }
+}
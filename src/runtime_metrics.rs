@@ -0,0 +1,101 @@
+use std::future::Future;
+#[cfg(feature = "tokio-metrics")]
+use std::sync::{Arc, Mutex};
+
+/// Per-task and runtime-wide tokio statistics, collected when the probe is
+/// built with the `tokio-metrics` feature and `enable_runtime_metrics` is set.
+/// Otherwise tasks run uninstrumented and nothing is reported.
+#[derive(Clone, Default)]
+pub struct RuntimeMetrics {
+    #[cfg(feature = "tokio-metrics")]
+    monitors: Option<Arc<Monitors>>,
+}
+
+#[cfg(feature = "tokio-metrics")]
+struct Monitors {
+    tasks: Mutex<Vec<(&'static str, tokio_metrics::TaskMonitor)>>,
+    /// Runtime metrics are reported for the time since the previous report
+    runtime: Mutex<tokio_metrics::RuntimeIntervals>,
+}
+
+impl RuntimeMetrics {
+    #[cfg(feature = "tokio-metrics")]
+    pub fn new(enabled: bool) -> Self {
+        let monitors = enabled.then(|| {
+            let runtime = tokio_metrics::RuntimeMonitor::new(&tokio::runtime::Handle::current());
+            Arc::new(Monitors {
+                tasks: Mutex::new(Vec::new()),
+                runtime: Mutex::new(runtime.intervals()),
+            })
+        });
+        Self { monitors }
+    }
+
+    #[cfg(not(feature = "tokio-metrics"))]
+    pub fn new(enabled: bool) -> Self {
+        if enabled {
+            log::warn!("enable_runtime_metrics is set but the probe was built without the tokio-metrics feature");
+        }
+        Self::default()
+    }
+
+    /// Wrap `task` so its polls are recorded under `name`
+    #[cfg(feature = "tokio-metrics")]
+    pub fn instrument<F: Future>(&self, name: &'static str, task: F) -> impl Future<Output = F::Output> {
+        use futures_util::future::Either;
+
+        match &self.monitors {
+            Some(monitors) => {
+                let monitor = tokio_metrics::TaskMonitor::new();
+                monitors.tasks.lock().unwrap().push((name, monitor.clone()));
+                Either::Left(monitor.instrument(task))
+            }
+            None => Either::Right(task),
+        }
+    }
+
+    #[cfg(not(feature = "tokio-metrics"))]
+    pub fn instrument<F: Future>(&self, _name: &'static str, task: F) -> impl Future<Output = F::Output> {
+        task
+    }
+
+    /// Cumulative per-task statistics and runtime statistics since the last
+    /// call, or `null` when metrics are off
+    #[cfg(feature = "tokio-metrics")]
+    pub fn to_json(&self) -> serde_json::Value {
+        let Some(monitors) = &self.monitors else {
+            return serde_json::Value::Null;
+        };
+
+        let mut tasks = serde_json::Map::new();
+        for (name, monitor) in monitors.tasks.lock().unwrap().iter() {
+            let metrics = monitor.cumulative();
+            tasks.insert(
+                name.to_string(),
+                serde_json::json!({
+                    "instrumented_count": metrics.instrumented_count,
+                    "dropped_count": metrics.dropped_count,
+                    "total_poll_count": metrics.total_poll_count,
+                    "mean_poll_duration_us": metrics.mean_poll_duration().as_micros() as u64,
+                    "mean_slow_poll_duration_us": metrics.mean_slow_poll_duration().as_micros() as u64,
+                }),
+            );
+        }
+
+        let runtime = monitors.runtime.lock().unwrap().next().unwrap_or_default();
+        serde_json::json!({
+            "tasks": tasks,
+            "runtime": {
+                "workers_count": runtime.workers_count,
+                "live_tasks_count": runtime.live_tasks_count,
+                "total_busy_duration_us": runtime.total_busy_duration.as_micros() as u64,
+                "elapsed_us": runtime.elapsed.as_micros() as u64,
+            },
+        })
+    }
+
+    #[cfg(not(feature = "tokio-metrics"))]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+}
@@ -0,0 +1,237 @@
+use crate::error_reporter::ErrorReporter;
+use crate::update_manager;
+use anyhow::Result;
+use log::{error, warn};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration, Instant};
+
+/// Window over which restarts are counted
+const RESTART_WINDOW: Duration = Duration::from_secs(3600);
+/// Pause before a task that ended is started again
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Restarts of each supervised task, shared by every clone
+#[derive(Debug, Clone, Default)]
+pub struct RestartTracker {
+    /// When each task was restarted within the last hour, oldest first
+    restarts: Arc<Mutex<HashMap<&'static str, VecDeque<Instant>>>>,
+    /// Restarts of each task since the probe started
+    totals: Arc<Mutex<HashMap<&'static str, u64>>>,
+}
+
+impl RestartTracker {
+    /// Count a restart of `task`, returning its restarts within the last hour
+    pub fn record(&self, task: &'static str) -> usize {
+        let now = Instant::now();
+        *self.totals.lock().unwrap().entry(task).or_default() += 1;
+        let mut restarts = self.restarts.lock().unwrap();
+        let times = restarts.entry(task).or_default();
+        times.push_back(now);
+        trim(times, now);
+        times.len()
+    }
+
+    /// Restarts of each task within the last hour, leaving out tasks without any
+    pub fn recent(&self) -> BTreeMap<String, usize> {
+        let now = Instant::now();
+        let mut restarts = self.restarts.lock().unwrap();
+        restarts
+            .iter_mut()
+            .filter_map(|(task, times)| {
+                trim(times, now);
+                (!times.is_empty()).then(|| (task.to_string(), times.len()))
+            })
+            .collect()
+    }
+
+    /// Tasks restarted more than `threshold` times within the last hour, or
+    /// `None` if there are none
+    pub fn alerting(&self, threshold: u32) -> Option<BTreeMap<String, usize>> {
+        let alerting: BTreeMap<String, usize> =
+            self.recent().into_iter().filter(|(_, count)| *count > threshold as usize).collect();
+        (!alerting.is_empty()).then_some(alerting)
+    }
+
+    /// Restarts of each task since the probe started
+    pub fn totals(&self) -> BTreeMap<String, u64> {
+        self.totals.lock().unwrap().iter().map(|(task, total)| (task.to_string(), *total)).collect()
+    }
+
+    /// Restarts within the last hour and since startup, for `get_status`
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({ "last_hour": self.recent(), "total": self.totals() })
+    }
+
+    /// The `probe_task_restarts_total` counter in the Prometheus text format
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::from(
+            "# HELP probe_task_restarts_total Restarts of each supervised probe task.\n\
+             # TYPE probe_task_restarts_total counter\n",
+        );
+        for (task, total) in self.totals() {
+            text.push_str(&format!("probe_task_restarts_total{{task=\"{}\"}} {}\n", task, total));
+        }
+        text
+    }
+}
+
+/// Drop restarts older than the window; they were recorded in order
+fn trim(times: &mut VecDeque<Instant>, now: Instant) {
+    while times.front().is_some_and(|at| now.duration_since(*at) > RESTART_WINDOW) {
+        times.pop_front();
+    }
+}
+
+/// What a task's restarts within the last hour call for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartVerdict {
+    Restart,
+    /// Restart, but the task is failing more often than it should
+    Alert,
+    /// The task cannot be kept running, reboot the probe
+    Reboot,
+}
+
+impl RestartVerdict {
+    pub fn for_count(count: usize, alert_threshold: u32, max_restarts: u32) -> Self {
+        if count > max_restarts as usize * 2 {
+            RestartVerdict::Reboot
+        } else if count > alert_threshold as usize {
+            RestartVerdict::Alert
+        } else {
+            RestartVerdict::Restart
+        }
+    }
+}
+
+/// Starts tasks again when they end or panic, counting the restarts
+#[derive(Clone)]
+pub struct Supervisor {
+    tracker: RestartTracker,
+    alert_threshold: u32,
+    max_restarts: u32,
+    error_reporter: ErrorReporter,
+    restart_delay: Duration,
+}
+
+impl Supervisor {
+    pub fn new(tracker: RestartTracker, alert_threshold: u32, max_restarts: u32, error_reporter: ErrorReporter) -> Self {
+        Self {
+            tracker,
+            alert_threshold,
+            max_restarts,
+            error_reporter,
+            restart_delay: RESTART_DELAY,
+        }
+    }
+
+    /// Run the task made by `start` and start a new one whenever it ends,
+    /// until the probe reboots
+    pub async fn supervise<F, Fut>(self, task: &'static str, mut start: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        loop {
+            match tokio::spawn(start()).await {
+                Ok(Ok(())) => warn!("Task {} ended", task),
+                Ok(Err(e)) => {
+                    error!("Task {} failed: {}", task, e);
+                    self.error_reporter.report(task, &e);
+                }
+                Err(e) => error!("Task {} panicked: {}", task, e),
+            }
+
+            let count = self.tracker.record(task);
+            match RestartVerdict::for_count(count, self.alert_threshold, self.max_restarts) {
+                RestartVerdict::Restart => {}
+                RestartVerdict::Alert => {
+                    warn!("Task {} restarted {} times within the last hour", task, count);
+                }
+                RestartVerdict::Reboot => {
+                    error!("Task {} restarted {} times within the last hour, rebooting", task, count);
+                    if let Err(e) = update_manager::reboot_system().await {
+                        error!("Reboot failed: {}", e);
+                    }
+                }
+            }
+            sleep(self.restart_delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_age_out_after_an_hour() {
+        let tracker = RestartTracker::default();
+        assert_eq!(tracker.record("sync"), 1);
+        tokio::time::advance(Duration::from_secs(1800)).await;
+        assert_eq!(tracker.record("sync"), 2);
+        assert_eq!(tracker.record("scheduler"), 1);
+
+        tokio::time::advance(Duration::from_secs(1801)).await;
+        assert_eq!(tracker.recent(), BTreeMap::from([("scheduler".to_string(), 1), ("sync".to_string(), 1)]));
+        tokio::time::advance(Duration::from_secs(1800)).await;
+        assert!(tracker.recent().is_empty());
+        assert_eq!(tracker.totals(), BTreeMap::from([("scheduler".to_string(), 1), ("sync".to_string(), 2)]));
+    }
+
+    #[tokio::test]
+    async fn only_tasks_above_the_threshold_alert() {
+        let tracker = RestartTracker::default();
+        for _ in 0..3 {
+            tracker.record("sync");
+        }
+        tracker.record("scheduler");
+        assert_eq!(tracker.alerting(3), None);
+        tracker.record("sync");
+        assert_eq!(tracker.alerting(3), Some(BTreeMap::from([("sync".to_string(), 4)])));
+        assert!(tracker.to_prometheus().contains("probe_task_restarts_total{task=\"sync\"} 4\n"));
+    }
+
+    #[test]
+    fn verdict_escalates_from_alert_to_reboot() {
+        assert_eq!(RestartVerdict::for_count(5, 5, 10), RestartVerdict::Restart);
+        assert_eq!(RestartVerdict::for_count(6, 5, 10), RestartVerdict::Alert);
+        assert_eq!(RestartVerdict::for_count(20, 5, 10), RestartVerdict::Alert);
+        assert_eq!(RestartVerdict::for_count(21, 5, 10), RestartVerdict::Reboot);
+    }
+
+    #[tokio::test]
+    async fn ended_and_panicking_tasks_are_restarted_and_counted() {
+        let tracker = RestartTracker::default();
+        let mut supervisor = Supervisor::new(tracker.clone(), 1, 100, ErrorReporter::new().0);
+        supervisor.restart_delay = Duration::from_millis(1);
+        let starts = Arc::new(AtomicUsize::new(0));
+
+        let counted = Arc::clone(&starts);
+        let supervised = tokio::spawn(supervisor.supervise("flaky", move || {
+            let start = counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match start {
+                    0 => Ok(()),
+                    1 => Err(anyhow::anyhow!("lost the connection")),
+                    2 => panic!("bug"),
+                    _ => std::future::pending().await,
+                }
+            }
+        }));
+
+        for _ in 0..100 {
+            if starts.load(Ordering::SeqCst) == 4 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(starts.load(Ordering::SeqCst), 4);
+        assert_eq!(tracker.recent(), BTreeMap::from([("flaky".to_string(), 3)]));
+        assert_eq!(tracker.alerting(1), Some(BTreeMap::from([("flaky".to_string(), 3)])));
+        supervised.abort();
+    }
+}
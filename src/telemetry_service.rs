@@ -0,0 +1,284 @@
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerLayer, CircuitBreakerService};
+use crate::command_executor::Command;
+use crate::compress;
+use crate::config::Config;
+use crate::error::ProbeError;
+use crate::telemetry_sync::{UploadFormat, UploadStats};
+use anyhow::Result;
+use bytes::Bytes;
+use log::{info, warn};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::time::{sleep, Duration, Instant, Sleep};
+use tower::retry::{Policy, Retry};
+use tower::timeout::error::Elapsed;
+use tower::timeout::Timeout;
+use tower::{BoxError, Service, ServiceBuilder};
+
+const INITIAL_BACKOFF_MS: u64 = 1000;
+const MAX_BACKOFF_MS: u64 = 60000;
+
+/// Upload service with a circuit breaker, retries and a per-attempt timeout
+pub type Uploader = CircuitBreakerService<Retry<RetryPolicy, Timeout<TelemetryService>>>;
+
+/// Stack `TelemetryService` behind the retry and timeout middleware configured
+/// by `upload_max_retries` and `upload_timeout_seconds`. An upload that still
+/// fails after its retries counts once against `breaker`.
+pub fn uploader(
+    client: reqwest::Client,
+    config: Arc<Config>,
+    format: UploadFormat,
+    stats: Arc<UploadStats>,
+    breaker: Arc<CircuitBreaker>,
+) -> Uploader {
+    ServiceBuilder::new()
+        .layer(CircuitBreakerLayer::new(breaker, is_retryable))
+        .retry(RetryPolicy::new(config.upload_max_retries))
+        .timeout(Duration::from_secs(config.upload_timeout_seconds))
+        .service(TelemetryService { client, config, format, stats })
+}
+
+/// An encoded upload request body and the headers describing it
+#[derive(Debug, Clone)]
+pub struct Payload {
+    pub request_id: String,
+    pub body: Bytes,
+    pub content_type: &'static str,
+    /// Set only when the body is compressed
+    pub content_encoding: Option<&'static str>,
+}
+
+/// One upload of an encoded log batch
+#[derive(Debug, Clone)]
+pub struct TelemetryRequest {
+    pub payload: Payload,
+    pub api_key: String,
+}
+
+/// Hub response to an upload. Older hubs answer with a bare command array,
+/// which is treated as accepting every uploaded entry.
+#[derive(Debug, Default, Deserialize)]
+pub struct TelemetryResponse {
+    #[serde(default)]
+    pub accepted: Option<usize>,
+    #[serde(default)]
+    pub total: Option<usize>,
+    #[serde(default)]
+    pub commands: Vec<Command>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TelemetryResponseBody {
+    Commands(Vec<Command>),
+    Full(TelemetryResponse),
+}
+
+impl From<TelemetryResponseBody> for TelemetryResponse {
+    fn from(body: TelemetryResponseBody) -> Self {
+        match body {
+            TelemetryResponseBody::Commands(commands) => TelemetryResponse {
+                accepted: None,
+                total: None,
+                commands,
+            },
+            TelemetryResponseBody::Full(response) => response,
+        }
+    }
+}
+
+fn deserialize_response(body: &[u8], format: UploadFormat) -> Result<TelemetryResponse> {
+    let body: TelemetryResponseBody = match format {
+        UploadFormat::Json => serde_json::from_slice(body)?,
+        UploadFormat::MessagePack => rmp_serde::from_slice(body)?,
+    };
+    Ok(body.into())
+}
+
+/// Posts upload requests to `{server_url}/update`
+#[derive(Clone)]
+pub struct TelemetryService {
+    client: reqwest::Client,
+    config: Arc<Config>,
+    format: UploadFormat,
+    stats: Arc<UploadStats>,
+}
+
+impl Service<TelemetryRequest> for TelemetryService {
+    type Response = TelemetryResponse;
+    type Error = ProbeError;
+    type Future = Pin<Box<dyn Future<Output = Result<TelemetryResponse, ProbeError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), ProbeError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: TelemetryRequest) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { service.send(request).await })
+    }
+}
+
+impl TelemetryService {
+    async fn send(self, request: TelemetryRequest) -> Result<TelemetryResponse, ProbeError> {
+        let config = &self.config;
+        let payload = request.payload;
+        let url = format!("{}/update", config.server_url);
+        let mut http_request = self
+            .client
+            .post(&url)
+            .header("Content-Type", payload.content_type)
+            .header("Content-Length", payload.body.len().to_string())
+            .header("Accept", payload.content_type)
+            .header("Accept-Encoding", compress::ACCEPT_ENCODING)
+            .header("X-Node-ID", config.node_id.to_string())
+            .header("X-Api-Key", &request.api_key)
+            .header("X-Request-ID", &payload.request_id);
+        if let Some(encoding) = payload.content_encoding {
+            http_request = http_request.header("Content-Encoding", encoding);
+        }
+
+        let size = payload.body.len();
+        let started = Instant::now();
+        let response = http_request.body(payload.body).send().await?;
+        let latency_avg = self.stats.record(started.elapsed(), size);
+        let threshold = Duration::from_millis(config.latency_warning_threshold_ms);
+        if !threshold.is_zero() && latency_avg > threshold {
+            warn!(
+                "Average upload latency is {}ms, over latency_warning_threshold_ms ({})",
+                latency_avg.as_millis(),
+                config.latency_warning_threshold_ms
+            );
+        }
+
+        let status = response.status();
+
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            return Err(ProbeError::AuthError(format!("hub answered {}, check api_key", status)));
+        }
+
+        if status == StatusCode::BAD_REQUEST {
+            warn!("Upload rejected with status: {}", status);
+            return Err(ProbeError::UploadRejected { status: status.as_u16() });
+        }
+
+        if !status.is_success() {
+            warn!("Upload failed with status: {}", status);
+            return Err(ProbeError::UploadFailed { status: status.as_u16() });
+        }
+
+        info!("Successfully uploaded telemetry");
+
+        // Parse response
+        let content_encoding = response
+            .headers()
+            .get(reqwest::header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let parsed = match response.bytes().await {
+            Ok(body) => {
+                compress::decompress_payload(&body, content_encoding.as_deref()).and_then(|body| deserialize_response(&body, self.format))
+            }
+            Err(e) => Err(e.into()),
+        };
+        match parsed {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                // Logs were delivered even though the response is unusable
+                warn!("Failed to parse response commands: {}. Logs considered delivered.", e);
+                Ok(TelemetryResponse::default())
+            }
+        }
+    }
+}
+
+/// Retries network errors, timeouts and 5xx answers with exponential backoff.
+/// Anything else, such as a 4xx, is returned right away.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    retries_left: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            retries_left: max_retries,
+            backoff: Duration::from_millis(INITIAL_BACKOFF_MS),
+        }
+    }
+}
+
+impl Policy<TelemetryRequest, TelemetryResponse, BoxError> for RetryPolicy {
+    type Future = Sleep;
+
+    fn retry(&mut self, _request: &mut TelemetryRequest, result: &mut Result<TelemetryResponse, BoxError>) -> Option<Sleep> {
+        let error = result.as_ref().err()?;
+        if self.retries_left == 0 || !is_retryable(error) {
+            return None;
+        }
+
+        self.retries_left -= 1;
+        let delay = self.backoff;
+        self.backoff = (self.backoff * 2).min(Duration::from_millis(MAX_BACKOFF_MS));
+        warn!("Telemetry upload attempt failed: {}. Retrying in {}ms...", error, delay.as_millis());
+        Some(sleep(delay))
+    }
+
+    fn clone_request(&mut self, request: &TelemetryRequest) -> Option<TelemetryRequest> {
+        Some(request.clone())
+    }
+}
+
+/// Network errors, timeouts and 5xx answers, i.e. a hub that is down or degraded
+fn is_retryable(error: &BoxError) -> bool {
+    if error.is::<Elapsed>() {
+        return true;
+    }
+    match error.downcast_ref::<ProbeError>() {
+        Some(ProbeError::HttpError(e)) => !e.is_builder(),
+        Some(ProbeError::UploadFailed { status }) => *status >= 500,
+        _ => false,
+    }
+}
+
+/// Turn a middleware error back into the `ProbeError` it wraps, if any
+pub fn upload_error(error: BoxError) -> anyhow::Error {
+    match error.downcast::<ProbeError>() {
+        Ok(e) => (*e).into(),
+        Err(e) => anyhow::anyhow!(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_decode_in_either_format_and_shape() {
+        let full = serde_json::json!({ "accepted": 3, "total": 5, "commands": [{ "command": "get_status" }] });
+        let bare = serde_json::json!([{ "command": "get_status", "parameters": { "verbose": true } }]);
+
+        for format in [UploadFormat::Json, UploadFormat::MessagePack] {
+            let encode = |value: &serde_json::Value| match format {
+                UploadFormat::Json => serde_json::to_vec(value).unwrap(),
+                UploadFormat::MessagePack => rmp_serde::to_vec_named(value).unwrap(),
+            };
+
+            let response = deserialize_response(&encode(&full), format).unwrap();
+            assert_eq!((response.accepted, response.total), (Some(3), Some(5)));
+            assert_eq!(response.commands[0].command, "get_status");
+
+            // Older hubs answer with only the commands
+            let response = deserialize_response(&encode(&bare), format).unwrap();
+            assert_eq!((response.accepted, response.total), (None, None));
+            assert_eq!(response.commands[0].parameters["verbose"], true);
+        }
+
+        assert!(deserialize_response(b"{\"accepted\": \"all\"}", UploadFormat::Json).is_err());
+    }
+}
@@ -1,110 +1,1111 @@
-use crate::command_executor::{self, Command};
+use crate::command_executor::{self, Command, CommandContext, CommandResult};
+use crate::compress::{self, CompressionFormat};
 use crate::config::Config;
+use crate::connectivity::{self, NetworkInterface};
+use crate::error::ProbeError;
+use crate::error_reporter::{self, ErrorEvent};
 use crate::log_entry::LogEntry;
-use crate::usb_manager::UsbHandle;
+use crate::rate_limit::CommandRateLimiter;
+use crate::telemetry_service::{self, Payload, TelemetryRequest, Uploader};
 use anyhow::Result;
+use bytes::Bytes;
+use chrono::Utc;
 use log::{debug, error, info, warn};
+use lru::LruCache;
 use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tokio::time::{sleep, Duration};
+use tokio::io::AsyncWriteExt;
+use tower::{Service, ServiceExt};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
 
-const INITIAL_BACKOFF_MS: u64 = 1000;
-const MAX_BACKOFF_MS: u64 = 60000;
+/// Weight of the newest sample in the upload rolling averages
+const UPLOAD_AVERAGE_ALPHA: f64 = 0.1;
+
+/// Rolling averages over upload requests, shared with `get_status`
+#[derive(Debug, Default)]
+pub struct UploadStats {
+    /// Exponential moving average of the upload request latency, in
+    /// microseconds; 0 until the first upload
+    pub latency_avg_us: AtomicU64,
+    /// Exponential moving average of the upload body size, in bytes
+    pub size_avg_bytes: AtomicU64,
+}
+
+impl UploadStats {
+    /// Fold one upload into the averages, returning the new average latency
+    pub fn record(&self, latency: Duration, size: usize) -> Duration {
+        let latency_us = moving_average(self.latency_avg_us.load(Ordering::Relaxed), latency.as_micros() as u64);
+        self.latency_avg_us.store(latency_us, Ordering::Relaxed);
+        let size_bytes = moving_average(self.size_avg_bytes.load(Ordering::Relaxed), size as u64);
+        self.size_avg_bytes.store(size_bytes, Ordering::Relaxed);
+        Duration::from_micros(latency_us)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "upload_latency_avg_ms": self.latency_avg_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            "upload_size_bytes_avg": self.size_avg_bytes.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Next exponential moving average value; the first sample seeds the average
+fn moving_average(average: u64, sample: u64) -> u64 {
+    if average == 0 {
+        return sample;
+    }
+    (UPLOAD_AVERAGE_ALPHA * sample as f64 + (1.0 - UPLOAD_AVERAGE_ALPHA) * average as f64).round() as u64
+}
+
+/// Facts about the probe itself, sent with every upload
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProbeInfo {
+    pub hostname: Option<String>,
+    pub network_interfaces: Vec<NetworkInterface>,
+    /// Tasks restarted more than `restart_alert_threshold` times within the last hour
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task_restarts: Option<BTreeMap<String, usize>>,
+}
+
+impl ProbeInfo {
+    /// Read the current values; not cached since addresses can change
+    pub fn collect(ctx: &CommandContext) -> Self {
+        Self {
+            hostname: hostname(),
+            network_interfaces: connectivity::network_interfaces(),
+            task_restarts: ctx.task_restarts.alerting(ctx.config.restart_alert_threshold),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    nix::unistd::gethostname().ok().and_then(|name| name.into_string().ok())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    None
+}
 
 #[derive(Debug, Serialize)]
-struct UploadRequest {
-    logs: Vec<LogEntry>,
+struct UploadRequest<'a> {
+    /// Same for every attempt at uploading the same batch
+    request_id: &'a str,
+    probe_info: &'a ProbeInfo,
+    logs: &'a [LogEntry],
+    /// Data returned by commands from earlier responses
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    command_results: &'a [CommandResult],
+    /// Task errors since the last delivered upload
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    error_events: &'a [ErrorEvent],
+}
+
+/// Wire format for upload requests and hub responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadFormat {
+    Json,
+    MessagePack,
+}
+
+impl UploadFormat {
+    fn parse(value: &str) -> Result<Self, ProbeError> {
+        match value.to_lowercase().as_str() {
+            "json" => Ok(UploadFormat::Json),
+            "msgpack" => Ok(UploadFormat::MessagePack),
+            other => Err(ProbeError::ConfigError(format!("Unknown upload_format '{}', expected json or msgpack", other))),
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            UploadFormat::Json => "application/json",
+            UploadFormat::MessagePack => "application/msgpack",
+        }
+    }
+}
+
+/// Serialize an upload request, returning the body and its content type
+fn serialize_payload(payload: &UploadRequest<'_>, format: UploadFormat) -> Result<(Bytes, &'static str)> {
+    let body = match format {
+        UploadFormat::Json => serde_json::to_vec(payload)?,
+        UploadFormat::MessagePack => rmp_serde::to_vec_named(payload)?,
+    };
+    Ok((Bytes::from(body), format.content_type()))
+}
+
+/// State carried by the sync task from one upload to the next
+struct SyncState {
+    format: UploadFormat,
+    compression: CompressionFormat,
+    /// Data returned by commands, waiting to be reported
+    command_results: Vec<CommandResult>,
+    /// Task errors taken from `PendingErrors`, waiting to be reported
+    error_events: Vec<ErrorEvent>,
+    /// Refreshed at the start of every upload
+    probe_info: ProbeInfo,
+    /// Hashes of recently delivered entries and when they were delivered
+    sent_entries: LruCache<u64, Instant>,
+    dedup_ttl: Duration,
+    /// Entries skipped because they had already been delivered
+    deduplicated_count: u64,
+    /// Hash and request ID of the last batch not fully accepted, reused when it is retried
+    last_batch: Option<(u64, String)>,
+    /// Uploads rejected with 401/403
+    auth_errors_total: u64,
+    /// Whether the startup hardware_info report has been queued
+    hardware_info_sent: bool,
+    command_limiter: CommandRateLimiter,
+}
+
+impl SyncState {
+    fn new(config: &Config) -> Result<Self> {
+        let capacity = NonZeroUsize::new(config.dedup_cache_size).unwrap_or(NonZeroUsize::MIN);
+        Ok(Self {
+            format: UploadFormat::parse(&config.upload_format)?,
+            compression: CompressionFormat::parse(&config.upload_compression)?,
+            command_results: Vec::new(),
+            error_events: Vec::new(),
+            probe_info: ProbeInfo::default(),
+            sent_entries: LruCache::new(capacity),
+            dedup_ttl: Duration::from_secs(config.dedup_ttl_seconds),
+            deduplicated_count: 0,
+            last_batch: None,
+            auth_errors_total: 0,
+            hardware_info_sent: false,
+            command_limiter: CommandRateLimiter::new(config.max_commands_per_upload, config.max_commands_per_hour),
+        })
+    }
+
+    /// Whether `entry` was delivered within the dedup TTL
+    fn already_sent(&mut self, entry: &LogEntry) -> bool {
+        let hash = entry_hash(entry);
+        match self.sent_entries.get(&hash) {
+            Some(sent_at) if sent_at.elapsed() < self.dedup_ttl => true,
+            Some(_) => {
+                self.sent_entries.pop(&hash);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Request ID for uploading `batch`: the previous one if this is a retry of
+    /// the same batch, a fresh UUID otherwise
+    fn request_id_for(&self, batch: &[LogEntry]) -> String {
+        match &self.last_batch {
+            Some((hash, request_id)) if *hash == batch_hash(batch) => request_id.clone(),
+            _ => uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    fn record_sent(&mut self, entries: &[LogEntry]) {
+        let now = Instant::now();
+        for entry in entries {
+            self.sent_entries.put(entry_hash(entry), now);
+        }
+    }
+}
+
+/// Content hash of a whole batch of entries
+fn batch_hash(batch: &[LogEntry]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for entry in batch {
+        entry_hash(entry).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Content hash of an entry's timestamp and message
+fn entry_hash(entry: &LogEntry) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    entry.timestamp.hash(&mut hasher);
+    entry.message.hash(&mut hasher);
+    hasher.finish()
 }
 
-pub async fn run(
-    config: Arc<Config>,
-    buffer: Arc<RwLock<Vec<LogEntry>>>,
-    upload_interval: Arc<RwLock<Duration>>,
-    filter_string: Arc<RwLock<String>>,
-    usb_handle: UsbHandle,
-) -> Result<()> {
-    let client = reqwest::Client::builder().use_rustls_tls().build()?;
+pub async fn run(ctx: CommandContext) -> Result<()> {
+    let client = connectivity::client_builder(&ctx.config)?.build()?;
 
-    let mut backoff_ms = INITIAL_BACKOFF_MS;
+    let mut state = SyncState::new(&ctx.config)?;
+    let mut uploader = telemetry_service::uploader(
+        client,
+        Arc::clone(&ctx.config),
+        state.format,
+        Arc::clone(&ctx.upload_stats),
+        Arc::clone(&ctx.circuit_breaker),
+    );
 
     loop {
-        let interval_duration = *upload_interval.read().await;
+        let (interval_duration, next_change) = {
+            let schedule = ctx.upload_schedule.read().await;
+            (Duration::from_secs(schedule.current_interval()), schedule.time_until_next_change())
+        };
 
-        sleep(interval_duration).await;
+        // Wake early at a schedule boundary so the new period takes effect right away
+        let mut wake_at = Instant::now() + interval_duration;
+        let mut at_boundary = false;
+        if let Some(boundary) = next_change {
+            if Instant::now() + boundary < wake_at {
+                wake_at = Instant::now() + boundary;
+                at_boundary = true;
+            }
+        }
 
-        match upload_telemetry(&client, &config, &buffer, &filter_string, &upload_interval, &usb_handle).await {
+        tokio::select! {
+            _ = sleep_until(wake_at) => {
+                if at_boundary {
+                    continue;
+                }
+            }
+            _ = ctx.upload_now.notified() => debug!("Upload requested before the interval elapsed"),
+        }
+
+        // Retries with backoff happen inside the uploader; after that the batch waits for the next interval
+        match upload_telemetry(&mut uploader, &ctx, &mut state).await {
             Ok(_) => {
-                backoff_ms = INITIAL_BACKOFF_MS;
+                *ctx.last_upload_at.write().await = Utc::now();
+                if ctx.config.send_hardware_info_on_startup && !state.hardware_info_sent {
+                    state.hardware_info_sent = true;
+                    state.command_results.push(command_executor::hardware_info_result().await);
+                    ctx.upload_now.notify_one();
+                }
+            }
+            Err(e) if matches!(e.downcast_ref::<ProbeError>(), Some(ProbeError::AuthError(_))) => {
+                // A wrong API key will not fix itself, so don't hammer the hub with retries
+                state.auth_errors_total += 1;
+                error!(
+                    "CRITICAL: Telemetry upload failed: {} ({} auth errors so far). Retrying in {}s...",
+                    e, state.auth_errors_total, ctx.config.api_key_error_retry_seconds
+                );
+                ctx.error_reporter.report("telemetry_sync", &e);
+                sleep(Duration::from_secs(ctx.config.api_key_error_retry_seconds)).await;
             }
             Err(e) => {
-                error!("Telemetry upload error: {}. Retrying in {}ms...", e, backoff_ms);
-                sleep(Duration::from_millis(backoff_ms)).await;
-                backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_MS);
+                error!("Telemetry upload error: {}. Retrying at the next upload interval", e);
+                ctx.error_reporter.report("telemetry_sync", &e);
             }
         }
     }
 }
 
-async fn upload_telemetry(
-    client: &reqwest::Client,
-    config: &Config,
-    buffer: &Arc<RwLock<Vec<LogEntry>>>,
-    filter_string: &Arc<RwLock<String>>,
-    upload_interval: &Arc<RwLock<Duration>>,
-    usb_handle: &UsbHandle,
-) -> Result<()> {
-    // Prepare request with buffered logs
-    let logs = {
-        let buf = buffer.read().await;
-        buf.clone()
+#[tracing::instrument(name = "telemetry_upload", skip_all, fields(batch_size = tracing::field::Empty))]
+async fn upload_telemetry(uploader: &mut Uploader, ctx: &CommandContext, state: &mut SyncState) -> Result<()> {
+    let config = &ctx.config;
+    let buffer = &ctx.buffer;
+
+    // Take the buffered logs out, so the collector never waits on the lock
+    // while the request is built and sent. Whatever is not delivered is put
+    // back ahead of the entries collected in the meantime.
+    let mut buffered: Vec<LogEntry> = {
+        let mut buf = buffer.write().await;
+        if let Some(max_age) = config.max_log_age_seconds {
+            let removed = buf.retain_recent(Duration::from_secs(max_age));
+            if removed > 0 {
+                info!("Discarded {} log entries older than {}s before upload", removed, max_age);
+            }
+        }
+        buf.drain().collect()
     };
+    let inspected_count = buffered.len();
+    state.probe_info = ProbeInfo::collect(ctx);
+    for event in ctx.pending_errors.take() {
+        error_reporter::merge_event(&mut state.error_events, event);
+    }
 
-    // Always upload, even with empty logs - hub response may contain commands
+    // Skip entries the hub already has, keeping each uploaded entry's buffer position
+    let (positions, logs): (Vec<usize>, Vec<LogEntry>) = buffered
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| !state.already_sent(entry))
+        .map(|(position, entry)| (position, entry.clone()))
+        .unzip();
+    tracing::Span::current().record("batch_size", logs.len());
+    let skipped = inspected_count - logs.len();
+    if skipped > 0 {
+        state.deduplicated_count += skipped as u64;
+        info!(
+            "Skipped {} already delivered log entries ({} total deduplicated)",
+            skipped, state.deduplicated_count
+        );
+    }
+
+    // Always upload, even with empty logs - hub response may contain commands.
+    // Bodies over max_payload_size_bytes are split into several requests.
     debug!("Uploading {} log entries to hub", logs.len());
 
-    let request_body = UploadRequest { logs };
+    let mut delivered = 0;
+    let mut commands = Vec::new();
+    let mut failure = None;
+    let mut rejected = Vec::new();
+
+    loop {
+        let remaining = &logs[delivered..];
+        // No early return: the taken entries must go back to the buffer
+        let (batch_len, payload) = match fit_payload(remaining, state, config.max_payload_size_bytes) {
+            Ok(fitted) => fitted,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        };
+        let batch = &remaining[..batch_len];
+        state.last_batch = Some((batch_hash(batch), payload.request_id.clone()));
+
+        debug!("Upload request ID {} for {} log entries", payload.request_id, batch_len);
+        // Read the key per request so a rotation applies from the next request on
+        let api_key = ctx.api_key.read().await.clone();
+        let accepted = match send_batch(uploader, batch, TelemetryRequest { payload, api_key }).await {
+            Ok(outcome) => {
+                state.command_results.clear();
+                state.error_events.clear();
+                commands.extend(state.command_limiter.admit(outcome.commands));
+                outcome.accepted
+            }
+            Err(e) => {
+                if matches!(e.downcast_ref::<ProbeError>(), Some(ProbeError::UploadRejected { .. })) {
+                    rejected = positions[delivered..delivered + batch_len].to_vec();
+                }
+                failure = Some(e);
+                break;
+            }
+        };
+
+        state.record_sent(&batch[..accepted]);
+        delivered += accepted;
+        if accepted == batch_len {
+            state.last_batch = None;
+        }
+
+        if accepted < batch_len || delivered == logs.len() {
+            break;
+        }
+    }
+
+    // Put back everything from the first entry not accepted on, for the next cycle
+    let mut delivered_count = 0;
+    if delivered > 0 || failure.is_none() {
+        delivered_count = positions.get(delivered).copied().unwrap_or(inspected_count);
+    }
+    let unsent = buffered.split_off(delivered_count);
+    let rejected: Vec<usize> = rejected.iter().map(|position| position - delivered_count).collect();
+    restore_unsent(ctx, unsent, &rejected).await;
+
+    // Execute commands
+    for command in commands {
+        match command_executor::execute_command(command, ctx).await {
+            Ok(result) if result.has_data() => state.command_results.push(result),
+            Ok(_) => {}
+            Err(e) => {
+                error!("Command execution error: {}", e);
+                ctx.error_reporter.report("telemetry_sync", &e);
+            }
+        }
+    }
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// What the hub did with one upload request
+struct BatchOutcome {
+    /// Leading entries of the batch that were accepted
+    accepted: usize,
+    commands: Vec<Command>,
+}
 
-    // Send request
-    let url = format!("{}/update", config.server_url);
-    let response = client
-        .post(&url)
-        .header("Content-Type", "application/json")
-        .header("X-Node-ID", config.node_id.to_string())
-        .header("X-Api-Key", &config.api_key)
-        .json(&request_body)
-        .send()
-        .await?;
+/// Serialize as many leading entries of `logs` as fit in `max_size` bytes,
+/// halving the batch until it fits. A single oversized entry is sent anyway.
+/// The size limit applies before compression.
+fn fit_payload(logs: &[LogEntry], state: &SyncState, max_size: usize) -> Result<(usize, Payload)> {
+    let mut batch_len = logs.len();
+    loop {
+        let request_id = state.request_id_for(&logs[..batch_len]);
+        let request = UploadRequest {
+            request_id: &request_id,
+            probe_info: &state.probe_info,
+            logs: &logs[..batch_len],
+            command_results: &state.command_results,
+            error_events: &state.error_events,
+        };
+        let (body, content_type) = serialize_payload(&request, state.format)?;
 
-    let status = response.status();
+        if body.len() <= max_size || batch_len <= 1 {
+            if body.len() > max_size {
+                warn!("Upload of a single log entry is {} bytes, over max_payload_size_bytes ({})", body.len(), max_size);
+            } else if batch_len < logs.len() {
+                debug!("Splitting upload: sending {} of {} log entries ({} bytes)", batch_len, logs.len(), body.len());
+            }
+            let (body, content_encoding) = match state.compression {
+                CompressionFormat::None => (body, None),
+                compression => {
+                    let (compressed, encoding) = compress::compress_payload(&body, compression)?;
+                    (Bytes::from(compressed), Some(encoding))
+                }
+            };
+            let payload = Payload {
+                request_id,
+                body,
+                content_type,
+                content_encoding,
+            };
+            return Ok((batch_len, payload));
+        }
 
-    if !status.is_success() {
-        warn!("Upload failed with status: {}", status);
-        return Err(anyhow::anyhow!("Non-success status: {}", status));
+        batch_len /= 2;
     }
+}
 
-    info!("Successfully uploaded telemetry");
+async fn send_batch(uploader: &mut Uploader, batch: &[LogEntry], request: TelemetryRequest) -> Result<BatchOutcome> {
+    let response = uploader
+        .ready()
+        .await
+        .map_err(telemetry_service::upload_error)?
+        .call(request)
+        .await
+        .map_err(telemetry_service::upload_error)?;
 
-    // Parse response commands
-    let commands: Vec<Command> = match response.json().await {
-        Ok(cmds) => cmds,
-        Err(e) => {
-            warn!("Failed to parse response commands: {}. Logs considered delivered.", e);
-            // Clear buffer anyway since logs were delivered
-            buffer.write().await.clear();
-            return Ok(());
+    let uploaded = batch.len();
+    let accepted = response.accepted.unwrap_or(uploaded).min(uploaded);
+    if accepted < uploaded {
+        warn!(
+            "Hub accepted only {} of {} log entries (reported total: {}), keeping the rest for the next upload",
+            accepted,
+            uploaded,
+            response.total.map_or("unknown".to_string(), |total| total.to_string())
+        );
+    }
+
+    Ok(BatchOutcome {
+        accepted,
+        commands: response.commands,
+    })
+}
+
+/// Put `unsent` entries back at the front of the buffer after an upload,
+/// counting a 400 against those at positions `rejected`, and discard entries
+/// rejected more than `max_entry_retries` times
+pub async fn restore_unsent(ctx: &CommandContext, unsent: Vec<LogEntry>, rejected: &[usize]) {
+    let discarded = {
+        let mut buf = ctx.buffer.write().await;
+        // Entries collected during the upload may have left no room for the oldest
+        let dropped = buf.restore(unsent);
+        if dropped > 0 {
+            warn!("Buffer filled up during the upload, dropped {} of the oldest undelivered entries", dropped);
         }
+        let indices: Vec<usize> = rejected.iter().filter_map(|position| position.checked_sub(dropped)).collect();
+        buf.record_rejected(&indices, ctx.config.max_entry_retries)
     };
+    if discarded.is_empty() {
+        return;
+    }
 
-    // Clear buffer after successful upload
-    buffer.write().await.clear();
+    for entry in &discarded {
+        warn!(
+            "Discarding log entry rejected {} times by the hub: {} {}",
+            entry.retry_count, entry.timestamp, entry.message
+        );
+    }
 
-    // Execute commands
-    for command in commands {
-        if let Err(e) = command_executor::execute_command(command, config, filter_string, upload_interval, usb_handle).await {
-            error!("Command execution error: {}", e);
+    if let Some(path) = &ctx.config.dead_letter_path {
+        if let Err(e) = write_dead_letters(path, &discarded).await {
+            error!("Failed to write {} discarded log entries to {:?}: {}", discarded.len(), path, e);
         }
     }
+}
+
+async fn write_dead_letters(path: &Path, entries: &[LogEntry]) -> Result<()> {
+    let mut lines = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut lines, entry)?;
+        lines.push(b'\n');
+    }
 
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    file.write_all(&lines).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::CircuitBreaker;
+
+    /// What the test hub saw of one request, and the status it answered with
+    struct HubRequest {
+        request_id: Option<String>,
+        content_type: Option<String>,
+        content_length: usize,
+        body: Vec<u8>,
+        status: &'static str,
+    }
+
+    /// Answer one HTTP request per status in `statuses`, reporting each request
+    async fn hub(statuses: &'static [&'static str]) -> (String, tokio::sync::mpsc::UnboundedReceiver<HubRequest>) {
+        hub_replying(statuses.iter().map(|status| (*status, "[]")).collect()).await
+    }
+
+    /// `hub`, answering each request with its own status and JSON body
+    async fn hub_replying(replies: Vec<(&'static str, &'static str)>) -> (String, tokio::sync::mpsc::UnboundedReceiver<HubRequest>) {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for (status, body) in replies {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut chunk = [0; 4096];
+                let head = loop {
+                    let n = socket.read(&mut chunk).await.unwrap();
+                    request.extend_from_slice(&chunk[..n]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break String::from_utf8_lossy(&request[..end]).to_lowercase();
+                    }
+                };
+                let header = |name: &str| {
+                    head.lines().find_map(|line| line.strip_prefix(name).map(|value| value.trim().to_string()))
+                };
+                let body_len: usize = header("content-length:").unwrap().parse().unwrap();
+                while request.len() < head.len() + 4 + body_len {
+                    let n = socket.read(&mut chunk).await.unwrap();
+                    request.extend_from_slice(&chunk[..n]);
+                }
+                let request_body = request[head.len() + 4..].to_vec();
+                tx.send(HubRequest {
+                    request_id: header("x-request-id:"),
+                    content_type: header("content-type:"),
+                    content_length: body_len,
+                    body: request_body,
+                    status,
+                })
+                .unwrap();
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    /// A context uploading to the hub at `url`, with `settings` added to the
+    /// config, and the sync state and uploader the sync loop would use
+    fn hub_context(url: &str, settings: &str) -> (CommandContext, SyncState, Uploader) {
+        let config: Config = toml::from_str(&format!(
+            "usb_port = \"/dev/null\"\nserver_url = \"{}\"\napi_key = \"test-key\"\nnode_id = 1\n\
+             node_firmware_url = \"{}/node\"\nprobe_firmware_url = \"{}/probe\"\nupload_max_retries = 0\n{}",
+            url, url, url, settings
+        ))
+        .unwrap();
+        let (ctx, _mock) = crate::command_executor::testing::context(config);
+        let state = SyncState::new(&ctx.config).unwrap();
+        let uploader = telemetry_service::uploader(
+            reqwest::Client::new(),
+            Arc::clone(&ctx.config),
+            state.format,
+            Arc::new(UploadStats::default()),
+            Arc::new(CircuitBreaker::new(0, Duration::from_secs(60))),
+        );
+        (ctx, state, uploader)
+    }
+
+    /// Buffer one entry per message
+    async fn buffer_messages(ctx: &CommandContext, messages: &[&str]) {
+        let mut buffer = ctx.buffer.write().await;
+        for message in messages {
+            buffer.push(LogEntry::new("2024-05-01T12:00:00Z".to_string(), message.to_string()));
+        }
+    }
+
+    /// Messages of the log entries in an upload request
+    fn uploaded(request: &HubRequest) -> Vec<String> {
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        body["logs"].as_array().unwrap().iter().map(|entry| entry["message"].as_str().unwrap().to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn entries_the_hub_already_has_are_not_uploaded_again() {
+        let (url, mut requests) = hub(&["200 OK", "200 OK"]).await;
+        let (ctx, mut state, mut uploader) = hub_context(&url, "");
+
+        buffer_messages(&ctx, &["[INFO] a", "[INFO] b"]).await;
+        upload_telemetry(&mut uploader, &ctx, &mut state).await.unwrap();
+        // The same entries turn up again, e.g. replayed from the archive
+        buffer_messages(&ctx, &["[INFO] a", "[INFO] b"]).await;
+        upload_telemetry(&mut uploader, &ctx, &mut state).await.unwrap();
+
+        assert_eq!(uploaded(&requests.recv().await.unwrap()), vec!["[INFO] a", "[INFO] b"]);
+        assert!(uploaded(&requests.recv().await.unwrap()).is_empty());
+        assert_eq!(state.deduplicated_count, 2);
+        assert!(ctx.buffer.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn entries_the_hub_did_not_accept_stay_buffered() {
+        let (url, mut requests) = hub_replying(vec![
+            ("200 OK", r#"{"accepted": 2, "total": 5, "commands": []}"#),
+            // Older hubs answer with just the commands, accepting everything
+            ("200 OK", "[]"),
+            ("200 OK", r#"{"accepted": 10, "commands": []}"#),
+        ])
+        .await;
+        let (ctx, mut state, mut uploader) = hub_context(&url, "");
+        let buffered = |ctx: &CommandContext| {
+            let buffer = ctx.buffer.try_read().unwrap();
+            buffer.iter().map(|entry| entry.message.clone()).collect::<Vec<_>>()
+        };
+
+        buffer_messages(&ctx, &["[INFO] a", "[INFO] b", "[INFO] c", "[INFO] d", "[INFO] e"]).await;
+        upload_telemetry(&mut uploader, &ctx, &mut state).await.unwrap();
+        assert_eq!(buffered(&ctx), vec!["[INFO] c", "[INFO] d", "[INFO] e"]);
+
+        upload_telemetry(&mut uploader, &ctx, &mut state).await.unwrap();
+        assert!(buffered(&ctx).is_empty());
+
+        // An accepted count above what was sent is capped
+        buffer_messages(&ctx, &["[INFO] f"]).await;
+        upload_telemetry(&mut uploader, &ctx, &mut state).await.unwrap();
+        assert!(buffered(&ctx).is_empty());
+
+        assert_eq!(uploaded(&requests.recv().await.unwrap()).len(), 5);
+        assert_eq!(uploaded(&requests.recv().await.unwrap()), vec!["[INFO] c", "[INFO] d", "[INFO] e"]);
+        assert_eq!(uploaded(&requests.recv().await.unwrap()), vec!["[INFO] f"]);
+        assert_eq!(ctx.buffer.read().await.total_dropped(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delivered_entries_become_eligible_again_after_the_ttl_or_eviction() {
+        let (_ctx, mut state, _uploader) = hub_context("http://127.0.0.1:9", "dedup_cache_size = 2\ndedup_ttl_seconds = 300\n");
+        let entry = |message: &str| LogEntry::new("2024-05-01T12:00:00Z".to_string(), message.to_string());
+        state.record_sent(&[entry("a"), entry("b")]);
+        assert!(state.already_sent(&entry("a")));
+        assert!(!state.already_sent(&entry("c")));
+
+        // Same message, different timestamp: a different entry
+        assert!(!state.already_sent(&LogEntry::new("2024-05-01T12:00:01Z".to_string(), "a".to_string())));
+
+        tokio::time::advance(Duration::from_secs(300)).await;
+        assert!(!state.already_sent(&entry("a")));
+
+        // Only the most recent dedup_cache_size entries are remembered
+        state.record_sent(&[entry("a"), entry("b"), entry("c")]);
+        assert!(!state.already_sent(&entry("a")));
+        assert!(state.already_sent(&entry("b")) && state.already_sent(&entry("c")));
+    }
+
+    #[tokio::test]
+    async fn oversized_uploads_are_split_with_a_content_length_each() {
+        let (url, mut requests) = hub(&["200 OK"; 12]).await;
+        let (ctx, mut state, mut uploader) = hub_context(&url, "max_payload_size_bytes = 1200\n");
+        let messages: Vec<String> = (0..12).map(|i| format!("[INFO] reading {:02} {}", i, "x".repeat(100))).collect();
+        buffer_messages(&ctx, &messages.iter().map(String::as_str).collect::<Vec<_>>()).await;
+
+        upload_telemetry(&mut uploader, &ctx, &mut state).await.unwrap();
+        assert!(ctx.buffer.read().await.is_empty());
+
+        let mut delivered = Vec::new();
+        let mut request_count = 0;
+        while let Ok(request) = requests.try_recv() {
+            request_count += 1;
+            assert_eq!(request.content_length, request.body.len());
+            assert!(request.body.len() <= 1200, "{} byte request", request.body.len());
+            assert_eq!(request.content_type.as_deref(), Some("application/json"));
+            delivered.extend(uploaded(&request));
+        }
+        assert!(request_count > 1);
+        assert_eq!(delivered, messages);
+    }
+
+    #[test]
+    fn upload_averages_converge_on_a_new_level() {
+        let stats = UploadStats::default();
+        assert_eq!(stats.record(Duration::from_millis(100), 1000), Duration::from_millis(100));
+        assert_eq!(stats.record(Duration::from_millis(200), 2000), Duration::from_millis(110));
+        assert_eq!(stats.size_avg_bytes.load(Ordering::Relaxed), 1100);
+
+        let mut previous = Duration::from_millis(110);
+        for _ in 0..60 {
+            let average = stats.record(Duration::from_millis(500), 2000);
+            assert!(average > previous && average <= Duration::from_millis(500));
+            previous = average;
+        }
+        // 500 - 390 * 0.9^61 ms
+        assert!(previous >= Duration::from_millis(499), "{:?}", previous);
+        // Rounding to whole bytes settles the size just short of the samples
+        let size = stats.to_json()["upload_size_bytes_avg"].as_u64().unwrap();
+        assert!((1995..=2000).contains(&size), "{}", size);
+
+        assert_eq!(moving_average(0, 42), 42);
+        assert_eq!(moving_average(1000, 1000), 1000);
+    }
+
+    #[test]
+    fn json_and_msgpack_bodies_carry_the_same_request() {
+        let probe_info = ProbeInfo { hostname: Some("probe-1".to_string()), ..ProbeInfo::default() };
+        let mut entry = LogEntry::new("2024-05-01T12:00:00Z".to_string(), "[WARN] sensor 2 slow".to_string());
+        entry.retry_count = 1;
+        let logs = [entry];
+        let request = UploadRequest {
+            request_id: "7d5c",
+            probe_info: &probe_info,
+            logs: &logs,
+            command_results: &[],
+            error_events: &[],
+        };
+
+        let (json, json_type) = serialize_payload(&request, UploadFormat::Json).unwrap();
+        let (msgpack, msgpack_type) = serialize_payload(&request, UploadFormat::MessagePack).unwrap();
+        assert_eq!((json_type, msgpack_type), ("application/json", "application/msgpack"));
+        let from_json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        let from_msgpack: serde_json::Value = rmp_serde::from_slice(&msgpack).unwrap();
+        assert_eq!(from_json, from_msgpack);
+        assert_eq!(from_json["logs"][0]["message"], "[WARN] sensor 2 slow");
+        assert!(from_json.get("command_results").is_none());
+        assert!(msgpack.len() < json.len());
+
+        assert_eq!(UploadFormat::parse("MsgPack").unwrap(), UploadFormat::MessagePack);
+        assert!(UploadFormat::parse("xml").is_err());
+    }
+
+    #[tokio::test]
+    async fn retries_of_a_batch_reuse_the_request_id_header() {
+        let (url, mut request_ids) = hub(&["500 Internal Server Error", "200 OK"]).await;
+        let config: Config = toml::from_str(&format!(
+            "usb_port = \"/dev/null\"\nserver_url = \"{}\"\napi_key = \"test-key\"\nnode_id = 1\n\
+             node_firmware_url = \"{}/node\"\nprobe_firmware_url = \"{}/probe\"\nupload_max_retries = 0\n",
+            url, url, url
+        ))
+        .unwrap();
+        let mut state = SyncState::new(&config).unwrap();
+        let mut uploader = telemetry_service::uploader(
+            reqwest::Client::new(),
+            Arc::new(config),
+            state.format,
+            Arc::new(UploadStats::default()),
+            Arc::new(CircuitBreaker::new(0, Duration::from_secs(60))),
+        );
+        let logs = vec![LogEntry::new("2024-05-01T12:00:00Z".to_string(), "[INFO] hello".to_string())];
+
+        let mut sent = Vec::new();
+        for _ in 0..2 {
+            // As the sync loop does: remember the batch until it is fully accepted
+            let (batch_len, payload) = fit_payload(&logs, &state, usize::MAX).unwrap();
+            state.last_batch = Some((batch_hash(&logs[..batch_len]), payload.request_id.clone()));
+            sent.push(payload.request_id.clone());
+            let _ = send_batch(&mut uploader, &logs, TelemetryRequest { payload, api_key: "test-key".to_string() }).await;
+        }
+
+        assert_eq!(sent[0], sent[1]);
+        assert!(uuid::Uuid::parse_str(&sent[0]).is_ok());
+        assert_eq!(request_ids.recv().await.unwrap().request_id.as_deref(), Some(sent[0].as_str()));
+        assert_eq!(request_ids.recv().await.unwrap().request_id.as_deref(), Some(sent[0].as_str()));
+    }
+
+    #[test]
+    fn a_different_batch_gets_a_fresh_request_id() {
+        let config: Config = toml::from_str(
+            "usb_port = \"/dev/null\"\nserver_url = \"http://127.0.0.1:9\"\napi_key = \"k\"\nnode_id = 1\n\
+             node_firmware_url = \"http://127.0.0.1:9/node\"\nprobe_firmware_url = \"http://127.0.0.1:9/probe\"\n",
+        )
+        .unwrap();
+        let mut state = SyncState::new(&config).unwrap();
+        let first = [LogEntry::new("2024-05-01T12:00:00Z".to_string(), "a".to_string())];
+        let second = [LogEntry::new("2024-05-01T12:00:01Z".to_string(), "b".to_string())];
+
+        let request_id = state.request_id_for(&first);
+        state.last_batch = Some((batch_hash(&first), request_id.clone()));
+
+        assert_eq!(state.request_id_for(&first), request_id);
+        assert_ne!(state.request_id_for(&second), request_id);
+        state.last_batch = None;
+        assert_ne!(state.request_id_for(&first), request_id);
+    }
+
+    /// Node firmware server offering version 99, whose download stalls
+    /// halfway until `release` fires
+    async fn stalling_firmware_server(release: tokio::sync::oneshot::Receiver<()>) -> String {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut release = Some(release);
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 4096];
+                let n = socket.read(&mut request).await.unwrap();
+                let head = String::from_utf8_lossy(&request[..n]).to_string();
+                if head.contains("version.json") {
+                    let body = r#"{"version":99,"crc32":"00000000"}"#;
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                    continue;
+                }
+                socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\nUF2\n").await.unwrap();
+                if let Some(release) = release.take() {
+                    let _ = release.await;
+                }
+                let _ = socket.write_all(b"UF2\n").await;
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn hub_can_cancel_the_update_it_triggered() {
+        use crate::command_executor::testing;
+        use crate::update_state::UpdateState;
+
+        let (url, _request_ids) = hub_replying(vec![
+            ("200 OK", r#"[{"command":"update_node"}]"#),
+            ("200 OK", r#"[{"command":"cancel_update"}]"#),
+        ])
+        .await;
+        let (release, released) = tokio::sync::oneshot::channel();
+        let firmware_url = stalling_firmware_server(released).await;
+        let config: Config = toml::from_str(&format!(
+            "usb_port = \"/dev/null\"\nserver_url = \"{}\"\napi_key = \"test-key\"\nnode_id = 1\n\
+             node_firmware_url = \"{}\"\nprobe_firmware_url = \"{}/probe\"\nupload_max_retries = 0\n",
+            url, firmware_url, firmware_url
+        ))
+        .unwrap();
+        let (ctx, mock) = testing::context(config);
+        let mut state = SyncState::new(&ctx.config).unwrap();
+        let mut uploader = telemetry_service::uploader(
+            reqwest::Client::new(),
+            Arc::clone(&ctx.config),
+            state.format,
+            Arc::new(UploadStats::default()),
+            Arc::new(CircuitBreaker::new(0, Duration::from_secs(60))),
+        );
+        let upload = |message: &'static str| {
+            let ctx = ctx.clone();
+            async move { ctx.buffer.write().await.push(LogEntry::new("2024-05-01T12:00:00Z".to_string(), message.to_string())) }
+        };
+
+        // The update runs on in the background, so the upload cycle returns
+        // and the next one can deliver the cancel
+        upload("[INFO] first").await;
+        tokio::time::timeout(Duration::from_secs(5), upload_telemetry(&mut uploader, &ctx, &mut state)).await.unwrap().unwrap();
+        while ctx.update_state.state() != UpdateState::Downloading {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        upload("[INFO] second").await;
+        tokio::time::timeout(Duration::from_secs(5), upload_telemetry(&mut uploader, &ctx, &mut state)).await.unwrap().unwrap();
+        let _ = release.send(());
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !matches!(ctx.update_state.state(), UpdateState::Cancelled { .. }) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(mock.sent_commands().iter().any(|command| command.trim_end() == "/BS_CANCEL_"));
+        // The lock is released with the update
+        assert!(ctx.command_lock.try_lock().is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn collection_continues_during_uploads_without_losing_entries() {
+        const ENTRIES: usize = 2000;
+        // Every third upload fails and its entries go back to the buffer
+        let replies = (0..400).map(|i| if i % 3 == 2 { ("500 Internal Server Error", "[]") } else { ("200 OK", "[]") }).collect();
+        let (url, mut requests) = hub_replying(replies).await;
+        let config: Config = toml::from_str(&format!(
+            "usb_port = \"/dev/null\"\nserver_url = \"{}\"\napi_key = \"test-key\"\nnode_id = 1\n\
+             node_firmware_url = \"{}/node\"\nprobe_firmware_url = \"{}/probe\"\nupload_max_retries = 0\n",
+            url, url, url
+        ))
+        .unwrap();
+        let (ctx, _mock) = crate::command_executor::testing::context(config);
+        let mut state = SyncState::new(&ctx.config).unwrap();
+        let mut uploader = telemetry_service::uploader(
+            reqwest::Client::new(),
+            Arc::clone(&ctx.config),
+            state.format,
+            Arc::new(UploadStats::default()),
+            Arc::new(CircuitBreaker::new(0, Duration::from_secs(60))),
+        );
+
+        let buffer = Arc::clone(&ctx.buffer);
+        let collector = tokio::spawn(async move {
+            for i in 0..ENTRIES {
+                let entry = LogEntry::new("2024-05-01T12:00:00Z".to_string(), format!("[INFO] {}", i));
+                buffer.write().await.push(entry);
+                if i % 10 == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+        loop {
+            let done = collector.is_finished();
+            let _ = upload_telemetry(&mut uploader, &ctx, &mut state).await;
+            if done && ctx.buffer.read().await.is_empty() {
+                break;
+            }
+        }
+
+        let mut delivered = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            if request.status == "200 OK" {
+                let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                delivered.extend(body["logs"].as_array().unwrap().iter().map(|entry| entry["message"].as_str().unwrap().to_string()));
+            }
+        }
+        // Each entry was delivered exactly once, in collection order
+        let expected: Vec<String> = (0..ENTRIES).map(|i| format!("[INFO] {}", i)).collect();
+        assert_eq!(delivered, expected);
+        assert_eq!(ctx.buffer.read().await.total_dropped(), 0);
+    }
+
+    /// A PTY node feeding a real `UsbManager` and collector into the buffer of
+    /// a context uploading to `hub_url`, with `settings` added to the config
+    struct PtyPipeline {
+        node: crate::usb_manager::testing::pty_serial::PtyHarness,
+        ctx: CommandContext,
+        usb_stats: Arc<crate::usb_manager::UsbStats>,
+        state: SyncState,
+        uploader: Uploader,
+    }
+
+    impl PtyPipeline {
+        fn start(hub_url: &str, settings: &str) -> Self {
+            use crate::usb_collector::{self, CollectorSettings};
+            use crate::usb_manager::testing::pty_serial::PtyHarness;
+            use crate::usb_manager::{UsbHandle, UsbManager, UsbProtocol, UsbStats};
+
+            let (node, path) = PtyHarness::new();
+            let config: Config = toml::from_str(&format!(
+                "usb_port = {:?}\nserver_url = \"{}\"\napi_key = \"test-key\"\nnode_id = 1\n\
+                 node_firmware_url = \"{}/node\"\nprobe_firmware_url = \"{}/probe\"\n{}",
+                path, hub_url, hub_url, hub_url, settings
+            ))
+            .unwrap();
+            let (mut ctx, _mock) = crate::command_executor::testing::context(config);
+
+            let usb_stats = Arc::new(UsbStats::default());
+            let (command_tx, command_rx) = tokio::sync::mpsc::channel(8);
+            let (message_tx, message_rx) = tokio::sync::mpsc::channel(64);
+            ctx.usb_handle = UsbHandle::new(command_tx, Arc::clone(&usb_stats), 8);
+            let manager = UsbManager::new(ctx.config.usb_port.clone(), command_rx, message_tx, Arc::clone(&usb_stats), UsbProtocol::Line);
+            tokio::spawn(manager.run());
+            let settings = CollectorSettings {
+                filter_string: Arc::clone(&ctx.filter_string),
+                timezone: Arc::clone(&ctx.timezone),
+                node_log_output: Arc::clone(&ctx.node_log_output),
+            };
+            tokio::spawn(usb_collector::run(
+                Arc::clone(&ctx.config),
+                Arc::clone(&ctx.buffer),
+                settings,
+                message_rx,
+                ctx.log_feed.clone(),
+                ctx.raw_lines.clone(),
+                Arc::clone(&usb_stats),
+            ));
+
+            let state = SyncState::new(&ctx.config).unwrap();
+            let uploader = telemetry_service::uploader(
+                reqwest::Client::new(),
+                Arc::clone(&ctx.config),
+                state.format,
+                Arc::new(UploadStats::default()),
+                Arc::new(CircuitBreaker::new(0, Duration::from_secs(60))),
+            );
+            Self { node, ctx, usb_stats, state, uploader }
+        }
+
+        /// Wait until the last buffered entry is `message`
+        async fn wait_for(&self, message: &str) {
+            for _ in 0..500 {
+                if self.ctx.buffer.read().await.iter().last().is_some_and(|entry| entry.message == message) {
+                    return;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+            let buffered: Vec<String> = self.ctx.buffer.read().await.iter().map(|entry| entry.message.clone()).collect();
+            panic!("{:?} was not buffered, buffer holds {:?}", message, buffered);
+        }
+
+        /// Upload the buffer and return the messages the hub received
+        async fn upload(&mut self, requests: &mut tokio::sync::mpsc::UnboundedReceiver<HubRequest>) -> Vec<String> {
+            upload_telemetry(&mut self.uploader, &self.ctx, &mut self.state).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&requests.recv().await.unwrap().body).unwrap();
+            body["logs"].as_array().unwrap().iter().map(|entry| entry["message"].as_str().unwrap().to_string()).collect()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pty_lines_are_filtered_before_upload() {
+        let (url, mut requests) = hub(&["200 OK"]).await;
+        let mut pipeline = PtyPipeline::start(&url, "");
+        *pipeline.ctx.filter_string.write().await = "sensor".to_string();
+
+        for line in ["[INFO] sensor 1 ok", "[INFO] radio idle", "[WARN] sensor 2 slow", "[DEBUG] tick"] {
+            pipeline.node.emit_line(line);
+        }
+        pipeline.wait_for("[WARN] sensor 2 slow").await;
+
+        assert_eq!(pipeline.upload(&mut requests).await, vec!["[INFO] sensor 1 ok", "[WARN] sensor 2 slow"]);
+        assert!(pipeline.ctx.buffer.read().await.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pty_collection_resumes_after_a_reconnect() {
+        let (url, mut requests) = hub(&["200 OK"]).await;
+        let mut pipeline = PtyPipeline::start(&url, "suppress_duplicates = true\n");
+
+        for _ in 0..3 {
+            pipeline.node.emit_line("[WARN] low battery");
+        }
+        pipeline.node.emit_line("[INFO] mark");
+        pipeline.wait_for("[INFO] mark").await;
+
+        // The run of repeats is summarised when the link drops, and lines
+        // sent after the manager reconnects are collected as before
+        let mut raw_lines = pipeline.ctx.raw_lines.subscribe();
+        pipeline.node.emit_line("[WARN] low battery");
+        pipeline.node.emit_line("[WARN] low battery");
+        for _ in 0..2 {
+            tokio::time::timeout(Duration::from_secs(5), raw_lines.recv()).await.unwrap().unwrap();
+        }
+        pipeline.ctx.usb_handle.simulate_disconnect().await.unwrap();
+        pipeline.wait_for("[INFO] previous message repeated 1 times").await;
+        for _ in 0..500 {
+            if pipeline.usb_stats.connection_attempts_total.load(Ordering::Relaxed) == 2 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        pipeline.node.emit_line("[INFO] back");
+        pipeline.wait_for("[INFO] back").await;
+
+        assert_eq!(
+            pipeline.upload(&mut requests).await,
+            vec![
+                "[WARN] low battery",
+                "[INFO] previous message repeated 2 times",
+                "[INFO] mark",
+                "[WARN] low battery",
+                "[INFO] previous message repeated 1 times",
+                "[INFO] back",
+            ]
+        );
+        assert_eq!(pipeline.usb_stats.connection_attempts_total.load(Ordering::Relaxed), 2);
+    }
+}
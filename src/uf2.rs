@@ -0,0 +1,217 @@
+use crate::error::ProbeError;
+use std::fmt;
+
+const BLOCK_SIZE: usize = 512;
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+const FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+
+const FAMILY_RP2040: u32 = 0xe48b_ff56;
+const FAMILY_RP2350: u32 = 0xe48b_ff59;
+
+/// Chip family a UF2 image is built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Uf2Target {
+    RP2040,
+    RP2350,
+    Unknown(u32),
+}
+
+impl From<u32> for Uf2Target {
+    fn from(family_id: u32) -> Self {
+        match family_id {
+            FAMILY_RP2040 => Uf2Target::RP2040,
+            FAMILY_RP2350 => Uf2Target::RP2350,
+            other => Uf2Target::Unknown(other),
+        }
+    }
+}
+
+impl fmt::Display for Uf2Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Uf2Target::RP2040 => write!(f, "rp2040"),
+            Uf2Target::RP2350 => write!(f, "rp2350"),
+            Uf2Target::Unknown(id) => write!(f, "unknown(0x{:08x})", id),
+        }
+    }
+}
+
+/// What `validate` learned about a UF2 image
+#[derive(Debug, Clone)]
+pub struct Uf2Metadata {
+    /// Family of the first block that declares one
+    pub target: Option<Uf2Target>,
+    pub block_count: usize,
+}
+
+/// Check that `data` is a well-formed UF2 image and report its target family
+pub fn validate(data: &[u8]) -> Result<Uf2Metadata, ProbeError> {
+    let mut validator = Uf2Validator::new();
+    validator.update(data)?;
+    validator.finish()
+}
+
+/// `validate` for an image read in pieces of any size, holding at most one block
+#[derive(Debug, Default)]
+pub struct Uf2Validator {
+    /// Start of the block not yet complete
+    partial: Vec<u8>,
+    block_count: usize,
+    target: Option<Uf2Target>,
+}
+
+impl Uf2Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check the blocks `data` completes
+    pub fn update(&mut self, mut data: &[u8]) -> Result<(), ProbeError> {
+        while !data.is_empty() {
+            let take = (BLOCK_SIZE - self.partial.len()).min(data.len());
+            self.partial.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.partial.len() == BLOCK_SIZE {
+                self.check_block()?;
+                self.partial.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if the image was empty or ended in the middle of a block
+    pub fn finish(self) -> Result<Uf2Metadata, ProbeError> {
+        if self.block_count == 0 || !self.partial.is_empty() {
+            return Err(ProbeError::FirmwareError(format!(
+                "UF2 size {} is not a non-zero multiple of {} bytes",
+                self.block_count * BLOCK_SIZE + self.partial.len(),
+                BLOCK_SIZE
+            )));
+        }
+
+        Ok(Uf2Metadata {
+            target: self.target,
+            block_count: self.block_count,
+        })
+    }
+
+    fn check_block(&mut self) -> Result<(), ProbeError> {
+        let block = &self.partial;
+        let word = |offset: usize| u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+
+        if word(0) != MAGIC_START0 || word(4) != MAGIC_START1 || word(BLOCK_SIZE - 4) != MAGIC_END {
+            return Err(ProbeError::FirmwareError(format!("UF2 block {} has invalid magic", self.block_count)));
+        }
+
+        if self.target.is_none() && word(8) & FLAG_FAMILY_ID_PRESENT != 0 {
+            self.target = Some(Uf2Target::from(word(28)));
+        }
+
+        self.block_count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(family: Option<u32>) -> [u8; BLOCK_SIZE] {
+        let mut block = [0u8; BLOCK_SIZE];
+        block[0..4].copy_from_slice(&MAGIC_START0.to_le_bytes());
+        block[4..8].copy_from_slice(&MAGIC_START1.to_le_bytes());
+        if let Some(family) = family {
+            block[8..12].copy_from_slice(&FLAG_FAMILY_ID_PRESENT.to_le_bytes());
+            block[28..32].copy_from_slice(&family.to_le_bytes());
+        }
+        block[BLOCK_SIZE - 4..].copy_from_slice(&MAGIC_END.to_le_bytes());
+        block
+    }
+
+    fn image(blocks: &[[u8; BLOCK_SIZE]]) -> Vec<u8> {
+        blocks.concat()
+    }
+
+    #[test]
+    fn valid_image_reports_blocks_and_target() {
+        let data = image(&[block(None), block(Some(FAMILY_RP2040)), block(Some(FAMILY_RP2350))]);
+
+        let metadata = validate(&data).unwrap();
+
+        assert_eq!(metadata.block_count, 3);
+        assert_eq!(metadata.target, Some(Uf2Target::RP2040));
+    }
+
+    #[test]
+    fn image_without_family_has_no_target() {
+        let metadata = validate(&image(&[block(None)])).unwrap();
+
+        assert_eq!(metadata.target, None);
+    }
+
+    #[test]
+    fn unknown_family_is_kept() {
+        let metadata = validate(&image(&[block(Some(0x1234_5678))])).unwrap();
+
+        assert_eq!(metadata.target, Some(Uf2Target::Unknown(0x1234_5678)));
+        assert_eq!(metadata.target.unwrap().to_string(), "unknown(0x12345678)");
+    }
+
+    #[test]
+    fn empty_image_is_rejected() {
+        assert!(validate(&[]).is_err());
+    }
+
+    #[test]
+    fn truncated_image_is_rejected() {
+        let mut data = image(&[block(None), block(None)]);
+        data.truncate(BLOCK_SIZE + 100);
+
+        let error = validate(&data).unwrap_err().to_string();
+
+        assert!(error.contains("612"), "{}", error);
+    }
+
+    #[test]
+    fn misaligned_image_is_rejected() {
+        // A stray byte in front shifts every block off its 512-byte boundary
+        let mut data = vec![0u8];
+        data.extend(image(&[block(None), block(None)]));
+        data.truncate(2 * BLOCK_SIZE);
+
+        let error = validate(&data).unwrap_err().to_string();
+
+        assert!(error.contains("block 0 has invalid magic"), "{}", error);
+    }
+
+    #[test]
+    fn bad_magic_is_reported_with_its_block() {
+        for offset in [0, 4, BLOCK_SIZE - 4] {
+            let mut bad = block(None);
+            bad[offset] ^= 0xff;
+            let data = image(&[block(None), bad]);
+
+            let error = validate(&data).unwrap_err().to_string();
+
+            assert!(error.contains("block 1 has invalid magic"), "offset {}: {}", offset, error);
+        }
+    }
+
+    #[test]
+    fn validator_accepts_any_piece_size() {
+        let data = image(&[block(Some(FAMILY_RP2350)), block(None), block(None)]);
+
+        for piece in [1, 7, 511, 512, 513, 1000] {
+            let mut validator = Uf2Validator::new();
+            for chunk in data.chunks(piece) {
+                validator.update(chunk).unwrap();
+            }
+            let metadata = validator.finish().unwrap();
+
+            assert_eq!(metadata.block_count, 3, "piece size {}", piece);
+            assert_eq!(metadata.target, Some(Uf2Target::RP2350));
+        }
+    }
+}
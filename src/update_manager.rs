@@ -1,174 +1,452 @@
 use crate::config::Config;
+use crate::connectivity;
+use crate::error::{self, FlashStage, ProbeError};
+use crate::error_reporter::ErrorReporter;
+use crate::rate_limit::RateLimitedStream;
+use crate::uf2;
+use crate::update_state::{UpdateEvent, UpdateTracker};
 use crate::usb_manager::UsbHandle;
 use anyhow::Result;
 use log::{debug, error, info, warn};
-use serde::Deserialize;
-use std::error::Error;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
-use tokio::time::{sleep, Duration};
+use tokio::sync::oneshot;
+use tokio::time::{sleep, timeout_at, Duration, Instant};
 
 const CHECK_INTERVAL_SECONDS: u64 = 3600; // Check every hour
-const DEPLOYED_DIR: &str = "node_firmware";
+pub const DEPLOYED_DIR: &str = "node_firmware";
+/// Prefix of the node's reply to `/VQ`
+pub const NODE_VERSION_PREFIX: &str = "VERSION:";
+const NODE_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Per-request timeout of `check_firmware_endpoint`
+const ENDPOINT_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Download progress is logged each time it advances this many percent
+const DOWNLOAD_PROGRESS_STEP: u64 = 10;
+/// Bytes read at a time when decompressing and validating a node firmware download
+const IMAGE_CHUNK_SIZE: usize = 64 * 1024;
+/// Command line flags that run once and are not passed on when re-executing
+const ONE_SHOT_FLAGS: &[&str] = &["--register", "--output-env", "--list-serial-ports"];
 
 #[derive(Debug, Deserialize)]
 struct VersionInfo {
     version: u32,
+    /// CRC32 of the file as downloaded (i.e. of the compressed bytes when `compressed`)
     crc32: String,
+    /// Whether the node firmware file is gzip-compressed
+    #[serde(default)]
+    compressed: Option<bool>,
+    /// Node firmware file name, overriding `moonblokz_node_<version>.uf2`
+    #[serde(default)]
+    filename: Option<String>,
+    /// SHA-256 of the file as downloaded, checked when present
+    #[serde(default)]
+    sha256: Option<String>,
+    /// Size of the file as downloaded. The `Content-Length` header is used
+    /// when absent.
+    #[serde(default)]
+    firmware_size_bytes: Option<u64>,
 }
 
-pub async fn run_node_update(config: Arc<Config>, usb_handle: UsbHandle) -> Result<()> {
+/// Contents of `bundle_version.json`
+#[derive(Debug, Deserialize)]
+struct BundleVersionInfo {
+    bundle_version: u32,
+    node_version: u32,
+    probe_version: u32,
+    /// CRC32 of `bundle_<bundle_version>.tar.gz`
+    crc32: String,
+    /// CRC32 of the node `.uf2` inside the archive
+    #[serde(default)]
+    node_crc32: Option<String>,
+    /// CRC32 of the probe binary inside the archive
+    #[serde(default)]
+    probe_crc32: Option<String>,
+}
+
+pub async fn run_node_update(
+    config: Arc<Config>,
+    client: reqwest::Client,
+    usb_handle: UsbHandle,
+    tracker: UpdateTracker,
+    reporter: ErrorReporter,
+) -> Result<()> {
     // Check on startup
-    if let Err(e) = check_and_update_node_firmware(&config, &usb_handle).await {
-        error!("Node firmware update check failed: {}", e);
+    if let Err(e) = check_and_update_node_firmware(&config, &client, &usb_handle, &tracker).await {
+        error!("Node firmware update check failed [{}]: {}", error::error_code(&e), e);
+        reporter.report("node_update", &e);
     }
 
     loop {
         sleep(Duration::from_secs(CHECK_INTERVAL_SECONDS)).await;
 
-        if let Err(e) = check_and_update_node_firmware(&config, &usb_handle).await {
-            error!("Node firmware update check failed: {}", e);
+        if let Err(e) = check_and_update_node_firmware(&config, &client, &usb_handle, &tracker).await {
+            error!("Node firmware update check failed [{}]: {}", error::error_code(&e), e);
+            reporter.report("node_update", &e);
         }
     }
 }
 
-pub async fn run_probe_update(config: Arc<Config>) -> Result<()> {
+pub async fn run_probe_update(config: Arc<Config>, client: reqwest::Client, reporter: ErrorReporter) -> Result<()> {
     // Check on startup
-    if let Err(e) = check_and_update_probe(&config).await {
-        error!("Probe update check failed: {}", e);
+    if let Err(e) = check_and_update_probe(&config, &client).await {
+        error!("Probe update check failed [{}]: {}", error::error_code(&e), e);
         if let Some(source) = e.source() {
             error!("  Caused by: {}", source);
         }
+        reporter.report("probe_update", &e);
     }
 
     loop {
         sleep(Duration::from_secs(CHECK_INTERVAL_SECONDS)).await;
 
-        if let Err(e) = check_and_update_probe(&config).await {
-            error!("Probe update check failed: {}", e);
+        if let Err(e) = check_and_update_probe(&config, &client).await {
+            error!("Probe update check failed [{}]: {}", error::error_code(&e), e);
             if let Some(source) = e.source() {
                 error!("  Caused by: {}", source);
             }
+            reporter.report("probe_update", &e);
         }
     }
 }
 
-pub async fn check_and_update_node_firmware(config: &Config, usb_handle: &UsbHandle) -> Result<()> {
+pub async fn check_and_update_node_firmware(
+    config: &Config,
+    client: &reqwest::Client,
+    usb_handle: &UsbHandle,
+    tracker: &UpdateTracker,
+) -> Result<()> {
+    tracker.apply(UpdateEvent::CheckStarted);
+
+    let result = match &config.bundle_update_url {
+        Some(bundle_url) => check_and_update_bundle(config, client, bundle_url, usb_handle, tracker).await,
+        None => check_and_update_node(config, client, usb_handle, tracker).await,
+    };
+
+    // Failures handled along the way (e.g. a bundle rollback) already left the active states
+    if let Err(e) = &result {
+        if matches!(e.downcast_ref::<ProbeError>(), Some(ProbeError::UpdateCancelled { .. })) {
+            warn!("{}", e);
+            let handed_off = tracker.state().is_past_bootloader_handoff();
+            tracker.apply(UpdateEvent::Cancelled);
+            // Lets a node that supports it leave a pending bootloader request. Once
+            // it is in its bootloader it no longer reads commands.
+            if !handed_off {
+                if let Err(e) = usb_handle.send_command("/BS_CANCEL_".to_string()).await {
+                    warn!("Failed to send /BS_CANCEL_ to the node: {}", e);
+                }
+            }
+        } else if tracker.state().is_active() {
+            tracker.apply(UpdateEvent::Failed(e.to_string()));
+        }
+    }
+
+    result
+}
+
+async fn check_and_update_node(config: &Config, client: &reqwest::Client, usb_handle: &UsbHandle, tracker: &UpdateTracker) -> Result<()> {
     // Fetch version info
+    let api_key = config.node_firmware_auth.then_some(config.api_key.as_str());
+
     let version_url = format!("{}/version.json", config.node_firmware_url);
-    let response = reqwest::get(&version_url).await?;
+    let response = fetch(client, &version_url, api_key).await?;
     let version_info: VersionInfo = response.json().await?;
 
     // Determine current version
-    let current_version = get_current_node_version().await?;
+    let current_version = get_current_node_version(config, usb_handle).await?;
 
     info!("Node firmware - Current: {}, Latest: {}", current_version, version_info.version);
 
     if version_info.version <= current_version {
+        tracker.apply(UpdateEvent::UpToDate);
         return Ok(());
     }
 
     info!("Updating node firmware to version {}...", version_info.version);
+    tracker.apply(UpdateEvent::VersionFetched { version: version_info.version });
+    let previous = fs::read(deployed_node_firmware_path(current_version)).await.ok();
 
     // Wrap the update process to handle failures with reboot
-    if let Err(e) = perform_node_firmware_update(config, usb_handle, &version_info).await {
+    if let Err(e) = perform_node_firmware_update(config, client, api_key, usb_handle, tracker, &version_info).await {
         error!("Node firmware update failed: {}. Rebooting system to recover...", e);
         //sleep(Duration::from_secs(2)).await;
         //let _ = reboot_system().await;
+        if let (true, Some(previous)) = (is_verification_failure(&e), previous) {
+            tracker.apply(UpdateEvent::Failed(e.to_string()));
+            rollback_node_firmware(config, usb_handle, tracker, &previous, current_version, version_info.version).await;
+        }
         return Err(e);
     }
 
     Ok(())
 }
 
-async fn perform_node_firmware_update(config: &Config, usb_handle: &UsbHandle, version_info: &VersionInfo) -> Result<()> {
+#[tracing::instrument(name = "firmware_update", skip_all, fields(version = version_info.version))]
+async fn perform_node_firmware_update(
+    config: &Config,
+    client: &reqwest::Client,
+    api_key: Option<&str>,
+    usb_handle: &UsbHandle,
+    tracker: &UpdateTracker,
+    version_info: &VersionInfo,
+) -> Result<()> {
     // Download new firmware
-    let firmware_url = format!("{}/moonblokz_node_{}.uf2", config.node_firmware_url, version_info.version);
-    let response = reqwest::get(&firmware_url).await?;
-    let firmware_data = response.bytes().await?;
+    let firmware_url = format!("{}/{}", config.node_firmware_url, node_firmware_filename(version_info));
+    let download_path = PathBuf::from(format!("/tmp/moonblokz_node_{}.download", version_info.version));
+    let rate_limit = config.firmware_download_rate_limit_kbps;
+    let downloaded =
+        download_to_file(client, &firmware_url, api_key, rate_limit, &download_path, version_info.firmware_size_bytes).await?;
+    tracker.apply(UpdateEvent::DownloadComplete);
+    if let Err(e) = tracker.checkpoint(false) {
+        let _ = fs::remove_file(&download_path).await;
+        return Err(e.into());
+    }
 
-    // Verify CRC32
-    let computed_crc = crc32fast::hash(&firmware_data);
-    let expected_crc =
-        u32::from_str_radix(&version_info.crc32, 16).map_err(|_| anyhow::anyhow!("Invalid CRC32 format in version.json: {}", version_info.crc32))?;
+    // Verify CRC32 (over the bytes as downloaded), hashed while streaming
+    verify_download(&downloaded, &download_path, version_info, "version.json").await?;
+
+    // Decompress if needed and validate, a chunk at a time
+    let image_path = node_image_path(version_info.version);
+    let compressed = version_info.compressed.unwrap_or(false);
+    let max_size = config.max_firmware_size_bytes;
+    let (source, dest) = (download_path.clone(), image_path.clone());
+    let prepared = tokio::task::spawn_blocking(move || prepare_node_image(&source, &dest, compressed, max_size)).await;
+    let _ = fs::remove_file(&download_path).await;
+    let (metadata, image_size) = prepared??;
+    if compressed {
+        info!("Decompressed firmware: {} -> {} bytes", downloaded.size, image_size);
+    }
 
-    if computed_crc != expected_crc {
-        return Err(anyhow::anyhow!("CRC32 mismatch: expected {:x}, got {:x}", expected_crc, computed_crc));
+    if let Err(e) = check_node_target(config, &metadata) {
+        let _ = fs::remove_file(&image_path).await;
+        return Err(e);
+    }
+    tracker.apply(UpdateEvent::VerificationPassed);
+    tracker.checkpoint(false)?;
+
+    flash_node_image(config, usb_handle, tracker, &image_path, version_info.version).await
+}
+
+/// Where a node firmware image waits to be flashed
+fn node_image_path(version: u32) -> PathBuf {
+    PathBuf::from(format!("/tmp/moonblokz_node_{}.uf2", version))
+}
+
+/// Copy the download at `source` to `dest`, gunzipping it when `compressed`
+/// and validating it as UF2 on the way. Reads `IMAGE_CHUNK_SIZE` bytes at a
+/// time, so memory use does not grow with the image. Returns the UF2 metadata
+/// and the image size; `dest` is removed on failure.
+fn prepare_node_image(source: &Path, dest: &Path, compressed: bool, max_size: u64) -> Result<(uf2::Uf2Metadata, u64)> {
+    use std::io::{BufReader, BufWriter, Read, Write};
+
+    let file = BufReader::new(std::fs::File::open(source)?);
+    let mut reader: Box<dyn Read> = if compressed { Box::new(flate2::read::GzDecoder::new(file)) } else { Box::new(file) };
+    let mut writer = BufWriter::new(std::fs::File::create(dest)?);
+
+    let copied = (|| -> Result<(uf2::Uf2Metadata, u64)> {
+        let mut validator = uf2::Uf2Validator::new();
+        let mut chunk = vec![0; IMAGE_CHUNK_SIZE];
+        let mut size = 0;
+        loop {
+            let read = reader.read(&mut chunk).map_err(|e| match compressed {
+                true => ProbeError::FirmwareError(format!("Failed to decompress firmware: {}", e)).into(),
+                false => anyhow::Error::from(e),
+            })?;
+            if read == 0 {
+                break;
+            }
+            size += read as u64;
+            if size > max_size {
+                return Err(ProbeError::FirmwareError(format!("Firmware exceeds max_firmware_size_bytes ({})", max_size)).into());
+            }
+            validator.update(&chunk[..read])?;
+            writer.write_all(&chunk[..read])?;
+        }
+        writer.flush()?;
+        Ok((validator.finish()?, size))
+    })();
+
+    if copied.is_err() {
+        let _ = std::fs::remove_file(dest);
     }
+    copied
+}
 
-    // Save to temporary file
-    let temp_file = format!("/tmp/moonblokz_node_{}.uf2", version_info.version);
-    fs::write(&temp_file, &firmware_data).await?;
+/// Check a node firmware image against the size limit and the expected target family
+fn validate_node_firmware(config: &Config, firmware_data: &[u8]) -> Result<()> {
+    if firmware_data.len() as u64 > config.max_firmware_size_bytes {
+        return Err(ProbeError::FirmwareError(format!(
+            "Firmware is {} bytes, exceeding max_firmware_size_bytes ({})",
+            firmware_data.len(),
+            config.max_firmware_size_bytes
+        ))
+        .into());
+    }
 
-    // Enter bootloader mode
+    check_node_target(config, &uf2::validate(firmware_data)?)
+}
+
+/// Log a validated UF2 image and check its target family against `node_target_family`
+fn check_node_target(config: &Config, metadata: &uf2::Uf2Metadata) -> Result<()> {
+    let target = metadata.target.map_or("none".to_string(), |t| t.to_string());
+    info!("Firmware image: {} UF2 blocks, target family {}", metadata.block_count, target);
+
+    if let Some(expected) = &config.node_target_family {
+        if !expected.eq_ignore_ascii_case(&target) {
+            return Err(ProbeError::FirmwareError(format!("target mismatch: expected {}, got {}", expected.to_lowercase(), target)).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// `flash_node_image` for an image held in memory
+async fn flash_node_firmware(
+    config: &Config,
+    usb_handle: &UsbHandle,
+    tracker: &UpdateTracker,
+    firmware_data: &[u8],
+    version: u32,
+) -> Result<()> {
+    let image_path = node_image_path(version);
+    fs::write(&image_path, firmware_data).await?;
+    flash_node_image(config, usb_handle, tracker, &image_path, version).await
+}
+
+/// Put the node into its bootloader, copy the image at `temp_file` onto it,
+/// check the node comes back reporting `version` and move the image to the
+/// deployed directory
+async fn flash_node_image(config: &Config, usb_handle: &UsbHandle, tracker: &UpdateTracker, temp_file: &Path, version: u32) -> Result<()> {
+    // Registered while the node is still connected, so it fires on the reconnect after the flash
+    let reconnected = usb_handle.next_connection().await.map_err(flash_error(FlashStage::EnteringBootloader))?;
+    tracker.checkpoint(true)?;
+
+    // Enter bootloader mode. From here on the flash always finishes, since
+    // stopping would leave the node in its bootloader without firmware.
     info!("Entering bootloader mode...");
-    usb_handle.send_command("/BS\r\n".to_string()).await?;
+    usb_handle.send_command("/BS\r\n".to_string()).await.map_err(flash_error(FlashStage::EnteringBootloader))?;
+    tracker.apply(UpdateEvent::BootloaderRequested);
 
     // Wait for bootloader device to appear and detect it
     info!("Waiting for bootloader device to appear...");
-    let bootloader_device = wait_for_bootloader_device().await?;
+    let bootloader_device = wait_for_bootloader_device().await.map_err(flash_error(FlashStage::WaitingForDevice))?;
     info!("Bootloader device detected: {}", bootloader_device);
+    tracker.apply(UpdateEvent::BootloaderReady);
 
     // Mount the bootloader device
     let mount_point = "/tmp/rpi-rp2-bootloader";
     // Delete and recreate the mount point directory to ensure clean state
     let _ = fs::remove_dir_all(mount_point).await;
-    fs::create_dir_all(mount_point).await?;
+    fs::create_dir_all(mount_point).await.map_err(|e| flash_error(FlashStage::Mounting)(e.into()))?;
 
     info!("Mounting bootloader at {}...", mount_point);
-    mount_bootloader(&bootloader_device, mount_point).await?;
+    mount_bootloader(&bootloader_device, mount_point).await.map_err(flash_error(FlashStage::Mounting))?;
+    tracker.apply(UpdateEvent::Mounted);
 
     // Copy firmware to the mounted bootloader
     let firmware_dest = format!("{}/firmware.uf2", mount_point);
     info!("Copying firmware to bootloader...");
-    let copy_status = Command::new("sudo").arg("cp").arg(&temp_file).arg(&firmware_dest).status().await;
+    let copy_status = Command::new("sudo").arg("cp").arg(temp_file).arg(&firmware_dest).status().await;
 
     if let Err(e) = copy_status {
         error!("Failed to copy firmware to bootloader: {}", e);
         // Try to unmount before returning error
         let _ = unmount_bootloader(mount_point).await;
-        return Err(e.into());
+        return Err(flash_error(FlashStage::Copying)(e.into()).into());
     }
 
     if !copy_status.unwrap().success() {
         error!("Failed to copy firmware to bootloader: copy command failed");
         let _ = unmount_bootloader(mount_point).await;
-        return Err(anyhow::anyhow!("Failed to copy firmware to bootloader"));
+        return Err(flash_error(FlashStage::Copying)(anyhow::anyhow!("copy command failed")).into());
     }
 
     // Sync to ensure data is written
-    sync_filesystem().await?;
+    sync_filesystem().await.map_err(flash_error(FlashStage::Copying))?;
+    tracker.apply(UpdateEvent::FlashComplete);
 
     // Unmount the bootloader (device will reboot automatically)
     info!("Unmounting bootloader...");
-    unmount_bootloader(mount_point).await?;
-
-    // Wait for device to reboot and reconnect
-    sleep(Duration::from_secs(5)).await;
+    unmount_bootloader(mount_point).await.map_err(flash_error(FlashStage::Unmounting))?;
+    tracker.apply(UpdateEvent::Unmounted);
+
+    // Wait for device to reboot and reconnect with the new firmware
+    let post_flash_timeout = Duration::from_secs(config.post_flash_timeout_seconds);
+    if let Err(e) = verify_flashed_version(usb_handle, reconnected, version, post_flash_timeout).await {
+        error!("Post-flash verification of node firmware {} failed: {}", version, e);
+        let _ = fs::remove_file(temp_file).await;
+        return Err(flash_error(FlashStage::Verifying)(e).into());
+    }
+    info!("Node reconnected running firmware version {}", version);
+    tracker.apply(UpdateEvent::DeviceReconnected);
 
     // Move to deployed directory
     fs::create_dir_all(DEPLOYED_DIR).await?;
-    let deployed_file = format!("{}/moonblokz_node_{}.uf2", DEPLOYED_DIR, version_info.version);
-    fs::rename(&temp_file, &deployed_file).await?;
+    let deployed_file = deployed_node_firmware_path(version);
+    fs::rename(temp_file, &deployed_file).await?;
 
     // Clean up old versions
-    cleanup_old_node_versions(version_info.version).await?;
+    cleanup_old_node_versions(version).await?;
 
-    info!("Node firmware updated successfully to version {}", version_info.version);
+    info!("Node firmware updated successfully to version {}", version);
 
     Ok(())
 }
 
-pub async fn check_and_update_probe(config: &Config) -> Result<()> {
+/// Wait up to `wait` for the node to reconnect, then check it reports `version`
+async fn verify_flashed_version(
+    usb_handle: &UsbHandle,
+    reconnected: oneshot::Receiver<()>,
+    version: u32,
+    wait: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + wait;
+    match timeout_at(deadline, reconnected).await {
+        Ok(Ok(())) => {}
+        Ok(Err(_)) => return Err(anyhow::anyhow!("USB manager stopped before the node reconnected")),
+        Err(_) => return Err(anyhow::anyhow!("node did not reconnect within {}s", wait.as_secs())),
+    }
+
+    let remaining = deadline.saturating_duration_since(Instant::now()).max(NODE_QUERY_TIMEOUT);
+    let line = usb_handle.query("/VQ".to_string(), NODE_VERSION_PREFIX, remaining).await?;
+    match line[NODE_VERSION_PREFIX.len()..].trim().parse::<u32>() {
+        Ok(reported) if reported == version => Ok(()),
+        Ok(reported) => Err(anyhow::anyhow!("node reports version {} after flashing {}", reported, version)),
+        Err(_) => Err(anyhow::anyhow!("unreadable version reply after flashing {}: {}", version, line)),
+    }
+}
+
+/// Whether `error` is a flash that completed but was not confirmed by the node
+fn is_verification_failure(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<ProbeError>(),
+        Some(ProbeError::FirmwareFlashError { stage: FlashStage::Verifying, .. })
+    )
+}
+
+pub async fn check_and_update_probe(config: &Config, client: &reqwest::Client) -> Result<()> {
+    // The bundle check run by the node update task covers the probe too
+    if config.bundle_update_url.is_some() {
+        debug!("Probe updates are delivered through the firmware bundle");
+        return Ok(());
+    }
+
     // Fetch version info
+    let api_key = config.probe_firmware_auth.then_some(config.api_key.as_str());
+
     let version_url = format!("{}/version.json", config.probe_firmware_url);
-    let response = reqwest::get(&version_url).await?;
+    let response = fetch(client, &version_url, api_key).await?;
     log::debug!("Fetched probe version.json: {:?}", response);
     let version_info: VersionInfo = response.json().await?;
 
     // Determine current version
-    let current_version = get_current_probe_version().await?;
+    let current_version = get_current_probe_version(config).await?;
 
     info!("Probe - Current: {}, Latest: {}", current_version, version_info.version);
 
@@ -179,26 +457,42 @@ pub async fn check_and_update_probe(config: &Config) -> Result<()> {
     info!("Updating probe to version {}...", version_info.version);
 
     // Download new binary
-    let binary_url = format!("{}/moonblokz_probe_{}", config.probe_firmware_url, version_info.version);
-    let response = reqwest::get(&binary_url).await?;
-    let binary_data = response.bytes().await?;
+    let binary_url = format!("{}/{}", config.probe_firmware_url, probe_binary_filename(version_info.version));
+    let download_path = probe_download_path(version_info.version);
+    let rate_limit = config.firmware_download_rate_limit_kbps;
+    let downloaded =
+        download_to_file(client, &binary_url, api_key, rate_limit, &download_path, version_info.firmware_size_bytes).await?;
 
     // Verify CRC32
-    let computed_crc = crc32fast::hash(&binary_data);
-    let expected_crc =
-        u32::from_str_radix(&version_info.crc32, 16).map_err(|_| anyhow::anyhow!("Invalid CRC32 format in version.json: {}", version_info.crc32))?;
+    verify_download(&downloaded, &download_path, &version_info, "version.json").await?;
 
-    if computed_crc != expected_crc {
-        return Err(anyhow::anyhow!("CRC32 mismatch: expected {:x}, got {:x}", expected_crc, computed_crc));
-    }
+    install_downloaded_probe_binary(&download_path, &downloaded.sha256, version_info.version).await?;
 
-    // Save to currrent directory
-    fs::create_dir_all(".").await?;
-    let new_binary = format!("./moonblokz_probe_{}", version_info.version);
-    fs::write(&new_binary, &binary_data).await?;
+    restart_probe(config).await
+}
+
+/// Write a new probe binary with its SHA-256 sidecar and point start.sh at it.
+/// The new version runs after the next reboot.
+async fn install_probe_binary(binary_data: &[u8], version: u32) -> Result<()> {
+    let download_path = probe_download_path(version);
+    fs::write(&download_path, binary_data).await?;
+    install_downloaded_probe_binary(&download_path, &sha256_hex(binary_data), version).await
+}
+
+/// Like `install_probe_binary`, for a binary already downloaded to
+/// `download_path` whose SHA-256 is `sha256`
+async fn install_downloaded_probe_binary(download_path: &Path, sha256: &str, version: u32) -> Result<()> {
+    // Move into the current directory
+    let new_binary = format!("./moonblokz_probe_{}", version);
+    fs::rename(download_path, &new_binary).await?;
 
     debug!("Wrote new probe binary to {}", new_binary);
 
+    // Write SHA-256 sidecar used by the startup integrity check
+    let sidecar = sha256_sidecar_path(Path::new(&new_binary));
+    fs::write(&sidecar, format!("{}\n", sha256)).await?;
+    debug!("Wrote SHA-256 sidecar to {:?}", sidecar);
+
     // Set executable bit
     #[cfg(unix)]
     {
@@ -224,20 +518,652 @@ pub async fn check_and_update_probe(config: &Config) -> Result<()> {
     }
 
     // Clean up old versions
-    cleanup_old_probe_versions(version_info.version).await?;
+    cleanup_old_probe_versions(version).await?;
 
-    info!("Probe updated successfully to version {}", version_info.version);
-    info!("Rebooting in 5 seconds...");
-    sleep(Duration::from_secs(5)).await;
+    info!("Probe updated successfully to version {}", version);
+
+    Ok(())
+}
+
+/// Update node and probe together from a `bundle_<n>.tar.gz` archive at `bundle_url`.
+///
+/// The node is flashed first. If installing the probe binary then fails, the
+/// previously deployed node firmware is flashed back so both stay in step.
+async fn check_and_update_bundle(
+    config: &Config,
+    client: &reqwest::Client,
+    bundle_url: &str,
+    usb_handle: &UsbHandle,
+    tracker: &UpdateTracker,
+) -> Result<()> {
+    let api_key = config.node_firmware_auth.then_some(config.api_key.as_str());
+
+    let version_url = format!("{}/bundle_version.json", bundle_url);
+    let response = fetch(client, &version_url, api_key).await?;
+    let bundle_info: BundleVersionInfo = response.json().await?;
+
+    let current_node = get_current_node_version(config, usb_handle).await?;
+    let current_probe = get_current_probe_version(config).await?;
+
+    info!(
+        "Firmware bundle - Current node: {}, probe: {}, Latest bundle: {} (node {}, probe {})",
+        current_node, current_probe, bundle_info.bundle_version, bundle_info.node_version, bundle_info.probe_version
+    );
 
-    // Reboot
-    reboot_system().await?;
+    if bundle_info.bundle_version <= current_node.max(current_probe) {
+        tracker.apply(UpdateEvent::UpToDate);
+        return Ok(());
+    }
+
+    info!("Applying firmware bundle {}...", bundle_info.bundle_version);
+    tracker.apply(UpdateEvent::VersionFetched { version: bundle_info.bundle_version });
+
+    // Download and verify the archive
+    let archive_url = format!("{}/bundle_{}.tar.gz", bundle_url, bundle_info.bundle_version);
+    let archive = download(client, &archive_url, api_key, config.firmware_download_rate_limit_kbps).await?;
+    tracker.apply(UpdateEvent::DownloadComplete);
+    tracker.checkpoint(false)?;
+    verify_crc32(&archive, &bundle_info.crc32, "bundle_version.json")?;
+
+    let node_name = format!("moonblokz_node_{}.uf2", bundle_info.node_version);
+    let probe_name = format!("moonblokz_probe_{}", bundle_info.probe_version);
+    let (node_firmware, probe_binary) = extract_bundle(&archive, &node_name, &probe_name)?;
+
+    if let Some(expected) = &bundle_info.node_crc32 {
+        verify_crc32(&node_firmware, expected, "bundle_version.json node_crc32")?;
+    }
+    if let Some(expected) = &bundle_info.probe_crc32 {
+        verify_crc32(&probe_binary, expected, "bundle_version.json probe_crc32")?;
+    }
+    validate_node_firmware(config, &node_firmware)?;
+
+    // Node first, keeping the deployed image around in case the probe step fails
+    let mut previous_node = None;
+    if bundle_info.node_version > current_node {
+        tracker.apply(UpdateEvent::VerificationPassed);
+        tracker.checkpoint(false)?;
+        previous_node = fs::read(deployed_node_firmware_path(current_node)).await.ok();
+        if let Err(e) = flash_node_firmware(config, usb_handle, tracker, &node_firmware, bundle_info.node_version).await {
+            if let (true, Some(previous)) = (is_verification_failure(&e), previous_node) {
+                tracker.apply(UpdateEvent::Failed(e.to_string()));
+                rollback_node_firmware(config, usb_handle, tracker, &previous, current_node, bundle_info.node_version).await;
+            }
+            return Err(e);
+        }
+    } else {
+        tracker.apply(UpdateEvent::UpToDate);
+    }
+
+    if bundle_info.probe_version > current_probe {
+        if let Err(e) = install_probe_binary(&probe_binary, bundle_info.probe_version).await {
+            error!("Probe update from bundle {} failed: {}", bundle_info.bundle_version, e);
+            tracker.apply(UpdateEvent::Failed(e.to_string()));
+            if let Some(previous) = previous_node {
+                rollback_node_firmware(config, usb_handle, tracker, &previous, current_node, bundle_info.node_version).await;
+            }
+            return Err(e);
+        }
+
+        info!("Firmware bundle {} applied", bundle_info.bundle_version);
+        restart_probe(config).await?;
+    } else {
+        info!("Firmware bundle {} applied", bundle_info.bundle_version);
+    }
 
     Ok(())
 }
 
-async fn get_current_node_version() -> Result<u32> {
-    let mut entries = fs::read_dir(DEPLOYED_DIR).await?;
+/// Pull the node `.uf2` and probe binary out of a gzipped tar archive
+fn extract_bundle(archive: &[u8], node_name: &str, probe_name: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    use std::io::Read;
+
+    let mut node_firmware = None;
+    let mut probe_binary = None;
+
+    let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+
+        let slot = if name == node_name {
+            &mut node_firmware
+        } else if name == probe_name {
+            &mut probe_binary
+        } else {
+            continue;
+        };
+
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        *slot = Some(data);
+    }
+
+    let node_firmware = node_firmware.ok_or_else(|| ProbeError::FirmwareError(format!("bundle is missing {}", node_name)))?;
+    let probe_binary = probe_binary.ok_or_else(|| ProbeError::FirmwareError(format!("bundle is missing {}", probe_name)))?;
+
+    Ok((node_firmware, probe_binary))
+}
+
+/// Re-flash `previous` (version `previous_version`) after a failed update and
+/// forget the firmware that replaced it
+async fn rollback_node_firmware(
+    config: &Config,
+    usb_handle: &UsbHandle,
+    tracker: &UpdateTracker,
+    previous: &[u8],
+    previous_version: u32,
+    failed_version: u32,
+) {
+    warn!("Rolling back node firmware to version {}...", previous_version);
+    tracker.apply(UpdateEvent::RollbackStarted);
+
+    if let Err(e) = flash_node_firmware(config, usb_handle, tracker, previous, previous_version).await {
+        error!("Node firmware rollback failed: {}", e);
+        tracker.apply(UpdateEvent::Failed(format!("rollback failed: {}", e)));
+        return;
+    }
+
+    // A firmware that failed verification was never deployed
+    match fs::remove_file(deployed_node_firmware_path(failed_version)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            warn!("Failed to remove rolled back node firmware {}: {}", failed_version, e);
+        }
+        _ => {}
+    }
+}
+
+fn deployed_node_firmware_path(version: u32) -> String {
+    format!("{}/moonblokz_node_{}.uf2", DEPLOYED_DIR, version)
+}
+
+/// Compare the CRC32 of `data` with the hex value `expected` taken from `source`
+fn verify_crc32(data: &[u8], expected: &str, source: &str) -> Result<()> {
+    check_crc32(crc32fast::hash(data), expected, source)
+}
+
+/// Compare an already computed CRC32 with the hex value `expected` taken from `source`
+fn check_crc32(computed_crc: u32, expected: &str, source: &str) -> Result<()> {
+    let expected_crc = u32::from_str_radix(expected, 16)
+        .map_err(|_| ProbeError::FirmwareError(format!("Invalid CRC32 format in {}: {}", source, expected)))?;
+
+    if computed_crc != expected_crc {
+        return Err(ProbeError::FirmwareCrcMismatch {
+            expected: expected_crc,
+            computed: computed_crc,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`
+#[cfg(unix)]
+pub fn available_disk_space(path: &Path) -> Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_disk_space(_path: &Path) -> Result<u64> {
+    Err(anyhow::anyhow!("Disk space check is not supported on this platform"))
+}
+
+/// Latest node firmware version offered by `node_firmware_url`
+pub async fn latest_node_version(config: &Config, client: &reqwest::Client) -> Result<u32> {
+    let api_key = config.node_firmware_auth.then_some(config.api_key.as_str());
+    let response = fetch(client, &format!("{}/version.json", config.node_firmware_url), api_key).await?;
+    Ok(response.json::<VersionInfo>().await?.version)
+}
+
+/// Latest probe version offered by `probe_firmware_url`
+pub async fn latest_probe_version(config: &Config, client: &reqwest::Client) -> Result<u32> {
+    let api_key = config.probe_firmware_auth.then_some(config.api_key.as_str());
+    let response = fetch(client, &format!("{}/version.json", config.probe_firmware_url), api_key).await?;
+    Ok(response.json::<VersionInfo>().await?.version)
+}
+
+/// Node firmware file named by `version.json`
+fn node_firmware_filename(version_info: &VersionInfo) -> String {
+    match &version_info.filename {
+        Some(filename) => filename.clone(),
+        None => format!("moonblokz_node_{}.uf2", version_info.version),
+    }
+}
+
+fn probe_binary_filename(version: u32) -> String {
+    format!("moonblokz_probe_{}", version)
+}
+
+/// Firmware server checked by `check_firmware_endpoint`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareComponent {
+    Node,
+    Probe,
+}
+
+impl FirmwareComponent {
+    pub fn parse(value: &str) -> Result<Self, ProbeError> {
+        match value.to_lowercase().as_str() {
+            "node" => Ok(FirmwareComponent::Node),
+            "probe" => Ok(FirmwareComponent::Probe),
+            other => Err(ProbeError::CommandError(format!("Unknown component '{}', expected node or probe", other))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FirmwareComponent::Node => "node",
+            FirmwareComponent::Probe => "probe",
+        }
+    }
+}
+
+/// Outcome of `check_firmware_endpoint`; fields stay unset from the first
+/// step that failed
+#[derive(Debug, Serialize)]
+pub struct EndpointCheck {
+    /// "reachable" or "unreachable"
+    pub status: &'static str,
+    pub version_url: String,
+    pub version: Option<u32>,
+    pub firmware_url: Option<String>,
+    /// Status of the `HEAD` request for the firmware file
+    pub http_status: Option<u16>,
+    pub content_length: Option<u64>,
+    pub response_time_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Fetch `version.json` for `component` and check with a `HEAD` request that
+/// the firmware file it names exists and has a `Content-Length`, without
+/// downloading it
+pub async fn check_firmware_endpoint(config: &Config, client: &reqwest::Client, component: FirmwareComponent) -> EndpointCheck {
+    let (base_url, auth) = match component {
+        FirmwareComponent::Node => (&config.node_firmware_url, config.node_firmware_auth),
+        FirmwareComponent::Probe => (&config.probe_firmware_url, config.probe_firmware_auth),
+    };
+    let mut check = EndpointCheck {
+        status: "unreachable",
+        version_url: format!("{}/version.json", base_url),
+        version: None,
+        firmware_url: None,
+        http_status: None,
+        content_length: None,
+        response_time_ms: None,
+        error: None,
+    };
+
+    let api_key = auth.then_some(config.api_key.as_str());
+    match run_endpoint_check(client, base_url, api_key, component, &mut check).await {
+        Ok(()) => check.status = "reachable",
+        Err(e) => {
+            warn!("{} firmware endpoint check failed: {}", component.as_str(), e);
+            check.error = Some(e.to_string());
+        }
+    }
+    check
+}
+
+async fn run_endpoint_check(
+    client: &reqwest::Client,
+    base_url: &str,
+    api_key: Option<&str>,
+    component: FirmwareComponent,
+    check: &mut EndpointCheck,
+) -> Result<()> {
+    let response = with_api_key(client.get(&check.version_url), api_key)
+        .timeout(ENDPOINT_CHECK_TIMEOUT)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|source| ProbeError::FirmwareDownloadError { url: check.version_url.clone(), source })?;
+    let version_info: VersionInfo = response
+        .json()
+        .await
+        .map_err(|e| ProbeError::FirmwareError(format!("Malformed version.json: {}", e)))?;
+    check.version = Some(version_info.version);
+
+    let filename = match component {
+        FirmwareComponent::Node => node_firmware_filename(&version_info),
+        FirmwareComponent::Probe => probe_binary_filename(version_info.version),
+    };
+    let firmware_url = format!("{}/{}", base_url, filename);
+    check.firmware_url = Some(firmware_url.clone());
+
+    let started = Instant::now();
+    let response = with_api_key(client.head(&firmware_url), api_key)
+        .timeout(ENDPOINT_CHECK_TIMEOUT)
+        .send()
+        .await
+        .map_err(|source| ProbeError::FirmwareDownloadError { url: firmware_url.clone(), source })?;
+    check.response_time_ms = Some(started.elapsed().as_millis() as u64);
+    check.http_status = Some(response.status().as_u16());
+    response
+        .error_for_status_ref()
+        .map_err(|source| ProbeError::FirmwareDownloadError { url: firmware_url.clone(), source })?;
+
+    // Read the header itself: reqwest reports the (empty) body length for HEAD
+    check.content_length = response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    if check.content_length.is_none() {
+        return Err(ProbeError::FirmwareError(format!("{} has no Content-Length", firmware_url)).into());
+    }
+    Ok(())
+}
+
+/// Client for firmware checks and downloads, kept separate from the telemetry
+/// client because UF2 files and probe binaries need a longer timeout. Built
+/// once and shared so checks reuse pooled connections.
+pub fn firmware_client(config: &Config) -> Result<reqwest::Client> {
+    let client = connectivity::client_builder(config)?
+        .user_agent(format!("moonblokz-probe/{}", config.probe_version))
+        .pool_max_idle_per_host(config.firmware_client_pool_size)
+        .connect_timeout(Duration::from_secs(config.firmware_connect_timeout_seconds))
+        .timeout(Duration::from_secs(config.firmware_download_timeout_seconds))
+        .build()?;
+    Ok(client)
+}
+
+/// Add `X-Api-Key` to `request` when `api_key` is set
+fn with_api_key(request: reqwest::RequestBuilder, api_key: Option<&str>) -> reqwest::RequestBuilder {
+    match api_key {
+        Some(api_key) => request.header("X-Api-Key", api_key),
+        None => request,
+    }
+}
+
+/// GET `url`, sending `X-Api-Key` when `api_key` is set
+async fn fetch(client: &reqwest::Client, url: &str, api_key: Option<&str>) -> Result<reqwest::Response, ProbeError> {
+    with_api_key(client.get(url), api_key)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|source| ProbeError::FirmwareDownloadError { url: url.to_string(), source })
+}
+
+/// Checksums of a file written by `download_to_file`
+#[derive(Debug, PartialEq)]
+struct StreamedDownload {
+    size: u64,
+    crc32: u32,
+    sha256: String,
+}
+
+/// CRC32 and SHA-256 of a download, fed one chunk at a time
+#[derive(Default)]
+struct DownloadHasher {
+    size: u64,
+    crc: crc32fast::Hasher,
+    sha: Sha256,
+}
+
+impl DownloadHasher {
+    fn update(&mut self, chunk: &[u8]) {
+        self.crc.update(chunk);
+        self.sha.update(chunk);
+        self.size += chunk.len() as u64;
+    }
+
+    fn finish(self) -> StreamedDownload {
+        StreamedDownload { size: self.size, crc32: self.crc.finalize(), sha256: format!("{:x}", self.sha.finalize()) }
+    }
+}
+
+/// Stream the body of `url` into `dest`, hashing it on the way so the file is
+/// never held in memory as a whole. `dest` is removed if the download fails.
+///
+/// `expected_size` (or else the `Content-Length` header) is checked against
+/// the free disk space before anything is written, and used to log progress.
+async fn download_to_file(
+    client: &reqwest::Client,
+    url: &str,
+    api_key: Option<&str>,
+    rate_limit_kbps: Option<u32>,
+    dest: &Path,
+    expected_size: Option<u64>,
+) -> Result<StreamedDownload> {
+    let response = fetch(client, url, api_key).await?;
+    let expected_size = expected_size.or_else(|| response.content_length());
+    match expected_size {
+        Some(expected_size) => check_disk_space(dest, expected_size)?,
+        None => debug!("Size of {} unknown, skipping disk space check", url),
+    }
+    let mut stream = match rate_limit_kbps {
+        Some(rate_limit_kbps) => RateLimitedStream::new(response.bytes_stream(), u64::from(rate_limit_kbps) * 1024).boxed(),
+        None => response.bytes_stream().boxed(),
+    };
+
+    let mut file = fs::File::create(dest).await?;
+    let mut hasher = DownloadHasher::default();
+    let mut logged_percent = 0;
+
+    let written: Result<()> = async {
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|source| ProbeError::FirmwareDownloadError { url: url.to_string(), source })?;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+
+            if let Some(percent) = expected_size.map(|expected_size| download_progress(hasher.size, expected_size)) {
+                if percent >= logged_percent + DOWNLOAD_PROGRESS_STEP {
+                    info!("Downloading {}: {}%", url, percent);
+                    logged_percent = percent;
+                }
+            }
+        }
+        file.flush().await?;
+        Ok(())
+    }
+    .await;
+
+    if let Err(e) = written {
+        drop(file);
+        let _ = fs::remove_file(dest).await;
+        return Err(e);
+    }
+
+    let downloaded = hasher.finish();
+    debug!("Downloaded {} bytes from {} to {:?}", downloaded.size, url, dest);
+    Ok(downloaded)
+}
+
+/// Fail if the filesystem holding `dest` has less than `needed` bytes free
+fn check_disk_space(dest: &Path, needed: u64) -> Result<()> {
+    let dir = dest.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let available = available_disk_space(dir)?;
+    if available < needed {
+        return Err(ProbeError::FirmwareError(format!(
+            "Not enough disk space in {:?} for download: {} bytes needed, {} available",
+            dir, needed, available
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Share of `expected_size` that `downloaded` bytes make up, in percent
+fn download_progress(downloaded: u64, expected_size: u64) -> u64 {
+    if expected_size == 0 {
+        return 100;
+    }
+    (downloaded.saturating_mul(100) / expected_size).min(100)
+}
+
+/// Check a streamed download against the CRC32 (and SHA-256, if given) from
+/// `version_info`, removing `path` if it does not match
+async fn verify_download(downloaded: &StreamedDownload, path: &Path, version_info: &VersionInfo, source: &str) -> Result<()> {
+    let verified = check_crc32(downloaded.crc32, &version_info.crc32, source).and_then(|()| match &version_info.sha256 {
+        Some(expected) if !expected.eq_ignore_ascii_case(&downloaded.sha256) => Err(ProbeError::FirmwareError(format!(
+            "SHA-256 mismatch for {} bytes: expected {}, computed {}",
+            downloaded.size, expected, downloaded.sha256
+        ))
+        .into()),
+        _ => Ok(()),
+    });
+
+    if verified.is_err() {
+        let _ = fs::remove_file(path).await;
+    }
+    verified
+}
+
+/// Where a probe binary is downloaded before being moved into place
+fn probe_download_path(version: u32) -> PathBuf {
+    PathBuf::from(format!("./moonblokz_probe_{}.download", version))
+}
+
+/// Fetch the whole body of `url`, at no more than `rate_limit_kbps` kilobytes
+/// per second when set
+async fn download(
+    client: &reqwest::Client,
+    url: &str,
+    api_key: Option<&str>,
+    rate_limit_kbps: Option<u32>,
+) -> Result<bytes::Bytes, ProbeError> {
+    let download_error = |source| ProbeError::FirmwareDownloadError { url: url.to_string(), source };
+    let response = fetch(client, url, api_key).await?;
+
+    let Some(rate_limit_kbps) = rate_limit_kbps else {
+        return response.bytes().await.map_err(download_error);
+    };
+
+    let mut body = bytes::BytesMut::new();
+    let mut stream = RateLimitedStream::new(response.bytes_stream(), u64::from(rate_limit_kbps) * 1024);
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk.map_err(download_error)?);
+    }
+
+    Ok(body.freeze())
+}
+
+/// Wrap a failure in a `FirmwareFlashError` for `stage`
+fn flash_error(stage: FlashStage) -> impl FnOnce(anyhow::Error) -> ProbeError {
+    move |source| ProbeError::FirmwareFlashError { stage, source }
+}
+
+/// A firmware or probe binary found on disk
+#[derive(Debug, Clone, Serialize)]
+pub struct DeployedVersion {
+    pub version: u32,
+    pub size_bytes: u64,
+    /// RFC 3339 modification time of the file
+    pub deployed_at: Option<String>,
+}
+
+/// Node firmware and probe binaries currently kept on disk, oldest first
+#[derive(Debug, Clone, Serialize)]
+pub struct DeployedVersions {
+    pub node_versions: Vec<DeployedVersion>,
+    pub probe_versions: Vec<DeployedVersion>,
+    pub current_node: u32,
+    pub current_probe: u32,
+}
+
+pub async fn list_deployed_versions() -> Result<DeployedVersions> {
+    Ok(DeployedVersions {
+        node_versions: scan_versions(Path::new(DEPLOYED_DIR), "moonblokz_node_", ".uf2").await?,
+        probe_versions: scan_versions(Path::new("."), "moonblokz_probe_", "").await?,
+        current_node: deployed_node_version().await?,
+        current_probe: deployed_probe_version().await?,
+    })
+}
+
+/// Files in `dir` named `<prefix><version><suffix>`, sorted by version
+async fn scan_versions(dir: &Path, prefix: &str, suffix: &str) -> Result<Vec<DeployedVersion>> {
+    let mut versions = Vec::new();
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(versions),
+        Err(e) => return Err(e.into()),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let filename = entry.file_name();
+        let filename_str = filename.to_string_lossy();
+
+        let Some(version) = filename_str
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .and_then(|version| version.parse::<u32>().ok())
+        else {
+            continue;
+        };
+
+        let metadata = entry.metadata().await?;
+        versions.push(DeployedVersion {
+            version,
+            size_bytes: metadata.len(),
+            deployed_at: metadata.modified().ok().map(|t| DateTime::<Utc>::from(t).to_rfc3339()),
+        });
+    }
+
+    versions.sort_by_key(|v| v.version);
+    Ok(versions)
+}
+
+/// Version the node is running, taken from the first source that knows it:
+/// the live `/VQ` query, the deployed firmware file, then
+/// `node_firmware_version_fallback`. 0 if none does.
+pub async fn get_current_node_version(config: &Config, usb_handle: &UsbHandle) -> Result<u32> {
+    if let Some(version) = query_node_version(usb_handle).await {
+        info!("Current node version {} (from live USB query)", version);
+        return Ok(version);
+    }
+
+    let deployed = deployed_node_version().await?;
+    if deployed > 0 {
+        info!("Current node version {} (from {})", deployed, DEPLOYED_DIR);
+        return Ok(deployed);
+    }
+
+    if let Some(version) = config.node_firmware_version_fallback {
+        info!("Current node version {} (from node_firmware_version_fallback)", version);
+        return Ok(version);
+    }
+
+    info!("Current node version unknown, assuming 0");
+    Ok(0)
+}
+
+/// Version the probe is running: the newest binary in the working directory,
+/// then `probe_firmware_version_fallback`, 0 if neither is available
+pub async fn get_current_probe_version(config: &Config) -> Result<u32> {
+    let deployed = deployed_probe_version().await?;
+    if deployed > 0 {
+        info!("Current probe version {} (from probe binary)", deployed);
+        return Ok(deployed);
+    }
+
+    if let Some(version) = config.probe_firmware_version_fallback {
+        info!("Current probe version {} (from probe_firmware_version_fallback)", version);
+        return Ok(version);
+    }
+
+    info!("Current probe version unknown, assuming 0");
+    Ok(0)
+}
+
+/// Ask the node for the firmware it is running
+pub async fn query_node_version(usb_handle: &UsbHandle) -> Option<u32> {
+    match usb_handle.query("/VQ".to_string(), NODE_VERSION_PREFIX, NODE_QUERY_TIMEOUT).await {
+        Ok(line) => line[NODE_VERSION_PREFIX.len()..].trim().parse::<u32>().ok(),
+        Err(e) => {
+            warn!("Live node version query failed: {}", e);
+            None
+        }
+    }
+}
+
+/// Version of the node firmware in `DEPLOYED_DIR`, 0 if there is none
+pub async fn deployed_node_version() -> Result<u32> {
+    let mut entries = match fs::read_dir(DEPLOYED_DIR).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
 
     while let Some(entry) = entries.next_entry().await? {
         let filename = entry.file_name();
@@ -256,7 +1182,8 @@ async fn get_current_node_version() -> Result<u32> {
     Ok(0) // No version found
 }
 
-async fn get_current_probe_version() -> Result<u32> {
+/// Version of the probe binary in the working directory, 0 if there is none
+pub async fn deployed_probe_version() -> Result<u32> {
     let mut entries = fs::read_dir(".").await?;
 
     while let Some(entry) = entries.next_entry().await? {
@@ -276,6 +1203,61 @@ async fn get_current_probe_version() -> Result<u32> {
     Ok(0) // No version found
 }
 
+/// Delete every node firmware image but the deployed one and every probe
+/// binary (with its `.sha256` sidecar) but the newest and the running one,
+/// returning what was removed
+pub async fn remove_inactive_versions() -> Result<Vec<PathBuf>> {
+    let current_node = deployed_node_version().await?;
+    let running_exe = std::env::current_exe().ok();
+    remove_inactive_versions_in(Path::new(DEPLOYED_DIR), Path::new("."), current_node, running_exe.as_deref()).await
+}
+
+async fn remove_inactive_versions_in(
+    node_dir: &Path,
+    probe_dir: &Path,
+    current_node: u32,
+    running_exe: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+
+    for deployed in scan_versions(node_dir, "moonblokz_node_", ".uf2").await? {
+        if deployed.version != current_node {
+            let path = node_dir.join(format!("moonblokz_node_{}.uf2", deployed.version));
+            fs::remove_file(&path).await?;
+            removed.push(path);
+        }
+    }
+
+    // Sorted by version, so the last one is the newest
+    let probe_versions = scan_versions(probe_dir, "moonblokz_probe_", "").await?;
+    let newest_probe = probe_versions.last().map(|deployed| deployed.version);
+    for deployed in probe_versions {
+        if Some(deployed.version) != newest_probe {
+            let path = probe_dir.join(format!("moonblokz_probe_{}", deployed.version));
+            if running_exe.is_some_and(|exe| is_same_file(&path, exe)) {
+                info!("Keeping {:?}, it is the running probe binary", path);
+                continue;
+            }
+            fs::remove_file(&path).await?;
+            removed.push(path.clone());
+
+            let sidecar = sha256_sidecar_path(&path);
+            if fs::remove_file(&sidecar).await.is_ok() {
+                removed.push(sidecar);
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
 async fn cleanup_old_node_versions(current: u32) -> Result<()> {
     let mut entries = fs::read_dir(DEPLOYED_DIR).await?;
 
@@ -306,7 +1288,7 @@ async fn cleanup_old_probe_versions(current: u32) -> Result<()> {
         let filename_str = filename.to_string_lossy();
 
         if filename_str.starts_with("moonblokz_probe_") {
-            let version_str = filename_str.trim_start_matches("moonblokz_probe_");
+            let version_str = filename_str.trim_start_matches("moonblokz_probe_").trim_end_matches(".sha256");
 
             if let Ok(version) = version_str.parse::<u32>() {
                 if version < current {
@@ -320,6 +1302,56 @@ async fn cleanup_old_probe_versions(current: u32) -> Result<()> {
     Ok(())
 }
 
+/// Result of comparing the running binary against its `.sha256` sidecar
+pub enum IntegrityCheck {
+    Passed,
+    SidecarMissing,
+    Mismatch { expected: String, computed: String },
+}
+
+/// Verify the currently running executable against the `.sha256` sidecar written
+/// next to it by `check_and_update_probe`.
+pub async fn check_probe_binary_integrity() -> Result<IntegrityCheck> {
+    let exe = std::env::current_exe()?;
+    verify_sha256_sidecar(&exe).await
+}
+
+/// Path and SHA-256 of the running probe executable
+pub async fn running_probe_binary() -> Result<(PathBuf, String)> {
+    let exe = std::env::current_exe()?;
+    let sha256 = sha256_hex(&fs::read(&exe).await?);
+    Ok((exe, sha256))
+}
+
+/// Compare `binary` against the SHA-256 in its `.sha256` sidecar
+pub async fn verify_sha256_sidecar(binary: &Path) -> Result<IntegrityCheck> {
+    let sidecar = sha256_sidecar_path(binary);
+    let expected = match fs::read_to_string(&sidecar).await {
+        Ok(contents) => contents.split_whitespace().next().unwrap_or_default().to_lowercase(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(IntegrityCheck::SidecarMissing),
+        Err(e) => return Err(e.into()),
+    };
+
+    let computed = sha256_hex(&fs::read(binary).await?);
+
+    if computed == expected {
+        Ok(IntegrityCheck::Passed)
+    } else {
+        Ok(IntegrityCheck::Mismatch { expected, computed })
+    }
+}
+
+/// `moonblokz_probe_21` -> `moonblokz_probe_21.sha256`
+fn sha256_sidecar_path(binary: &Path) -> PathBuf {
+    let mut path = binary.as_os_str().to_owned();
+    path.push(".sha256");
+    PathBuf::from(path)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 /// Wait for the RP2040 bootloader device to appear in /dev
 async fn wait_for_bootloader_device() -> Result<String> {
     const MAX_WAIT_SECONDS: u64 = 30;
@@ -372,7 +1404,7 @@ async fn is_rp2040_bootloader(device_path: &str) -> bool {
                 let label = String::from_utf8_lossy(&output.stdout);
                 let label = label.trim();
                 // RP2040 bootloader typically has label "RPI-RP2"
-                return label == "RPI-RP2" || label == "RPI-RP2";
+                return label == "RPI-RP2";
             }
             false
         }
@@ -399,7 +1431,7 @@ async fn mount_bootloader(device: &str, mount_point: &str) -> Result<()> {
 }
 
 /// Unmount the bootloader device
-async fn unmount_bootloader(mount_point: &str) -> Result<()> {
+async fn unmount_bootloader(_mount_point: &str) -> Result<()> {
     /*     let status = Command::new("sudo").arg("umount").arg(mount_point).status().await?;
 
         if !status.success() {
@@ -420,6 +1452,103 @@ async fn sync_filesystem() -> Result<()> {
     Ok(())
 }
 
+/// How the probe starts a newly installed binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartStrategy {
+    /// Reboot the whole system
+    Reboot,
+    /// Replace the running process with the new binary
+    Exec,
+    /// Exit with code 0 and let the service manager start the new binary
+    Exit,
+}
+
+impl RestartStrategy {
+    pub fn parse(value: &str) -> Result<Self, ProbeError> {
+        match value.to_lowercase().as_str() {
+            "reboot" => Ok(RestartStrategy::Reboot),
+            "exec" => Ok(RestartStrategy::Exec),
+            "exit" => Ok(RestartStrategy::Exit),
+            other => Err(ProbeError::ConfigError(format!(
+                "Unknown restart_strategy '{}', expected reboot, exec or exit",
+                other
+            ))),
+        }
+    }
+}
+
+/// Start the newly installed probe binary according to `restart_strategy`.
+/// Returns once a reboot is under way, otherwise only on failure.
+async fn restart_probe(config: &Config) -> Result<()> {
+    let strategy = RestartStrategy::parse(&config.restart_strategy)?;
+    info!("Restarting probe ({:?}) in 5 seconds...", strategy);
+    sleep(Duration::from_secs(5)).await;
+
+    match strategy {
+        RestartStrategy::Reboot => reboot_system().await,
+        RestartStrategy::Exec => exec_new_binary().await,
+        RestartStrategy::Exit => {
+            info!("Exiting so the service manager starts the new probe binary");
+            log::logger().flush();
+            std::process::exit(0);
+        }
+    }
+}
+
+/// Replace this process with the binary `start.sh` points at, keeping the
+/// current command line arguments
+#[cfg(unix)]
+async fn exec_new_binary() -> Result<()> {
+    use std::ffi::CString;
+
+    let binary = start_script_binary().await?;
+    info!("Executing new probe binary {:?}", binary);
+
+    let mut args = vec![CString::new(binary.to_string_lossy().into_owned())?];
+    for arg in restart_args(std::env::args().skip(1)) {
+        args.push(CString::new(arg)?);
+    }
+
+    log::logger().flush();
+    let error = nix::unistd::execv(&args[0], &args).unwrap_err();
+    Err(anyhow::anyhow!("Failed to execute {:?}: {}", binary, error))
+}
+
+/// `args` without the flags that only make sense on the first start, such as
+/// `--register`, so the new binary does not act on them again
+fn restart_args(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut kept = Vec::new();
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let flag = arg.split('=').next().unwrap_or_default();
+        if !ONE_SHOT_FLAGS.contains(&flag) {
+            kept.push(arg);
+            continue;
+        }
+        // `--output-env` takes an optional separate value
+        if flag == "--output-env" && !arg.contains('=') && args.peek().is_some_and(|next| !next.starts_with('-')) {
+            args.next();
+        }
+    }
+    kept
+}
+
+#[cfg(not(unix))]
+async fn exec_new_binary() -> Result<()> {
+    Err(ProbeError::ConfigError("restart_strategy \"exec\" is only supported on Unix".to_string()).into())
+}
+
+/// Binary started by the `exec` line of `start.sh`
+#[cfg(unix)]
+async fn start_script_binary() -> Result<PathBuf> {
+    let script = fs::read_to_string("start.sh").await?;
+    script
+        .lines()
+        .find_map(|line| line.strip_prefix("exec ")?.split_whitespace().next())
+        .map(PathBuf::from)
+        .ok_or_else(|| ProbeError::FirmwareError("start.sh has no exec line".to_string()).into())
+}
+
 pub async fn reboot_system() -> Result<()> {
     let status = Command::new("sudo").arg("reboot").status().await?;
 
@@ -429,3 +1558,157 @@ pub async fn reboot_system() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn touch(path: &Path) {
+        std::fs::write(path, b"x").unwrap();
+    }
+
+    /// `blocks` RP2040 UF2 blocks with distinct payloads
+    fn uf2_image(blocks: usize) -> Vec<u8> {
+        let mut image = Vec::new();
+        for index in 0..blocks {
+            let mut block = [index as u8; 512];
+            block[0..4].copy_from_slice(&0x0A32_4655u32.to_le_bytes());
+            block[4..8].copy_from_slice(&0x9E5D_5157u32.to_le_bytes());
+            block[8..12].copy_from_slice(&0x0000_2000u32.to_le_bytes());
+            block[28..32].copy_from_slice(&0xe48b_ff56u32.to_le_bytes());
+            block[508..512].copy_from_slice(&0x0AB1_6F30u32.to_le_bytes());
+            image.extend_from_slice(&block);
+        }
+        image
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn chunked_hashes_match_whole_buffer_hashes() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        for chunk_size in [1, 511, 4096, 65536, data.len()] {
+            let mut hasher = DownloadHasher::default();
+            data.chunks(chunk_size).for_each(|chunk| hasher.update(chunk));
+
+            let expected = StreamedDownload { size: data.len() as u64, crc32: crc32fast::hash(&data), sha256: sha256_hex(&data) };
+            assert_eq!(hasher.finish(), expected, "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn prepare_node_image_matches_whole_buffer_path() {
+        let dir = tempfile::tempdir().unwrap();
+        // Larger than one chunk, so blocks straddle chunk boundaries
+        let image = uf2_image(300);
+
+        for compressed in [false, true] {
+            let source = dir.path().join("download");
+            let dest = dir.path().join("image.uf2");
+            std::fs::write(&source, if compressed { gzip(&image) } else { image.clone() }).unwrap();
+
+            let (metadata, size) = prepare_node_image(&source, &dest, compressed, u64::MAX).unwrap();
+
+            let expected = uf2::validate(&image).unwrap();
+            assert_eq!(std::fs::read(&dest).unwrap(), image);
+            assert_eq!(size, image.len() as u64);
+            assert_eq!(metadata.block_count, expected.block_count);
+            assert_eq!(metadata.target, expected.target);
+        }
+    }
+
+    #[test]
+    fn prepare_node_image_enforces_max_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("download");
+        let dest = dir.path().join("image.uf2");
+        std::fs::write(&source, gzip(&uf2_image(4))).unwrap();
+
+        let error = prepare_node_image(&source, &dest, true, 3 * 512).unwrap_err();
+
+        assert!(error.to_string().contains("max_firmware_size_bytes"), "{}", error);
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn prepare_node_image_rejects_invalid_uf2() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("download");
+        let dest = dir.path().join("image.uf2");
+        let mut image = uf2_image(2);
+        image.truncate(700);
+        std::fs::write(&source, &image).unwrap();
+
+        assert!(prepare_node_image(&source, &dest, false, u64::MAX).is_err());
+        assert!(!dest.exists());
+    }
+
+    #[tokio::test]
+    async fn remove_inactive_versions_keeps_deployed_node_and_newest_probe() {
+        let node_dir = tempfile::tempdir().unwrap();
+        let probe_dir = tempfile::tempdir().unwrap();
+        for version in [1, 2, 3] {
+            touch(&node_dir.path().join(format!("moonblokz_node_{}.uf2", version)));
+        }
+        for version in [4, 10, 7] {
+            let binary = probe_dir.path().join(format!("moonblokz_probe_{}", version));
+            touch(&binary);
+            touch(&sha256_sidecar_path(&binary));
+        }
+
+        let removed = remove_inactive_versions_in(node_dir.path(), probe_dir.path(), 2, None).await.unwrap();
+
+        assert_eq!(removed.len(), 6);
+        assert!(node_dir.path().join("moonblokz_node_2.uf2").exists());
+        assert!(!node_dir.path().join("moonblokz_node_1.uf2").exists());
+        assert!(!node_dir.path().join("moonblokz_node_3.uf2").exists());
+        assert!(probe_dir.path().join("moonblokz_probe_10").exists());
+        assert!(sha256_sidecar_path(&probe_dir.path().join("moonblokz_probe_10")).exists());
+        assert!(!probe_dir.path().join("moonblokz_probe_4").exists());
+        assert!(!probe_dir.path().join("moonblokz_probe_7").exists());
+    }
+
+    #[tokio::test]
+    async fn remove_inactive_versions_never_deletes_running_binary() {
+        let node_dir = tempfile::tempdir().unwrap();
+        let probe_dir = tempfile::tempdir().unwrap();
+        for version in [4, 7, 10] {
+            touch(&probe_dir.path().join(format!("moonblokz_probe_{}", version)));
+        }
+        let running = probe_dir.path().join("moonblokz_probe_7");
+
+        let removed = remove_inactive_versions_in(node_dir.path(), probe_dir.path(), 0, Some(&running)).await.unwrap();
+
+        assert_eq!(removed, vec![probe_dir.path().join("moonblokz_probe_4")]);
+        assert!(running.exists());
+        assert!(probe_dir.path().join("moonblokz_probe_10").exists());
+    }
+
+    #[tokio::test]
+    async fn remove_inactive_versions_with_nothing_deployed() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+
+        let removed = remove_inactive_versions_in(&missing, dir.path(), 0, None).await.unwrap();
+
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn restart_drops_one_shot_flags() {
+        let args = ["--config", "probe.toml", "--register", "--skip-self-test", "--output-env", "docker", "--list-serial-ports"];
+        assert_eq!(restart_args(args.map(String::from)), vec!["--config", "probe.toml", "--skip-self-test"]);
+    }
+
+    #[test]
+    fn restart_keeps_values_after_a_bare_output_env() {
+        let args = ["--output-env", "--config=probe.toml", "--output-env=shell", "-c", "other.toml"];
+        assert_eq!(restart_args(args.map(String::from)), vec!["--config=probe.toml", "-c", "other.toml"]);
+    }
+}
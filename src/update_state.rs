@@ -0,0 +1,437 @@
+use crate::error::ProbeError;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+/// Where a node firmware update currently is
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum UpdateState {
+    Idle,
+    CheckingVersion,
+    Downloading,
+    Verifying,
+    EnteringBootloader,
+    WaitingForDevice,
+    Mounting,
+    Flashing,
+    Unmounting,
+    Rebooting,
+    /// `during` is the name of the state the update failed in
+    Failed { during: String, error: String },
+    RolledBack,
+    /// Stopped by `cancel_update` while in state `during`
+    Cancelled { during: String },
+}
+
+impl UpdateState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            UpdateState::Idle => "idle",
+            UpdateState::CheckingVersion => "checking_version",
+            UpdateState::Downloading => "downloading",
+            UpdateState::Verifying => "verifying",
+            UpdateState::EnteringBootloader => "entering_bootloader",
+            UpdateState::WaitingForDevice => "waiting_for_device",
+            UpdateState::Mounting => "mounting",
+            UpdateState::Flashing => "flashing",
+            UpdateState::Unmounting => "unmounting",
+            UpdateState::Rebooting => "rebooting",
+            UpdateState::Failed { .. } => "failed",
+            UpdateState::RolledBack => "rolled_back",
+            UpdateState::Cancelled { .. } => "cancelled",
+        }
+    }
+
+    /// True while an update is in progress
+    pub fn is_active(&self) -> bool {
+        !matches!(self, UpdateState::Idle | UpdateState::Failed { .. } | UpdateState::RolledBack | UpdateState::Cancelled { .. })
+    }
+
+    /// True once the node may have been told to enter its bootloader, after
+    /// which stopping could leave it without firmware
+    pub fn is_past_bootloader_entry(&self) -> bool {
+        self.is_active() && !matches!(self, UpdateState::CheckingVersion | UpdateState::Downloading | UpdateState::Verifying)
+    }
+
+    /// True once `/BS` has been sent, after which the node may be in its
+    /// bootloader and the flash has to finish
+    pub fn is_past_bootloader_handoff(&self) -> bool {
+        matches!(
+            self,
+            UpdateState::WaitingForDevice | UpdateState::Mounting | UpdateState::Flashing | UpdateState::Unmounting | UpdateState::Rebooting
+        )
+    }
+}
+
+/// Something that happened during a node firmware update
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateEvent {
+    CheckStarted,
+    /// The node already runs the latest version, or has nothing to flash
+    UpToDate,
+    VersionFetched { version: u32 },
+    DownloadComplete,
+    VerificationPassed,
+    BootloaderRequested,
+    BootloaderReady,
+    Mounted,
+    FlashComplete,
+    Unmounted,
+    DeviceReconnected,
+    Failed(String),
+    /// The previous firmware is about to be flashed back after a failure
+    RollbackStarted,
+    Cancelled,
+}
+
+impl UpdateEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            UpdateEvent::CheckStarted => "check_started",
+            UpdateEvent::UpToDate => "up_to_date",
+            UpdateEvent::VersionFetched { .. } => "version_fetched",
+            UpdateEvent::DownloadComplete => "download_complete",
+            UpdateEvent::VerificationPassed => "verification_passed",
+            UpdateEvent::BootloaderRequested => "bootloader_requested",
+            UpdateEvent::BootloaderReady => "bootloader_ready",
+            UpdateEvent::Mounted => "mounted",
+            UpdateEvent::FlashComplete => "flash_complete",
+            UpdateEvent::Unmounted => "unmounted",
+            UpdateEvent::DeviceReconnected => "device_reconnected",
+            UpdateEvent::Failed(_) => "failed",
+            UpdateEvent::RollbackStarted => "rollback_started",
+            UpdateEvent::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Node firmware update state with the transitions allowed between states
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateStateMachine {
+    state: UpdateState,
+    /// Version being installed, if any
+    target_version: Option<u32>,
+    /// Set while flashing back the previous firmware
+    rolling_back: bool,
+    changed_at: DateTime<Utc>,
+}
+
+impl Default for UpdateStateMachine {
+    fn default() -> Self {
+        Self { state: UpdateState::Idle, target_version: None, rolling_back: false, changed_at: Utc::now() }
+    }
+}
+
+impl UpdateStateMachine {
+    pub fn state(&self) -> &UpdateState {
+        &self.state
+    }
+
+    /// Apply `event`, returning the new state, or an error leaving the state
+    /// unchanged if `event` is not valid in the current state
+    pub fn transition(&mut self, event: UpdateEvent) -> Result<UpdateState, ProbeError> {
+        use UpdateEvent as E;
+        use UpdateState as S;
+
+        let next = match (&self.state, event) {
+            (S::Idle | S::Failed { .. } | S::RolledBack | S::Cancelled { .. }, E::CheckStarted) => {
+                self.target_version = None;
+                self.rolling_back = false;
+                S::CheckingVersion
+            }
+            (S::CheckingVersion | S::Verifying, E::UpToDate) => S::Idle,
+            (S::CheckingVersion, E::VersionFetched { version }) => {
+                self.target_version = Some(version);
+                S::Downloading
+            }
+            (S::Downloading, E::DownloadComplete) => S::Verifying,
+            (S::Verifying, E::VerificationPassed) => S::EnteringBootloader,
+            (S::EnteringBootloader, E::BootloaderRequested) => S::WaitingForDevice,
+            (S::WaitingForDevice, E::BootloaderReady) => S::Mounting,
+            (S::Mounting, E::Mounted) => S::Flashing,
+            (S::Flashing, E::FlashComplete) => S::Unmounting,
+            (S::Unmounting, E::Unmounted) => S::Rebooting,
+            (S::Rebooting, E::DeviceReconnected) if self.rolling_back => S::RolledBack,
+            (S::Rebooting, E::DeviceReconnected) => S::Idle,
+            (S::Failed { .. }, E::RollbackStarted) => {
+                self.rolling_back = true;
+                S::EnteringBootloader
+            }
+            (S::Failed { .. } | S::RolledBack | S::Cancelled { .. }, event @ (E::Failed(_) | E::Cancelled)) => {
+                return Err(ProbeError::InvalidUpdateTransition { state: self.state.name(), event: event.name() });
+            }
+            (state, E::Failed(error)) => S::Failed { during: state.name().to_string(), error },
+            (state, E::Cancelled) if state.is_active() => S::Cancelled { during: state.name().to_string() },
+            (state, event) => {
+                return Err(ProbeError::InvalidUpdateTransition { state: state.name(), event: event.name() });
+            }
+        };
+
+        self.state = next.clone();
+        self.changed_at = Utc::now();
+        Ok(next)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "state": self.state,
+            "target_version": self.target_version,
+            "rolling_back": self.rolling_back,
+            "changed_at": self.changed_at.to_rfc3339(),
+        })
+    }
+}
+
+/// Cancellation requested by `cancel_update` for the current update
+#[derive(Debug, Default)]
+struct CancelRequest {
+    token: CancellationToken,
+    /// Also stop after bootloader entry, up to the `/BS` handoff
+    force: bool,
+}
+
+/// Shared handle to the node update state, updated by the update task and
+/// read by `get_status`
+#[derive(Debug, Clone, Default)]
+pub struct UpdateTracker {
+    machine: Arc<Mutex<UpdateStateMachine>>,
+    cancel: Arc<Mutex<CancelRequest>>,
+}
+
+impl UpdateTracker {
+    /// Apply `event`, logging rather than failing on an invalid transition so
+    /// a confused state never blocks an update
+    pub fn apply(&self, event: UpdateEvent) {
+        let starting = event == UpdateEvent::CheckStarted;
+        let mut machine = self.machine.lock().unwrap();
+        let from = machine.state().name();
+        match machine.transition(event) {
+            Ok(state) => {
+                info!("Node update state: {} -> {}", from, state.name());
+                // A cancel only ever applies to the update it was sent for
+                if starting {
+                    *self.cancel.lock().unwrap() = CancelRequest::default();
+                }
+            }
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    pub fn state(&self) -> UpdateState {
+        self.machine.lock().unwrap().state().clone()
+    }
+
+    /// Ask the running update to stop at its next checkpoint. Past bootloader
+    /// entry this is refused unless `force` is set, and once the node has been
+    /// handed to its bootloader it is always refused: the flash then finishes
+    /// so the node is not left without firmware.
+    pub fn cancel(&self, force: bool) -> Result<UpdateState, ProbeError> {
+        let state = self.state();
+        if !state.is_active() {
+            return Err(ProbeError::CommandError("No node firmware update in progress".to_string()));
+        }
+        if state.is_past_bootloader_handoff() {
+            return Err(ProbeError::CommandError(format!(
+                "Node update is {}, the node is already in its bootloader; the flash will finish",
+                state.name()
+            )));
+        }
+        if state.is_past_bootloader_entry() {
+            if !force {
+                return Err(ProbeError::CommandError(format!(
+                    "Node update is {}, past bootloader entry; use force_cancel to stop it anyway",
+                    state.name()
+                )));
+            }
+            warn!(
+                "FORCE-CANCELLING node firmware update while {}, before the node is sent to its bootloader",
+                state.name()
+            );
+        }
+
+        let mut cancel = self.cancel.lock().unwrap();
+        cancel.force |= force;
+        cancel.token.cancel();
+        Ok(state)
+    }
+
+    /// Fail with `UpdateCancelled` if the update was cancelled. Checkpoints
+    /// `after_bootloader` only honour a forced cancel.
+    pub fn checkpoint(&self, after_bootloader: bool) -> Result<(), ProbeError> {
+        let stop = {
+            let cancel = self.cancel.lock().unwrap();
+            cancel.token.is_cancelled() && (!after_bootloader || cancel.force)
+        };
+        if stop {
+            return Err(ProbeError::UpdateCancelled { during: self.state().name() });
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        self.machine.lock().unwrap().to_json()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tracker that has gone through `events` after starting an update
+    fn tracker_after(events: &[UpdateEvent]) -> UpdateTracker {
+        let tracker = UpdateTracker::default();
+        tracker.apply(UpdateEvent::CheckStarted);
+        tracker.apply(UpdateEvent::VersionFetched { version: 2 });
+        for event in events {
+            tracker.apply(event.clone());
+        }
+        tracker
+    }
+
+    /// The events of a successful update from `CheckingVersion` back to `Idle`
+    const FLASH: [UpdateEvent; 8] = [
+        UpdateEvent::VersionFetched { version: 2 },
+        UpdateEvent::DownloadComplete,
+        UpdateEvent::VerificationPassed,
+        UpdateEvent::BootloaderRequested,
+        UpdateEvent::BootloaderReady,
+        UpdateEvent::Mounted,
+        UpdateEvent::FlashComplete,
+        UpdateEvent::Unmounted,
+    ];
+
+    fn names(machine: &mut UpdateStateMachine, events: impl IntoIterator<Item = UpdateEvent>) -> Vec<&'static str> {
+        events.into_iter().map(|event| machine.transition(event).unwrap().name()).collect()
+    }
+
+    #[test]
+    fn successful_update_walks_every_state_back_to_idle() {
+        let mut machine = UpdateStateMachine::default();
+
+        let events = [UpdateEvent::CheckStarted].into_iter().chain(FLASH).chain([UpdateEvent::DeviceReconnected]);
+        let states = names(&mut machine, events);
+
+        assert_eq!(
+            states,
+            [
+                "checking_version",
+                "downloading",
+                "verifying",
+                "entering_bootloader",
+                "waiting_for_device",
+                "mounting",
+                "flashing",
+                "unmounting",
+                "rebooting",
+                "idle",
+            ]
+        );
+        assert_eq!(machine.to_json()["target_version"], 2);
+    }
+
+    #[test]
+    fn up_to_date_returns_to_idle() {
+        let mut machine = UpdateStateMachine::default();
+        machine.transition(UpdateEvent::CheckStarted).unwrap();
+        assert_eq!(machine.transition(UpdateEvent::UpToDate).unwrap(), UpdateState::Idle);
+
+        let steps = [UpdateEvent::CheckStarted, UpdateEvent::VersionFetched { version: 3 }, UpdateEvent::DownloadComplete];
+        names(&mut machine, steps);
+        assert_eq!(machine.transition(UpdateEvent::UpToDate).unwrap(), UpdateState::Idle);
+    }
+
+    #[test]
+    fn failure_records_the_state_and_rollback_ends_rolled_back() {
+        let mut machine = UpdateStateMachine::default();
+        names(&mut machine, [UpdateEvent::CheckStarted].into_iter().chain(FLASH.into_iter().take(6)));
+
+        let failed = machine.transition(UpdateEvent::Failed("copy failed".to_string())).unwrap();
+        assert_eq!(failed, UpdateState::Failed { during: "flashing".to_string(), error: "copy failed".to_string() });
+
+        let rollback = [UpdateEvent::RollbackStarted].into_iter().chain(FLASH.into_iter().skip(3));
+        assert_eq!(names(&mut machine, rollback).last(), Some(&"rebooting"));
+        assert_eq!(machine.transition(UpdateEvent::DeviceReconnected).unwrap(), UpdateState::RolledBack);
+        assert_eq!(machine.transition(UpdateEvent::CheckStarted).unwrap(), UpdateState::CheckingVersion);
+        assert_eq!(machine.to_json()["rolling_back"], false);
+    }
+
+    #[test]
+    fn cancel_is_only_valid_while_active() {
+        let mut machine = UpdateStateMachine::default();
+        assert!(machine.transition(UpdateEvent::Cancelled).is_err());
+
+        names(&mut machine, [UpdateEvent::CheckStarted, UpdateEvent::VersionFetched { version: 2 }]);
+        let cancelled = machine.transition(UpdateEvent::Cancelled).unwrap();
+        assert_eq!(cancelled, UpdateState::Cancelled { during: "downloading".to_string() });
+        assert!(machine.transition(UpdateEvent::Cancelled).is_err());
+        assert!(machine.transition(UpdateEvent::Failed("late".to_string())).is_err());
+    }
+
+    #[test]
+    fn invalid_transitions_fail_and_leave_the_state_unchanged() {
+        let invalid = [
+            (vec![], UpdateEvent::DownloadComplete),
+            (vec![], UpdateEvent::RollbackStarted),
+            (vec![UpdateEvent::CheckStarted], UpdateEvent::CheckStarted),
+            (vec![UpdateEvent::CheckStarted], UpdateEvent::Mounted),
+            (vec![UpdateEvent::CheckStarted, UpdateEvent::VersionFetched { version: 2 }], UpdateEvent::UpToDate),
+            ([vec![UpdateEvent::CheckStarted], FLASH.to_vec()].concat(), UpdateEvent::FlashComplete),
+        ];
+
+        for (before, event) in invalid {
+            let mut machine = UpdateStateMachine::default();
+            names(&mut machine, before);
+            let state = machine.state().clone();
+
+            let error = machine.transition(event.clone()).unwrap_err();
+
+            assert!(matches!(error, ProbeError::InvalidUpdateTransition { .. }), "{:?} in {:?}", event, state);
+            assert_eq!(machine.state(), &state);
+        }
+    }
+
+    #[test]
+    fn state_survives_a_serde_round_trip() {
+        let mut machine = UpdateStateMachine::default();
+        names(&mut machine, [UpdateEvent::CheckStarted].into_iter().chain(FLASH.into_iter().take(4)));
+
+        let json = serde_json::to_string(&machine).unwrap();
+        let mut restored: UpdateStateMachine = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.state(), &UpdateState::WaitingForDevice);
+        assert_eq!(restored.transition(UpdateEvent::BootloaderReady).unwrap(), UpdateState::Mounting);
+    }
+
+    #[test]
+    fn cancel_before_bootloader_entry_stops_at_any_checkpoint() {
+        let tracker = tracker_after(&[]);
+        tracker.cancel(false).unwrap();
+        assert!(tracker.checkpoint(false).is_err());
+        assert!(tracker.checkpoint(true).is_ok());
+    }
+
+    #[test]
+    fn force_is_needed_past_bootloader_entry() {
+        let tracker = tracker_after(&[UpdateEvent::DownloadComplete, UpdateEvent::VerificationPassed]);
+        assert!(tracker.cancel(false).is_err());
+        assert!(tracker.checkpoint(true).is_ok());
+
+        assert_eq!(tracker.cancel(true).unwrap(), UpdateState::EnteringBootloader);
+        assert!(matches!(tracker.checkpoint(true), Err(ProbeError::UpdateCancelled { during: "entering_bootloader" })));
+    }
+
+    #[test]
+    fn cancel_is_refused_after_the_bootloader_handoff() {
+        let tracker = tracker_after(&[
+            UpdateEvent::DownloadComplete,
+            UpdateEvent::VerificationPassed,
+            UpdateEvent::BootloaderRequested,
+        ]);
+        assert!(tracker.state().is_past_bootloader_handoff());
+        assert!(tracker.cancel(true).is_err());
+        assert!(tracker.checkpoint(true).is_ok());
+    }
+}
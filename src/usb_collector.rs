@@ -1,53 +1,537 @@
 use crate::config::Config;
+use crate::local_archive::LocalArchive;
+use crate::log_buffer::LogBuffer;
 use crate::log_entry::LogEntry;
-use crate::usb_manager::UsbMessage;
+use crate::usb_manager::{UsbMessage, UsbStats};
 use anyhow::Result;
-use chrono::Utc;
-use log::{info, trace};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::{debug, info, trace, warn};
+use lru::LruCache;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::time::{Duration, Instant};
 
+/// Coalesces runs of identical lines, syslog style
+struct DuplicateSuppressor {
+    max_gap: Duration,
+    last_line: Option<String>,
+    last_emitted_at: Instant,
+    suppressed_count: u64,
+}
+
+impl DuplicateSuppressor {
+    fn new(max_gap: Duration) -> Self {
+        Self {
+            max_gap,
+            last_line: None,
+            last_emitted_at: Instant::now(),
+            suppressed_count: 0,
+        }
+    }
+
+    /// Returns true if `line` repeats the last emitted line within the gap
+    fn suppress(&mut self, line: &str) -> bool {
+        if self.last_line.as_deref() == Some(line) && self.last_emitted_at.elapsed() < self.max_gap {
+            self.suppressed_count += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Record `line` as emitted, returning the summary for any suppressed repeats
+    fn emitted(&mut self, line: &str) -> Option<String> {
+        let summary = self.take_summary();
+        self.last_line = Some(line.to_string());
+        self.last_emitted_at = Instant::now();
+        summary
+    }
+
+    /// A line that got past `suppress` was dropped further on; it still ends
+    /// the current run, whose summary goes out with the next emitted line
+    fn skipped(&mut self) {
+        self.last_line = None;
+    }
+
+    /// Summary for pending repeats, resetting the tracked line
+    fn take_summary(&mut self) -> Option<String> {
+        let count = std::mem::take(&mut self.suppressed_count);
+        (count > 0).then(|| format!("[INFO] previous message repeated {} times", count))
+    }
+
+    fn reset(&mut self) -> Option<String> {
+        self.last_line = None;
+        self.take_summary()
+    }
+}
+
+/// Checks the `SEQ:<n>:` prefix the node may put on each line
+#[derive(Default)]
+struct SequenceTracker {
+    last_seq: Option<u32>,
+    /// Take the next number as it comes instead of expecting one
+    resyncing: bool,
+}
+
+impl SequenceTracker {
+    /// Strip any sequence prefix from `line`, returning the rest of the line
+    /// and, if the number is not the one expected, how it differs
+    fn check<'a>(&mut self, line: &'a str) -> (&'a str, Option<String>) {
+        let Some((seq, rest)) = line.strip_prefix("SEQ:").and_then(|tail| tail.split_once(':')) else {
+            return (line, None);
+        };
+        let Ok(seq) = seq.parse::<u32>() else {
+            return (line, None);
+        };
+
+        let expected = match std::mem::take(&mut self.resyncing) {
+            true => seq,
+            false => self.last_seq.map_or(0, |last| last.wrapping_add(1)),
+        };
+        self.last_seq = Some(seq);
+        let gap = match seq.wrapping_sub(expected) {
+            0 => None,
+            missed if seq > expected => Some(format!("{} lines missing before sequence number {}", missed, seq)),
+            _ => Some(format!("sequence number {} after {}, expected {}", seq, expected.wrapping_sub(1), expected)),
+        };
+        (rest, gap)
+    }
+
+    fn reset(&mut self) {
+        self.last_seq = None;
+        self.resyncing = false;
+    }
+
+    /// Accept whatever number the next line carries, for when the node kept
+    /// counting lines the probe never saw
+    fn resync(&mut self) {
+        self.resyncing = true;
+    }
+}
+
+/// Collector settings that commands change while it runs
+#[derive(Clone)]
+pub struct CollectorSettings {
+    pub filter_string: Arc<RwLock<String>>,
+    /// Timezone log timestamps are written in; UTC when unset
+    pub timezone: Arc<RwLock<Option<Tz>>>,
+    /// Where the node sends its logs; line metrics are only counted while it is "usb"
+    pub node_log_output: Arc<RwLock<String>>,
+}
+
+#[tracing::instrument(name = "usb_collector", skip_all, fields(port = %config.usb_port))]
 pub async fn run(
     config: Arc<Config>,
-    buffer: Arc<RwLock<Vec<LogEntry>>>,
-    filter_string: Arc<RwLock<String>>,
+    buffer: Arc<RwLock<LogBuffer>>,
+    settings: CollectorSettings,
     mut usb_rx: mpsc::Receiver<UsbMessage>,
+    log_tx: broadcast::Sender<LogEntry>,
+    raw_line_tx: broadcast::Sender<String>,
+    usb_stats: Arc<UsbStats>,
 ) -> Result<()> {
     info!("USB collector task started");
+
+    let mut suppressor = config
+        .suppress_duplicates
+        .then(|| DuplicateSuppressor::new(Duration::from_secs(config.max_duplicate_gap_secs)));
+    let archive = config
+        .local_archive_path
+        .clone()
+        .map(|path| LocalArchive::spawn(path, config.local_archive_max_bytes, config.local_archive_keep_files));
+    let mut sequence = config.expect_sequence_numbers.then(SequenceTracker::default);
+    // Hashes of the most recent distinct lines, for dropping repeats that are not consecutive
+    let mut recent_lines: Option<LruCache<u64, ()>> = NonZeroUsize::new(config.dedup_window).map(LruCache::new);
+    let mut logs_were_on_usb = true;
     
     while let Some(msg) = usb_rx.recv().await {
-        match msg {
-            UsbMessage::LineReceived(line) => {
-                trace!("Processing line from USB: {}", line);
-                
-                // Generate timestamp in ISO 8601 UTC format
-                let timestamp = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
-                
-                // Apply filter
-                let filter = filter_string.read().await;
-                if !filter.is_empty() && !line.contains(filter.as_str()) {
-                    continue;
-                }
-                drop(filter);
-                
-                // Create log entry
-                let entry = LogEntry::new(timestamp, line);
-                
-                // Add to buffer, removing oldest if needed
-                let mut buf = buffer.write().await;
-                if buf.len() >= config.buffer_size {
-                    buf.remove(0);
-                }
-                buf.push(entry);
-            }
+        let line = match msg {
+            UsbMessage::LineReceived(line) => line,
+            // Frames are kept as their JSON text so filtering and suppression apply alike
+            UsbMessage::FrameReceived(frame) => frame.to_string(),
             UsbMessage::Connected => {
                 info!("USB collector notified of connection");
+                // A new connection may mean a restarted node with a fresh counter
+                if let Some(sequence) = sequence.as_mut() {
+                    sequence.reset();
+                }
+                flush_suppressed(&mut suppressor, &buffer, &archive, &log_tx, *settings.timezone.read().await).await;
+                continue;
             }
             UsbMessage::Disconnected => {
                 info!("USB collector notified of disconnection");
+                flush_suppressed(&mut suppressor, &buffer, &archive, &log_tx, *settings.timezone.read().await).await;
+                continue;
+            }
+        };
+        trace!("Processing line from USB: {}", line);
+        if raw_line_tx.receiver_count() > 0 {
+            let _ = raw_line_tx.send(line.clone());
+        }
+
+        // Lines still turn up while node logs go elsewhere (query replies, a
+        // late line), but they say nothing about the USB link, so they are kept
+        // out of the line metrics. The node numbers lines it does not send over
+        // USB too, so the sequence check resyncs once logs come back.
+        let logs_on_usb = *settings.node_log_output.read().await == "usb";
+        if logs_on_usb != logs_were_on_usb {
+            if let Some(sequence) = sequence.as_mut().filter(|_| logs_on_usb) {
+                sequence.resync();
+            }
+            logs_were_on_usb = logs_on_usb;
+        }
+
+        // Check and strip the sequence prefix, if the node sends one
+        let line = match sequence.as_mut() {
+            Some(sequence) => {
+                let (rest, gap) = sequence.check(&line);
+                if let Some(gap) = gap.filter(|_| logs_on_usb) {
+                    usb_stats.sequence_gaps_total.fetch_add(1, Ordering::Relaxed);
+                    warn!("USB sequence gap: {}", gap);
+                }
+                rest.to_string()
+            }
+            None => line,
+        };
+        
+        // Generate timestamp in ISO 8601 format
+        let (timestamp, timezone_offset) = format_timestamp(Utc::now(), *settings.timezone.read().await);
+        
+        // Apply filter
+        let filter = settings.filter_string.read().await;
+        if !filter.is_empty() && !line.contains(filter.as_str()) {
+            continue;
+        }
+        drop(filter);
+
+        // Coalesce consecutive repeats before the dedup window sees them, so a
+        // run of one line is logged once plus a "repeated <n> times" summary
+        // instead of being silently dropped as dedup hits
+        if suppressor.as_mut().is_some_and(|suppressor| suppressor.suppress(&line)) {
+            continue;
+        }
+
+        // Drop lines repeated within the dedup window. Only misses are inserted,
+        // so a line leaves the window after `dedup_window` other distinct lines
+        // however often it repeats in the meantime.
+        if let Some(recent_lines) = recent_lines.as_mut() {
+            let hash = line_hash(&line);
+            if recent_lines.contains(&hash) {
+                if logs_on_usb {
+                    usb_stats.dedup_hits_total.fetch_add(1, Ordering::Relaxed);
+                }
+                if let Some(suppressor) = suppressor.as_mut() {
+                    suppressor.skipped();
+                }
+                trace!("Dropping line repeated within dedup_window");
+                continue;
             }
+            recent_lines.put(hash, ());
         }
+        let summary = suppressor.as_mut().and_then(|suppressor| suppressor.emitted(&line));
+        
+        // Create log entry
+        let mut entry = match config.parse_structured_logs {
+            true => LogEntry::parse_structured(timestamp.clone(), &line).0,
+            false => LogEntry::new(timestamp.clone(), line),
+        };
+        entry.timezone_offset = timezone_offset.clone();
+        
+        // Add to buffer, removing stale and oldest entries if needed
+        let mut buf = buffer.write().await;
+        if let Some(max_age) = config.max_log_age_seconds {
+            let removed = buf.retain_recent(Duration::from_secs(max_age));
+            if removed > 0 {
+                debug!("Removed {} log entries older than {}s", removed, max_age);
+            }
+        }
+        if let Some(summary) = summary {
+            let mut summary = LogEntry::new(timestamp, summary);
+            summary.timezone_offset = timezone_offset;
+            push_entry(&mut buf, &archive, &log_tx, summary);
+        }
+        push_entry(&mut buf, &archive, &log_tx, entry);
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// `time` as an ISO 8601 timestamp in `timezone` (UTC when unset), with the
+/// offset applied if a timezone was given
+fn format_timestamp(time: DateTime<Utc>, timezone: Option<Tz>) -> (String, Option<String>) {
+    match timezone {
+        Some(timezone) => {
+            let local = time.with_timezone(&timezone);
+            (local.format("%Y-%m-%dT%H:%M:%S%:z").to_string(), Some(local.format("%:z").to_string()))
+        }
+        None => (time.format("%Y-%m-%dT%H:%M:%SZ").to_string(), None),
+    }
+}
+
+fn line_hash(line: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    line.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Emit any pending repeat summary and start suppression afresh
+async fn flush_suppressed(
+    suppressor: &mut Option<DuplicateSuppressor>,
+    buffer: &Arc<RwLock<LogBuffer>>,
+    archive: &Option<LocalArchive>,
+    log_tx: &broadcast::Sender<LogEntry>,
+    timezone: Option<Tz>,
+) {
+    if let Some(summary) = suppressor.as_mut().and_then(DuplicateSuppressor::reset) {
+        let (timestamp, timezone_offset) = format_timestamp(Utc::now(), timezone);
+        let mut entry = LogEntry::new(timestamp, summary);
+        entry.timezone_offset = timezone_offset;
+        push_entry(&mut *buffer.write().await, archive, log_tx, entry);
+    }
+}
+
+/// Buffer `entry` for upload, archive it locally when enabled and pass it to
+/// any live subscribers
+fn push_entry(buffer: &mut LogBuffer, archive: &Option<LocalArchive>, log_tx: &broadcast::Sender<LogEntry>, entry: LogEntry) {
+    if let Some(archive) = archive {
+        archive.archive(&entry);
+    }
+    if log_tx.receiver_count() > 0 {
+        let _ = log_tx.send(entry.clone());
+    }
+    buffer.push(entry);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::task::JoinHandle;
+
+    /// A running collector fed through `usb_tx`
+    struct Collector {
+        usb_tx: mpsc::Sender<UsbMessage>,
+        buffer: Arc<RwLock<LogBuffer>>,
+        settings: CollectorSettings,
+        stats: Arc<UsbStats>,
+        task: JoinHandle<Result<()>>,
+        marks: usize,
+    }
+
+    impl Collector {
+        /// Start a collector with `settings` (TOML lines) added to a minimal config
+        fn start(settings: &str) -> Self {
+            let config: Config = toml::from_str(&format!(
+                "usb_port = \"/dev/null\"\nserver_url = \"http://127.0.0.1:9\"\napi_key = \"k\"\nnode_id = 1\n\
+                 node_firmware_url = \"http://127.0.0.1:9/node\"\nprobe_firmware_url = \"http://127.0.0.1:9/probe\"\n{}",
+                settings
+            ))
+            .unwrap();
+            let buffer = Arc::new(RwLock::new(LogBuffer::new(config.buffer_size)));
+            let settings = CollectorSettings {
+                filter_string: Arc::new(RwLock::new(String::new())),
+                timezone: Arc::new(RwLock::new(None)),
+                node_log_output: Arc::new(RwLock::new("usb".to_string())),
+            };
+            let stats = Arc::new(UsbStats::default());
+            let (usb_tx, usb_rx) = mpsc::channel(64);
+            let task = tokio::spawn(run(
+                Arc::new(config),
+                buffer.clone(),
+                settings.clone(),
+                usb_rx,
+                broadcast::channel(16).0,
+                broadcast::channel(16).0,
+                stats.clone(),
+            ));
+            Self { usb_tx, buffer, settings, stats, task, marks: 0 }
+        }
+
+        async fn lines(&self, lines: &[&str]) {
+            for line in lines {
+                self.usb_tx.send(UsbMessage::LineReceived(line.to_string())).await.unwrap();
+            }
+        }
+
+        /// Wait until every line sent so far has been processed, by sending a
+        /// unique marker line and waiting for it to be buffered
+        async fn sync(&mut self) {
+            self.marks += 1;
+            let mark = format!("[INFO] mark {}", self.marks);
+            self.lines(&[&mark]).await;
+            while !self.buffer.read().await.iter().any(|entry| entry.message == mark) {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        /// Messages buffered so far, without markers
+        async fn messages(&mut self) -> Vec<String> {
+            self.sync().await;
+            let buffer = self.buffer.read().await;
+            buffer.iter().map(|entry| entry.message.clone()).filter(|message| !message.starts_with("[INFO] mark ")).collect()
+        }
+
+        fn counter(&self, counter: &std::sync::atomic::AtomicU64) -> u64 {
+            counter.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Drop for Collector {
+        fn drop(&mut self) {
+            self.task.abort();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn suppressor_summarises_a_run_when_it_ends_or_the_gap_passes() {
+        let mut suppressor = DuplicateSuppressor::new(Duration::from_secs(5));
+        assert!(!suppressor.suppress("a"));
+        assert_eq!(suppressor.emitted("a"), None);
+        assert!(suppressor.suppress("a"));
+        assert!(suppressor.suppress("a"));
+
+        // The gap counts from the last emitted copy, so a long run is logged again every gap
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(!suppressor.suppress("a"));
+        assert_eq!(suppressor.emitted("a").as_deref(), Some("[INFO] previous message repeated 2 times"));
+
+        assert!(suppressor.suppress("a"));
+        assert!(!suppressor.suppress("b"));
+        assert_eq!(suppressor.emitted("b").as_deref(), Some("[INFO] previous message repeated 1 times"));
+        assert_eq!(suppressor.emitted("c"), None);
+        assert_eq!(suppressor.reset(), None);
+    }
+
+    #[tokio::test]
+    async fn suppression_restarts_on_reconnect() {
+        let mut collector = Collector::start("suppress_duplicates = true\n");
+        collector.lines(&["[WARN] low battery", "[WARN] low battery", "[WARN] low battery"]).await;
+        collector.usb_tx.send(UsbMessage::Disconnected).await.unwrap();
+        collector.usb_tx.send(UsbMessage::Connected).await.unwrap();
+        collector.lines(&["[WARN] low battery", "[WARN] low battery"]).await;
+        collector.usb_tx.send(UsbMessage::Connected).await.unwrap();
+
+        assert_eq!(
+            collector.messages().await,
+            vec![
+                "[WARN] low battery",
+                "[INFO] previous message repeated 2 times",
+                "[WARN] low battery",
+                "[INFO] previous message repeated 1 times",
+            ]
+        );
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn timestamps_follow_dst_transitions() {
+        let new_york = Some(chrono_tz::America::New_York);
+        let cases = [
+            // Spring forward: 02:00 EST becomes 03:00 EDT
+            ("2024-03-10T06:59:59Z", "2024-03-10T01:59:59-05:00", "-05:00"),
+            ("2024-03-10T07:00:00Z", "2024-03-10T03:00:00-04:00", "-04:00"),
+            // Fall back: 02:00 EDT becomes 01:00 EST, so 01:xx happens twice
+            ("2024-11-03T05:30:00Z", "2024-11-03T01:30:00-04:00", "-04:00"),
+            ("2024-11-03T06:30:00Z", "2024-11-03T01:30:00-05:00", "-05:00"),
+        ];
+        for (time, local, offset) in cases {
+            assert_eq!(format_timestamp(utc(time), new_york), (local.to_string(), Some(offset.to_string())));
+        }
+
+        assert_eq!(format_timestamp(utc("2024-03-10T07:00:00Z"), None), ("2024-03-10T07:00:00Z".to_string(), None));
+        let kolkata = format_timestamp(utc("2024-03-10T07:00:00Z"), Some(chrono_tz::Asia::Kolkata));
+        assert_eq!(kolkata.0, "2024-03-10T12:30:00+05:30");
+    }
+
+    #[tokio::test]
+    async fn entries_carry_the_offset_of_the_timezone_in_use() {
+        let mut collector = Collector::start("");
+        collector.lines(&["[INFO] utc"]).await;
+        collector.sync().await;
+        *collector.settings.timezone.write().await = Some(chrono_tz::Asia::Kolkata);
+        collector.lines(&["[INFO] local"]).await;
+        collector.sync().await;
+
+        let buffer = collector.buffer.read().await;
+        let offsets: Vec<_> = buffer.iter().map(|entry| (entry.message.as_str(), entry.timezone_offset.as_deref())).collect();
+        assert_eq!(offsets[0], ("[INFO] utc", None));
+        assert!(offsets.contains(&("[INFO] local", Some("+05:30"))));
+        assert!(buffer.iter().find(|entry| entry.message == "[INFO] local").unwrap().timestamp.ends_with("+05:30"));
+    }
+
+    #[test]
+    fn sequence_tracker_reports_gaps_and_strips_prefixes() {
+        let mut sequence = SequenceTracker::default();
+        assert_eq!(sequence.check("SEQ:0:[INFO] a"), ("[INFO] a", None));
+        assert_eq!(sequence.check("SEQ:1:[INFO] b"), ("[INFO] b", None));
+        assert_eq!(sequence.check("SEQ:4:[INFO] c").1.as_deref(), Some("2 lines missing before sequence number 4"));
+        assert_eq!(sequence.check("SEQ:5:[INFO] d").1, None);
+        assert_eq!(sequence.check("SEQ:2:[INFO] e").1.as_deref(), Some("sequence number 2 after 5, expected 6"));
+
+        // Lines without a usable prefix pass through untouched and do not count
+        assert_eq!(sequence.check("[INFO] plain"), ("[INFO] plain", None));
+        assert_eq!(sequence.check("SEQ:x:[INFO] f"), ("SEQ:x:[INFO] f", None));
+        assert_eq!(sequence.check("SEQ:3:[INFO] g").1, None);
+    }
+
+    #[test]
+    fn sequence_tracker_wraps_resets_and_resyncs() {
+        let mut sequence = SequenceTracker::default();
+        sequence.resync();
+        assert_eq!(sequence.check(&format!("SEQ:{}:a", u32::MAX)).1, None);
+        assert_eq!(sequence.check("SEQ:0:b").1, None);
+
+        sequence.reset();
+        assert_eq!(sequence.check("SEQ:0:c").1, None);
+        sequence.resync();
+        assert_eq!(sequence.check("SEQ:900:d").1, None);
+        assert_eq!(sequence.check("SEQ:902:e").1.as_deref(), Some("1 lines missing before sequence number 902"));
+    }
+
+    #[tokio::test]
+    async fn sequence_prefixes_are_kept_unless_expected() {
+        let mut collector = Collector::start("");
+        collector.lines(&["SEQ:0:[INFO] a", "SEQ:5:[INFO] b"]).await;
+        assert_eq!(collector.messages().await, vec!["SEQ:0:[INFO] a", "SEQ:5:[INFO] b"]);
+        assert_eq!(collector.counter(&collector.stats.sequence_gaps_total), 0);
+    }
+
+    #[tokio::test]
+    async fn line_metrics_pause_while_node_logs_are_off_usb() {
+        let mut collector = Collector::start("expect_sequence_numbers = true\ndedup_window = 8\n");
+        collector.lines(&["SEQ:0:[INFO] a", "SEQ:2:[INFO] b", "SEQ:3:[INFO] b"]).await;
+        collector.sync().await;
+        assert_eq!(collector.counter(&collector.stats.sequence_gaps_total), 1);
+        assert_eq!(collector.counter(&collector.stats.dedup_hits_total), 1);
+
+        *collector.settings.node_log_output.write().await = "silent".to_string();
+        collector.lines(&["SEQ:9:[INFO] late", "SEQ:15:[INFO] late"]).await;
+        collector.sync().await;
+        assert_eq!(collector.counter(&collector.stats.sequence_gaps_total), 1);
+        assert_eq!(collector.counter(&collector.stats.dedup_hits_total), 1);
+
+        // Numbering picks up wherever the node got to while logging elsewhere
+        *collector.settings.node_log_output.write().await = "usb".to_string();
+        collector.lines(&["SEQ:40:[INFO] c", "SEQ:41:[INFO] d", "SEQ:43:[INFO] e"]).await;
+        assert_eq!(collector.messages().await, vec!["[INFO] a", "[INFO] b", "[INFO] late", "[INFO] c", "[INFO] d", "[INFO] e"]);
+        assert_eq!(collector.counter(&collector.stats.sequence_gaps_total), 2);
+    }
+
+    #[tokio::test]
+    async fn dedup_window_drops_interleaved_repeats_after_coalescing() {
+        let mut collector = Collector::start("suppress_duplicates = true\ndedup_window = 2\n");
+        collector.lines(&["[INFO] a", "[INFO] a", "[INFO] a", "[INFO] b", "[INFO] a", "[INFO] b", "[INFO] c", "[INFO] a"]).await;
+
+        // Hits do not refresh a line, so "a" has left the window by the time it comes back after "c"
+        assert_eq!(
+            collector.messages().await,
+            vec!["[INFO] a", "[INFO] previous message repeated 2 times", "[INFO] b", "[INFO] c", "[INFO] a"]
+        );
+        assert_eq!(collector.counter(&collector.stats.dedup_hits_total), 2);
+    }
+}
@@ -1,18 +1,65 @@
+use crate::error::ProbeError;
+use crate::usb_traffic_log::UsbTrafficLog;
 use anyhow::Result;
-use log::{debug, trace,error, info};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration};
-use tokio_serial::SerialPortBuilderExt;
+use bytes::{Buf, BytesMut};
+use log::{debug, error, info, trace, warn};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
+use tokio_serial::{SerialPortBuilderExt, SerialPortInfo, SerialPortType};
 
 const INITIAL_BACKOFF_MS: u64 = 1000;
 const MAX_BACKOFF_MS: u64 = 60000;
+/// USB vendor and product ID of an RP2040 running the Pico SDK's CDC serial
+const RP2040_USB_ID: (u16, u16) = (0x2E8A, 0x000A);
 
 /// Commands that can be sent to the USB manager
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum UsbCommand {
     /// Send a raw command to the USB port
     SendCommand(String),
+    /// Send a command and deliver the first received line starting with
+    /// `response_prefix` to `respond_to` instead of the log collector
+    Query {
+        command: String,
+        response_prefix: String,
+        respond_to: oneshot::Sender<String>,
+    },
+    /// Write bytes to the USB port verbatim, without a trailing CRLF
+    SendRaw(Vec<u8>),
+    /// Write bytes as one frame, preceded by their 2-byte big-endian length
+    SendRawFrame(Vec<u8>),
+    /// Drop the connection as if the port had failed (test command)
+    SimulateDisconnect,
+    /// Fail the next N connection attempts (test command)
+    SimulateConnectFailure(u32),
+    /// Signal `notify` the next time the port is (re)connected
+    NotifyOnConnect(oneshot::Sender<()>),
+    /// Stop (`true`) or resume reconnecting and sending keepalives when the
+    /// node goes quiet, e.g. while it sleeps
+    SuspendIdleTimeout(bool),
+}
+
+impl UsbCommand {
+    /// Bytes written to the port
+    pub fn wire_bytes(&self) -> Vec<u8> {
+        match self {
+            UsbCommand::SendCommand(command) | UsbCommand::Query { command, .. } => format!("{}\r\n", command).into_bytes(),
+            UsbCommand::SendRaw(bytes) => bytes.clone(),
+            UsbCommand::SendRawFrame(payload) => {
+                let mut bytes = (payload.len() as u16).to_be_bytes().to_vec();
+                bytes.extend_from_slice(payload);
+                bytes
+            }
+            UsbCommand::SimulateDisconnect
+            | UsbCommand::SimulateConnectFailure(_)
+            | UsbCommand::NotifyOnConnect(_)
+            | UsbCommand::SuspendIdleTimeout(_) => Vec::new(),
+        }
+    }
 }
 
 /// Messages from USB manager to consumers
@@ -20,16 +67,113 @@ pub enum UsbCommand {
 pub enum UsbMessage {
     /// A line was received from the USB port
     LineReceived(String),
+    /// A CBOR frame was received from the USB port (frame protocol only)
+    FrameReceived(serde_json::Value),
     /// Connection status changed
     Connected,
     Disconnected,
 }
 
+/// How the node's output is split into messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbProtocol {
+    /// Newline-terminated text lines
+    Line,
+    /// 2-byte big-endian length followed by a CBOR payload
+    Frame,
+}
+
+impl UsbProtocol {
+    pub fn parse(value: &str) -> Result<Self, ProbeError> {
+        match value.to_lowercase().as_str() {
+            "line" => Ok(UsbProtocol::Line),
+            "frame" => Ok(UsbProtocol::Frame),
+            other => Err(ProbeError::ConfigError(format!("Unknown usb_protocol '{}', expected line or frame", other))),
+        }
+    }
+}
+
+/// Traffic counters shared between the USB manager and its handles
+#[derive(Debug, Default)]
+pub struct UsbStats {
+    pub bytes_received_total: AtomicU64,
+    pub bytes_sent_total: AtomicU64,
+    /// Receive rate over the last full minute, bytes/sec
+    pub rx_rate_bps: AtomicU64,
+    /// Transmit rate over the last full minute, bytes/sec
+    pub tx_rate_bps: AtomicU64,
+    /// Commands queued by handles but not yet taken by the USB manager
+    pub pending_commands: AtomicI64,
+    /// Mean round-trip time of the last successful latency measurement, in
+    /// microseconds; 0 until one has run
+    pub rtt_us: AtomicU64,
+    /// Node lines dropped because they were within `dedup_window`
+    pub dedup_hits_total: AtomicU64,
+    /// Breaks in the node's `SEQ:<n>:` line numbering
+    pub sequence_gaps_total: AtomicU64,
+    /// Attempts to open the port, successful or not
+    pub connection_attempts_total: AtomicU64,
+}
+
+impl UsbStats {
+    const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+    /// Recompute the per-second rates once per minute
+    pub async fn run_rate_ticker(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(Self::RATE_WINDOW);
+        let mut last_rx = self.bytes_received_total.load(Ordering::Relaxed);
+        let mut last_tx = self.bytes_sent_total.load(Ordering::Relaxed);
+
+        loop {
+            ticker.tick().await;
+            let rx = self.bytes_received_total.load(Ordering::Relaxed);
+            let tx = self.bytes_sent_total.load(Ordering::Relaxed);
+            self.rx_rate_bps.store((rx - last_rx) / Self::RATE_WINDOW.as_secs(), Ordering::Relaxed);
+            self.tx_rate_bps.store((tx - last_tx) / Self::RATE_WINDOW.as_secs(), Ordering::Relaxed);
+            last_rx = rx;
+            last_tx = tx;
+        }
+    }
+
+    pub fn record_rtt(&self, rtt_ms: f64) {
+        self.rtt_us.store((rtt_ms * 1000.0) as u64, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "usb_bytes_received_total": self.bytes_received_total.load(Ordering::Relaxed),
+            "usb_bytes_sent_total": self.bytes_sent_total.load(Ordering::Relaxed),
+            "usb_rx_rate_bps": self.rx_rate_bps.load(Ordering::Relaxed),
+            "usb_tx_rate_bps": self.tx_rate_bps.load(Ordering::Relaxed),
+            "usb_pending_commands": self.pending_commands.load(Ordering::Relaxed),
+            "usb_rtt_ms": self.rtt_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            "usb_dedup_hits_total": self.dedup_hits_total.load(Ordering::Relaxed),
+            "usb_sequence_gaps_total": self.sequence_gaps_total.load(Ordering::Relaxed),
+            "usb_connection_attempts_total": self.connection_attempts_total.load(Ordering::Relaxed),
+        })
+    }
+}
+
 /// Manages the USB serial port connection and handles both reading and writing
 pub struct UsbManager {
     port_path: String,
     command_rx: mpsc::Receiver<UsbCommand>,
     message_tx: mpsc::Sender<UsbMessage>,
+    pending_queries: Vec<(String, oneshot::Sender<String>)>,
+    /// Waiting for the next successful connection
+    connect_waiters: Vec<oneshot::Sender<()>>,
+    stats: Arc<UsbStats>,
+    protocol: UsbProtocol,
+    /// Connection attempts still to fail on purpose, set by `SimulateConnectFailure`
+    simulated_connect_failures: u32,
+    /// Reconnect after this long without input from the node
+    idle_timeout: Option<Duration>,
+    /// Send `/KA` after this long without input, before `idle_timeout` hits
+    keepalive: Option<Duration>,
+    /// Neither `idle_timeout` nor `keepalive` apply while set
+    idle_timeout_suspended: bool,
+    /// Verbatim record of commands sent and lines received, if enabled
+    traffic_log: Option<UsbTrafficLog>,
 }
 
 impl UsbManager {
@@ -37,11 +181,50 @@ impl UsbManager {
         port_path: String,
         command_rx: mpsc::Receiver<UsbCommand>,
         message_tx: mpsc::Sender<UsbMessage>,
+        stats: Arc<UsbStats>,
+        protocol: UsbProtocol,
     ) -> Self {
         Self {
             port_path,
             command_rx,
             message_tx,
+            pending_queries: Vec::new(),
+            connect_waiters: Vec::new(),
+            stats,
+            protocol,
+            simulated_connect_failures: 0,
+            idle_timeout: None,
+            keepalive: None,
+            idle_timeout_suspended: false,
+            traffic_log: None,
+        }
+    }
+
+    /// Reconnect when the node sends nothing for `idle_timeout`, prodding it with
+    /// `/KA` after `keepalive`. Zero durations disable either.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration, keepalive: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout).filter(|d| !d.is_zero());
+        self.keepalive = Some(keepalive).filter(|d| !d.is_zero());
+        self
+    }
+
+    /// Append every command sent and line received to `path`, moving it to
+    /// `<path>.1` once it grows past `max_bytes`
+    pub fn with_traffic_log(mut self, path: PathBuf, max_bytes: u64) -> Self {
+        self.traffic_log = Some(UsbTrafficLog::new(path, max_bytes));
+        self
+    }
+
+    /// When to next act on an idle port: send a keepalive, or give up on the connection
+    fn idle_deadline(&self, last_input: Instant, keepalive_sent: bool) -> Option<(Instant, bool)> {
+        if self.idle_timeout_suspended {
+            return None;
+        }
+        let keepalive = self.keepalive.filter(|k| !keepalive_sent && self.idle_timeout.is_none_or(|idle| *k < idle));
+        match (keepalive, self.idle_timeout) {
+            (Some(keepalive), _) => Some((last_input + keepalive, true)),
+            (None, Some(idle)) => Some((last_input + idle, false)),
+            (None, None) => None,
         }
     }
 
@@ -49,11 +232,22 @@ impl UsbManager {
         let mut backoff_ms = INITIAL_BACKOFF_MS;
 
         loop {
-            match self.connect_and_handle().await {
-                Ok(_) => {
+            let result = self.connect_and_handle().await;
+            if let Some(traffic_log) = self.traffic_log.as_mut() {
+                traffic_log.close().await;
+            }
+
+            match result {
+                Ok(true) => {
                     info!("USB connection closed normally");
                     backoff_ms = INITIAL_BACKOFF_MS;
                 }
+                Ok(false) => {
+                    // The port opened but the node never spoke; do not hammer it
+                    warn!("USB connection closed before any data was received. Retrying in {}ms...", backoff_ms);
+                    sleep(Duration::from_millis(backoff_ms)).await;
+                    backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_MS);
+                }
                 Err(e) => {
                     error!("USB connection error: {}. Retrying in {}ms...", e, backoff_ms);
                     let _ = self.message_tx.send(UsbMessage::Disconnected).await;
@@ -64,37 +258,73 @@ impl UsbManager {
         }
     }
 
-    async fn connect_and_handle(&mut self) -> Result<()> {
+    /// Run one connection until it closes, returning whether any data arrived
+    #[tracing::instrument(name = "usb_session", skip_all, fields(port = %self.port_path, attempt = tracing::field::Empty))]
+    async fn connect_and_handle(&mut self) -> Result<bool> {
+        let attempt = self.stats.connection_attempts_total.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::Span::current().record("attempt", attempt);
+        if self.simulated_connect_failures > 0 {
+            self.simulated_connect_failures -= 1;
+            return Err(anyhow::anyhow!("simulated connection failure ({} more to come)", self.simulated_connect_failures));
+        }
+
         // Open serial port
         let port = tokio_serial::new(&self.port_path, 115200)
             .open_native_async()?;
 
         info!("Connected to USB port: {}", self.port_path);
+        if let Some(traffic_log) = self.traffic_log.as_mut() {
+            traffic_log.open().await;
+        }
         let _ = self.message_tx.send(UsbMessage::Connected).await;
+        for waiter in self.connect_waiters.drain(..) {
+            let _ = waiter.send(());
+        }
 
         // Split port into read and write halves
         let (reader, mut writer) = tokio::io::split(port);
         let mut reader = BufReader::new(reader);
         let mut line_buffer = String::new();
+        let mut frame_buffer = BytesMut::new();
+        let mut last_input = Instant::now();
+        let mut keepalive_sent = false;
+        let mut data_received = false;
 
         loop {
+            let idle_deadline = self.idle_deadline(last_input, keepalive_sent);
+
             tokio::select! {
-                // Handle incoming lines from USB
-                result = reader.read_line(&mut line_buffer) => {
+                // Handle incoming lines or frames from USB
+                result = read_input(&mut reader, self.protocol, &mut line_buffer, &mut frame_buffer) => {
                     match result {
                         Ok(0) => {
                             // EOF - connection closed
                             info!("USB connection closed");
                             break;
                         }
-                        Ok(_) => {
-                            // Remove trailing newline
-                            let line = line_buffer.trim_end().to_string();
-                            if !line.is_empty() {
-                                trace!("Received line from USB: {}", line);
-                                let _ = self.message_tx.send(UsbMessage::LineReceived(line)).await;
+                        Ok(n) => {
+                            self.stats.bytes_received_total.fetch_add(n as u64, Ordering::Relaxed);
+                            data_received = true;
+                            last_input = Instant::now();
+                            keepalive_sent = false;
+
+                            match self.protocol {
+                                UsbProtocol::Line => {
+                                    // Remove trailing newline
+                                    let line = line_buffer.trim_end().to_string();
+                                    if !line.is_empty() {
+                                        trace!("Received line from USB: {}", line);
+                                        if let Some(traffic_log) = self.traffic_log.as_mut() {
+                                            traffic_log.rx(&line).await;
+                                        }
+                                        if let Some(line) = self.answer_query(line) {
+                                            let _ = self.message_tx.send(UsbMessage::LineReceived(line)).await;
+                                        }
+                                    }
+                                    line_buffer.clear();
+                                }
+                                UsbProtocol::Frame => self.forward_frames(&mut frame_buffer).await,
                             }
-                            line_buffer.clear();
                         }
                         Err(e) => {
                             error!("Error reading from USB: {}", e);
@@ -103,26 +333,134 @@ impl UsbManager {
                     }
                 }
 
+                // Prod or give up on a node that has gone quiet
+                _ = sleep_until(idle_deadline.map_or(last_input, |(at, _)| at)), if idle_deadline.is_some() => {
+                    if idle_deadline.is_some_and(|(_, is_keepalive)| is_keepalive) {
+                        debug!("USB idle, sending keepalive");
+                        let keepalive = UsbCommand::SendCommand("/KA".to_string()).wire_bytes();
+                        writer.write_all(&keepalive).await?;
+                        writer.flush().await?;
+                        self.stats.bytes_sent_total.fetch_add(keepalive.len() as u64, Ordering::Relaxed);
+                        if let Some(traffic_log) = self.traffic_log.as_mut() {
+                            traffic_log.tx("/KA").await;
+                        }
+                        keepalive_sent = true;
+                        continue;
+                    }
+
+                    let idle = last_input.elapsed().as_secs();
+                    warn!("No input from USB for {}s, reconnecting", idle);
+                    return Err(anyhow::anyhow!("USB idle for {}s", idle));
+                }
+
                 // Handle commands to send to USB
                 Some(cmd) = self.command_rx.recv() => {
+                    self.stats.pending_commands.fetch_sub(1, Ordering::Relaxed);
                     match cmd {
-                        UsbCommand::SendCommand(command) => {
-                            debug!("Sending command to USB: {}", command);
-                            if let Err(e) = writer.write_all(format!("{}\r\n", command).as_bytes()).await {
-                                error!("Error writing to USB: {}", e);
-                                return Err(e.into());
-                            }
-                            if let Err(e) = writer.flush().await {
-                                error!("Error flushing USB: {}", e);
-                                return Err(e.into());
-                            }
+                        UsbCommand::SimulateDisconnect => {
+                            warn!("Simulating USB disconnect");
+                            return Err(anyhow::anyhow!("simulated disconnect"));
+                        }
+                        UsbCommand::SimulateConnectFailure(count) => {
+                            warn!("Simulating failure of the next {} USB connection attempts", count);
+                            self.simulated_connect_failures = count;
+                            continue;
+                        }
+                        UsbCommand::NotifyOnConnect(notify) => {
+                            self.connect_waiters.push(notify);
+                            continue;
+                        }
+                        UsbCommand::SuspendIdleTimeout(suspended) => {
+                            info!("USB idle timeout {}", if suspended { "suspended" } else { "resumed" });
+                            self.idle_timeout_suspended = suspended;
+                            // Count the quiet time from now rather than from before the suspension
+                            last_input = Instant::now();
+                            keepalive_sent = false;
+                            continue;
                         }
+                        _ => {}
+                    }
+
+                    let bytes = cmd.wire_bytes();
+                    let text_command = match &cmd {
+                        UsbCommand::SendCommand(command) | UsbCommand::Query { command, .. } => Some(command.clone()),
+                        _ => None,
+                    };
+                    if let UsbCommand::Query { response_prefix, respond_to, .. } = cmd {
+                        self.pending_queries.push((response_prefix, respond_to));
+                    }
+
+                    debug!("Sending command to USB: {}", String::from_utf8_lossy(&bytes).trim_end());
+                    if let Err(e) = writer.write_all(&bytes).await {
+                        error!("Error writing to USB: {}", e);
+                        return Err(e.into());
+                    }
+                    if let Err(e) = writer.flush().await {
+                        error!("Error flushing USB: {}", e);
+                        return Err(e.into());
+                    }
+                    self.stats.bytes_sent_total.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                    if let (Some(traffic_log), Some(command)) = (self.traffic_log.as_mut(), text_command) {
+                        traffic_log.tx(&command).await;
                     }
                 }
             }
         }
 
-        Ok(())
+        Ok(data_received)
+    }
+
+    /// Decode and forward every complete frame in `frame_buffer`, leaving any
+    /// partial frame for the next read
+    async fn forward_frames(&mut self, frame_buffer: &mut BytesMut) {
+        while frame_buffer.len() >= 2 {
+            let len = u16::from_be_bytes([frame_buffer[0], frame_buffer[1]]) as usize;
+            if frame_buffer.len() < 2 + len {
+                break;
+            }
+            frame_buffer.advance(2);
+            let payload = frame_buffer.split_to(len);
+
+            match ciborium::from_reader::<serde_json::Value, _>(&payload[..]) {
+                Ok(frame) => {
+                    trace!("Received frame from USB: {}", frame);
+                    if let Some(traffic_log) = self.traffic_log.as_mut() {
+                        traffic_log.rx(&frame.to_string()).await;
+                    }
+                    let _ = self.message_tx.send(UsbMessage::FrameReceived(frame)).await;
+                }
+                Err(e) => warn!("Dropping undecodable {}-byte USB frame: {}", len, e),
+            }
+        }
+    }
+
+    /// Hand `line` to the oldest pending query expecting it, or give it back
+    /// for normal processing. Queries whose caller gave up are discarded.
+    fn answer_query(&mut self, line: String) -> Option<String> {
+        self.pending_queries.retain(|(_, respond_to)| !respond_to.is_closed());
+
+        match self.pending_queries.iter().position(|(prefix, _)| line.starts_with(prefix.as_str())) {
+            Some(index) => {
+                let (_, respond_to) = self.pending_queries.remove(index);
+                let _ = respond_to.send(line);
+                None
+            }
+            None => Some(line),
+        }
+    }
+}
+
+/// Read the next line (line protocol) or the next chunk of frame data (frame
+/// protocol), returning the number of bytes read
+async fn read_input<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    protocol: UsbProtocol,
+    line_buffer: &mut String,
+    frame_buffer: &mut BytesMut,
+) -> std::io::Result<usize> {
+    match protocol {
+        UsbProtocol::Line => reader.read_line(line_buffer).await,
+        UsbProtocol::Frame => reader.read_buf(frame_buffer).await,
     }
 }
 
@@ -130,18 +468,568 @@ impl UsbManager {
 #[derive(Clone)]
 pub struct UsbHandle {
     command_tx: mpsc::Sender<UsbCommand>,
+    stats: Arc<UsbStats>,
+    max_pending_commands: usize,
 }
 
 impl UsbHandle {
-    pub fn new(command_tx: mpsc::Sender<UsbCommand>) -> Self {
-        Self { command_tx }
+    pub fn new(command_tx: mpsc::Sender<UsbCommand>, stats: Arc<UsbStats>, max_pending_commands: usize) -> Self {
+        Self {
+            command_tx,
+            stats,
+            max_pending_commands,
+        }
+    }
+
+    /// Queue `command` for the USB manager, failing right away instead of
+    /// blocking when more than `max_pending_commands` are already waiting
+    async fn enqueue(&self, command: UsbCommand) -> Result<()> {
+        let pending = self.stats.pending_commands.fetch_add(1, Ordering::Relaxed);
+        if pending >= self.max_pending_commands as i64 {
+            self.stats.pending_commands.fetch_sub(1, Ordering::Relaxed);
+            return Err(ProbeError::CommandError("USB command queue full".to_string()).into());
+        }
+
+        if let Err(e) = self.command_tx.send(command).await {
+            self.stats.pending_commands.fetch_sub(1, Ordering::Relaxed);
+            return Err(anyhow::anyhow!("USB manager is not running: {}", e));
+        }
+        Ok(())
+    }
+
+    /// Traffic counters of the USB manager this handle talks to
+    pub fn stats(&self) -> &UsbStats {
+        &self.stats
     }
 
     /// Send a command to the USB port
     pub async fn send_command(&self, command: String) -> Result<()> {
-        self.command_tx
-            .send(UsbCommand::SendCommand(command))
+        self.enqueue(UsbCommand::SendCommand(command))
+            .await
+            .map_err(|e| e.context("Failed to send USB command"))
+    }
+
+    /// Write bytes to the USB port as-is
+    pub async fn send_raw(&self, bytes: Vec<u8>) -> Result<()> {
+        self.enqueue(UsbCommand::SendRaw(bytes))
             .await
-            .map_err(|e| anyhow::anyhow!("Failed to send USB command: {}", e))
+            .map_err(|e| e.context("Failed to send raw USB data"))
+    }
+
+    /// Write `payload` to the USB port as one length-prefixed frame
+    pub async fn send_frame(&self, payload: Vec<u8>) -> Result<()> {
+        if payload.len() > u16::MAX as usize {
+            return Err(ProbeError::CommandError(format!("USB frame of {} bytes exceeds {} bytes", payload.len(), u16::MAX)).into());
+        }
+        self.enqueue(UsbCommand::SendRawFrame(payload))
+            .await
+            .map_err(|e| e.context("Failed to send USB frame"))
+    }
+
+    /// Make the USB manager drop the connection and go through reconnect backoff
+    pub async fn simulate_disconnect(&self) -> Result<()> {
+        self.enqueue(UsbCommand::SimulateDisconnect).await
+    }
+
+    /// Make the next `count` connection attempts fail
+    pub async fn simulate_connect_failure(&self, count: u32) -> Result<()> {
+        self.enqueue(UsbCommand::SimulateConnectFailure(count)).await
+    }
+
+    /// Stop (`true`) or resume reconnecting to and prodding a quiet node
+    pub async fn suspend_idle_timeout(&self, suspended: bool) -> Result<()> {
+        self.enqueue(UsbCommand::SuspendIdleTimeout(suspended)).await
+    }
+
+    /// Receiver that fires when the USB manager next connects to the port,
+    /// i.e. after the current connection has been lost and re-established
+    pub async fn next_connection(&self) -> Result<oneshot::Receiver<()>> {
+        let (notify, connected) = oneshot::channel();
+        self.enqueue(UsbCommand::NotifyOnConnect(notify)).await?;
+        Ok(connected)
+    }
+
+    /// Send a command and wait up to `wait` for the node's reply, i.e. the
+    /// first line starting with `response_prefix`
+    pub async fn query(&self, command: String, response_prefix: &str, wait: Duration) -> Result<String> {
+        let (respond_to, response) = oneshot::channel();
+        self.enqueue(UsbCommand::Query {
+            command: command.clone(),
+            response_prefix: response_prefix.to_string(),
+            respond_to,
+        })
+        .await
+        .map_err(|e| e.context("Failed to send USB query"))?;
+
+        match timeout(wait, response).await {
+            Ok(Ok(line)) => Ok(line),
+            Ok(Err(_)) => Err(anyhow::anyhow!("USB query {} was dropped", command)),
+            Err(_) => Err(anyhow::anyhow!("USB query {} timed out after {}ms", command, wait.as_millis())),
+        }
+    }
+}
+
+/// Table of `ports` for `--list-serial-ports`: path, type, USB VID:PID and
+/// product, with RP2040 nodes marked
+pub fn format_serial_ports(ports: &[SerialPortInfo]) -> String {
+    if ports.is_empty() {
+        return "No serial ports found\n".to_string();
+    }
+
+    let rows: Vec<[String; 4]> = ports
+        .iter()
+        .map(|port| {
+            let (port_type, usb_id, product) = match &port.port_type {
+                SerialPortType::UsbPort(usb) => {
+                    let product = [usb.manufacturer.as_deref(), usb.product.as_deref()].into_iter().flatten().collect::<Vec<_>>();
+                    let mut product = product.join(" ");
+                    if (usb.vid, usb.pid) == RP2040_USB_ID {
+                        product = format!("{} <- RP2040", product).trim_start().to_string();
+                    }
+                    ("USB", format!("{:04x}:{:04x}", usb.vid, usb.pid), product)
+                }
+                SerialPortType::PciPort => ("PCI", "-".to_string(), String::new()),
+                SerialPortType::BluetoothPort => ("Bluetooth", "-".to_string(), String::new()),
+                SerialPortType::Unknown => ("Unknown", "-".to_string(), String::new()),
+            };
+            [port.port_name.clone(), port_type.to_string(), usb_id, product]
+        })
+        .collect();
+
+    let header = ["PATH", "TYPE", "VID:PID", "PRODUCT"].map(String::from);
+    let widths: Vec<usize> = (0..3)
+        .map(|column| rows.iter().chain([&header]).map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+    let mut table = String::new();
+    for row in [&header].into_iter().chain(&rows) {
+        let line = format!("{:<w0$}  {:<w1$}  {:<w2$}  {}", row[0], row[1], row[2], row[3], w0 = widths[0], w1 = widths[1], w2 = widths[2]);
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Channel-compatible stand-in for `UsbManager` that needs no serial port.
+#[cfg(any(test, feature = "testing"))]
+#[cfg_attr(not(test), allow(dead_code))]
+pub mod testing {
+    use super::{UsbCommand, UsbMessage};
+    use anyhow::Result;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+
+    /// Emits a scripted sequence of `UsbMessage`s and records every `UsbCommand`
+    /// it receives. Cloning yields another handle to the same mock.
+    #[derive(Clone)]
+    pub struct MockUsbManager {
+        script: Vec<UsbMessage>,
+        responses: HashMap<String, String>,
+        command_rx: Arc<Mutex<Option<mpsc::Receiver<UsbCommand>>>>,
+        message_tx: mpsc::Sender<UsbMessage>,
+        sent: Arc<Mutex<Vec<String>>>,
+        /// Last `SuspendIdleTimeout` value received, if any
+        idle_timeout_suspended: Arc<Mutex<Option<bool>>>,
+    }
+
+    impl MockUsbManager {
+        pub fn new(
+            script: Vec<UsbMessage>,
+            command_rx: mpsc::Receiver<UsbCommand>,
+            message_tx: mpsc::Sender<UsbMessage>,
+        ) -> Self {
+            Self {
+                script,
+                responses: HashMap::new(),
+                command_rx: Arc::new(Mutex::new(Some(command_rx))),
+                message_tx,
+                sent: Arc::new(Mutex::new(Vec::new())),
+                idle_timeout_suspended: Arc::new(Mutex::new(None)),
+            }
+        }
+
+        /// Answer `UsbCommand::Query` for `command` with `line`
+        pub fn with_response(mut self, command: &str, line: &str) -> Self {
+            self.responses.insert(command.to_string(), line.to_string());
+            self
+        }
+
+        /// Emit the script, then capture commands until every `UsbHandle` is dropped.
+        pub async fn run(self) -> Result<()> {
+            let mut command_rx = self
+                .command_rx
+                .lock()
+                .unwrap()
+                .take()
+                .ok_or_else(|| anyhow::anyhow!("MockUsbManager is already running"))?;
+
+            for msg in &self.script {
+                let _ = self.message_tx.send(msg.clone()).await;
+            }
+
+            while let Some(cmd) = command_rx.recv().await {
+                self.sent.lock().unwrap().push(String::from_utf8_lossy(&cmd.wire_bytes()).into_owned());
+                match cmd {
+                    UsbCommand::Query { command, respond_to, .. } => {
+                        if let Some(line) = self.responses.get(&command) {
+                            let _ = respond_to.send(line.clone());
+                        }
+                    }
+                    UsbCommand::SuspendIdleTimeout(suspended) => *self.idle_timeout_suspended.lock().unwrap() = Some(suspended),
+                    _ => {}
+                }
+            }
+
+            Ok(())
+        }
+
+        /// Wire text of all commands captured so far, including line endings
+        pub fn sent_commands(&self) -> Vec<String> {
+            self.sent.lock().unwrap().clone()
+        }
+
+        /// Whether the idle timeout was last suspended or resumed, `None` if never touched
+        pub fn idle_timeout_suspended(&self) -> Option<bool> {
+            *self.idle_timeout_suspended.lock().unwrap()
+        }
+    }
+
+    /// Panic unless a command matching `expected` (ignoring trailing CRLF) was captured
+    pub fn assert_command_sent(mock: &MockUsbManager, expected: &str) {
+        let sent = mock.sent_commands();
+        let found = sent.iter().any(|command| command.trim_end() == expected);
+        assert!(found, "expected USB command {:?}, captured {:?}", expected, sent);
+    }
+
+    /// Deliver a message to consumers as if it came from the serial port
+    pub async fn inject_message(mock: &MockUsbManager, msg: UsbMessage) {
+        mock.message_tx.send(msg).await.expect("USB message receiver dropped");
+    }
+
+    /// A pseudo-terminal standing in for the node, so `UsbManager` can be run
+    /// against a real serial device path without hardware.
+    #[cfg(unix)]
+    pub mod pty_serial {
+        use nix::pty::openpty;
+        use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+        use nix::unistd::ttyname;
+        use std::fs::File;
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::fd::OwnedFd;
+        use std::path::PathBuf;
+        use std::sync::mpsc;
+        use std::time::{Duration, Instant};
+
+        /// Device end of a PTY pair. Lines written with `emit_line` arrive on
+        /// the serial port path returned by `PtyHarness::new`, and lines the
+        /// probe writes there can be checked with `assert_received`.
+        pub struct PtyHarness {
+            device: File,
+            received: mpsc::Receiver<String>,
+            /// Held open so the port path stays valid while `UsbManager` reconnects
+            _port: OwnedFd,
+        }
+
+        impl PtyHarness {
+            pub fn new() -> (PtyHarness, PathBuf) {
+                let pty = openpty(None, None).expect("failed to open PTY pair");
+                let mut termios = tcgetattr(&pty.slave).expect("failed to read PTY attributes");
+                cfmakeraw(&mut termios);
+                tcsetattr(&pty.slave, SetArg::TCSANOW, &termios).expect("failed to set PTY to raw mode");
+                let path = ttyname(&pty.slave).expect("failed to resolve PTY path");
+
+                let device = File::from(pty.master);
+                let reader = device.try_clone().expect("failed to clone PTY device");
+                let (tx, received) = mpsc::channel();
+                std::thread::spawn(move || {
+                    for line in BufReader::new(reader).lines() {
+                        let Ok(line) = line else { break };
+                        if tx.send(line.trim_end_matches('\r').to_string()).is_err() {
+                            break;
+                        }
+                    }
+                });
+
+                let harness = PtyHarness {
+                    device,
+                    received,
+                    _port: pty.slave,
+                };
+                (harness, path)
+            }
+
+            /// Send `line` to the probe as the node would, CRLF-terminated
+            pub fn emit_line(&mut self, line: &str) {
+                self.device
+                    .write_all(format!("{}\r\n", line).as_bytes())
+                    .expect("failed to write to PTY");
+            }
+
+            /// Panic unless the probe writes a line equal to `expected` within `timeout`.
+            /// Lines received before the match are discarded.
+            pub fn assert_received(&self, expected: &str, timeout: Duration) {
+                let deadline = Instant::now() + timeout;
+                let mut seen = Vec::new();
+                loop {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    match self.received.recv_timeout(remaining) {
+                        Ok(line) if line == expected => return,
+                        Ok(line) => seen.push(line),
+                        Err(_) => panic!("expected {:?} on the PTY within {:?}, received {:?}", expected, timeout, seen),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::pty_serial::PtyHarness;
+    use super::testing::{assert_command_sent, inject_message, MockUsbManager};
+    use super::*;
+
+    fn mock_handle(script: Vec<UsbMessage>) -> (MockUsbManager, UsbHandle, mpsc::Receiver<UsbMessage>) {
+        let (command_tx, command_rx) = mpsc::channel(8);
+        let (message_tx, message_rx) = mpsc::channel(8);
+        let mock = MockUsbManager::new(script, command_rx, message_tx).with_response("/MQ", "MQ:1:2:3:4");
+        let handle = UsbHandle::new(command_tx, Arc::new(UsbStats::default()), 8);
+        (mock, handle, message_rx)
+    }
+
+    #[tokio::test]
+    async fn mock_emits_script_then_records_commands() {
+        let (mock, handle, mut messages) = mock_handle(vec![UsbMessage::Connected, UsbMessage::LineReceived("hello".to_string())]);
+        let running = tokio::spawn(mock.clone().run());
+
+        assert!(matches!(messages.recv().await, Some(UsbMessage::Connected)));
+        assert!(matches!(messages.recv().await, Some(UsbMessage::LineReceived(line)) if line == "hello"));
+
+        handle.send_command("/LT".to_string()).await.unwrap();
+        assert_eq!(handle.query("/MQ".to_string(), "MQ:", Duration::from_secs(1)).await.unwrap(), "MQ:1:2:3:4");
+        // Queries without a scripted response are dropped rather than left hanging
+        assert!(handle.query("/XX".to_string(), "XX:", Duration::from_secs(1)).await.is_err());
+
+        drop(handle);
+        running.await.unwrap().unwrap();
+        assert_eq!(mock.sent_commands(), vec!["/LT\r\n", "/MQ\r\n", "/XX\r\n"]);
+        assert_command_sent(&mock, "/MQ");
+        assert!(mock.clone().run().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn mock_injects_messages() {
+        let (mock, _handle, mut messages) = mock_handle(Vec::new());
+        inject_message(&mock, UsbMessage::Disconnected).await;
+        assert!(matches!(messages.recv().await, Some(UsbMessage::Disconnected)));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected USB command")]
+    fn assert_command_sent_fails_for_missing_commands() {
+        let (mock, _handle, _messages) = mock_handle(Vec::new());
+        assert_command_sent(&mock, "/LT");
+    }
+
+    fn usb_port(name: &str, vid: u16, pid: u16, manufacturer: Option<&str>, product: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(tokio_serial::UsbPortInfo {
+                vid,
+                pid,
+                serial_number: None,
+                manufacturer: manufacturer.map(String::from),
+                product: product.map(String::from),
+            }),
+        }
+    }
+
+    #[test]
+    fn serial_port_table_aligns_columns_and_marks_the_rp2040() {
+        let ports = [
+            usb_port("/dev/ttyACM0", 0x2e8a, 0x000a, Some("Raspberry Pi"), Some("Pico")),
+            usb_port("/dev/ttyUSB0", 0x0403, 0x6001, None, Some("FT232R")),
+            usb_port("/dev/ttyACM1", 0x2e8a, 0x000a, None, None),
+            SerialPortInfo { port_name: "/dev/ttyS0".to_string(), port_type: SerialPortType::PciPort },
+            SerialPortInfo { port_name: "/dev/rfcomm0".to_string(), port_type: SerialPortType::BluetoothPort },
+        ];
+
+        assert_eq!(
+            format_serial_ports(&ports),
+            "PATH          TYPE       VID:PID    PRODUCT\n\
+             /dev/ttyACM0  USB        2e8a:000a  Raspberry Pi Pico <- RP2040\n\
+             /dev/ttyUSB0  USB        0403:6001  FT232R\n\
+             /dev/ttyACM1  USB        2e8a:000a  <- RP2040\n\
+             /dev/ttyS0    PCI        -\n\
+             /dev/rfcomm0  Bluetooth  -\n"
+        );
+        assert_eq!(format_serial_ports(&[]), "No serial ports found\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn manager_talks_to_a_pty_node() {
+        let (mut node, path) = PtyHarness::new();
+        let (command_tx, command_rx) = mpsc::channel(8);
+        let (message_tx, mut messages) = mpsc::channel(8);
+        let manager =
+            UsbManager::new(path.to_string_lossy().into_owned(), command_rx, message_tx, Arc::new(UsbStats::default()), UsbProtocol::Line);
+        let running = tokio::spawn(manager.run());
+        let handle = UsbHandle::new(command_tx, Arc::new(UsbStats::default()), 8);
+
+        assert!(matches!(timeout(Duration::from_secs(5), messages.recv()).await, Ok(Some(UsbMessage::Connected))));
+        node.emit_line("[INFO] booted");
+        let line = timeout(Duration::from_secs(5), messages.recv()).await.unwrap();
+        assert!(matches!(line, Some(UsbMessage::LineReceived(line)) if line == "[INFO] booted"));
+
+        handle.send_command("/LT".to_string()).await.unwrap();
+        tokio::task::spawn_blocking(move || node.assert_received("/LT", Duration::from_secs(5))).await.unwrap();
+        running.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn traffic_log_records_both_directions() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("usb.log");
+        let (mut node, path) = PtyHarness::new();
+        let (command_tx, command_rx) = mpsc::channel(8);
+        let (message_tx, mut messages) = mpsc::channel(8);
+        let manager =
+            UsbManager::new(path.to_string_lossy().into_owned(), command_rx, message_tx, Arc::new(UsbStats::default()), UsbProtocol::Line)
+                .with_traffic_log(log_path.clone(), 1024 * 1024);
+        let running = tokio::spawn(manager.run());
+        let handle = UsbHandle::new(command_tx, Arc::new(UsbStats::default()), 8);
+
+        assert!(matches!(timeout(Duration::from_secs(5), messages.recv()).await, Ok(Some(UsbMessage::Connected))));
+        node.emit_line("[INFO] booted");
+        assert!(matches!(timeout(Duration::from_secs(5), messages.recv()).await, Ok(Some(UsbMessage::LineReceived(_)))));
+        handle.send_command("/LT".to_string()).await.unwrap();
+        tokio::task::spawn_blocking(move || node.assert_received("/LT", Duration::from_secs(5))).await.unwrap();
+
+        let mut contents = String::new();
+        for _ in 0..100 {
+            contents = std::fs::read_to_string(&log_path).unwrap_or_default();
+            if contents.lines().count() >= 2 {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        let lines: Vec<&str> = contents.lines().map(|line| line.split_once("] ").unwrap().1).collect();
+        assert_eq!(lines, vec!["RX: [INFO] booted", "TX: /LT"]);
+        assert!(contents.starts_with('['));
+        running.abort();
+    }
+
+    /// A manager on a fresh PTY with the given idle timeout and keepalive
+    fn idle_manager(idle_timeout: Duration, keepalive: Duration) -> (PtyHarness, UsbHandle, mpsc::Receiver<UsbMessage>, Arc<UsbStats>) {
+        let (node, path) = PtyHarness::new();
+        let (command_tx, command_rx) = mpsc::channel(8);
+        let (message_tx, messages) = mpsc::channel(8);
+        let stats = Arc::new(UsbStats::default());
+        let manager = UsbManager::new(path.to_string_lossy().into_owned(), command_rx, message_tx, stats.clone(), UsbProtocol::Line)
+            .with_idle_timeout(idle_timeout, keepalive);
+        tokio::spawn(manager.run());
+        (node, UsbHandle::new(command_tx, stats.clone(), 8), messages, stats)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keepalive_prods_a_quiet_node_and_counts_its_bytes() {
+        let (node, _handle, mut messages, stats) = idle_manager(Duration::ZERO, Duration::from_millis(200));
+        assert!(matches!(timeout(Duration::from_secs(5), messages.recv()).await, Ok(Some(UsbMessage::Connected))));
+
+        tokio::task::spawn_blocking(move || node.assert_received("/KA", Duration::from_secs(5))).await.unwrap();
+        assert_eq!(stats.bytes_sent_total.load(Ordering::Relaxed), "/KA\r\n".len() as u64);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn idle_timeout_reconnects_unless_suspended() {
+        let (_node, handle, mut messages, stats) = idle_manager(Duration::from_millis(300), Duration::ZERO);
+        assert!(matches!(timeout(Duration::from_secs(5), messages.recv()).await, Ok(Some(UsbMessage::Connected))));
+        assert!(matches!(timeout(Duration::from_secs(5), messages.recv()).await, Ok(Some(UsbMessage::Disconnected))));
+        assert!(matches!(timeout(Duration::from_secs(5), messages.recv()).await, Ok(Some(UsbMessage::Connected))));
+        assert_eq!(stats.connection_attempts_total.load(Ordering::Relaxed), 2);
+
+        // A node told to keep quiet is left connected
+        handle.suspend_idle_timeout(true).await.unwrap();
+        assert!(timeout(Duration::from_secs(1), messages.recv()).await.is_err());
+        assert_eq!(stats.connection_attempts_total.load(Ordering::Relaxed), 2);
+
+        handle.suspend_idle_timeout(false).await.unwrap();
+        assert!(matches!(timeout(Duration::from_secs(5), messages.recv()).await, Ok(Some(UsbMessage::Disconnected))));
+    }
+
+    /// A PTY whose device end stays silent until written to; dropping it
+    /// hangs up the port
+    struct QuietPty {
+        device: std::fs::File,
+        _port: std::os::fd::OwnedFd,
+        path: std::path::PathBuf,
+    }
+
+    fn quiet_pty() -> QuietPty {
+        let pty = nix::pty::openpty(None, None).unwrap();
+        let path = nix::unistd::ttyname(&pty.slave).unwrap();
+        QuietPty { device: std::fs::File::from(pty.master), _port: pty.slave, path }
+    }
+
+    /// Hang up the port `link` points at, pointing it at the next one first
+    fn hang_up(link: &std::path::Path, ptys: &mut std::collections::VecDeque<QuietPty>) -> Instant {
+        let current = ptys.pop_front().unwrap();
+        let staged = link.with_extension("new");
+        std::os::unix::fs::symlink(&ptys[0].path, &staged).unwrap();
+        std::fs::rename(&staged, link).unwrap();
+        drop(current);
+        Instant::now()
+    }
+
+    /// When the next `Connected` arrives, with no `Disconnected` before it
+    async fn next_connect(messages: &mut mpsc::Receiver<UsbMessage>) -> Instant {
+        loop {
+            match timeout(Duration::from_secs(10), messages.recv()).await.unwrap() {
+                Some(UsbMessage::Connected) => return Instant::now(),
+                // A hang-up ends the connection without an error
+                Some(UsbMessage::Disconnected) => panic!("hang-up reported as a connection error"),
+                Some(_) => continue,
+                None => panic!("USB manager stopped"),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reconnect_backoff_grows_until_a_connection_delivers_data() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("ttyNODE");
+        let mut ptys: std::collections::VecDeque<QuietPty> = (0..5).map(|_| quiet_pty()).collect();
+        std::os::unix::fs::symlink(&ptys[0].path, &link).unwrap();
+
+        let (_command_tx, command_rx) = mpsc::channel(8);
+        let (message_tx, mut messages) = mpsc::channel(8);
+        let manager =
+            UsbManager::new(link.to_string_lossy().into_owned(), command_rx, message_tx, Arc::new(UsbStats::default()), UsbProtocol::Line);
+        let running = tokio::spawn(manager.run());
+        let within = |gap: Duration, from_ms: u64, to_ms: u64| gap >= Duration::from_millis(from_ms) && gap < Duration::from_millis(to_ms);
+
+        // Two ports that open and hang up without a byte: 1s, then 2s before the next attempt
+        let first = next_connect(&mut messages).await;
+        hang_up(&link, &mut ptys);
+        let second = next_connect(&mut messages).await;
+        hang_up(&link, &mut ptys);
+        let third = next_connect(&mut messages).await;
+        assert!(within(second - first, 900, 1900), "{:?}", second - first);
+        assert!(within(third - second, 1900, 3900), "{:?}", third - second);
+
+        // A port that delivers a line is reopened right away, and the backoff
+        // after the next silent one starts from 1s again
+        ptys[0].device.write_all(b"[INFO] hello\r\n").unwrap();
+        let line = timeout(Duration::from_secs(5), messages.recv()).await.unwrap();
+        assert!(matches!(line, Some(UsbMessage::LineReceived(line)) if line == "[INFO] hello"));
+        let hung_up = hang_up(&link, &mut ptys);
+        let fourth = next_connect(&mut messages).await;
+        assert!(within(fourth - hung_up, 0, 500), "{:?}", fourth - hung_up);
+        hang_up(&link, &mut ptys);
+        let fifth = next_connect(&mut messages).await;
+        assert!(within(fifth - fourth, 900, 1900), "{:?}", fifth - fourth);
+        running.abort();
     }
 }
@@ -0,0 +1,142 @@
+use crate::local_archive;
+use anyhow::Result;
+use chrono::Utc;
+use log::{info, warn};
+use std::path::PathBuf;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+/// Verbatim record of the text sent to and received from the node, for
+/// protocol debugging. The file is open only while the port is connected.
+pub struct UsbTrafficLog {
+    path: PathBuf,
+    /// The file is moved to `<path>.1` once it grows past this
+    max_bytes: u64,
+    file: Option<File>,
+    size: u64,
+}
+
+impl UsbTrafficLog {
+    pub fn new(path: PathBuf, max_bytes: u64) -> Self {
+        Self { path, max_bytes, file: None, size: 0 }
+    }
+
+    /// Open the file for a new connection, rotating it first if it is full
+    pub async fn open(&mut self) {
+        if let Err(e) = self.try_open().await {
+            warn!("Failed to open USB traffic log {:?}: {}", self.path, e);
+        }
+    }
+
+    async fn try_open(&mut self) -> Result<()> {
+        let size = match fs::metadata(&self.path).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        self.size = size;
+        if size > self.max_bytes {
+            local_archive::rotate(&self.path, 1).await?;
+            self.size = 0;
+        }
+        self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path).await?);
+        info!("Recording USB traffic to {:?}", self.path);
+        Ok(())
+    }
+
+    /// Close the file at the end of a connection
+    pub async fn close(&mut self) {
+        if let Some(mut file) = self.file.take() {
+            let _ = file.flush().await;
+        }
+    }
+
+    /// Record a command written to the node
+    pub async fn tx(&mut self, command: &str) {
+        self.record("TX", command).await;
+    }
+
+    /// Record a line received from the node
+    pub async fn rx(&mut self, line: &str) {
+        self.record("RX", line).await;
+    }
+
+    /// Append `[<timestamp>] <direction>: <text>`, rotating once the file is
+    /// full. The log is closed for the rest of the connection if writing fails.
+    async fn record(&mut self, direction: &str, text: &str) {
+        if self.file.is_none() {
+            return;
+        }
+        let line = format!("[{}] {}: {}\n", Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"), direction, text);
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.close().await;
+            if let Err(e) = local_archive::rotate(&self.path, 1).await {
+                warn!("Failed to rotate USB traffic log {:?}: {}", self.path, e);
+            }
+            self.open().await;
+        }
+        let Some(file) = self.file.as_mut() else { return };
+        match file.write_all(line.as_bytes()).await {
+            Ok(()) => self.size += line.len() as u64,
+            Err(e) => {
+                warn!("Failed to write USB traffic log {:?}, closing it: {}", self.path, e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn directions(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| line.split_once("] ").unwrap().1.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn records_only_while_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usb.log");
+        let mut log = UsbTrafficLog::new(path.clone(), 1024);
+
+        log.tx("/LT").await;
+        log.open().await;
+        log.tx("/LT").await;
+        log.rx("[INFO] ok").await;
+        log.close().await;
+        log.rx("[INFO] after close").await;
+
+        assert_eq!(directions(&path), vec!["TX: /LT", "RX: [INFO] ok"]);
+    }
+
+    #[tokio::test]
+    async fn rotates_to_a_single_old_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usb.log");
+        let line_len = "[2024-05-01T12:00:00.000Z] TX: /A\n".len() as u64;
+        let mut log = UsbTrafficLog::new(path.clone(), line_len * 2);
+
+        log.open().await;
+        for command in ["/A", "/B", "/C", "/D", "/E"] {
+            log.tx(command).await;
+        }
+        log.close().await;
+
+        assert_eq!(directions(&path), vec!["TX: /E"]);
+        assert_eq!(directions(&PathBuf::from(format!("{}.1", path.display()))), vec!["TX: /C", "TX: /D"]);
+        assert!(!dir.path().join("usb.log.2").exists());
+
+        // A full file left by an earlier connection is rotated when opened
+        std::fs::write(&path, "x".repeat(line_len as usize * 3)).unwrap();
+        log.open().await;
+        log.rx("[INFO] fresh").await;
+        log.close().await;
+        assert_eq!(directions(&path), vec!["RX: [INFO] fresh"]);
+    }
+}